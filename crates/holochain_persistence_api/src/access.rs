@@ -0,0 +1,355 @@
+//! Capability tokens for handing out scoped access to a manager, e.g. to the semi-trusted
+//! clients of a multi-tenant persistence service.
+//!
+//! `CapabilityPolicies` keeps what every token grants in a key value store: read, or read and
+//! write, access to the addresses starting with a prefix or to the attributes in a namespace.
+//! An `AccessControlledManager` wraps a manager and takes a token with every operation, failing
+//! with `PersistenceError::AccessDenied` if the token doesn't grant it. EAV queries only return
+//! the EAVIs the token can read.
+
+use cas::content::{Address, AddressableContent, Content};
+use eav::{Attribute, EaviQuery, EntityAttributeValueIndex};
+use error::{PersistenceError, PersistenceResult};
+use kv::KvStorage;
+use registry::DynManager;
+use std::{collections::BTreeSet, fmt::Display};
+use uuid::Uuid;
+
+const CAPABILITY_PREFIX: &str = "capability/";
+
+/// The secret a client presents with every operation.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CapabilityToken(pub String);
+
+impl CapabilityToken {
+    /// A new random token.
+    pub fn new() -> CapabilityToken {
+        CapabilityToken(Uuid::new_v4().to_string())
+    }
+}
+
+impl Default for CapabilityToken {
+    fn default() -> CapabilityToken {
+        CapabilityToken::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Access {
+    Read,
+    /// reading included
+    ReadWrite,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// CAS addresses and EAVI entities starting with the prefix
+    AddressPrefix(String),
+    /// EAVIs whose attribute starts with the namespace
+    AttributeNamespace(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    pub access: Access,
+    pub scope: Scope,
+}
+
+impl Grant {
+    pub fn new(access: Access, scope: Scope) -> Grant {
+        Grant { access, scope }
+    }
+}
+
+/// The grants of every token, kept in a key value store.
+#[derive(Clone, Debug)]
+pub struct CapabilityPolicies {
+    kv: Box<dyn KvStorage>,
+}
+
+impl CapabilityPolicies {
+    pub fn new<K: KvStorage + 'static>(kv: K) -> CapabilityPolicies {
+        CapabilityPolicies { kv: Box::new(kv) }
+    }
+
+    fn key(token: &CapabilityToken) -> String {
+        format!("{}{}", CAPABILITY_PREFIX, token.0)
+    }
+
+    /// A new token granting `grants`.
+    pub fn issue(&self, grants: &[Grant]) -> PersistenceResult<CapabilityToken> {
+        let token = CapabilityToken::new();
+        self.kv
+            .put(&Self::key(&token), &serde_json::to_vec(grants)?)?;
+        Ok(token)
+    }
+
+    /// What `token` grants, None if it wasn't issued or was revoked.
+    pub fn grants(&self, token: &CapabilityToken) -> PersistenceResult<Option<Vec<Grant>>> {
+        match self.kv.get(&Self::key(token))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds `grant` to what `token` grants, returning false if the token is unknown.
+    pub fn grant(&self, token: &CapabilityToken, grant: Grant) -> PersistenceResult<bool> {
+        let mut grants = match self.grants(token)? {
+            Some(grants) => grants,
+            None => return Ok(false),
+        };
+        grants.push(grant);
+        self.kv
+            .put(&Self::key(token), &serde_json::to_vec(&grants)?)?;
+        Ok(true)
+    }
+
+    /// Stops honoring `token`, returning whether it was known.
+    pub fn revoke(&self, token: &CapabilityToken) -> PersistenceResult<bool> {
+        self.kv.delete(&Self::key(token))
+    }
+}
+
+fn denied(what: &str) -> PersistenceError {
+    PersistenceError::AccessDenied(format!("capability token doesn't grant {}", what))
+}
+
+/// A manager only operated with a capability token, see the module docs.
+#[derive(Clone, Debug)]
+pub struct AccessControlledManager<M> {
+    manager: M,
+    policies: CapabilityPolicies,
+}
+
+impl<M> AccessControlledManager<M> {
+    pub fn new(manager: M, policies: CapabilityPolicies) -> AccessControlledManager<M> {
+        AccessControlledManager { manager, policies }
+    }
+
+    pub fn policies(&self) -> &CapabilityPolicies {
+        &self.policies
+    }
+
+    /// The grants of `token` giving at least `access`, failing if the token is unknown.
+    fn grants(&self, token: &CapabilityToken, access: Access) -> PersistenceResult<Vec<Scope>> {
+        let grants = self
+            .policies
+            .grants(token)?
+            .ok_or_else(|| denied("anything, it is unknown or revoked"))?;
+        Ok(grants
+            .into_iter()
+            .filter(|grant| grant.access >= access)
+            .map(|grant| grant.scope)
+            .collect())
+    }
+
+    fn allows_address(
+        &self,
+        token: &CapabilityToken,
+        access: Access,
+        address: &Address,
+    ) -> PersistenceResult<()> {
+        let address = address.to_string();
+        let allowed = self.grants(token, access)?.iter().any(|scope| match scope {
+            Scope::AddressPrefix(prefix) => address.starts_with(prefix.as_str()),
+            Scope::AttributeNamespace(_) => false,
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(denied(&format!("{:?} access to {}", access, address)))
+        }
+    }
+
+    pub fn add<A: Attribute>(
+        &self,
+        token: &CapabilityToken,
+        content: &dyn AddressableContent,
+    ) -> PersistenceResult<()>
+    where
+        M: DynManager<A>,
+    {
+        self.allows_address(token, Access::ReadWrite, &content.address())?;
+        self.manager.cas().add(content)
+    }
+
+    pub fn fetch<A: Attribute>(
+        &self,
+        token: &CapabilityToken,
+        address: &Address,
+    ) -> PersistenceResult<Option<Content>>
+    where
+        M: DynManager<A>,
+    {
+        self.allows_address(token, Access::Read, address)?;
+        self.manager.cas().fetch(address)
+    }
+
+    pub fn contains<A: Attribute>(
+        &self,
+        token: &CapabilityToken,
+        address: &Address,
+    ) -> PersistenceResult<bool>
+    where
+        M: DynManager<A>,
+    {
+        self.allows_address(token, Access::Read, address)?;
+        self.manager.cas().contains(address)
+    }
+
+    /// Whether any of `scopes` covers the entity or the attribute of `eavi`.
+    fn covers<A: Attribute + Display>(
+        scopes: &[Scope],
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> bool {
+        let entity = eavi.entity().to_string();
+        let attribute = eavi.attribute().to_string();
+        scopes.iter().any(|scope| match scope {
+            Scope::AddressPrefix(prefix) => entity.starts_with(prefix.as_str()),
+            Scope::AttributeNamespace(namespace) => attribute.starts_with(namespace.as_str()),
+        })
+    }
+
+    pub fn add_eavi<A: Attribute + Display>(
+        &mut self,
+        token: &CapabilityToken,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>>
+    where
+        M: DynManager<A>,
+    {
+        if !Self::covers(&self.grants(token, Access::ReadWrite)?, eavi) {
+            return Err(denied(&format!(
+                "{:?} access to {} or {}",
+                Access::ReadWrite,
+                eavi.entity(),
+                eavi.attribute()
+            )));
+        }
+        self.manager.eav_mut().add_eavi(eavi)
+    }
+
+    /// The EAVIs matching `query` that `token` can read.
+    pub fn fetch_eavi<A: Attribute + Display>(
+        &self,
+        token: &CapabilityToken,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>
+    where
+        M: DynManager<A>,
+    {
+        let scopes = self.grants(token, Access::Read)?;
+        Ok(self
+            .manager
+            .eav()
+            .fetch_eavi(query)?
+            .into_iter()
+            .filter(|eavi| Self::covers(&scopes, eavi))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleEntityAttributeValueStorage, IndexFilter, StringAttribute};
+    use kv::ExampleKvStorage;
+
+    fn everything() -> EaviQuery<'static, StringAttribute> {
+        EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        )
+    }
+
+    fn assert_denied<T: ::std::fmt::Debug>(result: PersistenceResult<T>) {
+        match result {
+            Err(PersistenceError::AccessDenied(_)) => (),
+            other => panic!("expected access to be denied: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tokens_only_reach_what_they_grant() {
+        let policies = CapabilityPolicies::new(ExampleKvStorage::new());
+        let mut manager = AccessControlledManager::new(
+            (
+                test_content_addressable_storage(),
+                ExampleEntityAttributeValueStorage::<StringAttribute>::new(),
+            ),
+            policies.clone(),
+        );
+        let content = Content::from_json("\"tenant data\"");
+        let prefix = content.address().to_string()[..4].to_string();
+        let writer = policies
+            .issue(&[
+                Grant::new(Access::ReadWrite, Scope::AddressPrefix(prefix.clone())),
+                Grant::new(
+                    Access::ReadWrite,
+                    Scope::AttributeNamespace("tenant/".to_string()),
+                ),
+            ])
+            .unwrap();
+        let reader = policies
+            .issue(&[Grant::new(
+                Access::Read,
+                Scope::AttributeNamespace("tenant/".to_string()),
+            )])
+            .unwrap();
+
+        assert_eq!(Ok(()), manager.add(&writer, &content));
+        assert_eq!(
+            Ok(Some(content.clone())),
+            manager.fetch(&writer, &content.address())
+        );
+        assert_denied(manager.add(&reader, &content));
+        assert_denied(manager.contains(&reader, &content.address()));
+        assert_denied(manager.fetch(&CapabilityToken::new(), &content.address()));
+
+        let tenant = EntityAttributeValueIndex::new(
+            &Address::from("elsewhere"),
+            &StringAttribute("tenant/link".to_string()),
+            &content.address(),
+        )
+        .unwrap();
+        let other = EntityAttributeValueIndex::new(
+            &Address::from("elsewhere"),
+            &StringAttribute("other/link".to_string()),
+            &content.address(),
+        )
+        .unwrap();
+        assert!(manager.add_eavi(&writer, &tenant).is_ok());
+        assert_denied(manager.add_eavi(&writer, &other));
+        assert_denied(manager.add_eavi(&reader, &tenant));
+        manager.manager.eav_mut().add_eavi(&other).unwrap();
+        assert_eq!(
+            Ok(vec![tenant]),
+            manager
+                .fetch_eavi(&reader, &everything())
+                .map(|eavis| eavis.into_iter().collect::<Vec<_>>())
+        );
+
+        // grants can be widened and tokens revoked
+        assert_eq!(
+            Ok(true),
+            policies.grant(
+                &reader,
+                Grant::new(Access::Read, Scope::AddressPrefix(prefix))
+            )
+        );
+        assert_eq!(Ok(true), manager.contains(&reader, &content.address()));
+        assert_eq!(Ok(true), policies.revoke(&reader));
+        assert_denied(manager.fetch_eavi(&reader, &everything()));
+        assert_eq!(
+            Ok(false),
+            policies.grant(
+                &reader,
+                Grant::new(Access::Read, Scope::AddressPrefix(String::new()))
+            )
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Bulk writes through a `StorageManager`, staged and checked before any is written.
+//!
+//! `StorageManager::batch` hands out a `WriteBatch` that stages content, EAVIs and removals of
+//! EAVIs. A removal is written as a tombstone: an EAVI with the entity and value of the removed
+//! one under a tombstone attribute, which queries with that attribute as their `tombstone`
+//! return in place of what it removes. `commit` checks the staged writes against the
+//! constraints of the batch, like `constraint::WriteCursor::commit`, and against the removals
+//! being of EAVIs that are stored or staged. Only if none is broken does it write them, the
+//! content first.
+//!
+//! A commit is not atomic. The stores behind a manager share no transaction, so the writes are
+//! made one at a time and a write failing leaves those before it in place. `commit` then fails
+//! with a `PartialCommit` telling which writes were applied.
+
+use cas::content::{Address, AddressableContent};
+use constraint::{Constraint, Rule, StagedContent, StagedView, Violation, ViolationReport};
+use eav::{Attribute, EaviQuery, EntityAttributeValueIndex, IndexFilter};
+use error::{PersistenceError, PersistenceResult};
+use registry::StorageManager;
+use std::sync::Arc;
+
+const REMOVALS: &str = "removals of stored EAVIs";
+
+/// Writes to the stores of a manager staged until they are committed, one at a time.
+pub struct WriteBatch<'m, A: Attribute> {
+    manager: &'m mut StorageManager<A>,
+    content: Vec<StagedContent>,
+    eavis: Vec<EntityAttributeValueIndex<A>>,
+    /// removed EAVIs and the attribute of their tombstones
+    removals: Vec<(EntityAttributeValueIndex<A>, A)>,
+    constraints: Vec<(String, Constraint<A>)>,
+}
+
+/// A commit that failed, with the writes applied before it did, none if a check failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialCommit<A: Attribute> {
+    /// addresses of the staged content written
+    pub content: Vec<Address>,
+    /// staged EAVIs and tombstones written
+    pub eavis: Vec<EntityAttributeValueIndex<A>>,
+    pub error: PersistenceError,
+}
+
+impl<A: Attribute> PartialCommit<A> {
+    pub(crate) fn failed(error: PersistenceError) -> PartialCommit<A> {
+        PartialCommit {
+            content: Vec::new(),
+            eavis: Vec::new(),
+            error,
+        }
+    }
+
+    /// Whether none of the staged writes were applied.
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty() && self.eavis.is_empty()
+    }
+}
+
+impl<A: Attribute> From<PartialCommit<A>> for PersistenceError {
+    fn from(partial: PartialCommit<A>) -> PersistenceError {
+        partial.error
+    }
+}
+
+impl<'m, A: Attribute> WriteBatch<'m, A> {
+    pub(crate) fn new(manager: &'m mut StorageManager<A>) -> WriteBatch<'m, A> {
+        WriteBatch {
+            manager,
+            content: Vec::new(),
+            eavis: Vec::new(),
+            removals: Vec::new(),
+            constraints: Vec::new(),
+        }
+    }
+
+    /// Checks `constraint` on commit, see `WriteCursor::with_constraint`.
+    pub fn with_constraint<F>(mut self, name: &str, constraint: F) -> WriteBatch<'m, A>
+    where
+        F: Fn(&StagedView<A>) -> PersistenceResult<Vec<String>> + Send + Sync + 'static,
+    {
+        self.constraints
+            .push((name.to_string(), Arc::new(constraint)));
+        self
+    }
+
+    /// Checks `rule` on commit.
+    pub fn with_rule(mut self, rule: Rule) -> WriteBatch<'m, A> {
+        self.constraints
+            .push((rule.name().to_string(), rule.constraint()));
+        self
+    }
+
+    /// Stages `content` at its own address, whatever type it has.
+    pub fn add(mut self, content: &dyn AddressableContent) -> WriteBatch<'m, A> {
+        self.content.push(StagedContent::new(content));
+        self
+    }
+
+    pub fn add_eavi(mut self, eavi: &EntityAttributeValueIndex<A>) -> WriteBatch<'m, A> {
+        self.eavis.push(eavi.clone());
+        self
+    }
+
+    /// Removes `eavi` by adding a tombstone for it with the attribute `tombstone`.
+    pub fn remove_eavi(
+        mut self,
+        eavi: &EntityAttributeValueIndex<A>,
+        tombstone: &A,
+    ) -> WriteBatch<'m, A> {
+        self.removals.push((eavi.clone(), tombstone.clone()));
+        self
+    }
+
+    /// Number of staged writes, counting a removal as one.
+    pub fn len(&self) -> usize {
+        self.content.len() + self.eavis.len() + self.removals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The staged EAVIs followed by the tombstones of the staged removals.
+    fn staged_eavis(&self) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        let mut eavis = self.eavis.clone();
+        for (removed, tombstone) in &self.removals {
+            eavis.push(EntityAttributeValueIndex::new(
+                &removed.entity(),
+                tombstone,
+                &removed.value(),
+            )?);
+        }
+        Ok(eavis)
+    }
+
+    fn check_staged(
+        &self,
+        eavis: &[EntityAttributeValueIndex<A>],
+    ) -> PersistenceResult<ViolationReport> {
+        let view = StagedView::new(&*self.manager.cas, &*self.manager.eav, &self.content, eavis);
+        let mut report = view.check(&self.constraints)?;
+        for (removed, _) in &self.removals {
+            if self.eavis.contains(removed) {
+                continue;
+            }
+            let stored = self.manager.eav.fetch_eavi(&EaviQuery::new(
+                Some(removed.entity()).into(),
+                Some(removed.attribute()).into(),
+                Some(removed.value()).into(),
+                IndexFilter::Range(Some(removed.index()), Some(removed.index())),
+                None,
+            ))?;
+            if stored.is_empty() {
+                report.violations.push(Violation {
+                    constraint: REMOVALS.to_string(),
+                    message: format!(
+                        "EAVI {} of {} to remove is neither stored nor staged",
+                        removed.index(),
+                        removed.entity()
+                    ),
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs every check `commit` runs without writing anything.
+    pub fn check(&self) -> PersistenceResult<ViolationReport> {
+        self.check_staged(&self.staged_eavis()?)
+    }
+
+    /// Writes the staged content and then the staged EAVIs and tombstones if no check fails,
+    /// otherwise fails with `PersistenceError::ConstraintViolation` without writing any. The
+    /// writes are made one at a time, not atomically: if one fails, those before it stay
+    /// written and the `PartialCommit` lists them.
+    pub fn commit(mut self) -> Result<(), PartialCommit<A>> {
+        let eavis = self.staged_eavis().map_err(PartialCommit::failed)?;
+        let report = self.check_staged(&eavis).map_err(PartialCommit::failed)?;
+        if !report.is_empty() {
+            return Err(PartialCommit::failed(
+                PersistenceError::ConstraintViolation(report.to_string()),
+            ));
+        }
+        let mut content = Vec::new();
+        for staged in &self.content {
+            if let Err(error) = self.manager.cas.add(staged) {
+                return Err(PartialCommit {
+                    content,
+                    eavis: Vec::new(),
+                    error,
+                });
+            }
+            content.push(staged.address.clone());
+        }
+        let mut written = Vec::new();
+        for eavi in eavis {
+            if let Err(error) = self.manager.eav.add_eavi(&eavi) {
+                return Err(PartialCommit {
+                    content,
+                    eavis: written,
+                    error,
+                });
+            }
+            written.push(eavi);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{content::Content, storage::ExampleContentAddressableStorage};
+    use eav::{ExampleAttribute, ExampleEntityAttributeValueStorage};
+    use limits::{Limited, Limits};
+
+    fn manager() -> StorageManager<ExampleAttribute> {
+        StorageManager::new(
+            ExampleContentAddressableStorage::new().unwrap(),
+            ExampleEntityAttributeValueStorage::new(),
+            Default::default(),
+        )
+    }
+
+    fn link(from: &Content, to: &Content) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new(
+            &from.address(),
+            &ExampleAttribute::WithoutPayload,
+            &to.address(),
+        )
+        .unwrap()
+    }
+
+    fn stored_eavis(manager: &StorageManager<ExampleAttribute>) -> usize {
+        manager
+            .eav
+            .fetch_eavi(&EaviQuery::new(
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                IndexFilter::Range(None, None),
+                None,
+            ))
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn batches_failing_a_check_write_nothing() {
+        let mut manager = manager();
+        let (post, comment) = (
+            Content::from_json("\"post\""),
+            Content::from_json("\"comment\""),
+        );
+        let commented = link(&post, &comment);
+        let batch = manager
+            .batch()
+            .with_rule(Rule::ValuesInCas)
+            .add(&post)
+            .add(&comment)
+            .add_eavi(&commented);
+        assert_eq!(3, batch.len());
+        assert_eq!(Ok(()), batch.commit());
+        assert_eq!(
+            Ok(Some(comment.clone())),
+            manager.cas.fetch(&comment.address())
+        );
+        assert_eq!(1, stored_eavis(&manager));
+
+        // the missing value breaks the rule, so the removal isn't written either
+        let removed = ExampleAttribute::WithPayload("removed".to_string());
+        let missing = Content::from_json("\"missing\"");
+        let partial = manager
+            .batch()
+            .with_rule(Rule::ValuesInCas)
+            .add_eavi(&link(&post, &missing))
+            .remove_eavi(&commented, &removed)
+            .commit()
+            .unwrap_err();
+        assert!(partial.is_empty());
+        match partial.error {
+            PersistenceError::ConstraintViolation(report) => {
+                assert!(report.starts_with("1 constraint violation(s)\nvalues in CAS"))
+            }
+            other => panic!("unexpected commit: {:?}", other),
+        }
+        assert_eq!(1, stored_eavis(&manager));
+
+        // nor can an EAVI that was never added be removed
+        let report = manager
+            .batch()
+            .remove_eavi(&link(&comment, &post), &removed)
+            .check()
+            .unwrap();
+        assert_eq!(
+            vec![REMOVALS],
+            report
+                .violations
+                .iter()
+                .map(|violation| violation.constraint.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            Ok(()),
+            manager.batch().remove_eavi(&commented, &removed).commit()
+        );
+        let tombstones = manager
+            .eav
+            .fetch_eavi(&EaviQuery::new(
+                Some(post.address()).into(),
+                Some(removed).into(),
+                Some(comment.address()).into(),
+                IndexFilter::LatestByAttribute,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(1, tombstones.len());
+    }
+
+    #[test]
+    fn content_is_committed_at_the_address_it_was_added_with() {
+        let mut manager = manager();
+        let custom = StagedContent {
+            address: Address::from("custom"),
+            content: Content::from_json("\"addressed by its type\""),
+        };
+        assert_eq!(Ok(()), manager.batch().add(&custom).commit());
+        assert_eq!(
+            Ok(Some(custom.content.clone())),
+            manager.cas.fetch(&custom.address)
+        );
+        assert_eq!(Ok(false), manager.cas.contains(&custom.content.address()));
+    }
+
+    #[test]
+    fn failed_writes_report_those_applied_before() {
+        let limits = Limits {
+            max_eavis_per_attribute: Some(1),
+            ..Limits::default()
+        };
+        let mut manager = StorageManager::new(
+            ExampleContentAddressableStorage::new().unwrap(),
+            Limited::new(ExampleEntityAttributeValueStorage::new(), limits),
+            Default::default(),
+        );
+        let (post, first, second) = (
+            Content::from_json("\"post\""),
+            Content::from_json("\"first\""),
+            Content::from_json("\"second\""),
+        );
+        let partial = manager
+            .batch()
+            .add(&post)
+            .add_eavi(&link(&post, &first))
+            .add_eavi(&link(&post, &second))
+            .commit()
+            .unwrap_err();
+        match partial.error {
+            PersistenceError::LimitExceeded(_) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+        assert_eq!(vec![post.address()], partial.content);
+        assert_eq!(vec![link(&post, &first)], partial.eavis);
+        assert_eq!(1, stored_eavis(&manager));
+    }
+}
@@ -0,0 +1,118 @@
+//! An in-memory bloom filter that storage backends can keep in front of their `contains()`
+//! so that looking up an address that was never added doesn't have to touch the store.
+//!
+//! A bloom filter can give false positives but never false negatives, so a `false` from
+//! `may_contain` is definitive while a `true` still has to be confirmed against the store.
+//! Entries can't be taken out again; a store that removes content keeps reporting it as a
+//! possible hit until the filter is rebuilt.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::{consts::LN_2, MIN_POSITIVE},
+    hash::{Hash, Hasher},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    items: usize,
+}
+
+impl BloomFilter {
+    /// Sizes the filter so that after `expected_items` insertions the chance of a false
+    /// positive is about `false_positive_rate`.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> BloomFilter {
+        let expected_items = expected_items.max(1) as f64;
+        let false_positive_rate = false_positive_rate.max(MIN_POSITIVE).min(0.5);
+        let num_bits = (-expected_items * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / expected_items) * LN_2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; ((num_bits + 63) / 64) as usize],
+            num_bits,
+            num_hashes,
+            items: 0,
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        for bit in self.bit_indexes(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    /// `false` if the item was definitely never inserted.
+    pub fn may_contain<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.bit_indexes(item)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// number of insertions since the filter was created or last cleared
+    pub fn len(&self) -> usize {
+        self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        self.items = 0;
+    }
+
+    /// double hashing, see Kirsch & Mitzenmacher "Less Hashing, Same Performance"
+    fn bit_indexes<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // feed one more word into the same state to get a second, independent hash
+        0x9e37_79b9_7f4a_7c15_u64.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let num_bits = self.num_bits;
+        (0..u64::from(self.num_hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(&format!("item {}", i));
+        }
+        assert_eq!(1000, bloom.len());
+        for i in 0..1000 {
+            assert!(bloom.may_contain(&format!("item {}", i)));
+        }
+    }
+
+    #[test]
+    fn false_positive_rate_is_close_to_requested() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(&format!("item {}", i));
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.may_contain(&format!("other {}", i)))
+            .count();
+        // 1% of 10000 is 100, leave plenty of room for an unlucky hash
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut bloom = BloomFilter::new(10, 0.01);
+        bloom.insert("foo");
+        assert!(bloom.may_contain("foo"));
+        bloom.clear();
+        assert!(bloom.is_empty());
+        assert!(!bloom.may_contain("foo"));
+    }
+}
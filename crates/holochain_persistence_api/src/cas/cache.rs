@@ -0,0 +1,360 @@
+//! A read cache that can be put in front of any ContentAddressableStorage.
+//!
+//! `CachedCas` keeps the most recently fetched `Content` in a size-bounded LRU so that hot
+//! entries don't have to be read and deserialized from the underlying store on every fetch.
+//! Clones share the same cache, the same way clones of a store share the same data.
+//! `warm` fills the cache ahead of the fetches, e.g. right after a restart.
+
+use crate::{
+    cas::{
+        content::{Address, AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    },
+    eav::{Attribute, EaviQuery, EntityAttributeValueStorage},
+    error::PersistenceResult,
+    reporting::{ReportStorage, StorageReport},
+    warm::{self, Warming},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+/// hit/miss counters of a `CachedCas` since it was created or its stats were last reset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    /// fraction of fetches answered from the cache, 0 if nothing has been fetched yet
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lru {
+    capacity: usize,
+    /// address -> (content, tick of the last access)
+    entries: HashMap<Address, (Content, u64)>,
+    /// tick of the last access -> address, oldest first
+    recency: BTreeMap<u64, Address>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, address: &Address) -> Option<Content> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(address) {
+            Some((content, last_used)) => {
+                self.recency.remove(last_used);
+                *last_used = tick;
+                self.recency.insert(tick, address.clone());
+                Some(content.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, address: Address, content: Content) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&address);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(evicted) = self.recency.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, address.clone());
+        self.entries.insert(address, (content, tick));
+    }
+
+    fn remove(&mut self, address: &Address) {
+        if let Some((_, last_used)) = self.entries.remove(address) {
+            self.recency.remove(&last_used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+/// wraps a ContentAddressableStorage with an LRU cache of up to `capacity` fetched contents
+#[derive(Clone, Debug)]
+pub struct CachedCas<S: ContentAddressableStorage> {
+    inner: S,
+    cache: Arc<Mutex<Lru>>,
+}
+
+impl<S: ContentAddressableStorage> CachedCas<S> {
+    pub fn new(inner: S, capacity: usize) -> CachedCas<S> {
+        CachedCas {
+            inner,
+            cache: Arc::new(Mutex::new(Lru::new(capacity))),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> PersistenceResult<CacheStats> {
+        let cache = self.cache.lock()?;
+        Ok(CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            entries: cache.entries.len(),
+        })
+    }
+
+    pub fn reset_stats(&self) -> PersistenceResult<()> {
+        let mut cache = self.cache.lock()?;
+        cache.hits = 0;
+        cache.misses = 0;
+        Ok(())
+    }
+
+    /// drops a single address from the cache,
+    /// for when content is changed in the underlying store without going through this wrapper
+    pub fn invalidate(&self, address: &Address) -> PersistenceResult<()> {
+        self.cache.lock()?.remove(address);
+        Ok(())
+    }
+
+    pub fn clear(&self) -> PersistenceResult<()> {
+        self.cache.lock()?.clear();
+        Ok(())
+    }
+
+    /// fetches `addresses` into the cache on a background thread without counting hits or
+    /// misses, skipping what is cached already. Only the first `capacity` are fetched, more
+    /// would evict the first ones again
+    pub fn warm(&self, addresses: &[Address]) -> Warming
+    where
+        S: Clone + 'static,
+    {
+        let cas = self.clone();
+        let addresses = addresses.to_vec();
+        Warming::spawn(move || {
+            let capacity = cas.cache.lock()?.capacity;
+            let mut warmed = 0;
+            for address in addresses.iter().take(capacity) {
+                if cas.cache.lock()?.entries.contains_key(address) {
+                    continue;
+                }
+                if let Some(content) = cas.inner.fetch(address)? {
+                    cas.cache.lock()?.insert(address.clone(), content);
+                    warmed += 1;
+                }
+            }
+            Ok(warmed)
+        })
+    }
+
+    /// warms the entities and values of the EAVIs `eav` has for `query`, see `warm`.
+    /// The query itself runs before this returns
+    pub fn warm_query<A: Attribute>(
+        &self,
+        eav: &dyn EntityAttributeValueStorage<A>,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Warming>
+    where
+        S: Clone + 'static,
+    {
+        Ok(self.warm(&warm::query_addresses(eav, query)?))
+    }
+}
+
+impl<S: ContentAddressableStorage + Clone + 'static> ContentAddressableStorage for CachedCas<S> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        self.cache.lock()?.remove(&content.address());
+        self.inner.add(content)
+    }
+
+    fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        if self.cache.lock()?.entries.contains_key(address) {
+            return Ok(true);
+        }
+        self.inner.contains(address)
+    }
+
+    fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        {
+            let mut cache = self.cache.lock()?;
+            if let Some(content) = cache.get(address) {
+                cache.hits += 1;
+                return Ok(Some(content));
+            }
+            cache.misses += 1;
+        }
+        // don't hold the cache lock while reading the underlying store
+        let fetched = self.inner.fetch(address)?;
+        if let Some(content) = &fetched {
+            self.cache.lock()?.insert(address.clone(), content.clone());
+        }
+        Ok(fetched)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.inner.get_id()
+    }
+}
+
+impl<S: ContentAddressableStorage> ReportStorage for CachedCas<S> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.inner.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{ExampleAddressableContent, OtherExampleAddressableContent},
+        storage::{test_content_addressable_storage, StorageTestSuite},
+    };
+    use eav::{EntityAttributeValueIndex, ExampleAttribute, ExampleEntityAttributeValueStorage};
+    use holochain_json_api::json::{JsonString, RawString};
+
+    fn content(s: &'static str) -> Content {
+        JsonString::from(RawString::from(s))
+    }
+
+    #[test]
+    fn cached_cas_round_trip() {
+        let cas = CachedCas::new(test_content_addressable_storage(), 10);
+        let test_suite = StorageTestSuite::new(cas);
+        test_suite.round_trip_test::<ExampleAddressableContent, OtherExampleAddressableContent>(
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
+    #[test]
+    fn cached_cas_counts_hits_and_misses() {
+        let cas = CachedCas::new(test_content_addressable_storage(), 10);
+        let entry = content("foo");
+        cas.add(&entry).unwrap();
+
+        assert_eq!(Ok(Some(entry.clone())), cas.fetch(&entry.address()));
+        assert_eq!(Ok(Some(entry.clone())), cas.fetch(&entry.address()));
+        assert_eq!(Ok(Some(entry.clone())), cas.fetch(&entry.address()));
+        assert_eq!(Ok(None), cas.fetch(&content("bar").address()));
+
+        let stats = cas.stats().unwrap();
+        assert_eq!(
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                entries: 1
+            },
+            stats
+        );
+        assert!((stats.hit_rate() - 0.5).abs() < ::std::f64::EPSILON);
+    }
+
+    #[test]
+    fn cached_cas_evicts_least_recently_used() {
+        let cas = CachedCas::new(test_content_addressable_storage(), 2);
+        let (a, b, c) = (content("a"), content("b"), content("c"));
+        for item in &[&a, &b, &c] {
+            cas.add(*item).unwrap();
+        }
+
+        cas.fetch(&a.address()).unwrap();
+        cas.fetch(&b.address()).unwrap();
+        // touch a so b becomes the oldest
+        cas.fetch(&a.address()).unwrap();
+        cas.fetch(&c.address()).unwrap();
+        cas.reset_stats().unwrap();
+
+        cas.fetch(&a.address()).unwrap();
+        cas.fetch(&c.address()).unwrap();
+        cas.fetch(&b.address()).unwrap();
+        let stats = cas.stats().unwrap();
+        assert_eq!((2, 1, 2), (stats.hits, stats.misses, stats.entries));
+    }
+
+    #[test]
+    fn warmed_entries_are_cache_hits() {
+        let cas = CachedCas::new(test_content_addressable_storage(), 2);
+        let (a, b, c) = (content("a"), content("b"), content("c"));
+        for item in &[&a, &b, &c] {
+            cas.add(*item).unwrap();
+        }
+        let mut eav = ExampleEntityAttributeValueStorage::new();
+        let link = EntityAttributeValueIndex::new(
+            &a.address(),
+            &ExampleAttribute::default(),
+            &b.address(),
+        )
+        .unwrap();
+        eav.add_eavi(&link).unwrap();
+
+        // a and b from the query, c is beyond the capacity
+        assert_eq!(
+            Ok(2),
+            cas.warm_query(&eav, &EaviQuery::default()).unwrap().wait()
+        );
+        assert_eq!(
+            Ok(0),
+            cas.warm(&[a.address(), b.address(), c.address()]).wait()
+        );
+        assert_eq!(
+            CacheStats {
+                hits: 0,
+                misses: 0,
+                entries: 2
+            },
+            cas.stats().unwrap()
+        );
+        cas.fetch(&a.address()).unwrap();
+        cas.fetch(&b.address()).unwrap();
+        assert_eq!(2, cas.stats().unwrap().hits);
+    }
+
+    #[test]
+    fn add_invalidates_cached_entry() {
+        let cas = CachedCas::new(test_content_addressable_storage(), 10);
+        let entry = content("foo");
+        cas.add(&entry).unwrap();
+        cas.fetch(&entry.address()).unwrap();
+        assert_eq!(1, cas.stats().unwrap().entries);
+
+        cas.add(&entry).unwrap();
+        assert_eq!(0, cas.stats().unwrap().entries);
+    }
+}
@@ -7,7 +7,11 @@ use crate::{cas::storage::ContentAddressableStorage, hash::HashString};
 use holochain_json_api::{error::JsonError, json::*};
 
 use multihash::Hash;
-use std::fmt::{Debug, Write};
+use std::{
+    any::type_name,
+    fmt::{Debug, Write},
+    ops::Deref,
+};
 
 /// an Address for some Content
 /// ideally would be the Content but pragmatically must be Address
@@ -53,6 +57,57 @@ impl AddressableContent for Content {
     }
 }
 
+/// Content known to hold a `T`, any serde type such as one deriving `DefaultJson`. It is stored
+/// as the JSON of the `T`, so it has the same address as the value itself, and converted back
+/// once when fetched instead of at every use.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypedContent<T> {
+    value: T,
+}
+
+impl<T: serde::Serialize + serde::de::DeserializeOwned + Debug> TypedContent<T> {
+    pub fn new(value: T) -> TypedContent<T> {
+        TypedContent { value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// the type the content holds, for error messages
+    pub fn type_name() -> &'static str {
+        type_name::<T>()
+    }
+}
+
+impl<T> Deref for TypedContent<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: serde::Serialize + serde::de::DeserializeOwned + Debug> AddressableContent
+    for TypedContent<T>
+{
+    fn content(&self) -> Content {
+        default_to_json(&self.value)
+    }
+
+    fn try_from_content(content: &Content) -> Result<Self, JsonError> {
+        default_try_from_json(content.clone())
+            .map(TypedContent::new)
+            .map_err(|e| {
+                JsonError::SerializationError(format!(
+                    "content is not a {}: {}",
+                    Self::type_name(),
+                    e
+                ))
+            })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 /// some struct that can be content addressed
 /// imagine an Entry, ChainHeader, Meta Value, etc.
@@ -138,7 +193,7 @@ impl AddressableContentTestSuite {
         );
     }
 
-    pub fn addressable_content_round_trip<T, K>(contents: Vec<T>, mut cas: K)
+    pub fn addressable_content_round_trip<T, K>(contents: Vec<T>, cas: K)
     where
         T: AddressableContent + PartialEq + Clone + Debug,
         K: ContentAddressableStorage,
@@ -0,0 +1,367 @@
+//! Delta encoding of content that only differs a little from content already stored, e.g. the
+//! versions of an updated entry.
+//!
+//! `DeltaCas` wraps any ContentAddressableStorage. Content added with `add_with_base` is stored
+//! at its own address as the bytes that differ from the content at the base address, which are
+//! put back together on fetch. Content added without a base, or for which the delta isn't
+//! smaller, is stored whole as before. A delta is stored as JSON of the form
+//! `{"__delta":{"base":...,"prefix":...,"suffix":...,"middle":...}}`, so content of exactly that
+//! form can't be stored whole in a `DeltaCas`.
+//!
+//! Deltas can be based on deltas, up to `MAX_CHAIN` of them. Content that would be based on
+//! itself, directly or along a chain, or would make a chain any longer is stored whole. A chain
+//! that loops or is longer anyway can only come from corrupt data and fails to be fetched with
+//! `PersistenceError::Corruption`.
+
+use crate::{
+    cas::{
+        content::{Address, AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    },
+    error::{PersistenceError, PersistenceResult},
+    reporting::{ReportStorage, StorageReport},
+};
+use holochain_json_api::{error::JsonError, json::JsonString};
+use uuid::Uuid;
+
+const DELTA_MARKER: &str = "{\"__delta\":";
+/// deltas followed from an address to the content stored whole
+pub const MAX_CHAIN: usize = 64;
+
+/// The content is the `prefix` first and `suffix` last bytes of the base with `middle` between.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Delta {
+    base: Address,
+    prefix: usize,
+    suffix: usize,
+    middle: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StoredDelta {
+    #[serde(rename = "__delta")]
+    delta: Delta,
+}
+
+impl Delta {
+    fn new(base_address: &Address, base: &str, content: &str) -> Delta {
+        let (base_bytes, bytes) = (base.as_bytes(), content.as_bytes());
+        let mut prefix = base_bytes
+            .iter()
+            .zip(bytes)
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !content.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        let mut suffix = base_bytes[prefix..]
+            .iter()
+            .rev()
+            .zip(bytes[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !content.is_char_boundary(bytes.len() - suffix) {
+            suffix -= 1;
+        }
+        Delta {
+            base: base_address.clone(),
+            prefix,
+            suffix,
+            middle: content[prefix..bytes.len() - suffix].to_string(),
+        }
+    }
+
+    fn apply(&self, base: &str) -> PersistenceResult<String> {
+        let base = base.as_bytes();
+        if self.prefix + self.suffix > base.len() {
+            return Err(PersistenceError::Corruption(format!(
+                "delta doesn't fit its base {}",
+                self.base
+            )));
+        }
+        let mut bytes = base[..self.prefix].to_vec();
+        bytes.extend_from_slice(self.middle.as_bytes());
+        bytes.extend_from_slice(&base[base.len() - self.suffix..]);
+        String::from_utf8(bytes).map_err(|e| {
+            PersistenceError::Corruption(format!("delta on {} error: {}", self.base, e))
+        })
+    }
+}
+
+/// A delta stored at the address of the content it stands for.
+struct DeltaContent {
+    address: Address,
+    content: Content,
+}
+
+impl AddressableContent for DeltaContent {
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(_content: &Content) -> Result<DeltaContent, JsonError> {
+        Err(JsonError::ErrorGeneric(
+            "a delta has no address of its own".to_string(),
+        ))
+    }
+}
+
+/// wraps a ContentAddressableStorage to store content as deltas on other content
+#[derive(Clone, Debug)]
+pub struct DeltaCas<S: ContentAddressableStorage> {
+    inner: S,
+}
+
+impl<S: ContentAddressableStorage + Clone + 'static> DeltaCas<S> {
+    pub fn new(inner: S) -> DeltaCas<S> {
+        DeltaCas { inner }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Adds `content` as a delta on the content at `base`, or whole if there is no content at
+    /// `base`, the delta wouldn't be smaller or the content would end up based on itself.
+    /// Returns whether it was stored as a delta.
+    pub fn add_with_base(
+        &self,
+        content: &dyn AddressableContent,
+        base: &Address,
+    ) -> PersistenceResult<bool> {
+        let address = content.address();
+        let base_content = match self.resolve(base)? {
+            Some((base_content, chain))
+                if !chain.contains(&address) && chain.len() <= MAX_CHAIN =>
+            {
+                base_content
+            }
+            // nothing to base it on, or it would be based on itself or make the chain too long
+            _ => {
+                self.inner.add(content)?;
+                return Ok(false);
+            }
+        };
+        let whole = String::from(content.content());
+        let delta = serde_json::to_string(&StoredDelta {
+            delta: Delta::new(base, &base_content, &whole),
+        })?;
+        if delta.len() >= whole.len() {
+            self.inner.add(content)?;
+            return Ok(false);
+        }
+        self.inner.add(&DeltaContent {
+            address,
+            content: JsonString::from_json(&delta),
+        })?;
+        Ok(true)
+    }
+
+    /// The content at `address` put back together and the addresses of the chain of deltas it
+    /// was put together from, starting with `address` and ending with the content stored whole.
+    /// None if there is nothing at `address`.
+    fn resolve(&self, address: &Address) -> PersistenceResult<Option<(String, Vec<Address>)>> {
+        let mut chain = vec![address.clone()];
+        let mut deltas: Vec<Delta> = Vec::new();
+        loop {
+            let at = &chain[chain.len() - 1];
+            let stored = match self.inner.fetch(at)? {
+                Some(stored) => String::from(stored),
+                None if deltas.is_empty() => return Ok(None),
+                None => {
+                    return Err(PersistenceError::Corruption(format!(
+                        "base {} of the delta at {} is missing",
+                        at,
+                        chain[chain.len() - 2]
+                    )))
+                }
+            };
+            let delta = match Self::delta(&stored) {
+                Some(delta) => delta,
+                None => {
+                    let mut content = stored;
+                    for delta in deltas.iter().rev() {
+                        content = delta.apply(&content)?;
+                    }
+                    return Ok(Some((content, chain)));
+                }
+            };
+            if chain.contains(&delta.base) {
+                return Err(PersistenceError::Corruption(format!(
+                    "the deltas from {} loop back to {}",
+                    address, delta.base
+                )));
+            }
+            if deltas.len() >= MAX_CHAIN {
+                return Err(PersistenceError::Corruption(format!(
+                    "the deltas from {} are more than {} long",
+                    address, MAX_CHAIN
+                )));
+            }
+            chain.push(delta.base.clone());
+            deltas.push(delta);
+        }
+    }
+
+    /// The base of the delta stored at `address`, None if the content there is stored whole.
+    pub fn base_of(&self, address: &Address) -> PersistenceResult<Option<Address>> {
+        Ok(match self.inner.fetch(address)? {
+            Some(stored) => Self::delta(&String::from(stored)).map(|delta| delta.base),
+            None => None,
+        })
+    }
+
+    /// The delta `stored` is, None if it is content stored whole, even looking like a delta.
+    fn delta(stored: &str) -> Option<Delta> {
+        if !stored.starts_with(DELTA_MARKER) {
+            return None;
+        }
+        serde_json::from_str::<StoredDelta>(stored)
+            .ok()
+            .map(|stored| stored.delta)
+    }
+}
+
+impl<S: ContentAddressableStorage + Clone + 'static> ContentAddressableStorage for DeltaCas<S> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        self.inner.add(content)
+    }
+
+    fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        self.inner.contains(address)
+    }
+
+    fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        Ok(self
+            .resolve(address)?
+            .map(|(content, _)| JsonString::from_json(&content)))
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.inner.get_id()
+    }
+}
+
+impl<S: ContentAddressableStorage> ReportStorage for DeltaCas<S> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.inner.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{ExampleAddressableContent, OtherExampleAddressableContent},
+        storage::{test_content_addressable_storage, StorageTestSuite},
+    };
+    use holochain_json_api::json::RawString;
+
+    #[test]
+    fn delta_cas_round_trip() {
+        let cas = DeltaCas::new(test_content_addressable_storage());
+        let test_suite = StorageTestSuite::new(cas);
+        test_suite.round_trip_test::<ExampleAddressableContent, OtherExampleAddressableContent>(
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
+    #[test]
+    fn similar_content_is_stored_as_deltas() {
+        let cas = DeltaCas::new(test_content_addressable_storage());
+        let profile = |name: &str| {
+            Content::from_json(&format!(
+                "{{\"name\":\"{}\",\"bio\":\"{}\"}}",
+                name,
+                "keeps bees and writes about them ".repeat(4)
+            ))
+        };
+        let (first, second, third) = (profile("Ann"), profile("Anne"), profile("Annë"));
+        cas.add(&first).unwrap();
+        assert_eq!(Ok(true), cas.add_with_base(&second, &first.address()));
+        // deltas can be based on deltas
+        assert_eq!(Ok(true), cas.add_with_base(&third, &second.address()));
+
+        assert_eq!(Ok(Some(third.clone())), cas.fetch(&third.address()));
+        assert_eq!(Ok(Some(second.clone())), cas.fetch(&second.address()));
+        assert_eq!(Ok(Some(second.address())), cas.base_of(&third.address()));
+        assert_eq!(Ok(None), cas.base_of(&first.address()));
+        let stored = String::from(cas.inner().fetch(&third.address()).unwrap().unwrap());
+        assert!(stored.len() < String::from(third).len());
+
+        // content with nothing in common, or without a base, is stored whole
+        let other = Content::from_json("\"something else\"");
+        assert_eq!(Ok(false), cas.add_with_base(&other, &first.address()));
+        let orphan = Content::from_json("\"orphan\"");
+        assert_eq!(
+            Ok(false),
+            cas.add_with_base(&orphan, &Address::from("missing"))
+        );
+        assert_eq!(Ok(Some(orphan.clone())), cas.fetch(&orphan.address()));
+    }
+
+    #[test]
+    fn content_is_never_based_on_itself() {
+        let cas = DeltaCas::new(test_content_addressable_storage());
+        let version = |n: usize| {
+            Content::from_json(&format!(
+                "{{\"version\":{},\"bio\":\"{}\"}}",
+                n,
+                "keeps bees and writes about them ".repeat(4)
+            ))
+        };
+        let (first, second) = (version(1), version(2));
+        cas.add(&first).unwrap();
+        assert_eq!(Ok(false), cas.add_with_base(&first, &first.address()));
+        assert_eq!(Ok(true), cas.add_with_base(&second, &first.address()));
+        // basing the first on the second would loop
+        assert_eq!(Ok(false), cas.add_with_base(&first, &second.address()));
+        assert_eq!(Ok(Some(first.clone())), cas.fetch(&first.address()));
+        assert_eq!(Ok(Some(second.clone())), cas.fetch(&second.address()));
+
+        // chains stop growing at MAX_CHAIN deltas
+        let mut base = second.address();
+        for n in 3..MAX_CHAIN + 4 {
+            let next = version(n);
+            cas.add_with_base(&next, &base).unwrap();
+            assert_eq!(Ok(Some(next.clone())), cas.fetch(&next.address()));
+            base = next.address();
+        }
+        assert_eq!(Ok(None), cas.base_of(&version(MAX_CHAIN + 2).address()));
+    }
+
+    #[test]
+    fn looping_deltas_are_corrupt() {
+        let cas = DeltaCas::new(test_content_addressable_storage());
+        let looping = |address: &str, base: &str| DeltaContent {
+            address: Address::from(address),
+            content: JsonString::from_json(
+                &serde_json::to_string(&StoredDelta {
+                    delta: Delta {
+                        base: Address::from(base),
+                        prefix: 0,
+                        suffix: 0,
+                        middle: String::new(),
+                    },
+                })
+                .unwrap(),
+            ),
+        };
+        cas.inner().add(&looping("a", "a")).unwrap();
+        cas.inner().add(&looping("b", "c")).unwrap();
+        cas.inner().add(&looping("c", "b")).unwrap();
+        for address in &["a", "b"] {
+            match cas.fetch(&Address::from(*address)) {
+                Err(PersistenceError::Corruption(_)) => (),
+                other => panic!("expected a corrupt chain, got {:?}", other),
+            }
+        }
+    }
+}
@@ -1,5 +1,9 @@
 //! This module contains trait definitions, examples, and test suites for AddressableContent
 //! and ContentAddressableStorage.
 
+pub mod bloom;
+pub mod cache;
 pub mod content;
+pub mod delta;
 pub mod storage;
+pub mod stream;
@@ -4,15 +4,18 @@
 //! A test suite for CAS is also implemented here.
 
 use crate::{
-    cas::content::{Address, AddressableContent, Content, ExampleAddressableContent},
+    cas::{
+        content::{Address, AddressableContent, Content, ExampleAddressableContent, TypedContent},
+        stream::{chunk_content, failed_stream, ContentChunks},
+    },
     eav::{
-        Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
-        IndexFilter,
+        Attribute, AttributeHistogram, AttributeUsage, EavFilter, EaviQuery,
+        EntityAttributeValueIndex, EntityAttributeValueStorage, IndexFilter, OrderBy,
     },
     error::{PersistenceError, PersistenceResult},
     holochain_json_api::{
         error::JsonError,
-        json::{JsonString, RawString},
+        json::{default_to_json, JsonString, RawString},
     },
     regex::Regex,
     reporting::ReportStorage,
@@ -32,7 +35,9 @@ use uuid::Uuid;
 /// CAS is append only
 pub trait ContentAddressableStorage: objekt::Clone + Send + Sync + Debug + ReportStorage {
     /// adds AddressableContent to the ContentAddressableStorage by its Address as Content
-    fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()>;
+    /// takes `&self` like the reads, so a store can be shared behind an `Arc`, implementations
+    /// synchronize their writes themselves
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()>;
     /// true if the Address is in the Store, false otherwise.
     /// may be more efficient than retrieve depending on the implementation.
     fn contains(&self, address: &Address) -> PersistenceResult<bool>;
@@ -40,6 +45,20 @@ pub trait ContentAddressableStorage: objekt::Clone + Send + Sync + Debug + Repor
     /// AddressableContent::from_content() can be used to allow the compiler to infer the type
     /// @see the fetch implementation for ExampleCas in the cas module tests
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>>;
+    /// The JSON of the content at `address` in chunks of at most `chunk_size` bytes, so it can
+    /// be sent on as it is read. Yields a single error if there is no content at `address`.
+    /// Stores that can read content piecemeal override this, by default the content is fetched
+    /// whole and then chunked.
+    fn stream_content(&self, address: &Address, chunk_size: usize) -> ContentChunks {
+        match self.fetch(address) {
+            Ok(Some(content)) => chunk_content(&content, chunk_size),
+            Ok(None) => failed_stream(PersistenceError::from(format!(
+                "no content at {} to stream",
+                address
+            ))),
+            Err(e) => failed_stream(e),
+        }
+    }
     //needed to find a way to compare two different CAS for partialord derives.
     //easiest solution was to just compare two ids which are based on uuids
     fn get_id(&self) -> Uuid;
@@ -47,6 +66,44 @@ pub trait ContentAddressableStorage: objekt::Clone + Send + Sync + Debug + Repor
 
 clone_trait_object!(ContentAddressableStorage);
 
+/// Adding and fetching values of a type rather than JSON, for any CAS including trait objects.
+pub trait TypedContentStorage {
+    /// Adds the JSON of `value`, returning the address it is stored at.
+    fn add_typed<T: serde::Serialize + serde::de::DeserializeOwned + Debug>(
+        &self,
+        value: &T,
+    ) -> PersistenceResult<Address>;
+
+    /// The content at `address` as a `T`, an error if it is something else.
+    fn fetch_typed<T: serde::Serialize + serde::de::DeserializeOwned + Debug>(
+        &self,
+        address: &Address,
+    ) -> PersistenceResult<Option<TypedContent<T>>>;
+}
+
+impl<S: ContentAddressableStorage + ?Sized> TypedContentStorage for S {
+    fn add_typed<T: serde::Serialize + serde::de::DeserializeOwned + Debug>(
+        &self,
+        value: &T,
+    ) -> PersistenceResult<Address> {
+        let content = default_to_json(value);
+        self.add(&content)?;
+        Ok(content.address())
+    }
+
+    fn fetch_typed<T: serde::Serialize + serde::de::DeserializeOwned + Debug>(
+        &self,
+        address: &Address,
+    ) -> PersistenceResult<Option<TypedContent<T>>> {
+        match self.fetch(address)? {
+            Some(content) => Ok(Some(TypedContent::try_from_content(&content).map_err(
+                |e| PersistenceError::from(format!("content at {} error: {}", address, e)),
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
 impl PartialEq for dyn ContentAddressableStorage {
     fn eq(&self, other: &dyn ContentAddressableStorage) -> bool {
         self.get_id() == other.get_id()
@@ -74,7 +131,7 @@ pub fn test_content_addressable_storage() -> ExampleContentAddressableStorage {
 }
 
 impl ContentAddressableStorage for ExampleContentAddressableStorage {
-    fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
         self.content
             .write()
             .unwrap()
@@ -152,7 +209,7 @@ where
 
     // does round trip test that can infer two Addressable Content Types
     pub fn round_trip_test<Addressable, OtherAddressable>(
-        mut self,
+        self,
         content: Content,
         other_content: Content,
     ) where
@@ -635,6 +692,318 @@ impl EavTestSuite {
         assert_eq!(&new_eavi.unwrap().unwrap(), results.iter().last().unwrap())
     }
 
+    pub fn test_ordering<A, AT: Attribute, S>(mut eav_storage: S, attributes: Vec<AT>)
+    where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT>,
+    {
+        let one = A::try_from_content(&Content::from(RawString::from("foo")))
+            .expect("could not create AddressableContent from Content");
+        let two = A::try_from_content(&Content::from(RawString::from("bar")))
+            .expect("could not create AddressableContent from Content");
+        let entities = vec![one.address(), two.address()];
+        let mut all = BTreeSet::new();
+
+        // indexes with different numbers of digits, added out of order
+        for (i, index) in [9, 1000, 10, 99, 100, 11].iter().enumerate() {
+            let eav = EntityAttributeValueIndex::new_with_index(
+                &entities[i % 2],
+                &attributes[i % attributes.len()],
+                &one.address(),
+                *index,
+            )
+            .expect("could not create EAV");
+            all.insert(
+                eav_storage
+                    .add_eavi(&eav)
+                    .expect("could not add eav")
+                    .expect("Could not get eavi option"),
+            );
+        }
+        let query = |entity: Option<Address>| {
+            EaviQuery::new(
+                entity.into(),
+                Default::default(),
+                Default::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+        };
+
+        // latest N
+        let latest = eav_storage
+            .fetch_eavi_ordered(
+                &query(None)
+                    .with_order_by(OrderBy::IndexDescending)
+                    .with_limit(3),
+            )
+            .unwrap();
+        assert_eq!(
+            vec![1000, 100, 99],
+            latest.iter().map(|eavi| eavi.index()).collect::<Vec<_>>()
+        );
+        let latest_of_one = eav_storage
+            .fetch_eavi_ordered(
+                &query(Some(one.address()))
+                    .with_order_by(OrderBy::IndexDescending)
+                    .with_limit(2),
+            )
+            .unwrap();
+        assert_eq!(
+            all.iter()
+                .rev()
+                .filter(|eavi| eavi.entity() == one.address())
+                .take(2)
+                .cloned()
+                .collect::<Vec<_>>(),
+            latest_of_one
+        );
+
+        // the default order is the order of fetch_eavi
+        assert_eq!(
+            all.iter().cloned().collect::<Vec<_>>(),
+            eav_storage.fetch_eavi_ordered(&query(None)).unwrap()
+        );
+
+        // ties keep index order
+        let by_entity = eav_storage
+            .fetch_eavi_ordered(&query(None).with_order_by(OrderBy::Entity))
+            .unwrap();
+        let mut expected: Vec<_> = all.iter().cloned().collect();
+        expected.sort_by_key(|eavi| eavi.entity());
+        assert_eq!(expected, by_entity);
+        let by_attribute = eav_storage
+            .fetch_eavi_ordered(&query(None).with_order_by(OrderBy::Attribute))
+            .unwrap();
+        expected.sort_by_key(|eavi| (eavi.attribute(), eavi.index()));
+        assert_eq!(expected, by_attribute);
+    }
+
+    pub fn test_distinct<A, AT: Attribute, S>(mut eav_storage: S, attributes: Vec<AT>)
+    where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT>,
+    {
+        let addresses: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|s| {
+                A::try_from_content(&Content::from(RawString::from(*s)))
+                    .expect("could not create AddressableContent from Content")
+                    .address()
+            })
+            .collect();
+        let (a, b, c) = (&addresses[0], &addresses[1], &addresses[2]);
+
+        // a links to b three times with the first attribute, and to c with the second one,
+        // b links to c
+        for (entity, attribute, value) in &[
+            (a, &attributes[0], b),
+            (a, &attributes[0], b),
+            (a, &attributes[1], c),
+            (a, &attributes[0], b),
+            (b, &attributes[0], c),
+        ] {
+            let eav = EntityAttributeValueIndex::new(entity, *attribute, value)
+                .expect("could not create EAV");
+            eav_storage.add_eavi(&eav).expect("could not add eav");
+        }
+        let query = |entity: Option<&Address>, attribute: Option<&AT>, value: Option<&Address>| {
+            EaviQuery::new(
+                entity.cloned().into(),
+                attribute.cloned().into(),
+                value.cloned().into(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+        };
+        let set = |addresses: Vec<&Address>| addresses.into_iter().cloned().collect();
+
+        assert_eq!(
+            Ok(set(vec![b, c])),
+            eav_storage.fetch_distinct_values(&query(Some(a), None, None))
+        );
+        assert_eq!(
+            Ok(set(vec![b])),
+            eav_storage.fetch_distinct_values(&query(Some(a), Some(&attributes[0]), None))
+        );
+        assert_eq!(
+            Ok(set(vec![b, c])),
+            eav_storage.fetch_distinct_values(&query(None, Some(&attributes[0]), None))
+        );
+        assert_eq!(
+            Ok(set(vec![a, b])),
+            eav_storage.fetch_distinct_entities(&query(None, None, Some(c)))
+        );
+        assert_eq!(
+            Ok(set(vec![a])),
+            eav_storage.fetch_distinct_entities(&query(None, Some(&attributes[1]), None))
+        );
+        assert_eq!(
+            Ok(BTreeSet::new()),
+            eav_storage.fetch_distinct_entities(&query(Some(c), None, None))
+        );
+    }
+
+    pub fn test_upsert<A, AT: Attribute, S>(mut eav_storage: S, attributes: Vec<AT>)
+    where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT>,
+    {
+        let addresses: Vec<_> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|s| {
+                A::try_from_content(&Content::from(RawString::from(*s)))
+                    .expect("could not create AddressableContent from Content")
+                    .address()
+            })
+            .collect();
+        let (single, other) = (&attributes[0], &attributes[1]);
+        let add = |eav_storage: &mut S, entity: &Address, attribute: &AT, value: &Address| {
+            let eav = EntityAttributeValueIndex::new(entity, attribute, value)
+                .expect("could not create EAV");
+            eav_storage
+                .add_eavi(&eav)
+                .expect("could not add eav")
+                .expect("Could not get eavi option")
+        };
+        let kept = vec![
+            add(&mut eav_storage, &addresses[0], other, &addresses[1]),
+            add(&mut eav_storage, &addresses[1], single, &addresses[1]),
+        ];
+        add(&mut eav_storage, &addresses[0], single, &addresses[1]);
+        add(&mut eav_storage, &addresses[0], single, &addresses[2]);
+
+        let eav = EntityAttributeValueIndex::new(&addresses[0], single, &addresses[3])
+            .expect("could not create EAV");
+        let upserted = eav_storage
+            .upsert_eavi(&eav)
+            .expect("could not upsert eav")
+            .expect("Could not get eavi option");
+
+        let all = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let mut expected: BTreeSet<_> = kept.into_iter().collect();
+        expected.insert(upserted);
+        assert_eq!(expected, eav_storage.fetch_eavi(&all).unwrap());
+        // the replaced values are gone from lookups by value too
+        assert_eq!(
+            Ok(BTreeSet::new()),
+            eav_storage.fetch_distinct_entities(&EaviQuery::new(
+                Default::default(),
+                Some(single.clone()).into(),
+                Some(addresses[2].clone()).into(),
+                IndexFilter::Range(None, None),
+                None,
+            ))
+        );
+    }
+
+    pub fn test_iter<A, AT: Attribute, S>(mut eav_storage: S, attribute: AT)
+    where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT> + 'static,
+    {
+        let one = A::try_from_content(&Content::from(RawString::from("a")))
+            .expect("could not create AddressableContent from Content")
+            .address();
+        for _ in 0..3 {
+            let eav = EntityAttributeValueIndex::new(&one, &attribute, &one)
+                .expect("could not create EAV");
+            eav_storage.add_eavi(&eav).expect("could not add eav");
+        }
+        let query = EaviQuery::new(
+            Some(one).into(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        )
+        .with_order_by(OrderBy::IndexDescending)
+        .with_limit(2);
+        let expected = eav_storage.fetch_eavi_ordered(&query).unwrap();
+        assert_eq!(2, expected.len());
+
+        // the cursor works the same through a trait object
+        let boxed: Box<dyn EntityAttributeValueStorage<AT>> = Box::new(eav_storage);
+        let iterated: PersistenceResult<Vec<_>> = boxed.iter_eavi(&query).collect();
+        assert_eq!(Ok(expected), iterated);
+    }
+
+    pub fn test_entities_with_attribute<A, AT: Attribute, S>(
+        mut eav_storage: S,
+        attributes: Vec<AT>,
+    ) where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT>,
+    {
+        let addresses: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|s| {
+                A::try_from_content(&Content::from(RawString::from(*s)))
+                    .expect("could not create AddressableContent from Content")
+                    .address()
+            })
+            .collect();
+        // a has the first attribute three times, c once, b only has the second one
+        for (entity, attribute) in &[
+            (0, &attributes[0]),
+            (0, &attributes[0]),
+            (2, &attributes[0]),
+            (1, &attributes[1]),
+            (0, &attributes[0]),
+        ] {
+            let eav =
+                EntityAttributeValueIndex::new(&addresses[*entity], *attribute, &addresses[1])
+                    .expect("could not create EAV");
+            eav_storage.add_eavi(&eav).expect("could not add eav");
+        }
+        let with = |attribute: &AT| -> PersistenceResult<Vec<Address>> {
+            let mut entities = eav_storage
+                .entities_with_attribute(attribute)
+                .collect::<PersistenceResult<Vec<_>>>()?;
+            entities.sort();
+            Ok(entities)
+        };
+        let mut expected = vec![addresses[0].clone(), addresses[2].clone()];
+        expected.sort();
+        assert_eq!(Ok(expected), with(&attributes[0]));
+        assert_eq!(Ok(vec![addresses[1].clone()]), with(&attributes[1]));
+        assert_eq!(Ok(vec![]), with(&attributes[2]));
+    }
+
+    pub fn test_attribute_histogram<A, AT: Attribute, S>(mut eav_storage: S, attributes: Vec<AT>)
+    where
+        A: AddressableContent + Clone,
+        S: EntityAttributeValueStorage<AT>,
+    {
+        let one = A::try_from_content(&Content::from(RawString::from("foo")))
+            .expect("could not create AddressableContent from Content");
+        let mut expected = AttributeHistogram::new();
+
+        // the first attribute is used once, the second twice and so on
+        for (uses, attribute) in attributes.iter().enumerate() {
+            for _ in 0..=uses {
+                let eav = EntityAttributeValueIndex::new(&one.address(), attribute, &one.address())
+                    .expect("could not create EAV");
+                let eavi = eav_storage
+                    .add_eavi(&eav)
+                    .expect("could not add eav")
+                    .expect("Could not get eavi option");
+                expected
+                    .entry(attribute.clone())
+                    .or_insert_with(AttributeUsage::default)
+                    .record(::serde_json::to_string(&eavi).unwrap().len() as u64);
+            }
+        }
+
+        assert_eq!(expected, eav_storage.attribute_histogram().unwrap());
+    }
+
     pub fn test_many_to_one<A, AT: Attribute, S>(mut eav_storage: S, attribute: &AT)
     where
         A: AddressableContent + Clone,
@@ -883,11 +1252,11 @@ impl CasBencher {
         ExampleAddressableContent::try_from_content(&RawString::from(s).into()).unwrap()
     }
 
-    pub fn bench_add(b: &mut test::Bencher, mut store: impl ContentAddressableStorage) {
+    pub fn bench_add(b: &mut test::Bencher, store: impl ContentAddressableStorage) {
         b.iter(|| store.add(&CasBencher::random_addressable_content()))
     }
 
-    pub fn bench_fetch(b: &mut test::Bencher, mut store: impl ContentAddressableStorage) {
+    pub fn bench_fetch(b: &mut test::Bencher, store: impl ContentAddressableStorage) {
         // add some values to make it realistic
         for _ in 0..100 {
             store
@@ -905,10 +1274,18 @@ impl CasBencher {
 #[cfg(test)]
 pub mod tests {
     use crate::cas::{
-        content::{ExampleAddressableContent, OtherExampleAddressableContent},
-        storage::{test_content_addressable_storage, StorageTestSuite},
+        content::{
+            Address, AddressableContent, ExampleAddressableContent, OtherExampleAddressableContent,
+        },
+        storage::{
+            test_content_addressable_storage, ContentAddressableStorage, StorageTestSuite,
+            TypedContentStorage,
+        },
+    };
+    use holochain_json_api::{
+        error::JsonError,
+        json::{JsonString, RawString},
     };
-    use holochain_json_api::json::{JsonString, RawString};
 
     /// show that content of different types can round trip through the same storage
     #[test]
@@ -919,4 +1296,34 @@ pub mod tests {
             JsonString::from(RawString::from("bar")),
         );
     }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DefaultJson)]
+    struct Profile {
+        name: String,
+        age: u8,
+    }
+
+    #[test]
+    fn values_round_trip_as_their_type() {
+        let cas: Box<dyn ContentAddressableStorage> = Box::new(test_content_addressable_storage());
+        let profile = Profile {
+            name: "alice".to_string(),
+            age: 30,
+        };
+        let address = cas.add_typed(&profile).unwrap();
+        assert_eq!(JsonString::from(profile.clone()).address(), address);
+
+        let fetched = cas.fetch_typed::<Profile>(&address).unwrap().unwrap();
+        assert_eq!("alice", fetched.name);
+        assert_eq!(profile, fetched.into_inner());
+
+        let other = JsonString::from(RawString::from("not a profile"));
+        cas.add(&other).unwrap();
+        let error = cas.fetch_typed::<Profile>(&other.address()).unwrap_err();
+        assert!(error.to_string().contains("Profile"));
+        assert_eq!(
+            Ok(None),
+            cas.fetch_typed::<Profile>(&Address::from("missing"))
+        );
+    }
 }
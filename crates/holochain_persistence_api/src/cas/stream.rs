@@ -0,0 +1,120 @@
+//! Moving content around in chunks.
+//!
+//! `ContentAddressableStorage::stream_content` hands out the JSON of stored content a chunk at a
+//! time so it can be framed and sent on as it is read. `ContentAssembler` puts the chunks back
+//! together on the other end and checks that they add up to the content that was asked for.
+
+use cas::content::{Address, AddressableContent, Content};
+use error::{PersistenceError, PersistenceResult};
+use holochain_json_api::json::JsonString;
+use std::str;
+
+/// Chunks of the JSON of some content, see `ContentAddressableStorage::stream_content`.
+pub type ContentChunks<'a> = Box<dyn Iterator<Item = PersistenceResult<Vec<u8>>> + 'a>;
+
+/// Chunks of content that is already in memory.
+pub fn chunk_content(content: &Content, chunk_size: usize) -> ContentChunks<'static> {
+    let chunks: Vec<_> = String::from(content.clone())
+        .into_bytes()
+        .chunks(chunk_size.max(1))
+        .map(|chunk| Ok(chunk.to_vec()))
+        .collect();
+    Box::new(chunks.into_iter())
+}
+
+/// A stream that fails straight away.
+pub fn failed_stream(error: PersistenceError) -> ContentChunks<'static> {
+    Box::new(Some(Err(error)).into_iter())
+}
+
+/// Collects chunks of content, checking the result against the address it was asked for.
+#[derive(Clone, Debug, Default)]
+pub struct ContentAssembler {
+    expected: Option<Address>,
+    bytes: Vec<u8>,
+}
+
+impl ContentAssembler {
+    pub fn new(expected: Option<Address>) -> ContentAssembler {
+        ContentAssembler {
+            expected,
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+    }
+
+    /// bytes collected so far
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn finish(self) -> PersistenceResult<Content> {
+        let content = JsonString::from_json(str::from_utf8(&self.bytes)?);
+        match self.expected {
+            Some(ref expected) if *expected != content.address() => {
+                Err(PersistenceError::from(format!(
+                    "reassembled content has address {} instead of {}",
+                    content.address(),
+                    expected
+                )))
+            }
+            _ => Ok(content),
+        }
+    }
+}
+
+/// Collects a whole stream of chunks, see `ContentAssembler`.
+pub fn reassemble<I>(chunks: I, expected: Option<Address>) -> PersistenceResult<Content>
+where
+    I: IntoIterator<Item = PersistenceResult<Vec<u8>>>,
+{
+    let mut assembler = ContentAssembler::new(expected);
+    for chunk in chunks {
+        assembler.push(&chunk?);
+    }
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::{test_content_addressable_storage, ContentAddressableStorage};
+
+    #[test]
+    fn content_streams_in_chunks_and_reassembles() {
+        let cas = test_content_addressable_storage();
+        let content = Content::from_json(&format!("\"{}\"", "chunk".repeat(100)));
+        cas.add(&content).unwrap();
+
+        let chunks: Vec<_> = cas
+            .stream_content(&content.address(), 64)
+            .collect::<PersistenceResult<_>>()
+            .unwrap();
+        assert_eq!(8, chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 64));
+        assert_eq!(
+            Ok(content.clone()),
+            reassemble(chunks.clone().into_iter().map(Ok), Some(content.address()))
+        );
+
+        // chunks of some other content
+        assert!(reassemble(
+            chunks[1..].to_vec().into_iter().map(Ok),
+            Some(content.address())
+        )
+        .is_err());
+        let missing = Content::from_json("\"missing\"");
+        assert!(cas
+            .stream_content(&missing.address(), 64)
+            .next()
+            .unwrap()
+            .is_err());
+    }
+}
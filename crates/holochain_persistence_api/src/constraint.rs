@@ -0,0 +1,392 @@
+//! Invariants checked when staged writes are committed, instead of ad hoc checks before every
+//! write in application code.
+//!
+//! A `WriteCursor` stages content and EAVIs for a CAS and an EAV store. `commit` runs every
+//! constraint registered on it against a `StagedView`, the stores as they would be with the
+//! staged writes, and only writes them if none is broken. Otherwise it fails with
+//! `PersistenceError::ConstraintViolation` describing every violation and keeps the writes
+//! staged, to be fixed up or dropped with `rollback`.
+
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+};
+use eav::{Attribute, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage};
+use error::{PersistenceError, PersistenceResult};
+use holochain_json_api::error::JsonError;
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Debug, Display, Formatter},
+    sync::Arc,
+};
+
+/// Checks the staged writes, returning a message for every way they break the constraint.
+pub type Constraint<A> =
+    Arc<dyn Fn(&StagedView<A>) -> PersistenceResult<Vec<String>> + Send + Sync>;
+
+/// Constraints common enough to be declared rather than written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    /// every staged EAVI's entity is content in the CAS
+    EntitiesInCas,
+    /// every staged EAVI's value is content in the CAS
+    ValuesInCas,
+}
+
+impl Rule {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Rule::EntitiesInCas => "entities in CAS",
+            Rule::ValuesInCas => "values in CAS",
+        }
+    }
+
+    pub(crate) fn constraint<A: Attribute>(self) -> Constraint<A> {
+        Arc::new(move |view: &StagedView<A>| {
+            let mut violations = Vec::new();
+            for eavi in view.staged_eavis() {
+                let (role, address) = match self {
+                    Rule::EntitiesInCas => ("entity", eavi.entity()),
+                    Rule::ValuesInCas => ("value", eavi.value()),
+                };
+                if !view.contains(&address)? {
+                    violations.push(format!(
+                        "the {} {} of EAVI {} isn't in the CAS",
+                        role,
+                        address,
+                        eavi.index()
+                    ));
+                }
+            }
+            Ok(violations)
+        })
+    }
+}
+
+/// A broken constraint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub constraint: String,
+    pub message: String,
+}
+
+/// Every violation found by a check, empty if the staged writes can be committed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ViolationReport {
+    pub violations: Vec<Violation>,
+}
+
+impl ViolationReport {
+    pub fn is_empty(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl Display for ViolationReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} constraint violation(s)", self.violations.len())?;
+        for violation in &self.violations {
+            write!(f, "\n{}: {}", violation.constraint, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Staged content with the address it was staged with, which needn't be the hash of its JSON.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StagedContent {
+    pub address: Address,
+    pub content: Content,
+}
+
+impl StagedContent {
+    pub fn new(content: &dyn AddressableContent) -> StagedContent {
+        StagedContent {
+            address: content.address(),
+            content: content.content(),
+        }
+    }
+}
+
+impl AddressableContent for StagedContent {
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(content: &Content) -> Result<Self, JsonError> {
+        Ok(StagedContent {
+            address: content.address(),
+            content: content.clone(),
+        })
+    }
+}
+
+/// The stores as they would be once the staged writes are committed.
+pub struct StagedView<'a, A: Attribute> {
+    cas: &'a dyn ContentAddressableStorage,
+    eav: &'a dyn EntityAttributeValueStorage<A>,
+    content: &'a [StagedContent],
+    eavis: &'a [EntityAttributeValueIndex<A>],
+}
+
+impl<'a, A: Attribute> StagedView<'a, A> {
+    pub(crate) fn new(
+        cas: &'a dyn ContentAddressableStorage,
+        eav: &'a dyn EntityAttributeValueStorage<A>,
+        content: &'a [StagedContent],
+        eavis: &'a [EntityAttributeValueIndex<A>],
+    ) -> StagedView<'a, A> {
+        StagedView {
+            cas,
+            eav,
+            content,
+            eavis,
+        }
+    }
+
+    pub fn staged_content(&self) -> &[StagedContent] {
+        self.content
+    }
+
+    pub fn staged_eavis(&self) -> &[EntityAttributeValueIndex<A>] {
+        self.eavis
+    }
+
+    pub fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        if self.content.iter().any(|staged| staged.address == *address) {
+            return Ok(true);
+        }
+        self.cas.contains(address)
+    }
+
+    pub fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        match self
+            .content
+            .iter()
+            .find(|staged| staged.address == *address)
+        {
+            Some(staged) => Ok(Some(staged.content.clone())),
+            None => self.cas.fetch(address),
+        }
+    }
+
+    /// The stored and the staged EAVIs matching the E, A and V filters and the index range of
+    /// `query`.
+    pub fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        let mut eavis = self.eav.fetch_eavi(query)?;
+        eavis.extend(
+            self.eavis
+                .iter()
+                .filter(|eavi| query.matches(eavi))
+                .cloned(),
+        );
+        Ok(eavis)
+    }
+
+    /// Runs every constraint against the view.
+    pub(crate) fn check(
+        &self,
+        constraints: &[(String, Constraint<A>)],
+    ) -> PersistenceResult<ViolationReport> {
+        let mut report = ViolationReport::default();
+        for (name, constraint) in constraints {
+            for message in constraint(self)? {
+                report.violations.push(Violation {
+                    constraint: name.clone(),
+                    message,
+                });
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Writes for a CAS and an EAV store staged until they are committed together.
+pub struct WriteCursor<A: Attribute, C, E> {
+    cas: C,
+    eav: E,
+    content: Vec<StagedContent>,
+    eavis: Vec<EntityAttributeValueIndex<A>>,
+    constraints: Vec<(String, Constraint<A>)>,
+}
+
+impl<A: Attribute, C: Debug, E: Debug> Debug for WriteCursor<A, C, E> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WriteCursor")
+            .field("cas", &self.cas)
+            .field("eav", &self.eav)
+            .field("staged_content", &self.content.len())
+            .field("staged_eavis", &self.eavis.len())
+            .finish()
+    }
+}
+
+impl<A, C, E> WriteCursor<A, C, E>
+where
+    A: Attribute,
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+{
+    pub fn new(cas: C, eav: E) -> WriteCursor<A, C, E> {
+        WriteCursor {
+            cas,
+            eav,
+            content: Vec::new(),
+            eavis: Vec::new(),
+            constraints: Vec::new(),
+        }
+    }
+
+    /// Checks `constraint` on every commit from now on.
+    pub fn with_constraint<F>(mut self, name: &str, constraint: F) -> WriteCursor<A, C, E>
+    where
+        F: Fn(&StagedView<A>) -> PersistenceResult<Vec<String>> + Send + Sync + 'static,
+    {
+        self.constraints
+            .push((name.to_string(), Arc::new(constraint)));
+        self
+    }
+
+    /// Checks `rule` on every commit from now on.
+    pub fn with_rule(mut self, rule: Rule) -> WriteCursor<A, C, E> {
+        self.constraints
+            .push((rule.name().to_string(), rule.constraint()));
+        self
+    }
+
+    /// Stages `content` at its own address, whatever type it has.
+    pub fn add(&mut self, content: &dyn AddressableContent) {
+        self.content.push(StagedContent::new(content));
+    }
+
+    pub fn add_eavi(&mut self, eavi: &EntityAttributeValueIndex<A>) {
+        self.eavis.push(eavi.clone());
+    }
+
+    fn view(&self) -> StagedView<A> {
+        StagedView::new(&self.cas, &self.eav, &self.content, &self.eavis)
+    }
+
+    /// Runs every constraint against the staged writes without committing them.
+    pub fn check(&self) -> PersistenceResult<ViolationReport> {
+        self.view().check(&self.constraints)
+    }
+
+    /// Writes the staged content and then the staged EAVIs if no constraint is broken.
+    pub fn commit(&mut self) -> PersistenceResult<()> {
+        let report = self.check()?;
+        if !report.is_empty() {
+            return Err(PersistenceError::ConstraintViolation(report.to_string()));
+        }
+        for content in self.content.drain(..) {
+            self.cas.add(&content)?;
+        }
+        for eavi in self.eavis.drain(..) {
+            self.eav.add_eavi(&eavi)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the staged writes.
+    pub fn rollback(&mut self) {
+        self.content.clear();
+        self.eavis.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleAttribute, ExampleEntityAttributeValueStorage, IndexFilter};
+
+    fn link(from: &Content, to: &Content) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new(
+            &from.address(),
+            &ExampleAttribute::WithoutPayload,
+            &to.address(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn commits_are_checked_against_staged_and_stored_writes() {
+        let cas = test_content_addressable_storage();
+        let eav = ExampleEntityAttributeValueStorage::new();
+        let stored = Content::from_json("\"stored\"");
+        cas.add(&stored).unwrap();
+        let mut cursor = WriteCursor::new(cas.clone(), eav.clone())
+            .with_rule(Rule::ValuesInCas)
+            .with_constraint("one link per entity", |view| {
+                let mut violations = Vec::new();
+                for eavi in view.staged_eavis() {
+                    let links = view.fetch_eavi(&EaviQuery::new(
+                        Some(eavi.entity()).into(),
+                        Default::default(),
+                        Default::default(),
+                        IndexFilter::Range(None, None),
+                        None,
+                    ))?;
+                    if links.len() > 1 {
+                        violations.push(format!("{} has {} links", eavi.entity(), links.len()));
+                    }
+                }
+                Ok(violations)
+            });
+
+        // the value is only staged, the entity only stored
+        let staged = Content::from_json("\"staged\"");
+        cursor.add(&staged);
+        cursor.add_eavi(&link(&stored, &staged));
+        assert_eq!(Ok(ViolationReport::default()), cursor.check());
+        assert_eq!(Ok(()), cursor.commit());
+        assert_eq!(Ok(true), cas.contains(&staged.address()));
+        assert_eq!(1, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+
+        // a second link from stored, to content that is nowhere
+        let missing = Content::from_json("\"missing\"");
+        cursor.add_eavi(&link(&stored, &missing));
+        let report = cursor.check().unwrap();
+        assert_eq!(
+            vec!["values in CAS", "one link per entity"],
+            report
+                .violations
+                .iter()
+                .map(|violation| violation.constraint.as_str())
+                .collect::<Vec<_>>()
+        );
+        match cursor.commit() {
+            Err(PersistenceError::ConstraintViolation(report)) => {
+                assert!(report.starts_with("2 constraint violation(s)\nvalues in CAS: the value"))
+            }
+            other => panic!("unexpected commit: {:?}", other),
+        }
+        assert_eq!(1, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+
+        cursor.rollback();
+        assert_eq!(Ok(()), cursor.commit());
+    }
+
+    #[test]
+    fn content_is_committed_at_the_address_it_was_staged_with() {
+        let cas = test_content_addressable_storage();
+        let mut cursor: WriteCursor<ExampleAttribute, _, _> =
+            WriteCursor::new(cas.clone(), ExampleEntityAttributeValueStorage::new());
+        let custom = StagedContent {
+            address: Address::from("custom"),
+            content: Content::from_json("\"addressed by its type\""),
+        };
+        cursor.add(&custom);
+        assert_eq!(Ok(true), cursor.view().contains(&custom.address));
+        assert_eq!(Ok(()), cursor.commit());
+        assert_eq!(Ok(Some(custom.content.clone())), cas.fetch(&custom.address));
+        assert_eq!(Ok(false), cas.contains(&custom.content.address()));
+    }
+}
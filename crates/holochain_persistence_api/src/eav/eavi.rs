@@ -71,6 +71,25 @@ impl From<String> for ExampleAttribute {
 }
 impl Attribute for ExampleAttribute {}
 
+/// Free form attributes, for hosts that name attributes at runtime (bindings to other languages).
+/// Serializes as a plain string.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StringAttribute(pub String);
+
+impl Display for StringAttribute {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for StringAttribute {
+    fn from(attribute: String) -> Self {
+        StringAttribute(attribute)
+    }
+}
+impl Attribute for StringAttribute {}
+
 #[derive(PartialEq, Debug)]
 pub enum AttributeError {
     Unrecognized(String),
@@ -392,6 +411,76 @@ pub mod tests {
         >(test_eav_storage(), &ExampleAttribute::default());
     }
 
+    #[test]
+    fn example_eav_ordering() {
+        EavTestSuite::test_ordering::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        >(
+            test_eav_storage(),
+            vec![
+                ExampleAttribute::WithPayload("b_".to_string()),
+                ExampleAttribute::WithPayload("a_".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn example_eav_distinct() {
+        EavTestSuite::test_distinct::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        >(
+            test_eav_storage(),
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn example_eav_upsert() {
+        EavTestSuite::test_upsert::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        >(
+            test_eav_storage(),
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn example_eav_iter() {
+        EavTestSuite::test_iter::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        >(test_eav_storage(), ExampleAttribute::WithoutPayload);
+    }
+
+    #[test]
+    fn example_eav_entities_with_attribute() {
+        EavTestSuite::test_entities_with_attribute::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        >(
+            test_eav_storage(),
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+                ExampleAttribute::WithoutPayload,
+            ],
+        );
+    }
+
     #[test]
     fn example_eav_prefixes() {
         EavTestSuite::test_multiple_attributes::<
@@ -16,6 +16,10 @@ pub struct EaviQuery<'a, A: Attribute> {
     pub tombstone: Option<AttributeFilter<'a, A>>,
     ///represents a filter for the Index
     pub index: IndexFilter,
+    ///the order `fetch_eavi_ordered` returns results in, `fetch_eavi` always returns them by index
+    pub order_by: OrderBy,
+    ///at most this many results from `fetch_eavi_ordered`, taken after ordering
+    pub limit: Option<usize>,
 }
 
 type EntityFilter<'a> = EavFilter<'a, Entity>;
@@ -49,9 +53,55 @@ impl<'a, A: Attribute> EaviQuery<'a, A> {
             value,
             tombstone,
             index,
+            order_by: OrderBy::default(),
+            limit: None,
         }
     }
 
+    pub fn with_order_by(mut self, order_by: OrderBy) -> Self {
+        self.order_by = order_by;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Puts the results of `run` in the order asked for and applies the limit.
+    /// Sorting by entity or attribute keeps results with the same one in index order.
+    pub fn order(
+        &self,
+        results: BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Vec<EntityAttributeValueIndex<A>> {
+        let mut ordered: Vec<_> = match self.order_by {
+            OrderBy::IndexDescending => results.into_iter().rev().collect(),
+            _ => results.into_iter().collect(),
+        };
+        match self.order_by {
+            OrderBy::Entity => ordered.sort_by_key(|eavi| eavi.entity()),
+            OrderBy::Attribute => ordered.sort_by_key(|eavi| eavi.attribute()),
+            OrderBy::IndexAscending | OrderBy::IndexDescending => (),
+        }
+        if let Some(limit) = self.limit {
+            ordered.truncate(limit);
+        }
+        ordered
+    }
+
+    /// Whether a single EAVI passes the E, A and V filters and an index range. Says nothing about
+    /// `LatestByAttribute`, which depends on the other EAVIs, use `run` for that.
+    pub fn matches(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        let in_range = match self.index {
+            IndexFilter::Range(start, end) => {
+                start.map(|lo| lo <= eavi.index()).unwrap_or(true)
+                    && end.map(|hi| eavi.index() <= hi).unwrap_or(true)
+            }
+            IndexFilter::LatestByAttribute => true,
+        };
+        in_range && EaviQuery::eav_check(eavi, &self.entity, &self.attribute, &self.value)
+    }
+
     /// This runs the query based the query configuration we have given.
     pub fn run<I>(&self, iter: I) -> BTreeSet<EntityAttributeValueIndex<A>>
     where
@@ -144,6 +194,12 @@ impl<'a, A: Attribute> EaviQuery<'a, A> {
     pub fn tombstone(&self) -> &Option<AttributeFilter<'a, A>> {
         &self.tombstone
     }
+    pub fn order_by(&self) -> OrderBy {
+        self.order_by
+    }
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
 }
 
 /// Represents a filter type which takes in a function to match on
@@ -200,8 +256,64 @@ impl<'a, T: Eq> From<Vec<T>> for EavFilter<'a, T> {
 /// LatestByAttribute is more complex. It first does a normal filter by E, A, and V.
 /// Then, for each group of items which differ *only* by Attribute and Index, only the item with
 /// highest Index is retained for that grouping.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum IndexFilter {
     LatestByAttribute,
     Range(Option<i64>, Option<i64>),
 }
+
+/// The order of the results of `fetch_eavi_ordered`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderBy {
+    IndexAscending,
+    /// newest first, what "latest N" queries want together with a limit
+    IndexDescending,
+    Entity,
+    Attribute,
+}
+
+impl Default for OrderBy {
+    fn default() -> OrderBy {
+        OrderBy::IndexAscending
+    }
+}
+
+/// An `EaviQuery` that owns its filters so that it can be sent
+/// across threads, e.g. to the `PersistenceActor`.
+/// Only exact matches can be expressed since predicates can't cross threads.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OwnedEaviQuery<A: Attribute> {
+    pub entity: Option<Entity>,
+    pub attribute: Option<A>,
+    pub value: Option<Value>,
+    pub index: IndexFilter,
+    pub tombstone: Option<A>,
+}
+
+impl<A: Attribute> OwnedEaviQuery<A> {
+    pub fn new(
+        entity: Option<Entity>,
+        attribute: Option<A>,
+        value: Option<Value>,
+        index: IndexFilter,
+        tombstone: Option<A>,
+    ) -> Self {
+        OwnedEaviQuery {
+            entity,
+            attribute,
+            value,
+            index,
+            tombstone,
+        }
+    }
+
+    pub fn as_query(&self) -> EaviQuery<A> {
+        EaviQuery::new(
+            self.entity.clone().into(),
+            self.attribute.clone().into(),
+            self.value.clone().into(),
+            self.index.clone(),
+            self.tombstone.clone().map(EavFilter::single),
+        )
+    }
+}
@@ -1,15 +1,15 @@
 use crate::holochain_json_api::json::RawString;
 use cas::content::{AddressableContent, ExampleAddressableContent};
 use eav::{
-    eavi::{EntityAttributeValueIndex, ExampleAttribute},
+    eavi::{Entity, EntityAttributeValueIndex, ExampleAttribute, Value},
     query::EaviQuery,
     Attribute, EavFilter, IndexFilter,
 };
-use error::PersistenceResult;
+use error::{PersistenceError, PersistenceResult};
 use objekt;
 use reporting::ReportStorage;
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fmt::Debug,
     sync::{Arc, RwLock},
 };
@@ -27,6 +27,18 @@ pub trait EntityAttributeValueStorage<A: Attribute>:
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>>;
 
+    /// Adds the EAVI and removes the ones with the same entity and attribute in one go, for
+    /// attributes an entity only has one value for. Stores that can't do this atomically
+    /// return an error.
+    fn upsert_eavi(
+        &mut self,
+        _eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        Err(PersistenceError::from(
+            "upsert_eavi is not supported by this store",
+        ))
+    }
+
     /// Fetch the set of EntityAttributeValues that match constraints according to the latest hash version
     /// - None = no constraint
     /// - Some(Entity) = requires the given entity (e.g. all a/v pairs for the entity)
@@ -37,14 +49,111 @@ pub trait EntityAttributeValueStorage<A: Attribute>:
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>;
 
-    // @TODO: would like to do this, but can't because of the generic type param
-    // fn iter<I>(&self) -> I
-    // where
-    //     I: Iterator<Item = EntityAttributeValueIndex>;
+    /// Like `fetch_eavi` but in the query's `order_by` and cut off at its `limit`.
+    fn fetch_eavi_ordered(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        Ok(query.order(self.fetch_eavi(query)?))
+    }
+
+    /// The values of the EAVIs matching the query, each once, e.g. everything linked from an entity.
+    fn fetch_distinct_values(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Value>> {
+        Ok(self
+            .fetch_eavi(query)?
+            .into_iter()
+            .map(|eavi| eavi.value())
+            .collect())
+    }
+
+    /// The entities of the EAVIs matching the query, each once.
+    fn fetch_distinct_entities(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Entity>> {
+        Ok(self
+            .fetch_eavi(query)?
+            .into_iter()
+            .map(|eavi| eavi.entity())
+            .collect())
+    }
+
+    /// Number of EAVIs and bytes of their JSON per attribute, to see which attributes dominate
+    /// a store. Stores that keep these counts up to date on write return them without reading
+    /// every EAVI.
+    fn attribute_histogram(&self) -> PersistenceResult<AttributeHistogram<A>> {
+        let query = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let mut histogram = AttributeHistogram::new();
+        for eavi in self.fetch_eavi(&query)? {
+            let bytes = ::serde_json::to_string(&eavi)?.len() as u64;
+            histogram
+                .entry(eavi.attribute())
+                .or_insert_with(AttributeUsage::default)
+                .record(bytes);
+        }
+        Ok(histogram)
+    }
+
+    /// The EAVIs matching the query one at a time, in the query's `order_by` and cut off at its
+    /// `limit`. The cursor is boxed so the trait stays usable as a trait object. By default the
+    /// EAVIs are all fetched up front, stores that can read them lazily override this.
+    fn iter_eavi<'a>(&'a self, query: &EaviQuery<A>) -> EaviCursor<'a, A>
+    where
+        A: 'a,
+    {
+        match self.fetch_eavi_ordered(query) {
+            Ok(eavis) => Box::new(eavis.into_iter().map(Ok)),
+            Err(e) => Box::new(Some(Err(e)).into_iter()),
+        }
+    }
+
+    /// The entities with at least one EAVI with `attribute`, each once, e.g. every agent that
+    /// published an entry of some type. By default they are fetched up
+    /// front, stores that index EAVIs by attribute override this.
+    fn entities_with_attribute<'a>(&'a self, attribute: &A) -> EntityCursor<'a> {
+        let query = EaviQuery::new(
+            Default::default(),
+            Some(attribute.clone()).into(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        match self.fetch_distinct_entities(&query) {
+            Ok(entities) => Box::new(entities.into_iter().map(Ok)),
+            Err(e) => Box::new(Some(Err(e)).into_iter()),
+        }
+    }
 }
 
 clone_trait_object!(<A:Attribute>EntityAttributeValueStorage<A>);
 
+/// EAVIs handed out one at a time, see `EntityAttributeValueStorage::iter_eavi`.
+pub type EaviCursor<'a, A> =
+    Box<dyn Iterator<Item = PersistenceResult<EntityAttributeValueIndex<A>>> + 'a>;
+
+/// Entities handed out one at a time, see `EntityAttributeValueStorage::entities_with_attribute`.
+pub type EntityCursor<'a> = Box<dyn Iterator<Item = PersistenceResult<Entity>> + 'a>;
+
+/// How many EAVIs with one attribute a store holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributeUsage {
+    pub count: u64,
+    /// size of the EAVIs as JSON
+    pub bytes: u64,
+}
+
+impl AttributeUsage {
+    pub fn record(&mut self, bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+pub type AttributeHistogram<A> = BTreeMap<A, AttributeUsage>;
+
 #[derive(Clone, Debug, Default)]
 pub struct ExampleEntityAttributeValueStorage<A: Attribute> {
     storage: Arc<RwLock<BTreeSet<EntityAttributeValueIndex<A>>>>,
@@ -73,6 +182,24 @@ where
         Ok(Some(new_eav))
     }
 
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let mut map = self.storage.write()?;
+        let replaced: Vec<_> = map
+            .iter()
+            .filter(|old| old.entity() == eav.entity() && old.attribute() == eav.attribute())
+            .cloned()
+            .collect();
+        for old in replaced {
+            map.remove(&old);
+        }
+        let new_eav = increment_key_till_no_collision(eav.clone(), map.clone())?;
+        map.insert(new_eav.clone());
+        Ok(Some(new_eav))
+    }
+
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
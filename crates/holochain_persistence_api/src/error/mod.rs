@@ -1,6 +1,7 @@
 //! This module contains Error type definitions that are used throughout persistence.
 
 use self::PersistenceError::*;
+#[cfg(feature = "async")]
 use futures::channel::oneshot::Canceled as FutureCanceled;
 use holochain_json_api::{error::JsonError, json::*};
 use serde_json::Error as SerdeError;
@@ -22,6 +23,16 @@ pub enum PersistenceError {
     ErrorGeneric(String),
     IoError(String),
     SerializationError(String),
+    /// a stored record failed its checksum
+    Corruption(String),
+    /// a store ran out of room to map its data into memory
+    AddressSpaceExhausted(String),
+    /// staged writes broke a constraint registered for them, see `constraint::WriteCursor`
+    ConstraintViolation(String),
+    /// a capability token doesn't grant the access an operation needs, see `access`
+    AccessDenied(String),
+    /// a write would go beyond the `Limits` of a store, see `limits`
+    LimitExceeded(String),
 }
 
 impl PersistenceError {
@@ -47,6 +58,11 @@ impl fmt::Display for PersistenceError {
             ErrorGeneric(err_msg) => write!(f, "{}", err_msg),
             SerializationError(err_msg) => write!(f, "{}", err_msg),
             IoError(err_msg) => write!(f, "{}", err_msg),
+            Corruption(err_msg) => write!(f, "{}", err_msg),
+            AddressSpaceExhausted(err_msg) => write!(f, "{}", err_msg),
+            ConstraintViolation(err_msg) => write!(f, "{}", err_msg),
+            AccessDenied(err_msg) => write!(f, "{}", err_msg),
+            LimitExceeded(err_msg) => write!(f, "{}", err_msg),
         }
     }
 }
@@ -110,6 +126,7 @@ impl From<std::str::Utf8Error> for PersistenceError {
     }
 }
 
+#[cfg(feature = "async")]
 impl From<FutureCanceled> for PersistenceError {
     fn from(_: FutureCanceled) -> Self {
         PersistenceError::ErrorGeneric("Failed future".to_string())
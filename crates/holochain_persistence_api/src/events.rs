@@ -0,0 +1,376 @@
+//! Notifications about what happens in a store, for caches, metrics and publishing.
+//!
+//! Subscribers register a callback on an `EventBus` and are called synchronously, on the
+//! writing thread, with every `StorageEvent` published to it after the fact. `Publishing`
+//! wraps any CAS or EAV store to publish its writes, stores publish what only they know about
+//! themselves, e.g. the LMDB stores publish when their map grows.
+
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+    stream::ContentChunks,
+};
+use eav::{
+    Attribute, AttributeHistogram, EaviCursor, EaviQuery, Entity, EntityAttributeValueIndex,
+    EntityAttributeValueStorage, EntityCursor, Value,
+};
+use error::PersistenceResult;
+use reporting::{ReportStorage, StorageReport};
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Debug, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex, RwLock,
+    },
+};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageEvent {
+    /// content was added to a CAS at this address, or an EAVI about this entity to an EAV store
+    Added(Address),
+    /// content at this address is gone, e.g. quarantined after failing its checksum
+    Removed(Address),
+    /// a write is visible to readers
+    Committed,
+    /// a store's memory map grew to this many bytes
+    Resized(usize),
+    /// a background check found what is stored at this address isn't as it should be
+    IntegrityIssue(Address, String),
+}
+
+/// What happened to the content at a watched address, see `EventBus::watch`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContentChange {
+    Added(Address),
+    Removed(Address),
+}
+
+pub type Subscriber = Arc<dyn Fn(&StorageEvent) + Send + Sync>;
+
+/// Identifies a subscription, see `EventBus::unsubscribe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriptionId(usize);
+
+/// Clones publish to and are subscribed to by the same subscribers.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<RwLock<Vec<(SubscriptionId, Subscriber)>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Debug for EventBus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let subscribers = self.subscribers.read().map(|s| s.len()).unwrap_or(0);
+        f.debug_struct("EventBus")
+            .field("subscribers", &subscribers)
+            .finish()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        Default::default()
+    }
+
+    /// Calls `subscriber` with every event published from now on.
+    pub fn subscribe<F>(&self, subscriber: F) -> PersistenceResult<SubscriptionId>
+    where
+        F: Fn(&StorageEvent) + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers.write()?.push((id, Arc::new(subscriber)));
+        Ok(id)
+    }
+
+    /// Stops calling a subscriber, returning whether it was subscribed.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> PersistenceResult<bool> {
+        let mut subscribers = self.subscribers.write()?;
+        let before = subscribers.len();
+        subscribers.retain(|(subscribed, _)| *subscribed != id);
+        Ok(subscribers.len() < before)
+    }
+
+    /// Sends a `ContentChange` whenever the content at `address` turns up in or disappears from
+    /// `cas`, once the write is visible. `cas` has to publish to this bus, EAV stores publishing
+    /// `Added` for the entities of their EAVIs aren't taken for content. The subscription ends
+    /// with the first change after the receiver was dropped.
+    pub fn watch<C>(&self, cas: C, address: &Address) -> PersistenceResult<Receiver<ContentChange>>
+    where
+        C: ContentAddressableStorage + 'static,
+    {
+        self.watch_with(address, move |address| cas.contains(address))
+    }
+
+    /// `watch` with whatever tells whether the content is there.
+    pub(crate) fn watch_with<F>(
+        &self,
+        address: &Address,
+        contains: F,
+    ) -> PersistenceResult<Receiver<ContentChange>>
+    where
+        F: Fn(&Address) -> PersistenceResult<bool> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        // the sender and whether the content is there, as of the last change sent
+        let watched = Mutex::new((sender, contains(address)?));
+        let address = address.clone();
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let subscribers = Arc::downgrade(&self.subscribers);
+        let watch = move |event: &StorageEvent| {
+            let mut watched = match watched.lock() {
+                Ok(watched) => watched,
+                Err(_) => return,
+            };
+            let (sender, present) = &mut *watched;
+            let change = match event {
+                StorageEvent::Added(added)
+                    if *added == address && !*present && contains(&address) == Ok(true) =>
+                {
+                    ContentChange::Added(address.clone())
+                }
+                StorageEvent::Removed(removed) if *removed == address && *present => {
+                    ContentChange::Removed(address.clone())
+                }
+                _ => return,
+            };
+            *present = !*present;
+            if sender.send(change).is_err() {
+                if let Some(subscribers) = subscribers.upgrade() {
+                    if let Ok(mut subscribers) = subscribers.write() {
+                        subscribers.retain(|(subscribed, _)| *subscribed != id);
+                    }
+                }
+            }
+        };
+        self.subscribers.write()?.push((id, Arc::new(watch)));
+        Ok(receiver)
+    }
+
+    /// Calls every subscriber with `event`. Subscribers may publish or subscribe themselves.
+    pub fn publish(&self, event: &StorageEvent) {
+        // a poisoned lock only means a subscriber panicked while (un)subscribing
+        let subscribers: Vec<Subscriber> = match self.subscribers.read() {
+            Ok(subscribers) => subscribers.iter().map(|(_, s)| s.clone()).collect(),
+            Err(poisoned) => poisoned.get_ref().iter().map(|(_, s)| s.clone()).collect(),
+        };
+        for subscriber in subscribers {
+            subscriber(event);
+        }
+    }
+}
+
+/// A CAS or EAV store publishing `Added` and `Committed` after every successful write.
+#[derive(Clone, Debug)]
+pub struct Publishing<S> {
+    store: S,
+    events: EventBus,
+}
+
+impl<S> Publishing<S> {
+    pub fn new(store: S, events: EventBus) -> Publishing<S> {
+        Publishing { store, events }
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// See `EventBus::watch`.
+    pub fn watch(&self, address: &Address) -> PersistenceResult<Receiver<ContentChange>>
+    where
+        S: ContentAddressableStorage + Clone + 'static,
+    {
+        self.events.watch(self.store.clone(), address)
+    }
+
+    fn added(&self, address: Address) {
+        self.events.publish(&StorageEvent::Added(address));
+        self.events.publish(&StorageEvent::Committed);
+    }
+}
+
+impl<S: ContentAddressableStorage + Clone> ContentAddressableStorage for Publishing<S> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        self.store.add(content)?;
+        self.added(content.address());
+        Ok(())
+    }
+
+    fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        self.store.contains(address)
+    }
+
+    fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        self.store.fetch(address)
+    }
+
+    fn stream_content(&self, address: &Address, chunk_size: usize) -> ContentChunks {
+        self.store.stream_content(address, chunk_size)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.store.get_id()
+    }
+}
+
+impl<A, S> EntityAttributeValueStorage<A> for Publishing<S>
+where
+    A: Attribute + Send + Sync,
+    S: EntityAttributeValueStorage<A> + Clone,
+{
+    fn add_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let added = self.store.add_eavi(eav)?;
+        if let Some(eavi) = &added {
+            self.added(eavi.entity());
+        }
+        Ok(added)
+    }
+
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let added = self.store.upsert_eavi(eav)?;
+        if let Some(eavi) = &added {
+            self.added(eavi.entity());
+        }
+        Ok(added)
+    }
+
+    fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        self.store.fetch_eavi(query)
+    }
+
+    fn fetch_eavi_ordered(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        self.store.fetch_eavi_ordered(query)
+    }
+
+    fn fetch_distinct_values(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Value>> {
+        self.store.fetch_distinct_values(query)
+    }
+
+    fn fetch_distinct_entities(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Entity>> {
+        self.store.fetch_distinct_entities(query)
+    }
+
+    fn attribute_histogram(&self) -> PersistenceResult<AttributeHistogram<A>> {
+        self.store.attribute_histogram()
+    }
+
+    fn iter_eavi<'a>(&'a self, query: &EaviQuery<A>) -> EaviCursor<'a, A>
+    where
+        A: 'a,
+    {
+        self.store.iter_eavi(query)
+    }
+
+    fn entities_with_attribute<'a>(&'a self, attribute: &A) -> EntityCursor<'a> {
+        self.store.entities_with_attribute(attribute)
+    }
+}
+
+impl<S: ReportStorage> ReportStorage for Publishing<S> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.store.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleAttribute, ExampleEntityAttributeValueStorage};
+
+    #[test]
+    fn subscribers_hear_about_writes() {
+        let events = EventBus::new();
+        let heard = Arc::new(RwLock::new(Vec::new()));
+        let log = heard.clone();
+        let id = events
+            .subscribe(move |event| log.write().unwrap().push(event.clone()))
+            .unwrap();
+
+        let cas = Publishing::new(test_content_addressable_storage(), events.clone());
+        let mut eav = Publishing::new(ExampleEntityAttributeValueStorage::new(), events.clone());
+        let content = Content::from_json("\"published\"");
+        cas.add(&content).unwrap();
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::WithoutPayload,
+            &content.address(),
+        )
+        .unwrap();
+        eav.add_eavi(&eavi).unwrap();
+        events.publish(&StorageEvent::Resized(4096));
+        assert_eq!(
+            vec![
+                StorageEvent::Added(content.address()),
+                StorageEvent::Committed,
+                StorageEvent::Added(content.address()),
+                StorageEvent::Committed,
+                StorageEvent::Resized(4096),
+            ],
+            *heard.read().unwrap()
+        );
+
+        assert_eq!(Ok(true), events.unsubscribe(id));
+        assert_eq!(Ok(false), events.unsubscribe(id));
+        cas.add(&Content::from_json("\"unheard\"")).unwrap();
+        assert_eq!(5, heard.read().unwrap().len());
+    }
+
+    #[test]
+    fn watchers_hear_about_their_address() {
+        let events = EventBus::new();
+        let cas = Publishing::new(test_content_addressable_storage(), events.clone());
+        let mut eav = Publishing::new(ExampleEntityAttributeValueStorage::new(), events.clone());
+        let content = Content::from_json("\"watched\"");
+        let changes = cas.watch(&content.address()).unwrap();
+
+        cas.add(&Content::from_json("\"other\"")).unwrap();
+        // EAVIs about the address aren't its content
+        eav.add_eavi(
+            &EntityAttributeValueIndex::new(
+                &content.address(),
+                &ExampleAttribute::WithoutPayload,
+                &content.address(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(changes.try_recv().is_err());
+
+        cas.add(&content).unwrap();
+        cas.add(&content).unwrap();
+        events.publish(&StorageEvent::Removed(content.address()));
+        assert_eq!(
+            vec![
+                ContentChange::Added(content.address()),
+                ContentChange::Removed(content.address()),
+            ],
+            changes.try_iter().collect::<Vec<_>>()
+        );
+
+        // the next change after the receiver is gone ends the subscription
+        drop(changes);
+        assert_eq!(1, events.subscribers.read().unwrap().len());
+        events.publish(&StorageEvent::Added(content.address()));
+        assert_eq!(0, events.subscribers.read().unwrap().len());
+    }
+}
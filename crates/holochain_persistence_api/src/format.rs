@@ -0,0 +1,156 @@
+//! Encodings for content and EAVIs at rest.
+//!
+//! The storage traits only ever deal in JSON. A store configured with a binary
+//! `SerializationFormat` re-encodes that JSON before writing it, prefixed with a tag byte
+//! naming the format, so a store can change formats and still read everything written before.
+//!
+//! Addresses are hashes of the exact JSON text, so JSON that wouldn't come back byte for byte
+//! (extra whitespace, escapes, content that isn't JSON at all) is always written as JSON.
+
+use error::{PersistenceError, PersistenceResult};
+use serde_json::Value;
+use std::str::{self, FromStr};
+
+const JSON_TAG: u8 = 0;
+const MESSAGE_PACK_TAG: u8 = 1;
+const CBOR_TAG: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SerializationFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Default for SerializationFormat {
+    fn default() -> SerializationFormat {
+        SerializationFormat::Json
+    }
+}
+
+fn format_error<E: ::std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::SerializationError(format!("serialization format error: {}", e))
+}
+
+/// the JSON parsed, if writing it out again gives back the same text
+fn exact_value(json: &str) -> Option<Value> {
+    let value: Value = ::serde_json::from_str(json).ok()?;
+    match ::serde_json::to_string(&value) {
+        Ok(ref again) if again == json => Some(value),
+        _ => None,
+    }
+}
+
+/// Parses the name of a format, in any case, e.g. from a storage URI.
+impl FromStr for SerializationFormat {
+    type Err = PersistenceError;
+
+    fn from_str(name: &str) -> PersistenceResult<SerializationFormat> {
+        match name.to_lowercase().as_str() {
+            "json" => Ok(SerializationFormat::Json),
+            "messagepack" => Ok(SerializationFormat::MessagePack),
+            "cbor" => Ok(SerializationFormat::Cbor),
+            _ => Err(format_error(format!("unknown format {}", name))),
+        }
+    }
+}
+
+impl SerializationFormat {
+    fn tag(self) -> u8 {
+        match self {
+            SerializationFormat::Json => JSON_TAG,
+            SerializationFormat::MessagePack => MESSAGE_PACK_TAG,
+            SerializationFormat::Cbor => CBOR_TAG,
+        }
+    }
+
+    /// The format `encode` wrote the bytes in.
+    pub fn of(bytes: &[u8]) -> Option<SerializationFormat> {
+        match bytes.first() {
+            Some(&JSON_TAG) => Some(SerializationFormat::Json),
+            Some(&MESSAGE_PACK_TAG) => Some(SerializationFormat::MessagePack),
+            Some(&CBOR_TAG) => Some(SerializationFormat::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Encodes JSON text in this format, falling back to JSON if it wouldn't round trip.
+    pub fn encode(self, json: &str) -> PersistenceResult<Vec<u8>> {
+        let value = match self {
+            SerializationFormat::Json => None,
+            _ => exact_value(json),
+        };
+        let (tag, payload) = match (self, value) {
+            (SerializationFormat::MessagePack, Some(value)) => (
+                self.tag(),
+                ::rmp_serde::to_vec(&value).map_err(format_error)?,
+            ),
+            (SerializationFormat::Cbor, Some(value)) => (
+                self.tag(),
+                ::serde_cbor::to_vec(&value).map_err(format_error)?,
+            ),
+            _ => (JSON_TAG, json.as_bytes().to_vec()),
+        };
+        let mut bytes = Vec::with_capacity(payload.len() + 1);
+        bytes.push(tag);
+        bytes.extend(payload);
+        Ok(bytes)
+    }
+
+    /// Gives back the JSON text `encode` was called with, whichever format it was written in.
+    pub fn decode(bytes: &[u8]) -> PersistenceResult<String> {
+        let payload = bytes
+            .get(1..)
+            .ok_or_else(|| format_error("nothing stored"))?;
+        let value: Value = match SerializationFormat::of(bytes) {
+            Some(SerializationFormat::Json) => return Ok(str::from_utf8(payload)?.to_string()),
+            Some(SerializationFormat::MessagePack) => {
+                ::rmp_serde::from_slice(payload).map_err(format_error)?
+            }
+            Some(SerializationFormat::Cbor) => {
+                ::serde_cbor::from_slice(payload).map_err(format_error)?
+            }
+            None => return Err(format_error(format!("unknown format tag {}", bytes[0]))),
+        };
+        Ok(::serde_json::to_string(&value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [SerializationFormat; 3] = [
+        SerializationFormat::Json,
+        SerializationFormat::MessagePack,
+        SerializationFormat::Cbor,
+    ];
+
+    #[test]
+    fn json_round_trips_through_every_format() {
+        let json = r#"{"entity":"Qm1","count":3,"ratio":0.5,"tags":["a","b"],"nested":{"z":null,"a":true}}"#;
+        for format in FORMATS.iter().cloned() {
+            let bytes = format.encode(json).unwrap();
+            assert_eq!(Some(format), SerializationFormat::of(&bytes));
+            assert_eq!(json, SerializationFormat::decode(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn text_that_would_not_round_trip_is_kept_as_json() {
+        for text in &["some bytes", "{ \"spaced\": 1 }", r#""\u00e9""#] {
+            let bytes = SerializationFormat::MessagePack.encode(text).unwrap();
+            assert_eq!(
+                Some(SerializationFormat::Json),
+                SerializationFormat::of(&bytes)
+            );
+            assert_eq!(*text, SerializationFormat::decode(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn unknown_tags_are_errors() {
+        assert!(SerializationFormat::decode(&[9, 1, 2]).is_err());
+        assert!(SerializationFormat::decode(&[]).is_err());
+    }
+}
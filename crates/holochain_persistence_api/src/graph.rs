@@ -0,0 +1,167 @@
+//! Walking the links between addresses that EAVIs make up, from entity to value.
+//!
+//! Each step of a walk is a single `fetch_eavi` for every address reached in the step before,
+//! rather than one query per address.
+
+use cas::content::Address;
+use eav::{Attribute, EavFilter, EaviQuery, EntityAttributeValueStorage, IndexFilter};
+use error::PersistenceResult;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The links from any of `from` with an attribute `follow` accepts, as (entity, value) pairs.
+fn links<A, S, F>(
+    eav: &S,
+    from: &BTreeSet<Address>,
+    follow: &F,
+) -> PersistenceResult<BTreeSet<(Address, Address)>>
+where
+    A: Attribute,
+    S: EntityAttributeValueStorage<A> + ?Sized,
+    F: Fn(A) -> bool,
+{
+    let query = EaviQuery::new(
+        EavFilter::predicate(|entity| from.contains(&entity)),
+        EavFilter::predicate(follow),
+        Default::default(),
+        IndexFilter::Range(None, None),
+        None,
+    );
+    Ok(eav
+        .fetch_eavi(&query)?
+        .into_iter()
+        .map(|eavi| (eavi.entity(), eavi.value()))
+        .collect())
+}
+
+/// Everything reachable from `start` in at most `depth` links with an attribute `follow`
+/// accepts, with the number of links it took to get there. `start` is in there at 0.
+pub fn traverse<A, S, F>(
+    eav: &S,
+    start: &Address,
+    follow: F,
+    depth: usize,
+) -> PersistenceResult<BTreeMap<Address, usize>>
+where
+    A: Attribute,
+    S: EntityAttributeValueStorage<A> + ?Sized,
+    F: Fn(A) -> bool,
+{
+    let mut reached = BTreeMap::new();
+    reached.insert(start.clone(), 0);
+    let mut frontier: BTreeSet<_> = Some(start.clone()).into_iter().collect();
+    for step in 1..=depth {
+        if frontier.is_empty() {
+            break;
+        }
+        frontier = links(eav, &frontier, &follow)?
+            .into_iter()
+            .map(|(_, to)| to)
+            .filter(|to| !reached.contains_key(to))
+            .collect();
+        for to in &frontier {
+            reached.insert(to.clone(), step);
+        }
+    }
+    Ok(reached)
+}
+
+/// The addresses on a shortest path of links from `from` to `to`, both included, looking no
+/// further than `max_depth` links. None if there is no such path.
+pub fn shortest_path<A, S, F>(
+    eav: &S,
+    from: &Address,
+    to: &Address,
+    follow: F,
+    max_depth: usize,
+) -> PersistenceResult<Option<Vec<Address>>>
+where
+    A: Attribute,
+    S: EntityAttributeValueStorage<A> + ?Sized,
+    F: Fn(A) -> bool,
+{
+    let mut parents: BTreeMap<Address, Option<Address>> = BTreeMap::new();
+    parents.insert(from.clone(), None);
+    let mut frontier: BTreeSet<_> = Some(from.clone()).into_iter().collect();
+    let mut depth = 0;
+    while !parents.contains_key(to) {
+        if frontier.is_empty() || depth == max_depth {
+            return Ok(None);
+        }
+        let mut next = BTreeSet::new();
+        for (entity, value) in links(eav, &frontier, &follow)? {
+            if !parents.contains_key(&value) {
+                parents.insert(value.clone(), Some(entity));
+                next.insert(value);
+            }
+        }
+        frontier = next;
+        depth += 1;
+    }
+
+    let mut path = vec![to.clone()];
+    while let Some(Some(parent)) = parents.get(path.last().unwrap()) {
+        path.push(parent.clone());
+    }
+    path.reverse();
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::{EntityAttributeValueIndex, ExampleEntityAttributeValueStorage, StringAttribute};
+
+    #[test]
+    fn links_are_walked_a_step_at_a_time() {
+        let mut eav = ExampleEntityAttributeValueStorage::new();
+        let address = |name: &str| Address::from(name);
+        // a -> b -> c -> d, a -> e -> d, and d -> f only with another attribute
+        for (from, attribute, to) in &[
+            ("a", "link", "b"),
+            ("b", "link", "c"),
+            ("c", "link", "d"),
+            ("a", "link", "e"),
+            ("e", "link", "d"),
+            ("d", "other", "f"),
+        ] {
+            let eavi = EntityAttributeValueIndex::new(
+                &address(from),
+                &StringAttribute((*attribute).to_string()),
+                &address(to),
+            )
+            .unwrap();
+            eav.add_eavi(&eavi).unwrap();
+        }
+        let link = |attribute: StringAttribute| attribute.0 == "link";
+
+        let reached = traverse(&eav, &address("a"), link, 2).unwrap();
+        assert_eq!(
+            vec![("a", 0), ("b", 1), ("c", 2), ("d", 2), ("e", 1)]
+                .into_iter()
+                .map(|(name, depth)| (address(name), depth))
+                .collect::<BTreeMap<_, _>>(),
+            reached
+        );
+        assert_eq!(
+            6,
+            traverse(&eav, &address("a"), |_| true, 10).unwrap().len()
+        );
+
+        assert_eq!(
+            Ok(Some(vec![address("a"), address("e"), address("d")])),
+            shortest_path(&eav, &address("a"), &address("d"), link, 10)
+        );
+        assert_eq!(
+            Ok(None),
+            shortest_path(&eav, &address("a"), &address("d"), link, 1)
+        );
+        assert_eq!(
+            Ok(None),
+            shortest_path(&eav, &address("a"), &address("f"), link, 10)
+        );
+        assert_eq!(
+            Ok(Some(vec![address("a")])),
+            shortest_path(&eav, &address("a"), &address("a"), link, 0)
+        );
+    }
+}
@@ -2,17 +2,44 @@
 //! to keep track of places where a string is the product of a hash function,
 //! and as a base type for Address to use.
 
-use crate::holochain_json_api::{error::JsonError, json::JsonString};
-use multihash::{encode, Hash};
+use crate::{
+    error::{PersistenceError, PersistenceResult},
+    holochain_json_api::{error::JsonError, json::JsonString},
+};
+use multihash::{decode, encode, Hash};
 use rust_base58::{FromBase58, ToBase58};
-use std::{convert::TryInto, fmt};
+use std::{convert::TryInto, fmt, str::FromStr};
+
+/// kinds of hcid encoded keys, such as agent ids, that are valid addresses too
+const HCID_KINDS: &[&str] = &["hcs0", "hck0", "hca0"];
 
 // HashString newtype for String
-#[derive(
-    PartialOrd, PartialEq, Eq, Ord, Clone, Debug, Serialize, Deserialize, DefaultJson, Default, Hash,
-)]
+// equality is implemented by hand but is the same relation the derived Hash relies on
+#[allow(clippy::derive_hash_xor_eq)]
+#[derive(PartialOrd, Eq, Ord, Clone, Debug, Serialize, Deserialize, DefaultJson, Default, Hash)]
 pub struct HashString(String);
 
+/// Takes as long for any two strings of the same length, so comparing an address from the
+/// network with stored ones doesn't give away how much of it matched.
+impl PartialEq for HashString {
+    fn eq(&self, other: &HashString) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.0.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
+
+/// Parses a base58 multihash or an hcid encoded key, rejecting anything else. The `From`
+/// conversions take any string as it is, so addresses from untrusted input should be parsed.
+impl FromStr for HashString {
+    type Err = PersistenceError;
+
+    fn from_str(s: &str) -> PersistenceResult<HashString> {
+        let hash = HashString::from(s);
+        hash.validate()?;
+        Ok(hash)
+    }
+}
+
 impl fmt::Display for HashString {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -88,6 +115,30 @@ impl HashString {
     pub fn encode_from_json_string(json_string: JsonString, hash_type: Hash) -> HashString {
         HashString::encode_from_str(&String::from(json_string), hash_type)
     }
+
+    /// Fails unless this is a base58 multihash or an hcid encoded key.
+    pub fn validate(&self) -> PersistenceResult<()> {
+        let multihash = self
+            .0
+            .from_base58()
+            .map(|bytes| decode(&bytes).is_ok())
+            .unwrap_or(false);
+        let hcid = || {
+            HCID_KINDS.iter().any(|kind| {
+                hcid::HcidEncoding::with_kind(kind)
+                    .and_then(|encoding| encoding.decode(&self.0))
+                    .is_ok()
+            })
+        };
+        if multihash || (self.0.starts_with("Hc") && hcid()) {
+            return Ok(());
+        }
+        let shown: String = self.0.chars().take(64).collect();
+        Err(PersistenceError::from(format!(
+            "malformed address {}: neither a base58 multihash nor an hcid",
+            shown
+        )))
+    }
 }
 
 #[cfg(test)]
@@ -116,6 +167,27 @@ pub mod tests {
         assert_eq!(test_hash_a(), HashString::from(test_entry_a().address()),);
     }
 
+    #[test]
+    fn addresses_are_validated_when_parsed() {
+        let hash = HashString::encode_from_str("foo", Hash::SHA2256);
+        assert_eq!(Ok(hash.clone()), hash.to_string().parse());
+        let agent = hcid::HcidEncoding::with_kind("hcs0")
+            .unwrap()
+            .encode(&[7; 32])
+            .unwrap();
+        assert_eq!(Ok(HashString::from(agent.clone())), agent.parse());
+
+        // too short, not base58 and not a known hash
+        let truncated = &hash.to_string()[..20];
+        for malformed in &["", truncated, "0OIl", "HcSnot-an-agent", "3yZe7d"] {
+            assert!(malformed.parse::<HashString>().is_err(), "{}", malformed);
+        }
+        // still usable as they are
+        assert_eq!("0OIl", HashString::from("0OIl").to_string());
+        assert_ne!(HashString::from("ab"), HashString::from("ac"));
+        assert_ne!(HashString::from("ab"), HashString::from("abc"));
+    }
+
     #[test]
     /// mimics tests from legacy golang holochain core hashing bytes
     fn bytes_to_b58_known_golang() {
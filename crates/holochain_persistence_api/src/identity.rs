@@ -0,0 +1,46 @@
+//! What a store is, kept with it so it can be told apart from other stores across restarts.
+//!
+//! `get_id` is what stores are compared by. Stores that only live as long as the process make
+//! up a random one, persistent stores write a `StoreIdentity` when they are first created and
+//! read it back every time they are opened after, so replication and backup tooling see the
+//! same store as the same store.
+
+use std::{
+    any,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// Version of the layout stores are written in now.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreIdentity {
+    pub id: Uuid,
+    /// when the store was created, in milliseconds since the epoch
+    pub created_at: u64,
+    /// type name of the attributes of an EAV store, None for other stores
+    pub attribute_type: Option<String>,
+    /// version of the layout the store was created with
+    pub format_version: u32,
+}
+
+impl StoreIdentity {
+    /// The identity of a store being created now.
+    pub fn new(attribute_type: Option<String>) -> StoreIdentity {
+        StoreIdentity {
+            id: Uuid::new_v4(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+            attribute_type,
+            format_version: FORMAT_VERSION,
+        }
+    }
+
+    /// The identity of an EAV store of `A`s being created now.
+    pub fn for_attribute<A>() -> StoreIdentity {
+        StoreIdentity::new(Some(any::type_name::<A>().to_string()))
+    }
+}
@@ -0,0 +1,259 @@
+//! An append only, hash linked log, for source chain like records kept on top of the CAS and an
+//! EAV store.
+//!
+//! Every record appended to a journal is stored in the CAS next to a `JournalEntry` holding its
+//! position, its address and the address of the entry before it, so an entry's address covers
+//! the whole chain up to it. The entry is linked from the journal with an EAVI whose attribute
+//! is the position, which is what `entry` and `iter_from` look up.
+
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+};
+use eav::{
+    EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, IndexFilter, OrderBy,
+    StringAttribute,
+};
+use error::{PersistenceError, PersistenceResult};
+use hash::HashString;
+use multihash::Hash;
+
+/// The position of a record in a journal, the first is 0.
+pub type SequenceNo = u64;
+
+/// Records handed out one at a time, see `Journal::iter_from`.
+pub type JournalCursor<'a> =
+    Box<dyn Iterator<Item = PersistenceResult<(SequenceNo, Content)>> + 'a>;
+
+/// Where the entries of the journal with this name are linked from.
+pub fn journal_address(name: &str) -> Address {
+    HashString::encode_from_str(&format!("journal::{}", name), Hash::SHA2256)
+}
+
+fn position(seq: SequenceNo) -> StringAttribute {
+    // zero padded so the attributes sort like the positions
+    StringAttribute(format!("journal/{:020}", seq))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: SequenceNo,
+    /// the entry before this one, None for the first
+    pub previous: Option<Address>,
+    /// the record appended
+    pub content: Address,
+}
+
+impl JournalEntry {
+    fn to_content(&self) -> PersistenceResult<Content> {
+        Ok(Content::from_json(&serde_json::to_string(self)?))
+    }
+}
+
+/// A single writer, as two writers could append at the same position.
+#[derive(Clone, Debug)]
+pub struct Journal<C, E> {
+    cas: C,
+    eav: E,
+    address: Address,
+}
+
+impl<C, E> Journal<C, E>
+where
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<StringAttribute>,
+{
+    /// Opens the journal `name` in the stores, picking up where it was left if it exists.
+    pub fn new(name: &str, cas: C, eav: E) -> Journal<C, E> {
+        Journal {
+            cas,
+            eav,
+            address: journal_address(name),
+        }
+    }
+
+    /// Appends `content`, returning its position.
+    pub fn append(&mut self, content: &dyn AddressableContent) -> PersistenceResult<SequenceNo> {
+        let (seq, previous) = match self.latest_entry()? {
+            Some((address, entry)) => (entry.seq + 1, Some(address)),
+            None => (0, None),
+        };
+        self.cas.add(content)?;
+        let entry = JournalEntry {
+            seq,
+            previous,
+            content: content.address(),
+        }
+        .to_content()?;
+        self.cas.add(&entry)?;
+        self.eav.add_eavi(&EntityAttributeValueIndex::new(
+            &self.address,
+            &position(seq),
+            &entry.address(),
+        )?)?;
+        Ok(seq)
+    }
+
+    /// The address of the entry linked at `query`, the last one linked if there are several.
+    fn last_linked(&self, query: EaviQuery<StringAttribute>) -> PersistenceResult<Option<Address>> {
+        let linked = self
+            .eav
+            .fetch_eavi_ordered(&query.with_order_by(OrderBy::IndexDescending).with_limit(1))?;
+        Ok(linked.into_iter().next().map(|eavi| eavi.value()))
+    }
+
+    fn fetch_entry(&self, address: &Address) -> PersistenceResult<JournalEntry> {
+        match self.cas.fetch(address)? {
+            Some(content) => Ok(serde_json::from_str(&String::from(content))?),
+            None => Err(PersistenceError::Corruption(format!(
+                "journal entry {} is missing",
+                address
+            ))),
+        }
+    }
+
+    fn latest_entry(&self) -> PersistenceResult<Option<(Address, JournalEntry)>> {
+        let latest = self.last_linked(EaviQuery::new(
+            Some(self.address.clone()).into(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        ))?;
+        match latest {
+            Some(address) => Ok(Some((address.clone(), self.fetch_entry(&address)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The entry at position `seq`, None past the end of the journal.
+    pub fn entry(&self, seq: SequenceNo) -> PersistenceResult<Option<JournalEntry>> {
+        let linked = self.last_linked(EaviQuery::new(
+            Some(self.address.clone()).into(),
+            Some(position(seq)).into(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        ))?;
+        linked.map(|address| self.fetch_entry(&address)).transpose()
+    }
+
+    fn record(&self, entry: &JournalEntry) -> PersistenceResult<Content> {
+        self.cas.fetch(&entry.content)?.ok_or_else(|| {
+            PersistenceError::Corruption(format!(
+                "journal record {} at {} is missing",
+                entry.content, entry.seq
+            ))
+        })
+    }
+
+    /// The last record appended and its position, None if the journal is empty.
+    pub fn latest(&self) -> PersistenceResult<Option<(SequenceNo, Content)>> {
+        match self.latest_entry()? {
+            Some((_, entry)) => Ok(Some((entry.seq, self.record(&entry)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The records from position `seq` on, in order.
+    pub fn iter_from(&self, seq: SequenceNo) -> JournalCursor {
+        let mut next = Some(seq);
+        Box::new(::std::iter::from_fn(move || {
+            let seq = next.take()?;
+            let record = match self.entry(seq) {
+                Ok(Some(entry)) => self.record(&entry).map(|record| (seq, record)),
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            };
+            if record.is_ok() {
+                next = Some(seq + 1);
+            }
+            Some(record)
+        }))
+    }
+
+    /// Checks that every entry up to the latest is there, at its position, links to the one
+    /// before it and has its record stored, failing with `PersistenceError::Corruption` if not.
+    pub fn verify(&self) -> PersistenceResult<()> {
+        let latest = match self.latest_entry()? {
+            Some((_, latest)) => latest.seq,
+            None => return Ok(()),
+        };
+        let mut previous = None;
+        for seq in 0..=latest {
+            let entry = self.entry(seq)?.ok_or_else(|| {
+                PersistenceError::Corruption(format!("journal entry {} is missing", seq))
+            })?;
+            if entry.seq != seq || entry.previous != previous {
+                return Err(PersistenceError::Corruption(format!(
+                    "journal entry {} doesn't follow the entry before it",
+                    seq
+                )));
+            }
+            self.record(&entry)?;
+            previous = Some(entry.to_content()?.address());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::ExampleEntityAttributeValueStorage;
+
+    fn record(n: u64) -> Content {
+        Content::from_json(&format!("{{\"record\":{}}}", n))
+    }
+
+    #[test]
+    fn journal_records_are_hash_linked() {
+        let cas = test_content_addressable_storage();
+        let eav = ExampleEntityAttributeValueStorage::new();
+        let mut journal = Journal::new("headers", cas.clone(), eav.clone());
+        assert_eq!(Ok(None), journal.latest());
+        assert_eq!(Ok(()), journal.verify());
+        for n in 0..4 {
+            assert_eq!(Ok(n), journal.append(&record(n)));
+        }
+        assert_eq!(Ok(Some((3, record(3)))), journal.latest());
+        assert_eq!(
+            Ok(vec![(2, record(2)), (3, record(3))]),
+            journal.iter_from(2).collect()
+        );
+        assert_eq!(0, journal.iter_from(4).count());
+        assert_eq!(Ok(()), journal.verify());
+
+        // journals are kept apart by name and picked up again by it
+        let mut other = Journal::new("other", cas.clone(), eav.clone());
+        assert_eq!(Ok(0), other.append(&record(9)));
+        let mut reopened = Journal::new("headers", cas.clone(), eav.clone());
+        assert_eq!(Ok(4), reopened.append(&record(4)));
+
+        // an entry written around the journal breaks the chain
+        let forged = JournalEntry {
+            seq: 5,
+            previous: None,
+            content: record(5).address(),
+        }
+        .to_content()
+        .unwrap();
+        cas.add(&record(5)).unwrap();
+        cas.add(&forged).unwrap();
+        let mut eav = eav;
+        eav.add_eavi(
+            &EntityAttributeValueIndex::new(
+                &journal_address("headers"),
+                &position(5),
+                &forged.address(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        match reopened.verify() {
+            Err(PersistenceError::Corruption(_)) => (),
+            other => panic!("forged entry passed verification: {:?}", other),
+        }
+    }
+}
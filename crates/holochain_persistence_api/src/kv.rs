@@ -0,0 +1,116 @@
+//! A key value store next to the CAS and the EAV store, for data that is neither addressed by its
+//! content nor a triple, e.g. config blobs, application indexes and checkpoints.
+
+use error::PersistenceResult;
+use objekt;
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+
+/// Entries handed out one at a time, see `KvStorage::scan`.
+pub type KvCursor<'a> = Box<dyn Iterator<Item = PersistenceResult<(String, Vec<u8>)>> + 'a>;
+
+pub trait KvStorage: objekt::Clone + Send + Sync + Debug {
+    fn get(&self, key: &str) -> PersistenceResult<Option<Vec<u8>>>;
+
+    /// Stores `value` under `key`, replacing what was stored under it.
+    fn put(&self, key: &str, value: &[u8]) -> PersistenceResult<()>;
+
+    /// Removes the value under `key`, returning whether there was one.
+    fn delete(&self, key: &str) -> PersistenceResult<bool>;
+
+    /// The entries whose key starts with `prefix`, in key order.
+    fn scan<'a>(&'a self, prefix: &str) -> KvCursor<'a>;
+}
+
+clone_trait_object!(KvStorage);
+
+/// Kept in memory, clones share the entries.
+#[derive(Clone, Debug, Default)]
+pub struct ExampleKvStorage {
+    entries: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl ExampleKvStorage {
+    pub fn new() -> ExampleKvStorage {
+        Default::default()
+    }
+}
+
+impl KvStorage for ExampleKvStorage {
+    fn get(&self, key: &str) -> PersistenceResult<Option<Vec<u8>>> {
+        Ok(self.entries.read()?.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> PersistenceResult<()> {
+        self.entries
+            .write()?
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> PersistenceResult<bool> {
+        Ok(self.entries.write()?.remove(key).is_some())
+    }
+
+    fn scan<'a>(&'a self, prefix: &str) -> KvCursor<'a> {
+        let entries = match self.entries.read() {
+            Ok(entries) => entries,
+            Err(e) => return Box::new(::std::iter::once(Err(e.into()))),
+        };
+        let matching: Vec<_> = entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(matching.into_iter())
+    }
+}
+
+/// Puts, replaces, deletes and scans entries of an empty `store`.
+pub fn test_kv_storage<K: KvStorage>(store: K) {
+    assert_eq!(Ok(None), store.get("config"));
+    assert_eq!(Ok(()), store.put("config", b"{}"));
+    assert_eq!(Ok(()), store.put("config", b"{\"replaced\":true}"));
+    assert_eq!(
+        Ok(Some(b"{\"replaced\":true}".to_vec())),
+        store.get("config")
+    );
+
+    for (key, value) in &[
+        ("checkpoint/2", "b"),
+        ("checkpoint/1", "a"),
+        ("checkpoint/10", "c"),
+        ("checkpoints", "not one"),
+        ("check", "neither"),
+    ] {
+        store.put(key, value.as_bytes()).unwrap();
+    }
+    let scanned: PersistenceResult<Vec<_>> = store.scan("checkpoint/").collect();
+    assert_eq!(
+        Ok(vec![
+            ("checkpoint/1".to_string(), b"a".to_vec()),
+            ("checkpoint/10".to_string(), b"c".to_vec()),
+            ("checkpoint/2".to_string(), b"b".to_vec()),
+        ]),
+        scanned
+    );
+    assert_eq!(6, store.scan("").count());
+
+    assert_eq!(Ok(true), store.delete("checkpoint/10"));
+    assert_eq!(Ok(false), store.delete("checkpoint/10"));
+    assert_eq!(Ok(None), store.get("checkpoint/10"));
+    assert_eq!(2, store.scan("checkpoint/").count());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_kv_storage() {
+        test_kv_storage(ExampleKvStorage::new());
+    }
+}
@@ -10,10 +10,13 @@ extern crate test;
 extern crate lazy_static;
 
 extern crate chrono;
+#[cfg(feature = "async")]
 extern crate futures;
 extern crate multihash;
 extern crate regex;
+extern crate rmp_serde;
 extern crate rust_base58;
+extern crate serde_cbor;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
@@ -22,12 +25,32 @@ extern crate holochain_json_derive;
 extern crate holochain_json_api;
 extern crate uuid;
 
+pub mod access;
+pub mod batch;
 pub mod cas;
+pub mod constraint;
 pub mod eav;
 pub mod error;
+pub mod events;
 pub mod fixture;
+pub mod format;
+pub mod graph;
 pub mod hash;
+pub mod identity;
+pub mod journal;
+pub mod kv;
+pub mod limits;
+pub mod merge;
+#[cfg(feature = "async")]
+pub mod persistence_service;
+pub mod persistence_wasm_host;
+pub mod registry;
+pub mod replication;
 pub mod reporting;
+pub mod retry;
+pub mod sequence;
+pub mod view;
+pub mod warm;
 
 #[macro_use]
 extern crate objekt;
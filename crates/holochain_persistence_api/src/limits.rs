@@ -0,0 +1,240 @@
+//! Bounds on what a single write may add, so one buggy or malicious app can't grow a store
+//! without end, e.g. with a single entry of gigabytes.
+//!
+//! `Limits` caps the size of content and the number of EAVIs an entity may have for a single
+//! attribute. Writes beyond either fail with `PersistenceError::LimitExceeded` before anything
+//! is written. `Limited` enforces them in front of any CAS or EAV store; stores that can check
+//! them more cheaply themselves, like the LMDB ones, take `Limits` directly.
+//!
+//! `Limited` counts the EAVIs an entity has in one call to its store and adds the new one in
+//! another, so adds racing through clones of a store that share its EAVIs can each pass the
+//! count and together go past `max_eavis_per_attribute`. The LMDB EAV store counts them in the
+//! transaction it writes the EAVI in and has no such race.
+
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+    stream::ContentChunks,
+};
+use eav::{
+    Attribute, AttributeHistogram, EaviCursor, EaviQuery, Entity, EntityAttributeValueIndex,
+    EntityAttributeValueStorage, EntityCursor, IndexFilter, Value,
+};
+use error::{PersistenceError, PersistenceResult};
+use reporting::{ReportStorage, StorageReport};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+/// Bounds on writes, None for no bound.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// bytes of the JSON of a single content
+    #[serde(default)]
+    pub max_content_bytes: Option<usize>,
+    /// EAVIs with the same entity and attribute
+    #[serde(default)]
+    pub max_eavis_per_attribute: Option<usize>,
+}
+
+impl Limits {
+    /// Fails if the JSON of `content` is longer than `max_content_bytes`.
+    pub fn check_content(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        match self.max_content_bytes {
+            Some(_) => {
+                self.check_content_bytes(&content.address(), String::from(content.content()).len())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if `bytes` of JSON at `address` are more than `max_content_bytes`, for stores
+    /// that have the JSON at hand already.
+    pub fn check_content_bytes(&self, address: &Address, bytes: usize) -> PersistenceResult<()> {
+        match self.max_content_bytes {
+            Some(max) if bytes > max => Err(PersistenceError::LimitExceeded(format!(
+                "content at {} is {} bytes, more than the {} allowed",
+                address, bytes, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if `stored` EAVIs with the entity and attribute of `eavi` already reach
+    /// `max_eavis_per_attribute`, so `eavi` can't be added.
+    pub fn check_eavi_count<A: Attribute>(
+        &self,
+        eavi: &EntityAttributeValueIndex<A>,
+        stored: usize,
+    ) -> PersistenceResult<()> {
+        match self.max_eavis_per_attribute {
+            Some(max) if stored >= max => Err(PersistenceError::LimitExceeded(format!(
+                "{} already has {} EAVIs with attribute {:?}, the most allowed",
+                eavi.entity(),
+                stored,
+                eavi.attribute()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A CAS or EAV store failing writes beyond its `Limits`.
+#[derive(Clone, Debug)]
+pub struct Limited<S> {
+    store: S,
+    limits: Limits,
+}
+
+impl<S> Limited<S> {
+    pub fn new(store: S, limits: Limits) -> Limited<S> {
+        Limited { store, limits }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: ContentAddressableStorage + Clone> ContentAddressableStorage for Limited<S> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        self.limits.check_content(content)?;
+        self.store.add(content)
+    }
+
+    fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        self.store.contains(address)
+    }
+
+    fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        self.store.fetch(address)
+    }
+
+    fn stream_content(&self, address: &Address, chunk_size: usize) -> ContentChunks {
+        self.store.stream_content(address, chunk_size)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.store.get_id()
+    }
+}
+
+impl<A, S> EntityAttributeValueStorage<A> for Limited<S>
+where
+    A: Attribute + Send + Sync,
+    S: EntityAttributeValueStorage<A> + Clone,
+{
+    /// Not atomic: another add between the count and the add can go past the limit, see the
+    /// module docs.
+    fn add_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        if self.limits.max_eavis_per_attribute.is_some() {
+            let stored = self.store.fetch_eavi(&EaviQuery::new(
+                Some(eav.entity()).into(),
+                Some(eav.attribute()).into(),
+                Default::default(),
+                IndexFilter::Range(None, None),
+                None,
+            ))?;
+            self.limits.check_eavi_count(eav, stored.len())?;
+        }
+        self.store.add_eavi(eav)
+    }
+
+    /// Replaces what the entity has for the attribute, so it never adds to their number.
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        self.store.upsert_eavi(eav)
+    }
+
+    fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        self.store.fetch_eavi(query)
+    }
+
+    fn fetch_eavi_ordered(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        self.store.fetch_eavi_ordered(query)
+    }
+
+    fn fetch_distinct_values(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Value>> {
+        self.store.fetch_distinct_values(query)
+    }
+
+    fn fetch_distinct_entities(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Entity>> {
+        self.store.fetch_distinct_entities(query)
+    }
+
+    fn attribute_histogram(&self) -> PersistenceResult<AttributeHistogram<A>> {
+        self.store.attribute_histogram()
+    }
+
+    fn iter_eavi<'a>(&'a self, query: &EaviQuery<A>) -> EaviCursor<'a, A>
+    where
+        A: 'a,
+    {
+        self.store.iter_eavi(query)
+    }
+
+    fn entities_with_attribute<'a>(&'a self, attribute: &A) -> EntityCursor<'a> {
+        self.store.entities_with_attribute(attribute)
+    }
+}
+
+impl<S: ReportStorage> ReportStorage for Limited<S> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.store.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleAttribute, ExampleEntityAttributeValueStorage};
+
+    #[test]
+    fn writes_beyond_the_limits_fail() {
+        let limits = Limits {
+            max_content_bytes: Some(16),
+            max_eavis_per_attribute: Some(2),
+        };
+        let cas = Limited::new(test_content_addressable_storage(), limits);
+        let mut eav = Limited::new(ExampleEntityAttributeValueStorage::new(), limits);
+
+        let small = Content::from_json("\"small\"");
+        let big = Content::from_json("\"far more than sixteen bytes\"");
+        assert_eq!(Ok(()), cas.add(&small));
+        match cas.add(&big) {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected the content to be too big, got {:?}", other),
+        }
+        assert_eq!(Ok(false), cas.contains(&big.address()));
+
+        let link = |attribute: &ExampleAttribute| {
+            EntityAttributeValueIndex::new(&small.address(), attribute, &small.address()).unwrap()
+        };
+        let linked = ExampleAttribute::WithPayload("linked".to_string());
+        eav.add_eavi(&link(&linked)).unwrap();
+        eav.add_eavi(&link(&linked)).unwrap();
+        match eav.add_eavi(&link(&linked)) {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+        // other attributes of the entity have their own count
+        assert!(eav
+            .add_eavi(&link(&ExampleAttribute::WithoutPayload))
+            .is_ok());
+    }
+}
@@ -0,0 +1,261 @@
+//! How EAVIs coming from another store, through replication or gossip, are merged into a store.
+//!
+//! Adding every incoming EAVI makes the result depend on the order they arrive in, and on the
+//! timestamps in their indexes. A `MergeStrategy` decides instead, from the incoming EAVI and
+//! those already stored for its entity and attribute, so stores merging the same EAVIs in any
+//! order converge. `merge_eavi` applies a strategy to a store.
+
+use eav::{
+    Attribute, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, IndexFilter,
+};
+use error::PersistenceResult;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+/// What to do with an incoming EAVI.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Merge<A: Attribute> {
+    /// nothing, the store already has what it needs
+    Skip,
+    /// add it next to the EAVIs stored for its entity and attribute
+    Add(EntityAttributeValueIndex<A>),
+    /// replace the EAVIs stored for its entity and attribute with it
+    Upsert(EntityAttributeValueIndex<A>),
+}
+
+pub trait MergeStrategy<A: Attribute>: Send + Sync + Debug {
+    /// Decides what to do with `incoming` given the EAVIs `existing` with its entity and
+    /// attribute. The same EAVIs have to lead to the same decision whatever order they came in.
+    fn merge(
+        &self,
+        incoming: &EntityAttributeValueIndex<A>,
+        existing: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Merge<A>;
+}
+
+/// Add-wins set semantics, e.g. for links: every EAVI is kept, once, so what was added on
+/// either side survives the merge.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AddWins;
+
+impl<A: Attribute> MergeStrategy<A> for AddWins {
+    fn merge(
+        &self,
+        incoming: &EntityAttributeValueIndex<A>,
+        existing: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Merge<A> {
+        if existing.contains(incoming) {
+            Merge::Skip
+        } else {
+            Merge::Add(incoming.clone())
+        }
+    }
+}
+
+/// Single valued attributes: only the EAVI with the highest index is kept, the one with the
+/// highest value if indexes tie.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LastWriterWins;
+
+impl<A: Attribute> MergeStrategy<A> for LastWriterWins {
+    fn merge(
+        &self,
+        incoming: &EntityAttributeValueIndex<A>,
+        existing: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Merge<A> {
+        let newer = existing
+            .iter()
+            .any(|eavi| (eavi.index(), eavi.value()) >= (incoming.index(), incoming.value()));
+        if newer {
+            Merge::Skip
+        } else {
+            Merge::Upsert(incoming.clone())
+        }
+    }
+}
+
+/// A strategy per attribute, and one for the others.
+#[derive(Clone, Debug)]
+pub struct PerAttribute<A: Attribute> {
+    strategies: BTreeMap<A, Arc<dyn MergeStrategy<A>>>,
+    default: Arc<dyn MergeStrategy<A>>,
+}
+
+impl<A: Attribute> PerAttribute<A> {
+    pub fn new<S: MergeStrategy<A> + 'static>(default: S) -> PerAttribute<A> {
+        PerAttribute {
+            strategies: BTreeMap::new(),
+            default: Arc::new(default),
+        }
+    }
+
+    pub fn with_strategy<S: MergeStrategy<A> + 'static>(
+        mut self,
+        attribute: A,
+        strategy: S,
+    ) -> PerAttribute<A> {
+        self.strategies.insert(attribute, Arc::new(strategy));
+        self
+    }
+}
+
+impl<A: Attribute + Send + Sync> MergeStrategy<A> for PerAttribute<A> {
+    fn merge(
+        &self,
+        incoming: &EntityAttributeValueIndex<A>,
+        existing: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Merge<A> {
+        self.strategies
+            .get(&incoming.attribute())
+            .unwrap_or(&self.default)
+            .merge(incoming, existing)
+    }
+}
+
+pub type MergeFn<A> = Arc<
+    dyn Fn(&EntityAttributeValueIndex<A>, &BTreeSet<EntityAttributeValueIndex<A>>) -> Merge<A>
+        + Send
+        + Sync,
+>;
+
+/// A custom strategy from a closure.
+#[derive(Clone)]
+pub struct CustomMerge<A: Attribute> {
+    name: String,
+    merge: MergeFn<A>,
+}
+
+impl<A: Attribute> Debug for CustomMerge<A> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("CustomMerge")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<A: Attribute> CustomMerge<A> {
+    pub fn new<F>(name: &str, merge: F) -> CustomMerge<A>
+    where
+        F: Fn(&EntityAttributeValueIndex<A>, &BTreeSet<EntityAttributeValueIndex<A>>) -> Merge<A>
+            + Send
+            + Sync
+            + 'static,
+    {
+        CustomMerge {
+            name: name.to_string(),
+            merge: Arc::new(merge),
+        }
+    }
+}
+
+impl<A: Attribute> MergeStrategy<A> for CustomMerge<A> {
+    fn merge(
+        &self,
+        incoming: &EntityAttributeValueIndex<A>,
+        existing: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> Merge<A> {
+        (self.merge)(incoming, existing)
+    }
+}
+
+/// Merges `incoming` into `eav` as `strategy` decides, returning what was written.
+pub fn merge_eavi<A: Attribute>(
+    eav: &mut dyn EntityAttributeValueStorage<A>,
+    strategy: &dyn MergeStrategy<A>,
+    incoming: &EntityAttributeValueIndex<A>,
+) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+    let existing = eav.fetch_eavi(&EaviQuery::new(
+        Some(incoming.entity()).into(),
+        Some(incoming.attribute()).into(),
+        Default::default(),
+        IndexFilter::Range(None, None),
+        None,
+    ))?;
+    match strategy.merge(incoming, &existing) {
+        Merge::Skip => Ok(None),
+        Merge::Add(eavi) => eav.add_eavi(&eavi),
+        Merge::Upsert(eavi) => eav.upsert_eavi(&eavi),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::content::Address;
+    use eav::{ExampleEntityAttributeValueStorage, StringAttribute};
+
+    fn eavi(
+        attribute: &str,
+        value: &str,
+        index: i64,
+    ) -> EntityAttributeValueIndex<StringAttribute> {
+        EntityAttributeValueIndex::new_with_index(
+            &Address::from("entity"),
+            &StringAttribute(attribute.to_string()),
+            &Address::from(value),
+            index,
+        )
+        .unwrap()
+    }
+
+    fn merged(
+        strategy: &dyn MergeStrategy<StringAttribute>,
+        incoming: &[EntityAttributeValueIndex<StringAttribute>],
+    ) -> BTreeSet<EntityAttributeValueIndex<StringAttribute>> {
+        let mut eav = ExampleEntityAttributeValueStorage::new();
+        for eavi in incoming {
+            merge_eavi(&mut eav, strategy, eavi).unwrap();
+        }
+        eav.fetch_eavi(&EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn merges_converge_whatever_the_order() {
+        let writes = vec![
+            eavi("name", "alice", 1),
+            eavi("name", "bob", 5),
+            eavi("name", "carol", 5),
+            eavi("link", "x", 2),
+            eavi("link", "y", 3),
+            eavi("link", "x", 2),
+        ];
+        let mut reversed = writes.clone();
+        reversed.reverse();
+        let strategy = PerAttribute::new(AddWins)
+            .with_strategy(StringAttribute("name".to_string()), LastWriterWins);
+        let forward = merged(&strategy, &writes);
+        assert_eq!(forward, merged(&strategy, &reversed));
+        assert_eq!(
+            vec![
+                eavi("link", "x", 2),
+                eavi("link", "y", 3),
+                eavi("name", "carol", 5)
+            ],
+            {
+                let mut forward: Vec<_> = forward.into_iter().collect();
+                forward.sort_by_key(|eavi| (eavi.attribute(), eavi.index()));
+                forward
+            }
+        );
+
+        // a custom strategy only taking the first value
+        let first_wins = CustomMerge::new("first wins", |incoming, existing| {
+            if existing.is_empty() {
+                Merge::Add(incoming.clone())
+            } else {
+                Merge::Skip
+            }
+        });
+        assert_eq!(1, merged(&first_wins, &writes[..3]).len());
+    }
+}
@@ -0,0 +1,603 @@
+//! A message passing front end for a CAS and an EAV store.
+//!
+//! `PersistenceActor` owns the stores on a thread of its own and serves requests sent through
+//! a `PersistenceHandle`. Handles are cheap to clone and every request returns a future, so
+//! the stores can be shared across threads and used from async code without locking them.
+//! Blocking callers can wait on a request with `futures::executor::block_on`.
+//! On Unix the actor can also be served to other processes, see `socket`.
+//!
+//! Writes can be grouped in a transaction: `begin_txn` hands out a `TxnId`, writes staged
+//! under it are kept by the actor and only written on `commit`, without requests from other
+//! handles in between. The stores have no transactions of their own, so a write failing during
+//! a commit leaves those staged before it in place and the commit fails with a `PartialCommit`
+//! telling which they are. A transaction left unused for `DEFAULT_TXN_TIMEOUT`, or the timeout
+//! the actor was spawned with, is rolled back, so one its handle forgot doesn't stay open.
+
+use batch::PartialCommit;
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+};
+use constraint::StagedContent;
+pub use eav::OwnedEaviQuery;
+use eav::{Attribute, EntityAttributeValueIndex, EntityAttributeValueStorage};
+use error::{PersistenceError, PersistenceResult};
+use futures::{
+    channel::oneshot::{self, Canceled},
+    future::{FutureExt, Map},
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    marker::PhantomData,
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+pub mod socket;
+
+type Reply<T> = oneshot::Sender<PersistenceResult<T>>;
+
+/// A transaction of a `PersistenceActor`, handed out by `PersistenceHandle::begin_txn`.
+pub type TxnId = u64;
+
+/// How long a transaction is kept open without being used unless set otherwise.
+pub const DEFAULT_TXN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The response to a request sent through a `PersistenceHandle`.
+pub type PersistenceFuture<T> = Map<
+    oneshot::Receiver<PersistenceResult<T>>,
+    fn(Result<PersistenceResult<T>, Canceled>) -> PersistenceResult<T>,
+>;
+
+/// The response to `PersistenceHandle::commit`.
+pub type CommitFuture<A> = Map<
+    oneshot::Receiver<Result<(), PartialCommit<A>>>,
+    fn(Result<Result<(), PartialCommit<A>>, Canceled>) -> Result<(), PartialCommit<A>>,
+>;
+
+/// The protocol spoken between `PersistenceHandle` and `PersistenceActor`.
+#[derive(Debug)]
+pub enum PersistenceMessage<A: Attribute> {
+    AddContent {
+        address: Address,
+        content: Content,
+        reply: Reply<()>,
+    },
+    FetchContent {
+        address: Address,
+        reply: Reply<Option<Content>>,
+    },
+    ContainsContent {
+        address: Address,
+        reply: Reply<bool>,
+    },
+    AddEavi {
+        eavi: EntityAttributeValueIndex<A>,
+        reply: Reply<Option<EntityAttributeValueIndex<A>>>,
+    },
+    QueryEavi {
+        query: OwnedEaviQuery<A>,
+        reply: Reply<BTreeSet<EntityAttributeValueIndex<A>>>,
+    },
+    BeginTxn {
+        reply: Reply<TxnId>,
+    },
+    /// content written on `Commit` of the transaction
+    StageContent {
+        txn: TxnId,
+        address: Address,
+        content: Content,
+        reply: Reply<()>,
+    },
+    /// an EAVI written on `Commit` of the transaction
+    StageEavi {
+        txn: TxnId,
+        eavi: EntityAttributeValueIndex<A>,
+        reply: Reply<()>,
+    },
+    /// writes what was staged in the transaction, in the order it was staged, and ends it,
+    /// failing with the writes applied before one failed
+    Commit {
+        txn: TxnId,
+        reply: oneshot::Sender<Result<(), PartialCommit<A>>>,
+    },
+    /// ends the transaction without writing what was staged in it
+    Rollback {
+        txn: TxnId,
+        reply: Reply<()>,
+    },
+    /// stops the actor once the messages sent before it have been handled
+    Shutdown,
+}
+
+/// A write staged in a transaction. Content keeps the address it was given by its original
+/// type.
+enum Staged<A: Attribute> {
+    Content(StagedContent),
+    Eavi(EntityAttributeValueIndex<A>),
+}
+
+/// An open transaction.
+struct Txn<A: Attribute> {
+    staged: Vec<Staged<A>>,
+    /// when the transaction was last begun or staged in
+    used: Instant,
+}
+
+pub struct PersistenceActor<C, E, A>
+where
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+    A: Attribute,
+{
+    cas: C,
+    eav: E,
+    /// the open transactions and what was staged in them
+    txns: HashMap<TxnId, Txn<A>>,
+    next_txn: TxnId,
+    txn_timeout: Duration,
+    attribute: PhantomData<A>,
+}
+
+fn no_txn(txn: TxnId) -> PersistenceError {
+    PersistenceError::from(format!(
+        "there is no open transaction {}, it was ended or timed out",
+        txn
+    ))
+}
+
+impl<C, E, A> PersistenceActor<C, E, A>
+where
+    C: ContentAddressableStorage + 'static,
+    E: EntityAttributeValueStorage<A> + 'static,
+    A: Attribute + Send + 'static,
+{
+    /// Moves the stores onto a new thread and returns a handle to talk to them.
+    /// The actor stops once every handle has been dropped or it is sent `Shutdown`.
+    pub fn spawn(cas: C, eav: E) -> PersistenceHandle<A> {
+        PersistenceActor::spawn_with_txn_timeout(cas, eav, DEFAULT_TXN_TIMEOUT)
+    }
+
+    /// Spawns the actor rolling back transactions that go unused for `txn_timeout`.
+    pub fn spawn_with_txn_timeout(cas: C, eav: E, txn_timeout: Duration) -> PersistenceHandle<A> {
+        let (sender, receiver) = channel();
+        let mut actor = PersistenceActor {
+            cas,
+            eav,
+            txns: HashMap::new(),
+            next_txn: 0,
+            txn_timeout,
+            attribute: PhantomData,
+        };
+        thread::Builder::new()
+            .name("persistence-actor".to_string())
+            .spawn(move || loop {
+                match receiver.recv_timeout(txn_timeout) {
+                    Ok(message) => {
+                        if !actor.handle(message) {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                actor.expire_txns();
+            })
+            .expect("Could not spawn the persistence actor thread");
+        PersistenceHandle { sender }
+    }
+
+    /// Rolls back the transactions that went unused for longer than the timeout.
+    fn expire_txns(&mut self) {
+        let timeout = self.txn_timeout;
+        self.txns.retain(|_, txn| txn.used.elapsed() < timeout);
+    }
+
+    /// Returns false once the actor should stop.
+    fn handle(&mut self, message: PersistenceMessage<A>) -> bool {
+        // a requester that stopped waiting for its reply is not an error
+        match message {
+            PersistenceMessage::AddContent {
+                address,
+                content,
+                reply,
+            } => {
+                let _ = reply.send(self.cas.add(&StagedContent { address, content }));
+            }
+            PersistenceMessage::FetchContent { address, reply } => {
+                let _ = reply.send(self.cas.fetch(&address));
+            }
+            PersistenceMessage::ContainsContent { address, reply } => {
+                let _ = reply.send(self.cas.contains(&address));
+            }
+            PersistenceMessage::AddEavi { eavi, reply } => {
+                let _ = reply.send(self.eav.add_eavi(&eavi));
+            }
+            PersistenceMessage::QueryEavi { query, reply } => {
+                let _ = reply.send(self.eav.fetch_eavi(&query.as_query()));
+            }
+            PersistenceMessage::BeginTxn { reply } => {
+                let txn = self.next_txn;
+                self.next_txn += 1;
+                self.txns.insert(
+                    txn,
+                    Txn {
+                        staged: Vec::new(),
+                        used: Instant::now(),
+                    },
+                );
+                let _ = reply.send(Ok(txn));
+            }
+            PersistenceMessage::StageContent {
+                txn,
+                address,
+                content,
+                reply,
+            } => {
+                let _ = reply
+                    .send(self.stage(txn, Staged::Content(StagedContent { address, content })));
+            }
+            PersistenceMessage::StageEavi { txn, eavi, reply } => {
+                let _ = reply.send(self.stage(txn, Staged::Eavi(eavi)));
+            }
+            PersistenceMessage::Commit { txn, reply } => {
+                let _ = reply.send(self.commit(txn));
+            }
+            PersistenceMessage::Rollback { txn, reply } => {
+                let _ = reply.send(
+                    self.txns
+                        .remove(&txn)
+                        .map(|_| ())
+                        .ok_or_else(|| no_txn(txn)),
+                );
+            }
+            PersistenceMessage::Shutdown => return false,
+        }
+        true
+    }
+
+    fn stage(&mut self, txn: TxnId, write: Staged<A>) -> PersistenceResult<()> {
+        let open = self.txns.get_mut(&txn).ok_or_else(|| no_txn(txn))?;
+        open.staged.push(write);
+        open.used = Instant::now();
+        Ok(())
+    }
+
+    /// Writes what was staged in `txn` until a write fails, then fails with those written.
+    fn commit(&mut self, txn: TxnId) -> Result<(), PartialCommit<A>> {
+        let open = self
+            .txns
+            .remove(&txn)
+            .ok_or_else(|| PartialCommit::failed(no_txn(txn)))?;
+        let (mut content, mut eavis) = (Vec::new(), Vec::new());
+        for write in open.staged {
+            let written = match &write {
+                Staged::Content(staged) => self.cas.add(staged),
+                Staged::Eavi(eavi) => self.eav.add_eavi(eavi).map(|_| ()),
+            };
+            if let Err(error) = written {
+                return Err(PartialCommit {
+                    content,
+                    eavis,
+                    error,
+                });
+            }
+            match write {
+                Staged::Content(staged) => content.push(staged.address),
+                Staged::Eavi(eavi) => eavis.push(eavi),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends requests to a `PersistenceActor`.
+#[derive(Clone, Debug)]
+pub struct PersistenceHandle<A: Attribute> {
+    sender: Sender<PersistenceMessage<A>>,
+}
+
+fn flatten<T>(result: Result<PersistenceResult<T>, Canceled>) -> PersistenceResult<T> {
+    result.unwrap_or_else(|canceled| Err(canceled.into()))
+}
+
+fn flatten_commit<A: Attribute>(
+    result: Result<Result<(), PartialCommit<A>>, Canceled>,
+) -> Result<(), PartialCommit<A>> {
+    result.unwrap_or_else(|canceled| Err(PartialCommit::failed(canceled.into())))
+}
+
+impl<A: Attribute> PersistenceHandle<A> {
+    /// Sends the message built around a fresh reply channel. If the actor is gone the message,
+    /// and with it the reply sender, is dropped and the future resolves to an error.
+    fn request<T, F>(&self, message: F) -> PersistenceFuture<T>
+    where
+        F: FnOnce(Reply<T>) -> PersistenceMessage<A>,
+    {
+        let (reply, response) = oneshot::channel();
+        let _ = self.sender.send(message(reply));
+        response.map(flatten as fn(_) -> _)
+    }
+
+    pub fn add_content(&self, content: &dyn AddressableContent) -> PersistenceFuture<()> {
+        let (address, content) = (content.address(), content.content());
+        self.request(|reply| PersistenceMessage::AddContent {
+            address,
+            content,
+            reply,
+        })
+    }
+
+    pub fn fetch_content(&self, address: &Address) -> PersistenceFuture<Option<Content>> {
+        let address = address.clone();
+        self.request(|reply| PersistenceMessage::FetchContent { address, reply })
+    }
+
+    pub fn contains_content(&self, address: &Address) -> PersistenceFuture<bool> {
+        let address = address.clone();
+        self.request(|reply| PersistenceMessage::ContainsContent { address, reply })
+    }
+
+    pub fn add_eavi(
+        &self,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceFuture<Option<EntityAttributeValueIndex<A>>> {
+        let eavi = eavi.clone();
+        self.request(|reply| PersistenceMessage::AddEavi { eavi, reply })
+    }
+
+    pub fn query_eavi(
+        &self,
+        query: OwnedEaviQuery<A>,
+    ) -> PersistenceFuture<BTreeSet<EntityAttributeValueIndex<A>>> {
+        self.request(|reply| PersistenceMessage::QueryEavi { query, reply })
+    }
+
+    /// Starts a transaction, writes staged in it are only written once it is committed. It is
+    /// rolled back if it goes unused for the timeout of the actor.
+    pub fn begin_txn(&self) -> PersistenceFuture<TxnId> {
+        self.request(|reply| PersistenceMessage::BeginTxn { reply })
+    }
+
+    pub fn stage_content(
+        &self,
+        txn: TxnId,
+        content: &dyn AddressableContent,
+    ) -> PersistenceFuture<()> {
+        let (address, content) = (content.address(), content.content());
+        self.request(|reply| PersistenceMessage::StageContent {
+            txn,
+            address,
+            content,
+            reply,
+        })
+    }
+
+    pub fn stage_eavi(
+        &self,
+        txn: TxnId,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceFuture<()> {
+        let eavi = eavi.clone();
+        self.request(|reply| PersistenceMessage::StageEavi { txn, eavi, reply })
+    }
+
+    /// Writes what was staged in `txn`, see `PersistenceMessage::Commit`.
+    pub fn commit(&self, txn: TxnId) -> CommitFuture<A> {
+        let (reply, response) = oneshot::channel();
+        let _ = self.sender.send(PersistenceMessage::Commit { txn, reply });
+        response.map(flatten_commit as fn(_) -> _)
+    }
+
+    /// Drops what was staged in `txn`.
+    pub fn rollback(&self, txn: TxnId) -> PersistenceFuture<()> {
+        self.request(|reply| PersistenceMessage::Rollback { txn, reply })
+    }
+
+    /// Asks the actor to stop after the requests already sent.
+    pub fn shutdown(&self) {
+        let _ = self.sender.send(PersistenceMessage::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{ExampleAddressableContent, OtherExampleAddressableContent},
+        storage::{test_content_addressable_storage, ExampleContentAddressableStorage},
+    };
+    use eav::{storage::ExampleEntityAttributeValueStorage, ExampleAttribute, IndexFilter};
+    use futures::executor::block_on;
+    use holochain_json_api::json::RawString;
+    use limits::{Limited, Limits};
+
+    fn spawn() -> PersistenceHandle<ExampleAttribute> {
+        PersistenceActor::<ExampleContentAddressableStorage, _, _>::spawn(
+            test_content_addressable_storage(),
+            ExampleEntityAttributeValueStorage::new(),
+        )
+    }
+
+    #[test]
+    fn content_round_trip_through_actor() {
+        let handle = spawn();
+        let content =
+            OtherExampleAddressableContent::try_from_content(&RawString::from("foo").into())
+                .unwrap();
+
+        assert_eq!(
+            Ok(false),
+            block_on(handle.contains_content(&content.address()))
+        );
+        assert_eq!(Ok(()), block_on(handle.add_content(&content)));
+        // the address given by the original type is kept
+        assert_eq!(
+            Ok(true),
+            block_on(handle.contains_content(&content.address()))
+        );
+        assert_eq!(
+            Ok(Some(content.content())),
+            block_on(handle.fetch_content(&content.address()))
+        );
+    }
+
+    #[test]
+    fn eavi_query_through_actor_from_other_threads() {
+        let handle = spawn();
+        let entity = address_of("e");
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let handle = handle.clone();
+                let entity = entity.clone();
+                let value = address_of(["a", "b", "c", "d"][i]);
+                thread::spawn(move || {
+                    let eavi = EntityAttributeValueIndex::new(
+                        &entity,
+                        &ExampleAttribute::default(),
+                        &value,
+                    )
+                    .unwrap();
+                    block_on(handle.add_eavi(&eavi)).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let query = OwnedEaviQuery::new(
+            Some(entity),
+            Some(ExampleAttribute::default()),
+            None,
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(4, block_on(handle.query_eavi(query)).unwrap().len());
+    }
+
+    #[test]
+    fn staged_writes_are_written_on_commit() {
+        let handle = spawn();
+        let content =
+            ExampleAddressableContent::try_from_content(&RawString::from("staged").into()).unwrap();
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        let query = || {
+            OwnedEaviQuery::new(
+                Some(content.address()),
+                None,
+                None,
+                IndexFilter::Range(None, None),
+                None,
+            )
+        };
+
+        let txn = block_on(handle.begin_txn()).unwrap();
+        assert_eq!(Ok(()), block_on(handle.stage_content(txn, &content)));
+        assert_eq!(Ok(()), block_on(handle.stage_eavi(txn, &eavi)));
+        assert_eq!(
+            Ok(false),
+            block_on(handle.contains_content(&content.address()))
+        );
+        assert_eq!(Ok(()), block_on(handle.commit(txn)));
+        assert_eq!(
+            Ok(true),
+            block_on(handle.contains_content(&content.address()))
+        );
+        assert_eq!(1, block_on(handle.query_eavi(query())).unwrap().len());
+
+        // a transaction ends with its commit or rollback
+        assert!(block_on(handle.commit(txn)).is_err());
+        let rolled_back = block_on(handle.begin_txn()).unwrap();
+        assert_ne!(txn, rolled_back);
+        let other = address_of("other");
+        let dropped = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &other,
+        )
+        .unwrap();
+        block_on(handle.stage_eavi(rolled_back, &dropped)).unwrap();
+        assert_eq!(Ok(()), block_on(handle.rollback(rolled_back)));
+        assert!(block_on(handle.stage_eavi(rolled_back, &dropped)).is_err());
+        assert_eq!(1, block_on(handle.query_eavi(query())).unwrap().len());
+    }
+
+    #[test]
+    fn failed_commits_report_the_writes_applied() {
+        let handle = PersistenceActor::<ExampleContentAddressableStorage, _, _>::spawn(
+            test_content_addressable_storage(),
+            Limited::new(
+                ExampleEntityAttributeValueStorage::new(),
+                Limits {
+                    max_eavis_per_attribute: Some(1),
+                    ..Limits::default()
+                },
+            ),
+        );
+        let link = |value: &'static str| {
+            EntityAttributeValueIndex::new(
+                &address_of("entity"),
+                &ExampleAttribute::default(),
+                &address_of(value),
+            )
+            .unwrap()
+        };
+        let content =
+            ExampleAddressableContent::try_from_content(&RawString::from("staged").into()).unwrap();
+
+        let txn = block_on(handle.begin_txn()).unwrap();
+        block_on(handle.stage_content(txn, &content)).unwrap();
+        block_on(handle.stage_eavi(txn, &link("first"))).unwrap();
+        block_on(handle.stage_eavi(txn, &link("second"))).unwrap();
+        let partial = block_on(handle.commit(txn)).unwrap_err();
+        match partial.error {
+            PersistenceError::LimitExceeded(_) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+        assert_eq!(vec![content.address()], partial.content);
+        assert_eq!(vec![link("first")], partial.eavis);
+    }
+
+    #[test]
+    fn unused_transactions_are_rolled_back() {
+        let handle: PersistenceHandle<ExampleAttribute> =
+            PersistenceActor::<ExampleContentAddressableStorage, _, _>::spawn_with_txn_timeout(
+                test_content_addressable_storage(),
+                ExampleEntityAttributeValueStorage::new(),
+                Duration::from_millis(50),
+            );
+        let content =
+            ExampleAddressableContent::try_from_content(&RawString::from("staged").into()).unwrap();
+
+        let txn = block_on(handle.begin_txn()).unwrap();
+        block_on(handle.stage_content(txn, &content)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(block_on(handle.commit(txn)).is_err());
+        assert_eq!(
+            Ok(false),
+            block_on(handle.contains_content(&content.address()))
+        );
+    }
+
+    #[test]
+    fn requests_fail_once_the_actor_has_stopped() {
+        let handle = spawn();
+        handle.shutdown();
+        assert!(block_on(handle.fetch_content(&address_of("foo"))).is_err());
+    }
+
+    fn address_of(s: &'static str) -> Address {
+        ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+            .unwrap()
+            .address()
+    }
+}
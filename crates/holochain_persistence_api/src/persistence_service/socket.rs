@@ -0,0 +1,486 @@
+//! Access to a running `PersistenceActor` from other processes over a Unix domain socket.
+//!
+//! Stores like LMDB can only be opened by a single process, so inspection and backup sidecars
+//! can't open them next to the process that owns them. `SocketServer` exposes the actor behind
+//! a `PersistenceHandle` on a socket instead, and `SocketClient` implements the storage traits
+//! on top of it so sidecars can use the stores like any other.
+//!
+//! Every message is a big endian `u32` length followed by a msgpack encoded `SocketRequest` or
+//! `SocketResponse`. Requests on a connection are answered in order.
+
+use super::{OwnedEaviQuery, PersistenceHandle};
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+};
+use constraint::StagedContent;
+use eav::{
+    Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
+    IndexFilter,
+};
+use error::{PersistenceError, PersistenceResult};
+use futures::executor::block_on;
+use holochain_json_api::json::JsonString;
+use reporting::ReportStorage;
+use retry::{Backoff, RetryPolicy};
+use rmp_serde;
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use uuid::Uuid;
+
+/// Messages larger than this are refused rather than allocated.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SocketRequest<A: Attribute> {
+    AddContent { address: Address, content: String },
+    FetchContent { address: Address },
+    ContainsContent { address: Address },
+    AddEavi { eavi: EntityAttributeValueIndex<A> },
+    QueryEavi { query: OwnedEaviQuery<A> },
+}
+
+impl<A: Attribute> SocketRequest<A> {
+    /// Whether carrying out the request twice does the same as carrying it out once. Adding
+    /// content stores it at the same address again, adding an EAVI stores a second one.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            SocketRequest::AddEavi { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SocketResponse<A: Attribute> {
+    Added,
+    Content(Option<String>),
+    Contains(bool),
+    Eavi(Option<EntityAttributeValueIndex<A>>),
+    Eavis(Vec<EntityAttributeValueIndex<A>>),
+    Error(String),
+}
+
+fn protocol_error<E: ::std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::from(format!("Socket protocol error: {}", e))
+}
+
+fn write_frame<T: serde::Serialize>(stream: &mut UnixStream, message: &T) -> PersistenceResult<()> {
+    let body = rmp_serde::to_vec_named(message).map_err(protocol_error)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(protocol_error("message too large"));
+    }
+    stream.write_all(&(body.len() as u32).to_be_bytes())?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+/// None once the other side has closed the connection between two messages.
+fn read_frame<T: serde::de::DeserializeOwned>(
+    stream: &mut UnixStream,
+) -> PersistenceResult<Option<T>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(protocol_error("message too large"));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    rmp_serde::from_slice(&body)
+        .map(Some)
+        .map_err(protocol_error)
+}
+
+/// Serves a `PersistenceHandle` on a Unix socket until dropped.
+/// Dropping the server stops accepting connections and removes the socket file,
+/// connections that are already open are served until the client closes them.
+#[derive(Debug)]
+pub struct SocketServer {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl SocketServer {
+    pub fn bind<A, P>(handle: PersistenceHandle<A>, path: P) -> PersistenceResult<SocketServer>
+    where
+        A: Attribute + serde::de::DeserializeOwned + Send + 'static,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let acceptor = thread::Builder::new()
+            .name("persistence-socket".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let handle = handle.clone();
+                        let _ = thread::Builder::new()
+                            .name("persistence-socket-connection".to_string())
+                            .spawn(move || serve_connection(stream, handle));
+                    }
+                }
+            })?;
+        Ok(SocketServer {
+            path,
+            stopped,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake the acceptor up so it sees the flag
+        let _ = UnixStream::connect(&self.path);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection<A>(mut stream: UnixStream, handle: PersistenceHandle<A>)
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    // a connection that sends garbage or goes away mid message is dropped
+    while let Ok(Some(request)) = read_frame(&mut stream) {
+        let response = match respond(&handle, request) {
+            Ok(response) => response,
+            Err(e) => SocketResponse::Error(e.to_string()),
+        };
+        if write_frame(&mut stream, &response).is_err() {
+            break;
+        }
+    }
+}
+
+fn respond<A: Attribute>(
+    handle: &PersistenceHandle<A>,
+    request: SocketRequest<A>,
+) -> PersistenceResult<SocketResponse<A>> {
+    Ok(match request {
+        SocketRequest::AddContent { address, content } => {
+            let content = StagedContent {
+                address,
+                content: JsonString::from_json(&content),
+            };
+            block_on(handle.add_content(&content))?;
+            SocketResponse::Added
+        }
+        SocketRequest::FetchContent { address } => {
+            SocketResponse::Content(block_on(handle.fetch_content(&address))?.map(String::from))
+        }
+        SocketRequest::ContainsContent { address } => {
+            SocketResponse::Contains(block_on(handle.contains_content(&address))?)
+        }
+        SocketRequest::AddEavi { eavi } => SocketResponse::Eavi(block_on(handle.add_eavi(&eavi))?),
+        SocketRequest::QueryEavi { query } => {
+            SocketResponse::Eavis(block_on(handle.query_eavi(query))?.into_iter().collect())
+        }
+    })
+}
+
+/// CAS and EAV storage backed by a `SocketServer` in another process.
+/// Clones share the connection.
+#[derive(Clone, Debug)]
+pub struct SocketClient<A: Attribute> {
+    path: PathBuf,
+    stream: Arc<Mutex<UnixStream>>,
+    retry: RetryPolicy,
+    /// whether requests that aren't idempotent are retried as well
+    retry_all: bool,
+    id: Uuid,
+    attribute: PhantomData<A>,
+}
+
+impl<A> SocketClient<A>
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    /// Requests that fail for a lost connection are retried on a new one, see
+    /// `with_retry_policy`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> PersistenceResult<SocketClient<A>> {
+        Ok(SocketClient {
+            path: path.as_ref().to_path_buf(),
+            stream: Arc::new(Mutex::new(UnixStream::connect(path)?)),
+            retry: reconnect_retries(),
+            retry_all: false,
+            id: Uuid::new_v4(),
+            attribute: PhantomData,
+        })
+    }
+
+    /// Retries requests as `policy` says, reconnecting before every retry. A request that was
+    /// cut off may have been carried out, so only idempotent requests are retried, see
+    /// `SocketRequest::is_idempotent` and `with_all_requests_retried`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> SocketClient<A> {
+        self.retry = policy;
+        self
+    }
+
+    /// Retries requests that aren't idempotent too, so an EAVI whose add was cut off after the
+    /// server carried it out is added twice.
+    pub fn with_all_requests_retried(mut self) -> SocketClient<A> {
+        self.retry_all = true;
+        self
+    }
+
+    fn request(&self, request: &SocketRequest<A>) -> PersistenceResult<SocketResponse<A>> {
+        let mut stream = self.stream.lock()?;
+        let policy = if self.retry_all || request.is_idempotent() {
+            self.retry.clone()
+        } else {
+            RetryPolicy::never()
+        };
+        policy.run(|attempt| {
+            if attempt > 0 {
+                *stream = UnixStream::connect(&self.path)?;
+            }
+            write_frame(&mut stream, request)?;
+            match read_frame(&mut stream)? {
+                Some(SocketResponse::Error(e)) => Err(PersistenceError::from(e)),
+                Some(response) => Ok(response),
+                None => Err(PersistenceError::IoError(
+                    "connection closed by the server".to_string(),
+                )),
+            }
+        })
+    }
+}
+
+/// The retries of an idempotent request unless set otherwise: up to three attempts over about a
+/// second while the connection is lost, e.g. while the server restarts.
+fn reconnect_retries() -> RetryPolicy {
+    RetryPolicy::new(3)
+        .with_backoff(Backoff::Exponential {
+            initial: Duration::from_millis(250),
+            max: Duration::from_secs(1),
+        })
+        .with_jitter(0.5)
+        .with_retry_on(|e| match e {
+            PersistenceError::IoError(_) => true,
+            _ => false,
+        })
+}
+
+fn unexpected<T>() -> PersistenceResult<T> {
+    Err(protocol_error("unexpected response"))
+}
+
+fn exact<T: Clone + Eq>(filter: &EavFilter<T>) -> Option<T> {
+    match filter {
+        EavFilter::Exact(value) => Some(value.clone()),
+        EavFilter::Predicate(_) => None,
+    }
+}
+
+impl<A> ContentAddressableStorage for SocketClient<A>
+where
+    A: Attribute + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        let request = SocketRequest::AddContent {
+            address: content.address(),
+            content: String::from(content.content()),
+        };
+        match self.request(&request)? {
+            SocketResponse::Added => Ok(()),
+            _ => unexpected(),
+        }
+    }
+
+    fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        let request = SocketRequest::ContainsContent {
+            address: address.clone(),
+        };
+        match self.request(&request)? {
+            SocketResponse::Contains(contains) => Ok(contains),
+            _ => unexpected(),
+        }
+    }
+
+    fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
+        let request = SocketRequest::FetchContent {
+            address: address.clone(),
+        };
+        match self.request(&request)? {
+            SocketResponse::Content(content) => {
+                Ok(content.map(|content| JsonString::from_json(&content)))
+            }
+            _ => unexpected(),
+        }
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl<A> EntityAttributeValueStorage<A> for SocketClient<A>
+where
+    A: Attribute + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    fn add_eavi(
+        &mut self,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let request = SocketRequest::AddEavi { eavi: eavi.clone() };
+        match self.request(&request)? {
+            SocketResponse::Eavi(eavi) => Ok(eavi),
+            _ => unexpected(),
+        }
+    }
+
+    fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        // Predicates can't be sent, so the server is only asked for the exact matches and the
+        // query runs again on what comes back. Picking the latest EAVI or a tombstone needs all
+        // the candidates, so those are left to the local run as well.
+        let index = match query.index() {
+            IndexFilter::LatestByAttribute => IndexFilter::Range(None, None),
+            range => range.clone(),
+        };
+        let request = SocketRequest::QueryEavi {
+            query: OwnedEaviQuery::new(
+                exact(query.entity()),
+                exact(query.attribute()),
+                exact(query.value()),
+                index,
+                None,
+            ),
+        };
+        match self.request(&request)? {
+            SocketResponse::Eavis(eavis) => Ok(query.run(eavis.iter().cloned())),
+            _ => unexpected(),
+        }
+    }
+}
+
+impl<A: Attribute> ReportStorage for SocketClient<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{ExampleAddressableContent, OtherExampleAddressableContent},
+        storage::{
+            test_content_addressable_storage, EavTestSuite, ExampleContentAddressableStorage,
+            StorageTestSuite,
+        },
+    };
+    use eav::{storage::ExampleEntityAttributeValueStorage, ExampleAttribute};
+    use holochain_json_api::json::RawString;
+    use persistence_service::PersistenceActor;
+    use std::sync::atomic::AtomicUsize;
+
+    fn serve<A>() -> SocketServer
+    where
+        A: Attribute + Default + serde::de::DeserializeOwned + Send + Sync + 'static,
+    {
+        let handle = PersistenceActor::<ExampleContentAddressableStorage, _, _>::spawn(
+            test_content_addressable_storage(),
+            ExampleEntityAttributeValueStorage::<A>::new(),
+        );
+        let path = ::std::env::temp_dir().join(format!("persistence-{}.sock", Uuid::new_v4()));
+        SocketServer::bind(handle, path).unwrap()
+    }
+
+    #[test]
+    fn socket_cas_round_trip() {
+        let server = serve::<ExampleAttribute>();
+        let client = SocketClient::<ExampleAttribute>::connect(server.path()).unwrap();
+        StorageTestSuite::new(client)
+            .round_trip_test::<ExampleAddressableContent, OtherExampleAddressableContent>(
+                RawString::from("foo").into(),
+                RawString::from("bar").into(),
+            );
+    }
+
+    #[test]
+    fn socket_eav_predicate_queries_run_locally() {
+        let server = serve::<ExampleAttribute>();
+        let client = SocketClient::<ExampleAttribute>::connect(server.path()).unwrap();
+        EavTestSuite::test_multiple_attributes::<ExampleAddressableContent, _, _>(
+            client,
+            vec!["a_", "b_", "c_", "d_"]
+                .into_iter()
+                .map(|p| ExampleAttribute::WithPayload(p.to_string() + "one_to_many"))
+                .collect(),
+        );
+    }
+
+    #[test]
+    fn server_removes_socket_on_drop() {
+        let server = serve::<ExampleAttribute>();
+        let path = server.path().to_path_buf();
+        assert!(path.exists());
+        drop(server);
+        assert!(!path.exists());
+        assert!(SocketClient::<ExampleAttribute>::connect(&path).is_err());
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_retried() {
+        // hangs up on every connection
+        let path = ::std::env::temp_dir().join(format!("persistence-{}.sock", Uuid::new_v4()));
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || listener.incoming().for_each(drop));
+        let retries = Arc::new(AtomicUsize::new(0));
+        let policy = {
+            let retries = retries.clone();
+            RetryPolicy::new(3).with_retry_on(move |_| {
+                retries.fetch_add(1, Ordering::SeqCst);
+                true
+            })
+        };
+        let mut client = SocketClient::<ExampleAttribute>::connect(&path)
+            .unwrap()
+            .with_retry_policy(policy);
+        let eavi = EntityAttributeValueIndex::new(
+            &Address::from("entity"),
+            &ExampleAttribute::default(),
+            &Address::from("value"),
+        )
+        .unwrap();
+
+        assert!(client.fetch(&Address::from("content")).is_err());
+        assert_eq!(2, retries.swap(0, Ordering::SeqCst));
+        assert!(client.add_eavi(&eavi).is_err());
+        assert_eq!(0, retries.swap(0, Ordering::SeqCst));
+
+        let mut client = client.with_all_requests_retried();
+        assert!(client.add_eavi(&eavi).is_err());
+        assert_eq!(2, retries.load(Ordering::SeqCst));
+        fs::remove_file(path).unwrap();
+    }
+}
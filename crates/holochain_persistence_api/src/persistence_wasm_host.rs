@@ -0,0 +1,264 @@
+//! Host functions exposing a CAS and an EAV store to WASM guests.
+//!
+//! The functions here do the marshalling every embedding would otherwise repeat: they read
+//! their JSON argument out of guest memory, run it against the stores and copy the JSON result
+//! back into memory the guest allocated. The runtime (wasmer, wasmtime, ...) only needs to
+//! implement `GuestMemory` for its memory type and register a wrapper per function.
+//!
+//! Arguments are passed as an offset and a length. Results are returned as a single `u64`
+//! made with `pack`, where 0 means there is no result. Errors are meant to become traps.
+
+use cas::{
+    content::{Address, AddressableContent},
+    storage::ContentAddressableStorage,
+};
+use eav::{Attribute, EntityAttributeValueIndex, EntityAttributeValueStorage, OwnedEaviQuery};
+use error::{PersistenceError, PersistenceResult};
+use holochain_json_api::json::JsonString;
+use std::{marker::PhantomData, str};
+
+/// A guest's linear memory, as seen from a host function.
+pub trait GuestMemory {
+    fn read(&self, offset: u32, len: u32) -> PersistenceResult<Vec<u8>>;
+    /// reserves `len` bytes in the guest, usually by calling an allocator it exports
+    fn allocate(&mut self, len: u32) -> PersistenceResult<u32>;
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> PersistenceResult<()>;
+}
+
+/// offset in the high half, length in the low half
+pub fn pack(offset: u32, len: u32) -> u64 {
+    (u64::from(offset) << 32) | u64::from(len)
+}
+
+pub fn unpack(packed: u64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
+
+fn wasm_error<E: ::std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::from(format!("WASM host error: {}", e))
+}
+
+fn read_json(memory: &dyn GuestMemory, offset: u32, len: u32) -> PersistenceResult<String> {
+    let bytes = memory.read(offset, len)?;
+    let json = str::from_utf8(&bytes)?;
+    // only JSON goes into the stores
+    ::serde_json::from_str::<::serde_json::Value>(json).map_err(wasm_error)?;
+    Ok(json.to_string())
+}
+
+fn write_bytes(memory: &mut dyn GuestMemory, bytes: &[u8]) -> PersistenceResult<u64> {
+    if bytes.len() > u32::max_value() as usize {
+        return Err(wasm_error("result does not fit into guest memory"));
+    }
+    let offset = memory.allocate(bytes.len() as u32)?;
+    memory.write(offset, bytes)?;
+    Ok(pack(offset, bytes.len() as u32))
+}
+
+fn write_json<T: serde::Serialize>(
+    memory: &mut dyn GuestMemory,
+    value: &T,
+) -> PersistenceResult<u64> {
+    let json = ::serde_json::to_string(value)?;
+    write_bytes(memory, json.as_bytes())
+}
+
+/// The stores behind the host functions.
+#[derive(Clone, Debug)]
+pub struct PersistenceHost<C, E, A>
+where
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+    A: Attribute,
+{
+    cas: C,
+    eav: E,
+    attribute: PhantomData<A>,
+}
+
+impl<C, E, A> PersistenceHost<C, E, A>
+where
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    pub fn new(cas: C, eav: E) -> Self {
+        PersistenceHost {
+            cas,
+            eav,
+            attribute: PhantomData,
+        }
+    }
+
+    /// Stores the JSON argument as content and returns its address.
+    pub fn cas_put(
+        &mut self,
+        memory: &mut dyn GuestMemory,
+        offset: u32,
+        len: u32,
+    ) -> PersistenceResult<u64> {
+        let content = JsonString::from_json(&read_json(memory, offset, len)?);
+        self.cas.add(&content)?;
+        write_bytes(memory, String::from(content.address()).as_bytes())
+    }
+
+    /// Takes an address and returns the content stored there, or 0.
+    pub fn cas_get(
+        &self,
+        memory: &mut dyn GuestMemory,
+        offset: u32,
+        len: u32,
+    ) -> PersistenceResult<u64> {
+        let address = Address::from(str::from_utf8(&memory.read(offset, len)?)?);
+        match self.cas.fetch(&address)? {
+            Some(content) => write_bytes(memory, String::from(content).as_bytes()),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the EAVI given as JSON and returns the EAVI stored, or 0.
+    pub fn eav_put(
+        &mut self,
+        memory: &mut dyn GuestMemory,
+        offset: u32,
+        len: u32,
+    ) -> PersistenceResult<u64> {
+        let eavi: EntityAttributeValueIndex<A> =
+            ::serde_json::from_str(&read_json(memory, offset, len)?)?;
+        match self.eav.add_eavi(&eavi)? {
+            Some(added) => write_json(memory, &added),
+            None => Ok(0),
+        }
+    }
+
+    /// Runs the `OwnedEaviQuery` given as JSON and returns the matching EAVIs as a JSON array.
+    pub fn eav_query(
+        &self,
+        memory: &mut dyn GuestMemory,
+        offset: u32,
+        len: u32,
+    ) -> PersistenceResult<u64> {
+        let query: OwnedEaviQuery<A> = ::serde_json::from_str(&read_json(memory, offset, len)?)?;
+        let eavis: Vec<_> = self
+            .eav
+            .fetch_eavi(&query.as_query())?
+            .into_iter()
+            .collect();
+        write_json(memory, &eavis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::ExampleAddressableContent,
+        storage::{test_content_addressable_storage, ExampleContentAddressableStorage},
+    };
+    use eav::{storage::ExampleEntityAttributeValueStorage, ExampleAttribute, IndexFilter};
+    use holochain_json_api::json::RawString;
+
+    /// memory with a bump allocator, the way a guest's would be driven by the runtime
+    struct TestMemory(Vec<u8>);
+
+    impl GuestMemory for TestMemory {
+        fn read(&self, offset: u32, len: u32) -> PersistenceResult<Vec<u8>> {
+            let (start, end) = (offset as usize, (offset + len) as usize);
+            self.0
+                .get(start..end)
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| wasm_error("out of bounds"))
+        }
+
+        fn allocate(&mut self, len: u32) -> PersistenceResult<u32> {
+            let offset = self.0.len() as u32;
+            self.0.resize(self.0.len() + len as usize, 0);
+            Ok(offset)
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> PersistenceResult<()> {
+            let start = offset as usize;
+            self.0[start..start + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    impl TestMemory {
+        fn put(&mut self, bytes: &str) -> (u32, u32) {
+            let packed = write_bytes(self, bytes.as_bytes()).unwrap();
+            unpack(packed)
+        }
+
+        fn get(&self, packed: u64) -> String {
+            let (offset, len) = unpack(packed);
+            String::from_utf8(self.read(offset, len).unwrap()).unwrap()
+        }
+    }
+
+    type TestHost = PersistenceHost<
+        ExampleContentAddressableStorage,
+        ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        ExampleAttribute,
+    >;
+
+    fn host() -> TestHost {
+        PersistenceHost::new(
+            test_content_addressable_storage(),
+            ExampleEntityAttributeValueStorage::new(),
+        )
+    }
+
+    #[test]
+    fn cas_through_guest_memory() {
+        let mut host = host();
+        let mut memory = TestMemory(Vec::new());
+
+        let (offset, len) = memory.put("\"foo\"");
+        let packed = host.cas_put(&mut memory, offset, len).unwrap();
+        let address = memory.get(packed);
+        let expected = ExampleAddressableContent::try_from_content(&RawString::from("foo").into())
+            .unwrap()
+            .address();
+        assert_eq!(String::from(expected), address);
+
+        let (offset, len) = memory.put(&address);
+        let fetched = host.cas_get(&mut memory, offset, len).unwrap();
+        assert_eq!("\"foo\"", memory.get(fetched));
+
+        let (offset, len) = memory.put("QmMissing");
+        assert_eq!(Ok(0), host.cas_get(&mut memory, offset, len));
+
+        let (offset, len) = memory.put("not json");
+        assert!(host.cas_put(&mut memory, offset, len).is_err());
+    }
+
+    #[test]
+    fn eav_through_guest_memory() {
+        let mut host = host();
+        let mut memory = TestMemory(Vec::new());
+        let eavi = EntityAttributeValueIndex::new(
+            &Address::from("entity"),
+            &ExampleAttribute::default(),
+            &Address::from("value"),
+        )
+        .unwrap();
+
+        let (offset, len) = memory.put(&::serde_json::to_string(&eavi).unwrap());
+        let packed = host.eav_put(&mut memory, offset, len).unwrap();
+        let added: EntityAttributeValueIndex<ExampleAttribute> =
+            ::serde_json::from_str(&memory.get(packed)).unwrap();
+
+        let query = OwnedEaviQuery::<ExampleAttribute>::new(
+            Some(Address::from("entity")),
+            None,
+            None,
+            IndexFilter::LatestByAttribute,
+            None,
+        );
+        let (offset, len) = memory.put(&::serde_json::to_string(&query).unwrap());
+        let packed = host.eav_query(&mut memory, offset, len).unwrap();
+        let found: Vec<EntityAttributeValueIndex<ExampleAttribute>> =
+            ::serde_json::from_str(&memory.get(packed)).unwrap();
+        assert_eq!(vec![added], found);
+    }
+}
@@ -0,0 +1,392 @@
+//! Storage backends picked by a URI at run time, e.g. from a conductor config.
+//!
+//! Backend crates register a constructor under a scheme such as `lmdb` or `memory`.
+//! `create_manager("lmdb:///var/lib/holochain?map_size=1073741824")` then opens the stores with
+//! the constructor registered for the scheme of the URI, handing it the path and parameters.
+
+use batch::WriteBatch;
+use cas::{content::Address, storage::ContentAddressableStorage};
+use eav::{Attribute, EntityAttributeValueStorage};
+use error::{PersistenceError, PersistenceResult};
+use events::{ContentChange, EventBus, Publishing};
+use kv::{ExampleKvStorage, KvStorage};
+use sequence::{MemorySequences, SequenceStorage};
+use std::{
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display},
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc::Receiver, Arc, RwLock},
+};
+
+/// A parsed `scheme://path?name=value&...` URI. Parameters aren't percent-decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageUri {
+    pub scheme: String,
+    pub path: PathBuf,
+    pub params: BTreeMap<String, String>,
+}
+
+impl StorageUri {
+    /// The parameter `name` parsed as a `T`, None if the URI doesn't have it.
+    pub fn param<T>(&self, name: &str) -> PersistenceResult<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.params.get(name) {
+            Some(value) => value.parse().map(Some).map_err(|e| {
+                PersistenceError::from(format!("storage URI parameter {} error: {}", name, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails for a parameter not in `known`, so a misspelt one isn't silently ignored.
+    pub fn check_params(&self, known: &[&str]) -> PersistenceResult<()> {
+        match self
+            .params
+            .keys()
+            .find(|name| !known.contains(&name.as_str()))
+        {
+            Some(name) => Err(PersistenceError::from(format!(
+                "storage URI parameter {} is not understood by {} storage",
+                name, self.scheme
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for StorageUri {
+    type Err = PersistenceError;
+
+    fn from_str(uri: &str) -> PersistenceResult<StorageUri> {
+        let separator = uri
+            .find("://")
+            .ok_or_else(|| PersistenceError::from(format!("storage URI {} has no scheme", uri)))?;
+        let rest = &uri[separator + 3..];
+        let (path, query) = match rest.find('?') {
+            Some(question) => (&rest[..question], &rest[question + 1..]),
+            None => (rest, ""),
+        };
+        let mut params = BTreeMap::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or_default();
+            params.insert(
+                name.to_string(),
+                parts.next().unwrap_or_default().to_string(),
+            );
+        }
+        Ok(StorageUri {
+            scheme: uri[..separator].to_lowercase(),
+            path: PathBuf::from(path),
+            params,
+        })
+    }
+}
+
+/// The stores a URI opened.
+#[derive(Clone, Debug)]
+pub struct StorageManager<A: Attribute> {
+    pub cas: Box<dyn ContentAddressableStorage>,
+    pub eav: Box<dyn EntityAttributeValueStorage<A>>,
+    pub kv: Box<dyn KvStorage>,
+    /// where writes through `cas` and `eav` are published, see `events`
+    pub events: EventBus,
+    pub sequences: Arc<dyn SequenceStorage>,
+}
+
+impl<A: Attribute + Send + Sync + 'static> StorageManager<A> {
+    /// Wraps both stores to publish their writes to `events`. Key value entries and sequences
+    /// are kept in memory unless set with `with_kv` and `with_sequences`.
+    pub fn new<C, E>(cas: C, eav: E, events: EventBus) -> StorageManager<A>
+    where
+        C: ContentAddressableStorage + Clone + 'static,
+        E: EntityAttributeValueStorage<A> + Clone + 'static,
+    {
+        StorageManager {
+            cas: Box::new(Publishing::new(cas, events.clone())),
+            eav: Box::new(Publishing::new(eav, events.clone())),
+            kv: Box::new(ExampleKvStorage::new()),
+            events,
+            sequences: Arc::new(MemorySequences::new()),
+        }
+    }
+
+    pub fn with_kv<K: KvStorage + 'static>(mut self, kv: K) -> StorageManager<A> {
+        self.kv = Box::new(kv);
+        self
+    }
+
+    /// Keeps the sequences handed out by `next_sequence` in `sequences`.
+    pub fn with_sequences<S: SequenceStorage + 'static>(
+        mut self,
+        sequences: S,
+    ) -> StorageManager<A> {
+        self.sequences = Arc::new(sequences);
+        self
+    }
+
+    /// Changes to the content at `address` in `cas`, see `EventBus::watch`.
+    pub fn watch(&self, address: &Address) -> PersistenceResult<Receiver<ContentChange>> {
+        let cas = self.cas.clone();
+        self.events
+            .watch_with(address, move |address| cas.contains(address))
+    }
+
+    /// Stages writes to `cas` and `eav` to be checked and then committed one at a time, see `batch`.
+    pub fn batch(&mut self) -> WriteBatch<A> {
+        WriteBatch::new(self)
+    }
+
+    /// See `SequenceStorage::next_sequence`.
+    pub fn next_sequence(&self, name: &str) -> PersistenceResult<u64> {
+        self.sequences.next_sequence(name)
+    }
+}
+
+/// A CAS and an EAV store opened together, as an object safe trait so managers of different
+/// backends can be kept side by side as `Box<dyn DynManager<A>>`.
+pub trait DynManager<A: Attribute>: Send + Sync + Debug {
+    fn cas(&self) -> &dyn ContentAddressableStorage;
+    fn cas_mut(&mut self) -> &mut dyn ContentAddressableStorage;
+    fn eav(&self) -> &dyn EntityAttributeValueStorage<A>;
+    fn eav_mut(&mut self) -> &mut dyn EntityAttributeValueStorage<A>;
+
+    /// The key value store opened with the others, None if there isn't one.
+    fn kv(&self) -> Option<&dyn KvStorage> {
+        None
+    }
+}
+
+impl<A: Attribute> DynManager<A> for StorageManager<A> {
+    fn cas(&self) -> &dyn ContentAddressableStorage {
+        &*self.cas
+    }
+
+    fn cas_mut(&mut self) -> &mut dyn ContentAddressableStorage {
+        &mut *self.cas
+    }
+
+    fn eav(&self) -> &dyn EntityAttributeValueStorage<A> {
+        &*self.eav
+    }
+
+    fn eav_mut(&mut self) -> &mut dyn EntityAttributeValueStorage<A> {
+        &mut *self.eav
+    }
+
+    fn kv(&self) -> Option<&dyn KvStorage> {
+        Some(&*self.kv)
+    }
+}
+
+/// A pair of concrete stores, so they don't have to be boxed one by one.
+impl<A, C, E> DynManager<A> for (C, E)
+where
+    A: Attribute,
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+{
+    fn cas(&self) -> &dyn ContentAddressableStorage {
+        &self.0
+    }
+
+    fn cas_mut(&mut self) -> &mut dyn ContentAddressableStorage {
+        &mut self.0
+    }
+
+    fn eav(&self) -> &dyn EntityAttributeValueStorage<A> {
+        &self.1
+    }
+
+    fn eav_mut(&mut self) -> &mut dyn EntityAttributeValueStorage<A> {
+        &mut self.1
+    }
+}
+
+pub type StorageConstructor<A> = fn(&StorageUri) -> PersistenceResult<StorageManager<A>>;
+
+lazy_static! {
+    /// constructors by scheme and the type id of the attribute they take
+    static ref CONSTRUCTORS: RwLock<HashMap<(String, TypeId), Box<dyn Any + Send + Sync>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Registers `constructor` for URIs with `scheme`, replacing what was registered for it before.
+/// Constructors are registered per attribute type.
+pub fn register<A: Attribute + 'static>(
+    scheme: &str,
+    constructor: StorageConstructor<A>,
+) -> PersistenceResult<()> {
+    CONSTRUCTORS.write()?.insert(
+        (scheme.to_lowercase(), TypeId::of::<A>()),
+        Box::new(constructor),
+    );
+    Ok(())
+}
+
+/// Schemes with a constructor registered for attribute type `A`.
+pub fn schemes<A: Attribute + 'static>() -> PersistenceResult<Vec<String>> {
+    let mut schemes: Vec<_> = CONSTRUCTORS
+        .read()?
+        .keys()
+        .filter(|(_, attribute)| *attribute == TypeId::of::<A>())
+        .map(|(scheme, _)| scheme.clone())
+        .collect();
+    schemes.sort();
+    Ok(schemes)
+}
+
+/// Opens the stores `uri` names with the constructor registered for its scheme.
+pub fn create_manager<A: Attribute + 'static>(uri: &str) -> PersistenceResult<StorageManager<A>> {
+    let uri: StorageUri = uri.parse()?;
+    let constructor = CONSTRUCTORS
+        .read()?
+        .get(&(uri.scheme.clone(), TypeId::of::<A>()))
+        .and_then(|constructor| constructor.downcast_ref::<StorageConstructor<A>>())
+        .cloned();
+    match constructor {
+        Some(constructor) => constructor(&uri),
+        None => Err(PersistenceError::from(format!(
+            "no storage registered for scheme {}, registered are: {}",
+            uri.scheme,
+            schemes::<A>()?.join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{AddressableContent, Content},
+        storage::ExampleContentAddressableStorage,
+    };
+    use eav::{
+        EaviQuery, EntityAttributeValueIndex, EntityCursor, ExampleAttribute,
+        ExampleEntityAttributeValueStorage,
+    };
+    use error::PersistenceResult;
+    use limits::{Limited, Limits};
+    use reporting::ReportStorage;
+    use std::{
+        collections::BTreeSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use view::MaterializedViews;
+
+    /// An EAV store counting how often its own `entities_with_attribute` is asked, as a store
+    /// with an attribute index would answer it.
+    #[derive(Clone, Debug, Default)]
+    struct AttributeIndexed {
+        eav: ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        asked: Arc<AtomicUsize>,
+    }
+
+    impl ReportStorage for AttributeIndexed {}
+
+    impl EntityAttributeValueStorage<ExampleAttribute> for AttributeIndexed {
+        fn add_eavi(
+            &mut self,
+            eav: &EntityAttributeValueIndex<ExampleAttribute>,
+        ) -> PersistenceResult<Option<EntityAttributeValueIndex<ExampleAttribute>>> {
+            self.eav.add_eavi(eav)
+        }
+
+        fn fetch_eavi(
+            &self,
+            query: &EaviQuery<ExampleAttribute>,
+        ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<ExampleAttribute>>> {
+            self.eav.fetch_eavi(query)
+        }
+
+        fn entities_with_attribute<'a>(&'a self, attribute: &ExampleAttribute) -> EntityCursor<'a> {
+            self.asked.fetch_add(1, Ordering::SeqCst);
+            self.eav.entities_with_attribute(attribute)
+        }
+    }
+
+    fn example(uri: &StorageUri) -> PersistenceResult<StorageManager<ExampleAttribute>> {
+        uri.check_params(&["capacity"])?;
+        let _: Option<usize> = uri.param("capacity")?;
+        Ok(StorageManager::new(
+            ExampleContentAddressableStorage::new()?,
+            ExampleEntityAttributeValueStorage::new(),
+            Default::default(),
+        ))
+    }
+
+    #[test]
+    fn uris_are_parsed() {
+        let uri: StorageUri = "LMDB:///var/lib/holochain?map_size=1024&checksums"
+            .parse()
+            .unwrap();
+        assert_eq!("lmdb", uri.scheme);
+        assert_eq!(PathBuf::from("/var/lib/holochain"), uri.path);
+        assert_eq!(Ok(Some(1024)), uri.param::<usize>("map_size"));
+        assert_eq!(Some(&String::new()), uri.params.get("checksums"));
+        assert!(uri.param::<usize>("checksums").is_err());
+        assert!(uri.check_params(&["map_size"]).is_err());
+
+        let uri: StorageUri = "memory://".parse().unwrap();
+        assert_eq!(PathBuf::new(), uri.path);
+        assert!(uri.params.is_empty());
+        assert!("/no/scheme".parse::<StorageUri>().is_err());
+    }
+
+    #[test]
+    fn managers_are_created_by_scheme() {
+        register("example", example).unwrap();
+        assert!(schemes::<ExampleAttribute>()
+            .unwrap()
+            .contains(&"example".to_string()));
+        // other attribute types have their own constructors
+        assert!(create_manager::<::eav::StringAttribute>("example://").is_err());
+        assert!(create_manager::<ExampleAttribute>("nothing://").is_err());
+        assert!(create_manager::<ExampleAttribute>("example://?size=1").is_err());
+
+        let content = Content::from_json("\"registered\"");
+        let managers: Vec<Box<dyn DynManager<ExampleAttribute>>> = vec![
+            Box::new(create_manager::<ExampleAttribute>("example://?capacity=8").unwrap()),
+            Box::new((
+                ExampleContentAddressableStorage::new().unwrap(),
+                ExampleEntityAttributeValueStorage::new(),
+            )),
+        ];
+        for mut manager in managers {
+            manager.cas_mut().add(&content).unwrap();
+            assert_eq!(Ok(true), manager.cas().contains(&content.address()));
+        }
+    }
+
+    #[test]
+    fn wrappers_leave_entities_with_attribute_to_the_store() {
+        let indexed = AttributeIndexed::default();
+        let asked = indexed.asked.clone();
+        let cas = ExampleContentAddressableStorage::new().unwrap();
+        let eav = Limited::new(
+            MaterializedViews::new(indexed, cas.clone()),
+            Limits::default(),
+        );
+        let mut manager = StorageManager::new(cas, eav, Default::default());
+
+        let content = Content::from_json("\"indexed\"");
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::WithoutPayload,
+            &content.address(),
+        )
+        .unwrap();
+        manager.eav.add_eavi(&eavi).unwrap();
+        let entities: PersistenceResult<Vec<_>> = manager
+            .eav
+            .entities_with_attribute(&ExampleAttribute::WithoutPayload)
+            .collect();
+        assert_eq!(Ok(vec![content.address()]), entities);
+        assert_eq!(1, asked.load(Ordering::SeqCst));
+    }
+}
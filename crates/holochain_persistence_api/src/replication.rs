@@ -0,0 +1,408 @@
+//! Read replicas of a manager, kept up to date with incremental snapshots.
+//!
+//! A `ReplicationPrimary` logs the address of all content added to its manager, in order, to
+//! the manager's key value store, numbered by a sequence. Content linked by new EAVIs is logged
+//! again, as EAV stores publish their entities too. `export_since` takes a `Snapshot` of
+//! what was added after a `Watermark`: the logged content after its position and the EAVIs
+//! with a higher index. Snapshots serialize, so they can be shipped over any transport, and a
+//! `ReplicaFollower` applies them to its own manager to serve reads from. `ship` does both for
+//! a follower in the same process; the host calls it as often as replicas should be fresh.
+//! EAVIs are merged into replicas with a `MergeStrategy`, `AddWins` unless told otherwise.
+
+use cas::content::{Address, AddressableContent, Content};
+use eav::{Attribute, EaviQuery, EntityAttributeValueIndex, IndexFilter, OrderBy};
+use error::{PersistenceError, PersistenceResult};
+use events::{StorageEvent, SubscriptionId};
+use holochain_json_api::{error::JsonError, json::JsonString};
+use merge::{merge_eavi, AddWins, MergeStrategy};
+use registry::{DynManager, StorageManager};
+use std::{
+    collections::BTreeSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const LOG_SEQUENCE: &str = "replication";
+const LOG_PREFIX: &str = "replication/";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn log_key(position: u64) -> String {
+    // zero padded so the keys sort like the positions
+    format!("{}{:020}", LOG_PREFIX, position)
+}
+
+fn replication_error<E: ::std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::from(format!("replication error: {}", e))
+}
+
+/// How much of a primary a snapshot or a replica covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermark {
+    /// position of the last content in the primary's log, 0 for none
+    pub content: u64,
+    /// highest EAVI index, None for none
+    pub eavi: Option<i64>,
+}
+
+/// What was added to a primary between two watermarks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot<A: Attribute> {
+    pub since: Watermark,
+    pub until: Watermark,
+    /// milliseconds since the epoch
+    pub taken_at: u64,
+    pub content: Vec<(Address, String)>,
+    pub eavis: Vec<EntityAttributeValueIndex<A>>,
+}
+
+/// Content stored at the address it had on the primary.
+struct ShippedContent {
+    address: Address,
+    content: Content,
+}
+
+impl AddressableContent for ShippedContent {
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(_content: &Content) -> Result<ShippedContent, JsonError> {
+        Err(JsonError::ErrorGeneric(
+            "shipped content has no address of its own".to_string(),
+        ))
+    }
+}
+
+/// Logs the content added to a manager for its replicas, until dropped.
+#[derive(Debug)]
+pub struct ReplicationPrimary<A: Attribute + Send + Sync + 'static> {
+    manager: StorageManager<A>,
+    subscription: SubscriptionId,
+}
+
+impl<A: Attribute + Send + Sync + 'static> ReplicationPrimary<A> {
+    /// Logs what is added to `manager` from now on, after what was logged in its key value
+    /// store before.
+    pub fn new(manager: StorageManager<A>) -> PersistenceResult<ReplicationPrimary<A>> {
+        let cas = manager.cas.clone();
+        let kv = manager.kv.clone();
+        let sequences = manager.sequences.clone();
+        let subscription = manager.events.subscribe(move |event| {
+            // EAV stores publish the entities of their EAVIs too
+            if let StorageEvent::Added(address) = event {
+                if cas.contains(address) == Ok(true) {
+                    if let Ok(position) = sequences.next_sequence(LOG_SEQUENCE) {
+                        let _ = kv.put(&log_key(position), address.to_string().as_bytes());
+                    }
+                }
+            }
+        })?;
+        Ok(ReplicationPrimary {
+            manager,
+            subscription,
+        })
+    }
+
+    pub fn manager(&self) -> &StorageManager<A> {
+        &self.manager
+    }
+
+    fn latest_eavi(&self) -> PersistenceResult<Option<i64>> {
+        let latest = self.manager.eav.fetch_eavi_ordered(
+            &EaviQuery::new(
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_order_by(OrderBy::IndexDescending)
+            .with_limit(1),
+        )?;
+        Ok(latest.into_iter().next().map(|eavi| eavi.index()))
+    }
+
+    /// Everything added so far.
+    pub fn head(&self) -> PersistenceResult<Watermark> {
+        Ok(Watermark {
+            content: self
+                .manager
+                .sequences
+                .current_sequence(LOG_SEQUENCE)?
+                .unwrap_or(0),
+            eavi: self.latest_eavi()?,
+        })
+    }
+
+    /// What was added after `since`.
+    pub fn export_since(&self, since: Watermark) -> PersistenceResult<Snapshot<A>> {
+        let taken_at = now();
+        let mut until = since;
+        let mut content = Vec::new();
+        let mut shipped = BTreeSet::new();
+        for entry in self.manager.kv.scan(LOG_PREFIX) {
+            let (key, address) = entry?;
+            let position: u64 = key[LOG_PREFIX.len()..].parse().map_err(replication_error)?;
+            if position <= since.content {
+                continue;
+            }
+            until.content = position;
+            let address = Address::from(String::from_utf8_lossy(&address).to_string());
+            if !shipped.insert(address.clone()) {
+                continue;
+            }
+            // content removed since is left out
+            if let Some(stored) = self.manager.cas.fetch(&address)? {
+                content.push((address, String::from(stored)));
+            }
+        }
+        let eavis: Vec<_> = self
+            .manager
+            .eav
+            .fetch_eavi(&EaviQuery::new(
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                IndexFilter::Range(since.eavi.map(|index| index + 1), None),
+                None,
+            ))?
+            .into_iter()
+            .collect();
+        until.eavi = eavis.iter().map(|eavi| eavi.index()).max().or(since.eavi);
+        Ok(Snapshot {
+            since,
+            until,
+            taken_at,
+            content,
+            eavis,
+        })
+    }
+
+    /// Brings `follower` up to date.
+    pub fn ship<M: DynManager<A>>(
+        &self,
+        follower: &mut ReplicaFollower<M>,
+    ) -> PersistenceResult<()> {
+        self.ship_with(follower, &AddWins)
+    }
+
+    /// `ship`, merging the EAVIs as `strategy` decides.
+    pub fn ship_with<M: DynManager<A>>(
+        &self,
+        follower: &mut ReplicaFollower<M>,
+        strategy: &dyn MergeStrategy<A>,
+    ) -> PersistenceResult<()> {
+        let snapshot = self.export_since(follower.watermark())?;
+        follower.apply_with(&snapshot, strategy)
+    }
+}
+
+impl<A: Attribute + Send + Sync + 'static> Drop for ReplicationPrimary<A> {
+    fn drop(&mut self) {
+        let _ = self.manager.events.unsubscribe(self.subscription);
+    }
+}
+
+/// Where a replica is at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplicaStatus {
+    pub watermark: Watermark,
+    /// when the last snapshot applied was taken, None before the first
+    pub snapshot_taken_at: Option<u64>,
+}
+
+/// A manager kept as a replica of a primary, to read from.
+#[derive(Clone, Debug)]
+pub struct ReplicaFollower<M> {
+    manager: M,
+    status: ReplicaStatus,
+}
+
+impl<M> ReplicaFollower<M> {
+    /// A replica that has applied nothing yet, `manager` should be empty.
+    pub fn new(manager: M) -> ReplicaFollower<M> {
+        ReplicaFollower {
+            manager,
+            status: ReplicaStatus::default(),
+        }
+    }
+
+    /// A replica resuming from `status`, e.g. as it was before a restart.
+    pub fn resume(manager: M, status: ReplicaStatus) -> ReplicaFollower<M> {
+        ReplicaFollower { manager, status }
+    }
+
+    pub fn manager(&self) -> &M {
+        &self.manager
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.status
+    }
+
+    pub fn watermark(&self) -> Watermark {
+        self.status.watermark
+    }
+
+    /// How far behind the primary the replica may be: the age of the last snapshot applied,
+    /// None if none was.
+    pub fn staleness(&self) -> Option<Duration> {
+        self.staleness_at(now())
+    }
+
+    /// `staleness` as if it were `now`.
+    pub fn staleness_at(&self, now: u64) -> Option<Duration> {
+        self.status
+            .snapshot_taken_at
+            .map(|taken_at| Duration::from_millis(now.saturating_sub(taken_at)))
+    }
+
+    /// Adds what `snapshot` holds, failing without it if the snapshot starts after what the
+    /// replica has. Snapshots overlapping what it has are fine.
+    pub fn apply<A: Attribute>(&mut self, snapshot: &Snapshot<A>) -> PersistenceResult<()>
+    where
+        M: DynManager<A>,
+    {
+        self.apply_with(snapshot, &AddWins)
+    }
+
+    /// `apply`, merging the EAVIs as `strategy` decides.
+    pub fn apply_with<A: Attribute>(
+        &mut self,
+        snapshot: &Snapshot<A>,
+        strategy: &dyn MergeStrategy<A>,
+    ) -> PersistenceResult<()>
+    where
+        M: DynManager<A>,
+    {
+        let watermark = self.status.watermark;
+        if snapshot.since.content > watermark.content || snapshot.since.eavi > watermark.eavi {
+            return Err(replication_error(format!(
+                "snapshot since {:?} doesn't follow the replica at {:?}",
+                snapshot.since, watermark
+            )));
+        }
+        for (address, content) in &snapshot.content {
+            self.manager.cas().add(&ShippedContent {
+                address: address.clone(),
+                content: JsonString::from_json(content),
+            })?;
+        }
+        for eavi in &snapshot.eavis {
+            merge_eavi(self.manager.eav_mut(), strategy, eavi)?;
+        }
+        self.status = ReplicaStatus {
+            watermark: Watermark {
+                content: watermark.content.max(snapshot.until.content),
+                eavi: watermark.eavi.max(snapshot.until.eavi),
+            },
+            snapshot_taken_at: Some(snapshot.taken_at),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleAttribute, ExampleEntityAttributeValueStorage};
+    use events::EventBus;
+
+    fn manager() -> StorageManager<ExampleAttribute> {
+        StorageManager::new(
+            test_content_addressable_storage(),
+            ExampleEntityAttributeValueStorage::new(),
+            EventBus::new(),
+        )
+    }
+
+    fn link(from: &Content, to: &Content) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new(
+            &from.address(),
+            &ExampleAttribute::WithoutPayload,
+            &to.address(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn followers_catch_up_with_the_primary() {
+        let primary = ReplicationPrimary::new(manager()).unwrap();
+        let mut follower = ReplicaFollower::new(manager());
+        assert_eq!(None, follower.staleness());
+        let (a, b, c) = (
+            Content::from_json("\"a\""),
+            Content::from_json("\"b\""),
+            Content::from_json("\"c\""),
+        );
+        let mut writer = primary.manager().clone();
+        writer.cas.add(&a).unwrap();
+        writer.cas.add(&b).unwrap();
+        writer.eav.add_eavi(&link(&a, &b)).unwrap();
+
+        primary.ship(&mut follower).unwrap();
+        assert_eq!(primary.head(), Ok(follower.watermark()));
+        assert_eq!(Ok(true), follower.manager().cas.contains(&a.address()));
+        assert_eq!(
+            Ok(Some(b.clone())),
+            follower.manager().cas.fetch(&b.address())
+        );
+        assert!(
+            follower.staleness_at(follower.status().snapshot_taken_at.unwrap() + 5)
+                == Some(Duration::from_millis(5))
+        );
+
+        // only what was added since is shipped, and the content the new EAVI links from
+        writer.cas.add(&c).unwrap();
+        writer.eav.add_eavi(&link(&b, &c)).unwrap();
+        let snapshot = primary.export_since(follower.watermark()).unwrap();
+        assert_eq!(
+            vec![c.address(), b.address()],
+            snapshot
+                .content
+                .iter()
+                .map(|(address, _)| address.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, snapshot.eavis.len());
+
+        // a follower further behind can't skip it
+        let mut behind = ReplicaFollower::new(manager());
+        assert!(behind.apply(&snapshot).is_err());
+        assert_eq!(Watermark::default(), behind.watermark());
+
+        follower.apply(&snapshot).unwrap();
+        // shipping again is harmless
+        follower.apply(&snapshot).unwrap();
+        primary.ship(&mut behind).unwrap();
+        for replica in &[&follower, &behind] {
+            assert_eq!(primary.head(), Ok(replica.watermark()));
+            assert_eq!(Ok(true), replica.manager().cas.contains(&c.address()));
+            assert_eq!(
+                2,
+                replica
+                    .manager()
+                    .eav
+                    .fetch_eavi(&EaviQuery::new(
+                        Default::default(),
+                        Default::default(),
+                        Default::default(),
+                        IndexFilter::Range(None, None),
+                        None,
+                    ))
+                    .unwrap()
+                    .len()
+            );
+        }
+    }
+}
@@ -4,11 +4,30 @@ use holochain_json_api::{error::JsonError, json::JsonString};
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize, DefaultJson)]
 pub struct StorageReport {
     pub bytes_total: usize,
+    /// Only reported by storage implementations with a bounded number of concurrent readers
+    pub reader_slots: Option<ReaderSlotReport>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct ReaderSlotReport {
+    pub in_use: usize,
+    pub max_readers: usize,
 }
 
 impl StorageReport {
     pub fn new(bytes_total: usize) -> Self {
-        Self { bytes_total }
+        Self {
+            bytes_total,
+            reader_slots: None,
+        }
+    }
+
+    pub fn with_reader_slots(mut self, in_use: usize, max_readers: usize) -> Self {
+        self.reader_slots = Some(ReaderSlotReport {
+            in_use,
+            max_readers,
+        });
+        self
     }
 }
 
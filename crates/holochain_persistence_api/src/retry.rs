@@ -0,0 +1,190 @@
+//! How many times and how far apart to try an operation that may fail for a while, e.g. an LMDB
+//! write that first has to grow the map or a request to a persistence server being restarted.
+
+use error::PersistenceError;
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// The wait before each retry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    /// retry straight away
+    Immediate,
+    Constant(Duration),
+    /// `initial` before the first retry, doubling before each one after it up to `max`
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+/// Decides whether an error is worth another attempt.
+pub type RetryOn<E> = Arc<dyn Fn(&E) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RetryPolicy<E = PersistenceError> {
+    /// attempts in all, including the first
+    pub max_attempts: usize,
+    pub backoff: Backoff,
+    /// the fraction, from 0 to 1, by which each wait is shortened at random so that clients
+    /// failing together don't retry together
+    pub jitter: f64,
+    retry_on: RetryOn<E>,
+}
+
+impl<E> Debug for RetryPolicy<E> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+impl<E> RetryPolicy<E> {
+    /// Retries every error straight away until `max_attempts` attempts have been made.
+    pub fn new(max_attempts: usize) -> RetryPolicy<E> {
+        RetryPolicy {
+            max_attempts,
+            backoff: Backoff::Immediate,
+            jitter: 0.0,
+            retry_on: Arc::new(|_| true),
+        }
+    }
+
+    /// A single attempt.
+    pub fn never() -> RetryPolicy<E> {
+        RetryPolicy::new(1)
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> RetryPolicy<E> {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> RetryPolicy<E> {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// Only retries the errors `retry_on` is true for, the others are returned straight away.
+    pub fn with_retry_on<F>(mut self, retry_on: F) -> RetryPolicy<E>
+    where
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.retry_on = Arc::new(retry_on);
+        self
+    }
+
+    /// The same policy for errors that `into` turns into the errors it retries.
+    pub fn for_errors<F, D>(&self, into: F) -> RetryPolicy<D>
+    where
+        F: Fn(&D) -> E + Send + Sync + 'static,
+        E: 'static,
+    {
+        let retry_on = self.retry_on.clone();
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            jitter: self.jitter,
+            retry_on: Arc::new(move |e| retry_on(&into(e))),
+        }
+    }
+
+    pub fn retries(&self, error: &E) -> bool {
+        (self.retry_on)(error)
+    }
+
+    /// The wait before retry number `retry`, counting from 1, without jitter.
+    pub fn delay(&self, retry: usize) -> Duration {
+        match self.backoff {
+            Backoff::Immediate => Duration::from_millis(0),
+            Backoff::Constant(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let doublings = retry.saturating_sub(1).min(31) as u32;
+                initial
+                    .checked_mul(1 << doublings)
+                    .map_or(max, |delay| delay.min(max))
+            }
+        }
+    }
+
+    /// Calls `attempt` with the number of the attempt, counting from 0, until it succeeds,
+    /// fails with an error that isn't retried or `max_attempts` attempts have been made.
+    pub fn run<T, F>(&self, mut attempt: F) -> Result<T, E>
+    where
+        F: FnMut(usize) -> Result<T, E>,
+    {
+        let mut attempts = 0;
+        loop {
+            match attempt(attempts) {
+                Err(e) if attempts + 1 < self.max_attempts && self.retries(&e) => {
+                    attempts += 1;
+                    let delay = self.delay(attempts);
+                    if delay > Duration::from_millis(0) {
+                        thread::sleep(delay.mul_f64(1.0 - self.jitter * rand::random::<f64>()));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let policy: RetryPolicy = RetryPolicy::new(10).with_backoff(Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        });
+        let delays: Vec<u64> = (1..6)
+            .map(|retry| policy.delay(retry).as_millis() as u64)
+            .collect();
+        assert_eq!(vec![10, 20, 40, 50, 50], delays);
+        assert_eq!(Duration::from_millis(50), policy.delay(1000));
+    }
+
+    #[test]
+    fn only_retried_errors_are_retried() {
+        let policy = RetryPolicy::new(3)
+            .with_backoff(Backoff::Constant(Duration::from_millis(1)))
+            .with_jitter(0.5)
+            .with_retry_on(|e| match e {
+                PersistenceError::IoError(_) => true,
+                _ => false,
+            });
+
+        let mut attempts = Vec::new();
+        let result: Result<(), _> = policy.run(|attempt| {
+            attempts.push(attempt);
+            Err(PersistenceError::IoError("gone".to_string()))
+        });
+        assert_eq!(Err(PersistenceError::IoError("gone".to_string())), result);
+        assert_eq!(vec![0, 1, 2], attempts);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = policy.run(|_| {
+            attempts += 1;
+            Err(PersistenceError::new("bad request"))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+
+        assert_eq!(
+            Ok(2),
+            policy.run(|attempt| match attempt {
+                2 => Ok(attempt),
+                _ => Err(PersistenceError::IoError("not yet".to_string())),
+            })
+        );
+    }
+}
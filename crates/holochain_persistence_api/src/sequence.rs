@@ -0,0 +1,79 @@
+//! Named counters handing out increasing numbers, e.g. for chain header positions or the local
+//! order of writes, instead of relying on the timestamps in EAV indexes.
+
+use error::PersistenceResult;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+pub trait SequenceStorage: Send + Sync + Debug {
+    /// The next number of the sequence `name`, starting from 1. No two calls return the same
+    /// number for a sequence and a number is only returned once it has been stored.
+    fn next_sequence(&self, name: &str) -> PersistenceResult<u64>;
+
+    /// The last number handed out by the sequence `name`, None if there was none.
+    fn current_sequence(&self, name: &str) -> PersistenceResult<Option<u64>>;
+}
+
+/// Sequences kept in memory, they start over with every new `MemorySequences`.
+/// Clones share the sequences.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySequences {
+    sequences: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl MemorySequences {
+    pub fn new() -> MemorySequences {
+        Default::default()
+    }
+}
+
+impl SequenceStorage for MemorySequences {
+    fn next_sequence(&self, name: &str) -> PersistenceResult<u64> {
+        let mut sequences = self.sequences.lock()?;
+        let next = sequences.entry(name.to_string()).or_insert(0);
+        *next += 1;
+        Ok(*next)
+    }
+
+    fn current_sequence(&self, name: &str) -> PersistenceResult<Option<u64>> {
+        Ok(self.sequences.lock()?.get(name).cloned())
+    }
+}
+
+/// Checks that concurrent callers of `next_sequence` get distinct, consecutive numbers and that
+/// sequences are independent of each other.
+pub fn test_sequences<S: SequenceStorage + Clone + 'static>(sequences: S) {
+    assert_eq!(Ok(None), sequences.current_sequence("headers"));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let sequences = sequences.clone();
+            ::std::thread::spawn(move || {
+                (0..25)
+                    .map(|_| sequences.next_sequence("headers").unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut numbers: Vec<u64> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().unwrap())
+        .collect();
+    numbers.sort();
+    assert_eq!((1..=100).collect::<Vec<_>>(), numbers);
+    assert_eq!(Ok(Some(100)), sequences.current_sequence("headers"));
+    assert_eq!(Ok(1), sequences.next_sequence("writes"));
+    assert_eq!(Ok(101), sequences.next_sequence("headers"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sequences_count_up() {
+        test_sequences(MemorySequences::new());
+    }
+}
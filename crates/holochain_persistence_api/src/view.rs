@@ -0,0 +1,317 @@
+//! Materialized views over an EAV store.
+//!
+//! A view is a query and a reducer folding the EAVIs matching it into some content. Its result is
+//! kept in a CAS at an address derived from its name, so reading it is a single fetch instead of
+//! a scan. `MaterializedViews` wraps an EAV store and, once an add through it has been committed,
+//! folds the new EAVI into every view it matches.
+
+use cas::{
+    content::{Address, AddressableContent, Content},
+    storage::ContentAddressableStorage,
+};
+use eav::{
+    Attribute, AttributeHistogram, EaviCursor, EaviQuery, Entity, EntityAttributeValueIndex,
+    EntityAttributeValueStorage, EntityCursor, IndexFilter, OwnedEaviQuery, Value,
+};
+use error::{PersistenceError, PersistenceResult};
+use hash::HashString;
+use holochain_json_api::error::JsonError;
+use multihash::Hash;
+use reporting::{ReportStorage, StorageReport};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Debug, Formatter},
+    sync::{Arc, RwLock},
+};
+
+/// Folds an EAVI into the result of a view so far.
+pub type Reducer<A> =
+    Arc<dyn Fn(Content, &EntityAttributeValueIndex<A>) -> PersistenceResult<Content> + Send + Sync>;
+
+#[derive(Clone)]
+struct View<A: Attribute> {
+    query: OwnedEaviQuery<A>,
+    initial: Content,
+    reduce: Reducer<A>,
+}
+
+/// Where the result of the view with this name is kept.
+pub fn view_address(name: &str) -> Address {
+    HashString::encode_from_str(&format!("materialized-view::{}", name), Hash::SHA2256)
+}
+
+/// The result of a view, stored at the address of the view rather than of the result.
+struct ViewResult<'a> {
+    name: &'a str,
+    content: Content,
+}
+
+impl<'a> AddressableContent for ViewResult<'a> {
+    fn address(&self) -> Address {
+        view_address(self.name)
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(_: &Content) -> Result<Self, JsonError> {
+        Err(JsonError::ErrorGeneric(
+            "view results are read as plain content".to_string(),
+        ))
+    }
+}
+
+/// An EAV store that keeps the views registered on it up to date.
+#[derive(Clone)]
+pub struct MaterializedViews<A: Attribute, E, C> {
+    eav: E,
+    cas: C,
+    views: Arc<RwLock<BTreeMap<String, View<A>>>>,
+}
+
+impl<A: Attribute, E: Debug, C: Debug> Debug for MaterializedViews<A, E, C> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("MaterializedViews")
+            .field("eav", &self.eav)
+            .field("cas", &self.cas)
+            .finish()
+    }
+}
+
+impl<A, E, C> MaterializedViews<A, E, C>
+where
+    A: Attribute,
+    E: EntityAttributeValueStorage<A>,
+    C: ContentAddressableStorage,
+{
+    pub fn new(eav: E, cas: C) -> MaterializedViews<A, E, C> {
+        MaterializedViews {
+            eav,
+            cas,
+            views: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Registers a view, replacing any view with the same name, and computes its result from
+    /// what the store holds. Views are kept up to date an EAVI at a time, so the index filter of
+    /// the query has to be a range.
+    pub fn register<F>(
+        &mut self,
+        name: &str,
+        query: OwnedEaviQuery<A>,
+        initial: Content,
+        reduce: F,
+    ) -> PersistenceResult<Content>
+    where
+        F: Fn(Content, &EntityAttributeValueIndex<A>) -> PersistenceResult<Content>
+            + Send
+            + Sync
+            + 'static,
+    {
+        if let IndexFilter::LatestByAttribute = query.index {
+            return Err(PersistenceError::from(format!(
+                "view {} can't be kept up to date with a LatestByAttribute query",
+                name
+            )));
+        }
+        let view = View {
+            query,
+            initial,
+            reduce: Arc::new(reduce),
+        };
+        let result = self.recompute(name, &view)?;
+        self.views.write()?.insert(name.to_string(), view);
+        Ok(result)
+    }
+
+    /// Stops keeping the view up to date, returning whether there was one. Its last result stays
+    /// in the CAS.
+    pub fn unregister(&mut self, name: &str) -> PersistenceResult<bool> {
+        Ok(self.views.write()?.remove(name).is_some())
+    }
+
+    /// The result of a view, None if no view with this name was ever registered on the CAS.
+    pub fn fetch_view(&self, name: &str) -> PersistenceResult<Option<Content>> {
+        self.cas.fetch(&view_address(name))
+    }
+
+    fn store(&mut self, name: &str, content: Content) -> PersistenceResult<()> {
+        self.cas.add(&ViewResult { name, content })
+    }
+
+    fn recompute(&mut self, name: &str, view: &View<A>) -> PersistenceResult<Content> {
+        let mut result = view.initial.clone();
+        for eavi in self.eav.fetch_eavi(&view.query.as_query())? {
+            result = (view.reduce)(result, &eavi)?;
+        }
+        self.store(name, result.clone())?;
+        Ok(result)
+    }
+
+    /// The commit hook: folds a committed EAVI into the views it matches. After an upsert the
+    /// views it matches are computed again, as the EAVIs it replaced can't be taken back out.
+    fn committed(
+        &mut self,
+        eavi: &EntityAttributeValueIndex<A>,
+        upserted: bool,
+    ) -> PersistenceResult<()> {
+        let matched: Vec<(String, View<A>)> = self
+            .views
+            .read()?
+            .iter()
+            .filter(|(_, view)| view.query.as_query().matches(eavi))
+            .map(|(name, view)| (name.clone(), view.clone()))
+            .collect();
+        for (name, view) in matched {
+            if upserted {
+                self.recompute(&name, &view)?;
+                continue;
+            }
+            let so_far = match self.fetch_view(&name)? {
+                Some(so_far) => so_far,
+                None => view.initial.clone(),
+            };
+            let result = (view.reduce)(so_far, eavi)?;
+            self.store(&name, result)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A, E, C> EntityAttributeValueStorage<A> for MaterializedViews<A, E, C>
+where
+    A: Attribute + Send + Sync,
+    E: EntityAttributeValueStorage<A> + Clone,
+    C: ContentAddressableStorage + Clone,
+{
+    fn add_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let added = self.eav.add_eavi(eav)?;
+        if let Some(eavi) = &added {
+            self.committed(eavi, false)?;
+        }
+        Ok(added)
+    }
+
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let added = self.eav.upsert_eavi(eav)?;
+        if let Some(eavi) = &added {
+            self.committed(eavi, true)?;
+        }
+        Ok(added)
+    }
+
+    fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        self.eav.fetch_eavi(query)
+    }
+
+    fn fetch_eavi_ordered(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        self.eav.fetch_eavi_ordered(query)
+    }
+
+    fn fetch_distinct_values(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Value>> {
+        self.eav.fetch_distinct_values(query)
+    }
+
+    fn fetch_distinct_entities(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Entity>> {
+        self.eav.fetch_distinct_entities(query)
+    }
+
+    fn attribute_histogram(&self) -> PersistenceResult<AttributeHistogram<A>> {
+        self.eav.attribute_histogram()
+    }
+
+    fn iter_eavi<'a>(&'a self, query: &EaviQuery<A>) -> EaviCursor<'a, A>
+    where
+        A: 'a,
+    {
+        self.eav.iter_eavi(query)
+    }
+
+    fn entities_with_attribute<'a>(&'a self, attribute: &A) -> EntityCursor<'a> {
+        self.eav.entities_with_attribute(attribute)
+    }
+}
+
+impl<A: Attribute, E: ReportStorage, C> ReportStorage for MaterializedViews<A, E, C> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.eav.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::storage::test_content_addressable_storage;
+    use eav::{ExampleEntityAttributeValueStorage, StringAttribute};
+
+    fn link(from: &str, attribute: &str, to: &str) -> EntityAttributeValueIndex<StringAttribute> {
+        EntityAttributeValueIndex::new(
+            &Address::from(from),
+            &StringAttribute(attribute.to_string()),
+            &Address::from(to),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn views_follow_committed_eavis() {
+        let mut eav = ExampleEntityAttributeValueStorage::new();
+        let cas = test_content_addressable_storage();
+        eav.add_eavi(&link("a", "link", "b")).unwrap();
+        eav.add_eavi(&link("a", "tag", "c")).unwrap();
+
+        let mut views = MaterializedViews::new(eav, cas.clone());
+        let count = |so_far: Content, _: &EntityAttributeValueIndex<StringAttribute>| {
+            let count: u64 = ::serde_json::from_str(&String::from(so_far))?;
+            Ok(Content::from_json(&(count + 1).to_string()))
+        };
+        let links_from_a = OwnedEaviQuery::new(
+            Some(Address::from("a")),
+            Some(StringAttribute("link".to_string())),
+            None,
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(
+            Ok(Content::from_json("1")),
+            views.register("links from a", links_from_a, Content::from_json("0"), count)
+        );
+
+        views.add_eavi(&link("a", "link", "c")).unwrap();
+        views.add_eavi(&link("a", "tag", "d")).unwrap();
+        views.add_eavi(&link("b", "link", "c")).unwrap();
+        assert_eq!(
+            Ok(Some(Content::from_json("2"))),
+            views.fetch_view("links from a")
+        );
+
+        // the result outlives the views it was registered on
+        let reopened: MaterializedViews<StringAttribute, ExampleEntityAttributeValueStorage<_>, _> =
+            MaterializedViews::new(ExampleEntityAttributeValueStorage::new(), cas);
+        assert_eq!(
+            Ok(Some(Content::from_json("2"))),
+            reopened.fetch_view("links from a")
+        );
+        assert_eq!(Ok(None), reopened.fetch_view("something else"));
+
+        // an upsert replaces both links from a
+        views.upsert_eavi(&link("a", "link", "e")).unwrap();
+        assert_eq!(
+            Ok(Some(Content::from_json("1"))),
+            views.fetch_view("links from a")
+        );
+    }
+}
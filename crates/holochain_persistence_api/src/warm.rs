@@ -0,0 +1,60 @@
+//! Warming stores up right after they were opened.
+//!
+//! After a restart neither the page cache of the OS nor any read cache holds what is about to
+//! be read, so the first fetches take as long as the disk does. Warming reads what is expected
+//! to be read soon on a thread of its own: `CachedCas::warm` fills the LRU of the cache, the
+//! LMDB stores touch the pages their content is on. The `Warming` it returns can be waited on
+//! or dropped to let it finish on its own.
+
+use cas::content::Address;
+use eav::{Attribute, EaviQuery, EntityAttributeValueStorage};
+use error::{PersistenceError, PersistenceResult};
+use std::{
+    collections::HashSet,
+    thread::{self, JoinHandle},
+};
+
+/// A warm up running in the background.
+#[derive(Debug)]
+pub struct Warming {
+    thread: PersistenceResult<JoinHandle<PersistenceResult<usize>>>,
+}
+
+impl Warming {
+    /// Runs `warm` on a thread of its own, it returns how many entries it warmed.
+    pub fn spawn<F>(warm: F) -> Warming
+    where
+        F: FnOnce() -> PersistenceResult<usize> + Send + 'static,
+    {
+        let thread = thread::Builder::new()
+            .name("warming".to_string())
+            .spawn(warm)
+            .map_err(|e| PersistenceError::from(format!("could not start warming: {}", e)));
+        Warming { thread }
+    }
+
+    /// Waits for the warm up to finish, returning how many entries were warmed.
+    pub fn wait(self) -> PersistenceResult<usize> {
+        self.thread?
+            .join()
+            .map_err(|_| PersistenceError::from("warming panicked"))?
+    }
+}
+
+/// The entities and values of the EAVIs matching `query`, each once, in the order of the
+/// EAVIs.
+pub fn query_addresses<A: Attribute>(
+    eav: &dyn EntityAttributeValueStorage<A>,
+    query: &EaviQuery<A>,
+) -> PersistenceResult<Vec<Address>> {
+    let mut seen = HashSet::new();
+    let mut addresses = Vec::new();
+    for eavi in eav.fetch_eavi(query)? {
+        for address in &[eavi.entity(), eavi.value()] {
+            if seen.insert(address.clone()) {
+                addresses.push(address.clone());
+            }
+        }
+    }
+    Ok(addresses)
+}
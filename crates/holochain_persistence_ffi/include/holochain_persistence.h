@@ -0,0 +1,76 @@
+/*
+ * C API for the holochain LMDB persistence stores.
+ *
+ * Strings passed in are borrowed for the duration of the call and must be NUL terminated
+ * UTF-8. Strings handed out through an out pointer belong to the caller and must be released
+ * with hcp_string_free. A store opened with hcp_open belongs to the caller, must be released
+ * with hcp_close and must not be used from two threads at the same time.
+ *
+ * Content is written in the background and is guaranteed to be visible once hcp_commit has
+ * returned HCP_OK. EAVIs are written before hcp_add_eavi returns.
+ */
+
+#ifndef HOLOCHAIN_PERSISTENCE_H
+#define HOLOCHAIN_PERSISTENCE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define HCP_OK 0
+/* hcp_fetch found nothing at the address */
+#define HCP_NOT_FOUND 1
+#define HCP_NULL_ARGUMENT -1
+#define HCP_INVALID_UTF8 -2
+#define HCP_INVALID_JSON -3
+#define HCP_STORAGE_ERROR -4
+/* a bug, the store should not be used any further */
+#define HCP_PANIC -5
+
+typedef struct HcpStore HcpStore;
+
+/* Opens (or creates) the stores in the directory at path. */
+int32_t hcp_open(const char *path, HcpStore **store_out);
+
+/* Commits pending writes, ignoring failures, and releases the store. Accepts NULL. */
+void hcp_close(HcpStore *store);
+
+/* Adds JSON content and hands out its address. */
+int32_t hcp_add(HcpStore *store, const char *content_json, char **address_out);
+
+/* Hands out the content stored at the address or returns HCP_NOT_FOUND. */
+int32_t hcp_fetch(HcpStore *store, const char *address, char **content_out);
+
+/* Adds an EAVI and hands out the EAVI stored, as JSON, once it has been written. */
+int32_t hcp_add_eavi(HcpStore *store,
+                     const char *entity,
+                     const char *attribute,
+                     const char *value,
+                     char **eavi_out);
+
+/*
+ * Runs a query and hands out the matching EAVIs as a JSON array. Null fields match anything:
+ * {"entity": null, "attribute": "name", "value": null,
+ *  "index": "LatestByAttribute" or {"Range": [from, to]}, "tombstone": null}
+ */
+int32_t hcp_query(HcpStore *store, const char *query_json, char **result_out);
+
+/* Waits until everything added so far has been written. */
+int32_t hcp_commit(HcpStore *store);
+
+/*
+ * Hands out the message of the last failure on this thread, or NULL if there is none.
+ * The message is cleared, it must be released with hcp_string_free.
+ */
+char *hcp_last_error(void);
+
+/* Releases a string handed out by this library. Accepts NULL. */
+void hcp_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
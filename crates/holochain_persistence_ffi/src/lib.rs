@@ -0,0 +1,410 @@
+//! C API for the LMDB stores
+//!
+//! Lets hosts that can't link Rust directly (Node.js addons, Android NDK, iOS) embed a CAS and
+//! an EAV store. `include/holochain_persistence.h` declares everything exported here.
+//!
+//! Ownership rules:
+//!
+//! - strings passed in are only borrowed for the duration of the call and must be NUL
+//!   terminated UTF-8
+//! - strings handed out through an out pointer belong to the caller and must be released with
+//!   `hcp_string_free`
+//! - a store opened with `hcp_open` belongs to the caller and must be released with `hcp_close`,
+//!   it must not be used from two threads at the same time
+//!
+//! Every function but the release functions returns one of the `HCP_*` codes. On a negative
+//! code `hcp_last_error` returns a description of what went wrong on the calling thread.
+//!
+//! Content is written in the background; it is guaranteed to be visible to `hcp_fetch` once
+//! `hcp_commit` has returned `HCP_OK`. EAVIs are written before `hcp_add_eavi` returns, as their
+//! index is only settled in the transaction they are written in.
+#![warn(unused_extern_crates)]
+// the pointer rules above hold for every exported function
+#![allow(clippy::missing_safety_doc)]
+
+use holochain_json_api::json::JsonString;
+use holochain_persistence_api::{
+    cas::{
+        content::{Address, AddressableContent},
+        storage::ContentAddressableStorage,
+    },
+    eav::{
+        EntityAttributeValueIndex, EntityAttributeValueStorage, OwnedEaviQuery, StringAttribute,
+    },
+    error::PersistenceError,
+};
+use holochain_persistence_lmdb::{
+    cas::lmdb::LmdbStorage, eav::lmdb::EavLmdbStorage, writer::WriteReceipt,
+};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    fmt::Display,
+    fs,
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr,
+};
+
+pub const HCP_OK: i32 = 0;
+/// `hcp_fetch` found nothing at the address
+pub const HCP_NOT_FOUND: i32 = 1;
+pub const HCP_NULL_ARGUMENT: i32 = -1;
+pub const HCP_INVALID_UTF8: i32 = -2;
+pub const HCP_INVALID_JSON: i32 = -3;
+pub const HCP_STORAGE_ERROR: i32 = -4;
+/// a bug, the store should not be used any further
+pub const HCP_PANIC: i32 = -5;
+
+/// queued writes before `hcp_add` blocks
+const WRITE_QUEUE_CAPACITY: usize = 1024;
+
+/// The handle C code holds on to.
+pub struct HcpStore {
+    cas: LmdbStorage,
+    eav: EavLmdbStorage<StringAttribute>,
+    /// writes that weren't committed when the last one was queued
+    pending: Vec<WriteReceipt>,
+    /// the first write since the last commit that failed
+    failed: Option<PersistenceError>,
+}
+
+impl HcpStore {
+    fn open(path: &Path) -> Result<HcpStore, Failure> {
+        let (cas_path, eav_path) = (path.join("cas"), path.join("eav"));
+        fs::create_dir_all(&cas_path).map_err(storage_failure)?;
+        fs::create_dir_all(&eav_path).map_err(storage_failure)?;
+        Ok(HcpStore {
+            cas: LmdbStorage::new(cas_path, None, None)
+                .with_write_queue(WRITE_QUEUE_CAPACITY, None),
+            eav: EavLmdbStorage::new(eav_path, None, None),
+            pending: Vec::new(),
+            failed: None,
+        })
+    }
+
+    /// Keeps track of a queued write, dropping the writes committed since the last one so
+    /// that only those still queued are kept.
+    fn push(&mut self, receipt: WriteReceipt) {
+        let failed = &mut self.failed;
+        self.pending.retain(|pending| match pending.try_wait() {
+            None => true,
+            Some(Ok(())) => false,
+            Some(Err(e)) => {
+                failed.get_or_insert(e);
+                false
+            }
+        });
+        self.pending.push(receipt);
+    }
+
+    /// Waits for every pending write, reporting the first that failed.
+    fn commit(&mut self) -> Result<(), Failure> {
+        let failed = self.failed.take().map_or(Ok(()), Err);
+        self.pending
+            .drain(..)
+            .map(WriteReceipt::wait)
+            .fold(failed, |result, outcome| result.and(outcome))
+            .map_err(Failure::from)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// An error code and the message for `hcp_last_error`.
+struct Failure(i32, String);
+
+impl From<PersistenceError> for Failure {
+    fn from(e: PersistenceError) -> Failure {
+        Failure(HCP_STORAGE_ERROR, e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Failure {
+    fn from(e: serde_json::Error) -> Failure {
+        Failure(HCP_INVALID_JSON, e.to_string())
+    }
+}
+
+fn storage_failure<E: Display>(e: E) -> Failure {
+    Failure(HCP_STORAGE_ERROR, e.to_string())
+}
+
+/// Runs the body of an exported function, turning failures and panics into codes.
+fn guard<F: FnOnce() -> Result<i32, Failure>>(f: F) -> i32 {
+    let (code, message) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => return code,
+        Ok(Err(Failure(code, message))) => (code, message),
+        Err(_) => (HCP_PANIC, "holochain persistence panicked".to_string()),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+unsafe fn borrow_str<'a>(s: *const c_char) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(Failure(HCP_NULL_ARGUMENT, "string argument is null".into()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| Failure(HCP_INVALID_UTF8, e.to_string()))
+}
+
+unsafe fn borrow_store<'a>(store: *mut HcpStore) -> Result<&'a mut HcpStore, Failure> {
+    store
+        .as_mut()
+        .ok_or_else(|| Failure(HCP_NULL_ARGUMENT, "store is null".into()))
+}
+
+/// Checked before doing any work so that nothing is written without the caller learning of it.
+fn check_out<T>(out: *mut T) -> Result<(), Failure> {
+    if out.is_null() {
+        Err(Failure(HCP_NULL_ARGUMENT, "out pointer is null".into()))
+    } else {
+        Ok(())
+    }
+}
+
+unsafe fn hand_out(out: *mut *mut c_char, s: String) -> Result<i32, Failure> {
+    let s = CString::new(s).map_err(|e| Failure(HCP_INVALID_UTF8, e.to_string()))?;
+    *out = s.into_raw();
+    Ok(HCP_OK)
+}
+
+/// Opens (or creates) the stores in the directory at `path`.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_open(path: *const c_char, store_out: *mut *mut HcpStore) -> i32 {
+    guard(|| {
+        check_out(store_out)?;
+        let store = HcpStore::open(Path::new(borrow_str(path)?))?;
+        *store_out = Box::into_raw(Box::new(store));
+        Ok(HCP_OK)
+    })
+}
+
+/// Commits pending writes, ignoring failures, and releases the store. Accepts null.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_close(store: *mut HcpStore) {
+    if !store.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut store = Box::from_raw(store);
+            let _ = store.commit();
+        }));
+    }
+}
+
+/// Adds JSON content and hands out its address.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_add(
+    store: *mut HcpStore,
+    content_json: *const c_char,
+    address_out: *mut *mut c_char,
+) -> i32 {
+    guard(|| {
+        check_out(address_out)?;
+        let store = borrow_store(store)?;
+        let json = borrow_str(content_json)?;
+        serde_json::from_str::<serde_json::Value>(json)?;
+        let content = JsonString::from_json(json);
+        let receipt = store.cas.add_async(&content);
+        store.push(receipt);
+        hand_out(address_out, content.address().to_string())
+    })
+}
+
+/// Hands out the content stored at the address or returns `HCP_NOT_FOUND`.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_fetch(
+    store: *mut HcpStore,
+    address: *const c_char,
+    content_out: *mut *mut c_char,
+) -> i32 {
+    guard(|| {
+        check_out(content_out)?;
+        let store = borrow_store(store)?;
+        let address = Address::from(borrow_str(address)?);
+        match store.cas.fetch(&address)? {
+            Some(content) => hand_out(content_out, String::from(content)),
+            None => Ok(HCP_NOT_FOUND),
+        }
+    })
+}
+
+/// Adds an EAVI and hands out the EAVI stored, as JSON, once it has been written.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_add_eavi(
+    store: *mut HcpStore,
+    entity: *const c_char,
+    attribute: *const c_char,
+    value: *const c_char,
+    eavi_out: *mut *mut c_char,
+) -> i32 {
+    guard(|| {
+        check_out(eavi_out)?;
+        let store = borrow_store(store)?;
+        let eavi = EntityAttributeValueIndex::new(
+            &Address::from(borrow_str(entity)?),
+            &StringAttribute(borrow_str(attribute)?.to_string()),
+            &Address::from(borrow_str(value)?),
+        )?;
+        let added = store.eav.add_eavi(&eavi)?;
+        hand_out(eavi_out, serde_json::to_string(&added)?)
+    })
+}
+
+/// Runs a query given as the JSON of an `OwnedEaviQuery` and hands out the matching EAVIs
+/// as a JSON array.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_query(
+    store: *mut HcpStore,
+    query_json: *const c_char,
+    result_out: *mut *mut c_char,
+) -> i32 {
+    guard(|| {
+        check_out(result_out)?;
+        let store = borrow_store(store)?;
+        let query: OwnedEaviQuery<StringAttribute> = serde_json::from_str(borrow_str(query_json)?)?;
+        let eavis: Vec<_> = store
+            .eav
+            .fetch_eavi(&query.as_query())?
+            .into_iter()
+            .collect();
+        hand_out(result_out, serde_json::to_string(&eavis)?)
+    })
+}
+
+/// Waits until everything added so far has been written.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_commit(store: *mut HcpStore) -> i32 {
+    guard(|| {
+        borrow_store(store)?.commit()?;
+        Ok(HCP_OK)
+    })
+}
+
+/// Hands out the message of the last failure on this thread, or null if there is none.
+/// The message is cleared, it must be released with `hcp_string_free`.
+#[no_mangle]
+pub extern "C" fn hcp_last_error() -> *mut c_char {
+    LAST_ERROR
+        .with(|last| last.borrow_mut().take())
+        .and_then(|message| CString::new(message).ok())
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Releases a string handed out by this library. Accepts null.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe fn take(s: *mut c_char) -> String {
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        hcp_string_free(s);
+        owned
+    }
+
+    #[test]
+    fn committed_writes_are_not_kept() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut store = HcpStore::open(temp.path()).unwrap();
+        for i in 0..10_000 {
+            let content = JsonString::from_json(&i.to_string());
+            let receipt = store.cas.add_async(&content);
+            store.push(receipt);
+        }
+        // what is queued, what is being committed and the last write
+        assert!(store.pending.len() <= WRITE_QUEUE_CAPACITY + 2);
+        assert!(store.commit().is_ok());
+        assert!(store.pending.is_empty());
+    }
+
+    #[test]
+    fn content_and_eavis_through_c_api() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let path = c(temp.path().to_str().unwrap());
+        unsafe {
+            let mut store = ptr::null_mut();
+            assert_eq!(HCP_OK, hcp_open(path.as_ptr(), &mut store));
+
+            let mut address = ptr::null_mut();
+            assert_eq!(HCP_OK, hcp_add(store, c("\"foo\"").as_ptr(), &mut address));
+            let address = c(&take(address));
+            let mut eavi = ptr::null_mut();
+            assert_eq!(
+                HCP_OK,
+                hcp_add_eavi(
+                    store,
+                    address.as_ptr(),
+                    c("name").as_ptr(),
+                    address.as_ptr(),
+                    &mut eavi
+                )
+            );
+            let eavi = take(eavi);
+            assert_eq!(HCP_OK, hcp_commit(store));
+
+            let mut content = ptr::null_mut();
+            assert_eq!(HCP_OK, hcp_fetch(store, address.as_ptr(), &mut content));
+            assert_eq!("\"foo\"", take(content));
+
+            let query = c(
+                r#"{"entity":null,"attribute":"name","value":null,"index":"LatestByAttribute","tombstone":null}"#,
+            );
+            let mut result = ptr::null_mut();
+            assert_eq!(HCP_OK, hcp_query(store, query.as_ptr(), &mut result));
+            assert_eq!(format!("[{}]", eavi), take(result));
+
+            hcp_close(store);
+        }
+    }
+
+    #[test]
+    fn failures_are_reported_as_codes() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let path = c(temp.path().to_str().unwrap());
+        unsafe {
+            let mut store = ptr::null_mut();
+            assert_eq!(HCP_OK, hcp_open(path.as_ptr(), &mut store));
+
+            let mut out = ptr::null_mut();
+            assert_eq!(
+                HCP_NOT_FOUND,
+                hcp_fetch(store, c("QmMissing").as_ptr(), &mut out)
+            );
+            assert!(out.is_null());
+            assert!(hcp_last_error().is_null());
+
+            assert_eq!(
+                HCP_INVALID_JSON,
+                hcp_add(store, c("not json").as_ptr(), &mut out)
+            );
+            assert!(!take(hcp_last_error()).is_empty());
+            assert_eq!(
+                HCP_NULL_ARGUMENT,
+                hcp_add(store, c("\"foo\"").as_ptr(), ptr::null_mut())
+            );
+            assert_eq!(
+                HCP_NULL_ARGUMENT,
+                hcp_fetch(ptr::null_mut(), c("Qm").as_ptr(), &mut out)
+            );
+
+            hcp_close(store);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use holochain_json_api::json::JsonString;
 use holochain_persistence_api::{
     cas::{
+        bloom::BloomFilter,
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
     },
@@ -9,7 +10,7 @@ use holochain_persistence_api::{
 };
 
 use std::{
-    fs::{create_dir_all, read_to_string, write},
+    fs::{create_dir_all, read_dir, read_to_string, write},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
@@ -22,6 +23,7 @@ pub struct FilesystemStorage {
     dir_path: PathBuf,
     id: Uuid,
     lock: Arc<RwLock<()>>,
+    bloom: Option<Arc<RwLock<BloomFilter>>>,
 }
 
 impl PartialEq for FilesystemStorage {
@@ -38,9 +40,32 @@ impl FilesystemStorage {
             dir_path,
             id: Uuid::new_v4(),
             lock: Arc::new(RwLock::new(())),
+            bloom: None,
         })
     }
 
+    /// Keeps a bloom filter of every stored address in memory so `contains` can answer
+    /// misses without hitting the filesystem. The filter is filled from the existing content.
+    pub fn with_bloom_filter(
+        mut self,
+        expected_items: usize,
+        false_positive_rate: f64,
+    ) -> PersistenceResult<FilesystemStorage> {
+        let mut bloom = BloomFilter::new(expected_items, false_positive_rate);
+        if self.dir_path.is_dir() {
+            for entry in read_dir(&self.dir_path)? {
+                let path = entry?.path();
+                if path.extension().map_or(false, |ext| ext == "txt") {
+                    if let Some(address) = path.file_stem().and_then(|stem| stem.to_str()) {
+                        bloom.insert(address);
+                    }
+                }
+            }
+        }
+        self.bloom = Some(Arc::new(RwLock::new(bloom)));
+        Ok(self)
+    }
+
     /// builds an absolute path for an AddressableContent address
     fn address_to_path(&self, address: &Address) -> PathBuf {
         // using .txt extension because content is arbitrary and controlled by the
@@ -52,7 +77,7 @@ impl FilesystemStorage {
 }
 
 impl ContentAddressableStorage for FilesystemStorage {
-    fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
         let _guard = self.lock.write()?;
         // @TODO be more efficient here
         // @see https://github.com/holochain/holochain-rust/issues/248
@@ -63,11 +88,20 @@ impl ContentAddressableStorage for FilesystemStorage {
             content.content().to_string(),
         )?;
 
+        if let Some(bloom) = &self.bloom {
+            bloom.write()?.insert(&*content.address().to_string());
+        }
+
         Ok(())
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
         let _guard = self.lock.read()?;
+        if let Some(bloom) = &self.bloom {
+            if !bloom.read()?.may_contain(&*address.to_string()) {
+                return Ok(false);
+            }
+        }
         Ok(Path::new(&self.address_to_path(address)).is_file())
     }
 
@@ -94,8 +128,10 @@ pub mod tests {
     use crate::cas::file::FilesystemStorage;
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::cas::{
-        content::{ExampleAddressableContent, OtherExampleAddressableContent},
-        storage::StorageTestSuite,
+        content::{
+            AddressableContent, Content, ExampleAddressableContent, OtherExampleAddressableContent,
+        },
+        storage::{ContentAddressableStorage, StorageTestSuite},
     };
     use tempfile::{tempdir, TempDir};
 
@@ -115,4 +151,26 @@ pub mod tests {
             RawString::from("bar").into(),
         );
     }
+
+    #[test]
+    fn file_contains_with_bloom_filter() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let existing = Content::from_json("\"existing\"");
+        let cas = FilesystemStorage::new(dir.path()).unwrap();
+        cas.add(&existing).unwrap();
+
+        // reopening fills the filter from what is already stored
+        let cas = FilesystemStorage::new(dir.path())
+            .unwrap()
+            .with_bloom_filter(100, 0.01)
+            .unwrap();
+        assert_eq!(Ok(true), cas.contains(&existing.address()));
+
+        let added = Content::from_json("\"added\"");
+        let missing = Content::from_json("\"missing\"");
+        assert_eq!(Ok(false), cas.contains(&added.address()));
+        cas.add(&added).unwrap();
+        assert_eq!(Ok(true), cas.contains(&added.address()));
+        assert_eq!(Ok(false), cas.contains(&missing.address()));
+    }
 }
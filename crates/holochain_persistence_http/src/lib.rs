@@ -0,0 +1,200 @@
+//! Read-only HTTP gateway
+//!
+//! Serves the stores behind a `PersistenceHandle` over HTTP so browser UIs and debugging tools
+//! can look into them:
+//!
+//! - `GET /cas/{address}` returns the content stored at the address. Content never changes
+//!   once stored, so the address doubles as its ETag and `If-None-Match` gets a 304.
+//! - `POST /eav/query` takes an `OwnedEaviQuery` as JSON and returns the matching EAVIs.
+//!
+//! Nothing can be written through the gateway.
+#![warn(unused_extern_crates)]
+
+use holochain_persistence_api::{
+    cas::content::Address,
+    eav::Attribute,
+    error::PersistenceError,
+    persistence_service::{OwnedEaviQuery, PersistenceHandle},
+};
+use serde::de::DeserializeOwned;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use warp::{
+    http::{header, StatusCode},
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+fn etag(address: &Address) -> String {
+    format!("\"{}\"", address)
+}
+
+fn error_reply(e: PersistenceError) -> Response {
+    reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+}
+
+async fn get_content<A: Attribute + Send>(
+    address: String,
+    if_none_match: Option<String>,
+    handle: PersistenceHandle<A>,
+) -> Result<Response, Rejection> {
+    let address: Address = match address.parse() {
+        Ok(address) => address,
+        Err(e) => {
+            return Ok(reply::with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    let etag = etag(&address);
+    if if_none_match.as_ref() == Some(&etag) {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+    // the handle isn't Sync, so it must not be borrowed across the await
+    let fetched = handle.fetch_content(&address);
+    Ok(match fetched.await {
+        Ok(Some(content)) => reply::with_header(
+            reply::with_header(String::from(content), header::ETAG, etag),
+            header::CONTENT_TYPE,
+            "application/json",
+        )
+        .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => error_reply(e),
+    })
+}
+
+async fn query_eav<A: Attribute + Send>(
+    query: OwnedEaviQuery<A>,
+    handle: PersistenceHandle<A>,
+) -> Result<Response, Rejection> {
+    let queried = handle.query_eavi(query);
+    Ok(match queried.await {
+        Ok(eavis) => reply::json(&eavis.into_iter().collect::<Vec<_>>()).into_response(),
+        Err(e) => error_reply(e),
+    })
+}
+
+/// The gateway's routes, for mounting into a larger warp server.
+pub fn routes<A>(
+    handle: PersistenceHandle<A>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    A: Attribute + DeserializeOwned + Send + 'static,
+{
+    // the handle itself can't be shared between threads, every request gets its own clone
+    let handle = Arc::new(Mutex::new(handle));
+    let with_handle = warp::any().map(move || {
+        handle
+            .lock()
+            .expect("persistence handle lock poisoned")
+            .clone()
+    });
+
+    let cas = warp::get()
+        .and(warp::path!("cas" / String))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_handle.clone())
+        .and_then(get_content);
+
+    let eav = warp::post()
+        .and(warp::path!("eav" / "query"))
+        .and(warp::body::json())
+        .and(with_handle)
+        .and_then(query_eav);
+
+    cas.or(eav).unify()
+}
+
+/// Serves the gateway on `address` until the returned future is dropped.
+pub async fn serve<A>(handle: PersistenceHandle<A>, address: impl Into<SocketAddr> + 'static)
+where
+    A: Attribute + DeserializeOwned + Send + 'static,
+{
+    warp::serve(routes(handle)).run(address).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, Content},
+        eav::{EntityAttributeValueIndex, ExampleAttribute, IndexFilter},
+        persistence_service::PersistenceActor,
+    };
+    use holochain_persistence_mem::{cas::memory::MemoryStorage, eav::memory::EavMemoryStorage};
+    use warp::test::request;
+
+    fn spawn() -> PersistenceHandle<ExampleAttribute> {
+        PersistenceActor::spawn(MemoryStorage::new(), EavMemoryStorage::new())
+    }
+
+    #[tokio::test]
+    async fn serves_content_with_etag() {
+        let handle = spawn();
+        let content = Content::from_json("\"foo\"");
+        handle.add_content(&content).await.unwrap();
+        let routes = routes(handle);
+        let path = format!("/cas/{}", content.address());
+
+        let response = request().path(&path).reply(&routes).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("\"foo\"", response.body());
+        let tag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(etag(&content.address()), tag);
+
+        let response = request()
+            .path(&path)
+            .header("if-none-match", tag)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        let missing = format!("/cas/{}", Content::from_json("\"bar\"").address());
+        let response = request().path(&missing).reply(&routes).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let response = request().path("/cas/not-an-address").reply(&routes).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[tokio::test]
+    async fn answers_eav_queries() {
+        let handle = spawn();
+        let entity = Content::from_json("\"e\"").address();
+        let value = Content::from_json("\"v\"").address();
+        let eavi =
+            EntityAttributeValueIndex::new(&entity, &ExampleAttribute::default(), &value).unwrap();
+        let added = handle.add_eavi(&eavi).await.unwrap().unwrap();
+        let routes = routes(handle);
+
+        let query = OwnedEaviQuery::<ExampleAttribute>::new(
+            Some(entity),
+            None,
+            None,
+            IndexFilter::LatestByAttribute,
+            None,
+        );
+        let response = request()
+            .method("POST")
+            .path("/eav/query")
+            .json(&query)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let eavis: Vec<EntityAttributeValueIndex<ExampleAttribute>> =
+            serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(vec![added], eavis);
+
+        // nothing can be written
+        let response = request()
+            .method("POST")
+            .path(&format!("/cas/{}", value))
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+    }
+}
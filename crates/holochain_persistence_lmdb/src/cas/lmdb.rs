@@ -1,34 +1,65 @@
-use crate::common::LmdbInstance;
+#[cfg(feature = "search")]
+use crate::search::{SearchConfig, SearchIndex};
+use crate::{
+    checksum::{self, QuarantinedRecord},
+    common::{stored_json, write_error, Encoded, LmdbInstance},
+    config::LmdbConfig,
+    identity,
+    rewrite::{self, RewriteProgress},
+    sequence::LmdbSequences,
+    trash::{self, TrashedContent},
+    writer::WriteReceipt,
+};
 use holochain_json_api::json::JsonString;
+use holochain_logging::prelude::*;
 use holochain_persistence_api::{
     cas::{
+        bloom::BloomFilter,
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
+        stream::ContentChunks,
     },
     error::{PersistenceError, PersistenceResult},
+    events::{EventBus, StorageEvent},
+    format::SerializationFormat,
+    identity::StoreIdentity,
+    limits::Limits,
     reporting::{ReportStorage, StorageReport},
+    retry::RetryPolicy,
+    warm::Warming,
 };
-use rkv::{
-    error::{DataError, StoreError},
-    Value,
-};
+use rkv::{error::StoreError, Value};
 use std::{
+    cmp,
     fmt::{Debug, Error, Formatter},
     path::Path,
+    sync::PoisonError,
+    time::Duration,
 };
 use uuid::Uuid;
 
 const CAS_BUCKET: &str = "cas";
+/// addresses warmed per read transaction
+const WARM_BATCH: usize = 256;
+/// bytes between the reads touching the pages of a value
+const PAGE_BYTES: usize = 4096;
 
 #[derive(Clone)]
 pub struct LmdbStorage {
-    id: Uuid,
-    lmdb: LmdbInstance,
+    identity: StoreIdentity,
+    pub(crate) lmdb: LmdbInstance,
+    format: SerializationFormat,
+    checksums: bool,
+    limits: Limits,
+    #[cfg(feature = "search")]
+    search: Option<SearchIndex>,
 }
 
 impl Debug for LmdbStorage {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        f.debug_struct("LmdbStorage").field("id", &self.id).finish()
+        f.debug_struct("LmdbStorage")
+            .field("id", &self.identity.id)
+            .finish()
     }
 }
 
@@ -36,44 +67,491 @@ impl LmdbStorage {
     pub fn new<P: AsRef<Path> + Clone>(
         db_path: P,
         initial_map_bytes: Option<usize>,
+        max_readers: Option<u32>,
     ) -> LmdbStorage {
+        let lmdb = LmdbInstance::new(CAS_BUCKET, db_path, initial_map_bytes, max_readers);
+        let identity = identity::load_or_create(&lmdb, &StoreIdentity::new(None))
+            .expect("Could not load the store identity");
         LmdbStorage {
-            id: Uuid::new_v4(),
-            lmdb: LmdbInstance::new(CAS_BUCKET, db_path, initial_map_bytes),
+            identity,
+            lmdb,
+            format: SerializationFormat::default(),
+            checksums: false,
+            limits: Limits::default(),
+            #[cfg(feature = "search")]
+            search: None,
+        }
+    }
+
+    /// Opens the store described by `config`.
+    pub fn from_config(config: &LmdbConfig) -> PersistenceResult<LmdbStorage> {
+        let mut cas = LmdbStorage::new(&config.path, config.initial_map_bytes, config.max_readers)
+            .with_serialization_format(config.serialization_format)
+            .with_limits(config.limits);
+        if config.checksums {
+            cas = cas.with_checksums();
+        }
+        if config.max_map_bytes.is_some() {
+            cas = cas.with_max_map_bytes(config.max_map_bytes);
+        }
+        if let Some(queue) = &config.write_queue {
+            cas = cas.with_write_queue(queue.capacity, queue.commit_window());
+        }
+        #[cfg(feature = "search")]
+        {
+            if let Some(search) = &config.search {
+                cas = cas.with_search_index(search)?;
+            }
+        }
+        match &config.bloom_filter {
+            Some(bloom) => cas.with_bloom_filter(bloom.expected_items, bloom.false_positive_rate),
+            None => Ok(cas),
+        }
+    }
+
+    /// What this store is, written when it was created. `get_id` is its `id`.
+    pub fn identity(&self) -> &StoreIdentity {
+        &self.identity
+    }
+
+    /// Gives this store an identity of its own, e.g. after it was copied from another.
+    pub(crate) fn renew_identity(&mut self) -> PersistenceResult<()> {
+        let fresh = StoreIdentity::new(None);
+        identity::replace(&self.lmdb, &fresh)?;
+        self.identity = fresh;
+        Ok(())
+    }
+
+    /// Named sequences kept in the environment of this store, see `SequenceStorage`.
+    pub fn sequences(&self) -> LmdbSequences {
+        LmdbSequences::new(&self.lmdb)
+    }
+
+    /// Writes new content in `format`. Content already stored is read whatever format it was
+    /// written in.
+    pub fn with_serialization_format(mut self, format: SerializationFormat) -> LmdbStorage {
+        self.format = format;
+        self
+    }
+
+    /// Re-encodes all stored content in `format`, `batch_size` entries per transaction, calling
+    /// `progress` after every batch. Pass the last progress back in as `resume` to carry on
+    /// where a rewrite stopped, or `RewriteProgress::default()` to start from the beginning.
+    pub fn rewrite_all<F: FnMut(&RewriteProgress)>(
+        &self,
+        format: SerializationFormat,
+        batch_size: usize,
+        resume: RewriteProgress,
+        mut progress: F,
+    ) -> PersistenceResult<RewriteProgress> {
+        rewrite::rewrite_all(
+            &self.lmdb,
+            format,
+            self.checksums,
+            batch_size,
+            resume,
+            &|_| Ok(Vec::new()),
+            &mut progress,
+        )
+    }
+
+    /// Fails adding content with more JSON than `limits.max_content_bytes` with
+    /// `PersistenceError::LimitExceeded`. Only `max_content_bytes` applies to the CAS.
+    pub fn with_limits(mut self, limits: Limits) -> LmdbStorage {
+        self.limits = limits;
+        self
+    }
+
+    fn encode(&self, content: &dyn AddressableContent) -> PersistenceResult<Encoded> {
+        let json = content.content().to_string();
+        self.limits
+            .check_content_bytes(&content.address(), json.len())?;
+        let encoded = Encoded::new(self.format, json)?;
+        if self.checksums {
+            encoded.sealed()
+        } else {
+            Ok(encoded)
+        }
+    }
+
+    /// Caps how far the memory map may grow when it fills up. Writes that don't fit into a map
+    /// of `max_map_bytes` fail with `PersistenceError::AddressSpaceExhausted`, as do writes the
+    /// map can't grow for because there is no address space left, without being retried. On 32
+    /// bit targets the map is capped at 1GB unless set otherwise, `None` keeps that default.
+    pub fn with_max_map_bytes(mut self, max_map_bytes: Option<usize>) -> LmdbStorage {
+        self.lmdb = self.lmdb.with_max_map_bytes(max_map_bytes);
+        self
+    }
+
+    /// Retries writes as `policy` says, by default only the writes that didn't fit, once the
+    /// map has grown. Writes that don't fit fail with `PersistenceError::AddressSpaceExhausted`
+    /// and are retried after growing the map, so `policy` has to retry that for the map to grow.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> LmdbStorage {
+        self.lmdb = self.lmdb.with_retry_policy(policy);
+        self
+    }
+
+    /// Writes new content with a checksum. Content that fails its checksum when fetched is
+    /// moved into the quarantine and the fetch returns `PersistenceError::Corruption`.
+    pub fn with_checksums(mut self) -> LmdbStorage {
+        self.checksums = true;
+        self
+    }
+
+    /// Content that failed its checksum, see `with_checksums`.
+    pub fn quarantined(&self) -> PersistenceResult<Vec<QuarantinedRecord>> {
+        checksum::quarantined(&self.lmdb)
+    }
+
+    /// Where this store publishes growing its map and quarantining content.
+    pub fn events(&self) -> &EventBus {
+        &self.lmdb.events
+    }
+
+    /// Drops everything in the quarantine, returning how many records were dropped.
+    pub fn purge_quarantine(&self) -> PersistenceResult<usize> {
+        checksum::purge_quarantine(&self.lmdb)
+    }
+
+    /// Reads the content at `addresses` on a background thread so the pages it is stored on
+    /// are in memory before it is fetched, e.g. right after the store was opened. Counts the
+    /// addresses that had content.
+    pub fn warm(&self, addresses: &[Address]) -> Warming {
+        let lmdb = self.lmdb.clone();
+        let addresses = addresses.to_vec();
+        Warming::spawn(move || {
+            let (mut warmed, mut touched) = (0, 0u8);
+            for batch in addresses.chunks(WARM_BATCH) {
+                warmed += lmdb
+                    .read(|reader| {
+                        let mut found = 0;
+                        for address in batch {
+                            if let Some(bytes) = stored_bytes(lmdb.store.get(reader, address)?) {
+                                // one byte per page is enough to have the OS read it in
+                                touched =
+                                    bytes.iter().step_by(PAGE_BYTES).fold(touched, |a, b| a ^ b);
+                                found += 1;
+                            }
+                        }
+                        Ok(found)
+                    })
+                    .map_err(|e| PersistenceError::from(format!("CAS warm error: {}", e)))?;
+            }
+            // logged so that the reads aren't optimized away
+            trace!("Warmed {} entries, pages xor to {:02x}", warmed, touched);
+            Ok(warmed)
+        })
+    }
+
+    /// Moves the content at `address` into the trash, from where `restore` can bring it back
+    /// until `empty_trash` drops it. False if there is no content at `address`. Publishes
+    /// `StorageEvent::Removed` for trashed content.
+    pub fn trash(&self, address: &Address) -> PersistenceResult<bool> {
+        let trashed = trash::trash(&self.lmdb, address)?;
+        if trashed {
+            self.lmdb
+                .events
+                .publish(&StorageEvent::Removed(address.clone()));
+        }
+        Ok(trashed)
+    }
+
+    /// Moves the content at `address` back out of the trash, false if it isn't in the trash.
+    /// Publishes `StorageEvent::Added` for restored content.
+    pub fn restore(&self, address: &Address) -> PersistenceResult<bool> {
+        let restored = trash::restore(&self.lmdb, address)?;
+        if restored {
+            self.remember(address);
+            self.lmdb
+                .events
+                .publish(&StorageEvent::Added(address.clone()));
+        }
+        Ok(restored)
+    }
+
+    /// Everything in the trash, see `trash`.
+    pub fn trashed(&self) -> PersistenceResult<Vec<TrashedContent>> {
+        trash::trashed(&self.lmdb)
+    }
+
+    /// Drops what was trashed more than `older_than` ago, returning how much was dropped.
+    pub fn empty_trash(&self, older_than: Duration) -> PersistenceResult<usize> {
+        trash::empty_trash(&self.lmdb, older_than)
+    }
+
+    /// Keeps a bloom filter of every stored address in memory so `contains` can answer
+    /// misses without reading the database. The filter is filled from the existing content.
+    /// It is kept with the environment, so every store open on it uses the filter and
+    /// remembers what it adds, and the first filter asked for is the one kept.
+    pub fn with_bloom_filter(
+        self,
+        expected_items: usize,
+        false_positive_rate: f64,
+    ) -> PersistenceResult<LmdbStorage> {
+        // held while the filter is filled, so content added meanwhile is remembered once it is
+        let mut keys = self
+            .lmdb
+            .keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if keys.is_none() {
+            let mut bloom = BloomFilter::new(expected_items, false_positive_rate);
+            self.lmdb
+                .read(|reader| {
+                    for entry in self.lmdb.store.iter_start(reader)? {
+                        let (key, _) = entry?;
+                        bloom.insert(&*String::from_utf8_lossy(key));
+                    }
+                    Ok(())
+                })
+                .map_err(|e| PersistenceError::from(format!("CAS bloom filter error: {}", e)))?;
+            *keys = Some(bloom);
+        }
+        drop(keys);
+        Ok(self)
+    }
+
+    fn remember(&self, address: &Address) {
+        // setting bits can't leave the filter half updated, so a poisoned lock is still usable
+        if let Some(bloom) = self
+            .lmdb
+            .keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            bloom.insert(&*address.to_string());
+        }
+    }
+
+    /// Commits writes made with `add_async` on a background thread, queueing up to `capacity`
+    /// of them before `add_async` blocks. With a `commit_window` the writes queued within that
+    /// window are committed together in one transaction.
+    pub fn with_write_queue(
+        mut self,
+        capacity: usize,
+        commit_window: Option<Duration>,
+    ) -> LmdbStorage {
+        self.lmdb = self.lmdb.with_write_queue(capacity, commit_window);
+        self
+    }
+
+    /// Keeps a full-text index of the content added from now on, and indexes what is already
+    /// stored. Content added with `add_async` isn't indexed until the index is opened again.
+    #[cfg(feature = "search")]
+    pub fn with_search_index(mut self, config: &SearchConfig) -> PersistenceResult<LmdbStorage> {
+        let index = SearchIndex::new(&self.lmdb, config);
+        index
+            .index_stored(&self.lmdb)
+            .map_err(|e| write_error(e, "CAS search index error"))?;
+        self.search = Some(index);
+        Ok(self)
+    }
+
+    /// Addresses of the content matching any term of `query`, with their BM25 score, best match
+    /// first. Trashed content is left out of the `limit` best matches.
+    #[cfg(feature = "search")]
+    pub fn search(&self, query: &str, limit: usize) -> PersistenceResult<Vec<(Address, f32)>> {
+        let index = self.search.as_ref().ok_or_else(|| {
+            PersistenceError::from("CAS search error: no search index, see with_search_index")
+        })?;
+        let search = || -> Result<Vec<(Address, f32)>, StoreError> {
+            let mut matches = Vec::new();
+            for (address, score) in index.search(&self.lmdb, query, limit)? {
+                if !trash::contains(&self.lmdb, &address)? {
+                    matches.push((address, score));
+                }
+            }
+            Ok(matches)
+        };
+        search().map_err(|e| PersistenceError::from(format!("CAS search error: {}", e)))
+    }
+
+    /// Adds content without waiting for the write to be committed.
+    /// The returned receipt resolves once the content can be fetched.
+    pub fn add_async(&self, content: &dyn AddressableContent) -> WriteReceipt {
+        // a failed write only costs the filter a false positive
+        self.remember(&content.address());
+        match self.encode(content) {
+            Ok(encoded) => self.lmdb.add_async(content.address(), encoded),
+            Err(e) => WriteReceipt::completed(Err(e)),
         }
     }
 }
 
 impl LmdbStorage {
-    fn lmdb_add(&mut self, content: &dyn AddressableContent) -> Result<(), StoreError> {
-        self.lmdb.add(
-            content.address(),
-            &Value::Json(&content.content().to_string()),
-        )
+    fn lmdb_add(&self, address: Address, encoded: &Encoded) -> Result<(), StoreError> {
+        #[cfg(feature = "search")]
+        {
+            if let Some(index) = &self.search {
+                let key = address.to_string();
+                let json = stored_json(Some(encoded.value()))?.into_owned();
+                return self.lmdb.write(|writer| {
+                    self.lmdb.store.put(writer, &key, &encoded.value())?;
+                    index.index(writer, &key, &json)
+                });
+            }
+        }
+        self.lmdb.add(address, &encoded.value())
     }
 
-    fn lmdb_fetch(&self, address: &Address) -> Result<Option<Content>, StoreError> {
-        let env = self.lmdb.manager.read().unwrap();
-        let reader = env.read()?;
+    /// Quarantines content failing its checksum and publishes `StorageEvent::Removed` for it.
+    fn read_error(&self, address: &Address, e: StoreError, context: &str) -> PersistenceError {
+        let mismatched = checksum::mismatched_key(&e).is_some();
+        let error = checksum::persistence_error(&self.lmdb, self.lmdb.store, e, context);
+        if mismatched {
+            self.lmdb
+                .events
+                .publish(&StorageEvent::Removed(address.clone()));
+        }
+        error
+    }
 
-        match self.lmdb.store.get(&reader, address.clone()) {
-            Ok(Some(value)) => match value {
-                Value::Json(s) => Ok(Some(JsonString::from_json(s))),
-                _ => Err(StoreError::DataError(DataError::Empty)),
+    fn lmdb_fetch(&self, address: &Address) -> Result<Option<Content>, StoreError> {
+        self.lmdb.read(
+            |reader| match self.lmdb.store.get(reader, address.clone()) {
+                Ok(Some(value)) => stored_json(Some(value))
+                    .map(|json| Some(JsonString::from_json(&json)))
+                    .map_err(|e| checksum::at_key(e, address.to_string().as_bytes())),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
             },
-            Ok(None) => Ok(None),
-            Err(e) => Err(e),
+        )
+    }
+}
+
+/// Where the JSON of content being streamed is read from.
+enum Source {
+    /// `len` bytes of JSON stored as they are, `skip` bytes into the stored value
+    Stored { skip: usize, len: usize },
+    /// content stored in a binary format has to be decoded whole
+    Decoded(Vec<u8>),
+}
+
+/// Reads streamed content a chunk per read transaction, so only the chunk is copied out of the
+/// map. A checksum is verified once, before the first chunk.
+struct LmdbContentChunks<'a> {
+    cas: &'a LmdbStorage,
+    address: Address,
+    chunk_size: usize,
+    offset: usize,
+    source: Option<Source>,
+    done: bool,
+}
+
+fn stored_bytes(value: Option<Value>) -> Option<&[u8]> {
+    match value {
+        Some(Value::Json(json)) => Some(json.as_bytes()),
+        Some(Value::Blob(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+impl<'a> LmdbContentChunks<'a> {
+    fn locate(&self) -> Result<Option<Source>, StoreError> {
+        let key = self.address.to_string();
+        self.cas.lmdb.read(|reader| {
+            let value = match self.cas.lmdb.store.get(reader, &key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            if let Value::Json(json) = value {
+                return Ok(Some(Source::Stored {
+                    skip: 0,
+                    len: json.len(),
+                }));
+            }
+            if let Value::Blob(bytes) = value {
+                let encoded =
+                    checksum::unseal(bytes).map_err(|e| checksum::at_key(e, key.as_bytes()))?;
+                if SerializationFormat::of(encoded) == Some(SerializationFormat::Json) {
+                    return Ok(Some(Source::Stored {
+                        skip: bytes.len() - encoded.len() + 1,
+                        len: encoded.len() - 1,
+                    }));
+                }
+            }
+            let json = stored_json(Some(value)).map_err(|e| checksum::at_key(e, key.as_bytes()))?;
+            Ok(Some(Source::Decoded(json.into_owned().into_bytes())))
+        })
+    }
+
+    fn next_chunk(&mut self) -> PersistenceResult<Option<Vec<u8>>> {
+        if self.source.is_none() {
+            let source = self
+                .locate()
+                .map_err(|e| self.cas.read_error(&self.address, e, "CAS stream error"))?;
+            match source {
+                Some(source) => self.source = Some(source),
+                None => {
+                    return Err(PersistenceError::from(format!(
+                        "no content at {} to stream",
+                        self.address
+                    )))
+                }
+            }
         }
+        let start = self.offset;
+        let chunk = match &self.source {
+            Some(Source::Decoded(json)) if start < json.len() => {
+                json[start..cmp::min(start + self.chunk_size, json.len())].to_vec()
+            }
+            Some(Source::Stored { skip, len }) if start < *len => {
+                let range = skip + start..skip + cmp::min(start + self.chunk_size, *len);
+                let key = self.address.to_string();
+                self.cas
+                    .lmdb
+                    .read(|reader| {
+                        Ok(stored_bytes(self.cas.lmdb.store.get(reader, &key)?)
+                            .and_then(|bytes| bytes.get(range))
+                            .map(<[u8]>::to_vec))
+                    })
+                    .map_err(|e| PersistenceError::from(format!("CAS stream error: {}", e)))?
+                    .ok_or_else(|| {
+                        PersistenceError::from(format!(
+                            "CAS stream error: content at {} went away while it was streamed",
+                            self.address
+                        ))
+                    })?
+            }
+            _ => return Ok(None),
+        };
+        self.offset += chunk.len();
+        Ok(Some(chunk))
+    }
+}
+
+impl<'a> Iterator for LmdbContentChunks<'a> {
+    type Item = PersistenceResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.next_chunk();
+        self.done = match chunk {
+            Ok(Some(_)) => false,
+            _ => true,
+        };
+        chunk.transpose()
     }
 }
 
 impl ContentAddressableStorage for LmdbStorage {
-    fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
-        self.lmdb_add(content)
-            .map_err(|e| PersistenceError::from(format!("CAS add error: {}", e)))
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        let encoded = self.encode(content)?;
+        self.lmdb_add(content.address(), &encoded)
+            .map_err(|e| write_error(e, "CAS add error"))?;
+        self.remember(&content.address());
+        Ok(())
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
+        if let Some(bloom) = &*self.lmdb.keys.read()? {
+            if !bloom.may_contain(&*address.to_string()) {
+                return Ok(false);
+            }
+        }
         self.fetch(address).map(|result| match result {
             Some(_) => true,
             None => false,
@@ -82,36 +560,69 @@ impl ContentAddressableStorage for LmdbStorage {
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
         self.lmdb_fetch(address)
-            .map_err(|e| PersistenceError::from(format!("CAS fetch error: {}", e)))
+            .map_err(|e| self.read_error(address, e, "CAS fetch error"))
+    }
+
+    /// Content stored as JSON is read from the map a chunk at a time, content stored in a binary
+    /// format is decoded whole first.
+    fn stream_content(&self, address: &Address, chunk_size: usize) -> ContentChunks {
+        Box::new(LmdbContentChunks {
+            cas: self,
+            address: address.clone(),
+            chunk_size: chunk_size.max(1),
+            offset: 0,
+            source: None,
+            done: false,
+        })
     }
 
     fn get_id(&self) -> Uuid {
-        self.id
+        self.identity.id
     }
 }
 
 impl ReportStorage for LmdbStorage {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
-        Ok(StorageReport::new(0)) // TODO: implement this
+        let (in_use, max_readers) = self.lmdb.reader_slots();
+        Ok(StorageReport::new(0).with_reader_slots(in_use, max_readers)) // TODO: implement bytes_total
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cas::lmdb::LmdbStorage;
+    use crate::{
+        cas::lmdb::LmdbStorage,
+        checksum::{self, tests::tamper},
+        config::{BloomFilterConfig, LmdbConfig},
+        rewrite::RewriteProgress,
+    };
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
-            content::{Content, ExampleAddressableContent, OtherExampleAddressableContent},
+            content::{
+                AddressableContent, Content, ExampleAddressableContent,
+                OtherExampleAddressableContent,
+            },
             storage::{CasBencher, ContentAddressableStorage, StorageTestSuite},
+            stream::reassemble,
         },
-        reporting::{ReportStorage, StorageReport},
+        error::{PersistenceError, PersistenceResult},
+        events::StorageEvent,
+        format::SerializationFormat,
+        limits::Limits,
+        reporting::{ReaderSlotReport, ReportStorage},
+    };
+    use rkv::Value;
+    use std::{
+        sync::{Arc, RwLock},
+        thread,
+        time::Duration,
     };
     use tempfile::{tempdir, TempDir};
 
     pub fn test_lmdb_cas() -> (LmdbStorage, TempDir) {
         let dir = tempdir().expect("Could not create a tempdir for CAS testing");
-        (LmdbStorage::new(dir.path(), None), dir)
+        (LmdbStorage::new(dir.path(), None, None), dir)
     }
 
     #[bench]
@@ -140,15 +651,474 @@ mod tests {
 
     #[test]
     fn lmdb_report_storage_test() {
-        let (mut cas, _) = test_lmdb_cas();
+        let (cas, _) = test_lmdb_cas();
         // add some content
         cas.add(&Content::from_json("some bytes"))
             .expect("could not add to CAS");
-        assert_eq!(cas.get_storage_report().unwrap(), StorageReport::new(0),);
+        assert_eq!(cas.get_storage_report().unwrap().bytes_total, 0);
 
         // add some more
         cas.add(&Content::from_json("more bytes"))
             .expect("could not add to CAS");
-        assert_eq!(cas.get_storage_report().unwrap(), StorageReport::new(0 + 0),);
+        assert_eq!(cas.get_storage_report().unwrap().bytes_total, 0 + 0);
+
+        // no reads in flight once fetch has returned
+        assert_eq!(
+            cas.get_storage_report().unwrap().reader_slots,
+            Some(ReaderSlotReport {
+                in_use: 0,
+                max_readers: 126,
+            }),
+        );
+    }
+
+    #[test]
+    fn lmdb_add_async_round_trip() {
+        let (cas, _dir) = test_lmdb_cas();
+        let cas = cas.with_write_queue(4, None);
+        let contents: Vec<Content> = (0..20)
+            .map(|i| Content::from_json(&format!("\"content {}\"", i)))
+            .collect();
+
+        let receipts: Vec<_> = contents.iter().map(|c| cas.add_async(c)).collect();
+        for receipt in receipts {
+            receipt.wait().expect("queued write failed");
+        }
+
+        for content in contents {
+            assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        }
+    }
+
+    #[test]
+    fn lmdb_add_async_coalesces_writes_in_commit_window() {
+        let (cas, _dir) = test_lmdb_cas();
+        let cas = cas.with_write_queue(64, Some(Duration::from_millis(50)));
+        let contents: Vec<Content> = (0..50)
+            .map(|i| Content::from_json(&format!("\"content {}\"", i)))
+            .collect();
+
+        let receipts: Vec<_> = contents.iter().map(|c| cas.add_async(c)).collect();
+        for receipt in receipts {
+            receipt.wait().expect("coalesced write failed");
+        }
+
+        for content in contents {
+            assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        }
+    }
+
+    #[test]
+    fn lmdb_add_async_without_queue_writes_immediately() {
+        let (cas, _dir) = test_lmdb_cas();
+        let content = Content::from_json("\"some bytes\"");
+
+        let receipt = cas.add_async(&content);
+        assert_eq!(Some(Ok(())), receipt.try_wait());
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+    }
+
+    #[test]
+    fn lmdb_content_round_trip_in_binary_formats() {
+        for format in &[SerializationFormat::MessagePack, SerializationFormat::Cbor] {
+            let (cas, _dir) = test_lmdb_cas();
+            let test_suite = StorageTestSuite::new(cas.with_serialization_format(*format));
+            test_suite
+                .round_trip_test::<ExampleAddressableContent, OtherExampleAddressableContent>(
+                    RawString::from("foo").into(),
+                    RawString::from("bar").into(),
+                );
+        }
+    }
+
+    #[test]
+    fn lmdb_reads_content_written_in_other_formats() {
+        let (json_cas, dir) = test_lmdb_cas();
+        let old = Content::from_json(r#"{"written":"as json"}"#);
+        json_cas.add(&old).unwrap();
+
+        let msgpack_cas = LmdbStorage::new(dir.path(), None, None)
+            .with_serialization_format(SerializationFormat::MessagePack);
+        let new = Content::from_json(r#"{"written":"as msgpack"}"#);
+        msgpack_cas.add(&new).unwrap();
+        let queued = Content::from_json("[1,2,3]");
+        msgpack_cas.add_async(&queued).wait().unwrap();
+
+        for content in &[old, new, queued] {
+            assert_eq!(
+                Ok(Some(content.clone())),
+                msgpack_cas.fetch(&content.address())
+            );
+            assert_eq!(
+                Ok(Some(content.clone())),
+                json_cas.fetch(&content.address())
+            );
+        }
+    }
+
+    /// the format every entry is stored in, in key order
+    fn stored_formats(cas: &LmdbStorage) -> Vec<Option<SerializationFormat>> {
+        cas.lmdb
+            .read(|reader| {
+                let mut formats = Vec::new();
+                for entry in cas.lmdb.store.iter_start(reader)? {
+                    formats.push(match entry?.1 {
+                        Some(Value::Blob(bytes)) => SerializationFormat::of(bytes),
+                        _ => Some(SerializationFormat::Json),
+                    });
+                }
+                Ok(formats)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lmdb_rewrite_all_in_batches() {
+        let (cas, _dir) = test_lmdb_cas();
+        let contents: Vec<Content> = (0..25)
+            .map(|i| Content::from_json(&format!("{{\"content\":{}}}", i)))
+            .collect();
+        for content in &contents {
+            cas.add(content).unwrap();
+        }
+
+        let mut batches = Vec::new();
+        let done = cas
+            .rewrite_all(
+                SerializationFormat::MessagePack,
+                10,
+                RewriteProgress::default(),
+                |progress| batches.push(progress.clone()),
+            )
+            .unwrap();
+        assert_eq!(
+            vec![(10, 10), (20, 20), (25, 25)],
+            batches
+                .iter()
+                .map(|p| (p.visited, p.rewritten))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(&done), batches.last());
+        assert!(stored_formats(&cas)
+            .iter()
+            .all(|f| *f == Some(SerializationFormat::MessagePack)));
+
+        // nothing left to do when run again
+        let again = cas
+            .rewrite_all(
+                SerializationFormat::MessagePack,
+                10,
+                RewriteProgress::default(),
+                |_| (),
+            )
+            .unwrap();
+        assert_eq!((25, 0), (again.visited, again.rewritten));
+
+        // resuming after the first batch leaves that batch alone
+        let resumed = cas
+            .rewrite_all(SerializationFormat::Json, 10, batches[0].clone(), |_| ())
+            .unwrap();
+        assert_eq!((25, 25), (resumed.visited, resumed.rewritten));
+        let formats = stored_formats(&cas);
+        assert!(formats[..10]
+            .iter()
+            .all(|f| *f == Some(SerializationFormat::MessagePack)));
+        assert!(formats[10..]
+            .iter()
+            .all(|f| *f == Some(SerializationFormat::Json)));
+
+        for content in contents {
+            assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        }
+    }
+
+    #[test]
+    fn lmdb_quarantines_content_failing_its_checksum() {
+        let (cas, _dir) = test_lmdb_cas();
+        let cas = cas
+            .with_serialization_format(SerializationFormat::MessagePack)
+            .with_checksums();
+        let intact = Content::from_json("{\"intact\":true}");
+        let corrupt = Content::from_json("\"corrupt\"");
+        cas.add(&intact).unwrap();
+        cas.add(&corrupt).unwrap();
+        let key = corrupt.address().to_string();
+        tamper(&cas.lmdb, cas.lmdb.store, key.as_bytes());
+        let removed = Arc::new(RwLock::new(Vec::new()));
+        let log = removed.clone();
+        cas.events()
+            .subscribe(move |event| log.write().unwrap().push(event.clone()))
+            .unwrap();
+
+        match cas.fetch(&corrupt.address()) {
+            Err(PersistenceError::Corruption(_)) => (),
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        assert_eq!(
+            vec![StorageEvent::Removed(corrupt.address())],
+            *removed.read().unwrap()
+        );
+        assert_eq!(Ok(None), cas.fetch(&corrupt.address()));
+        assert_eq!(Ok(Some(intact.clone())), cas.fetch(&intact.address()));
+
+        let quarantined = cas.quarantined().unwrap();
+        assert_eq!(
+            vec![key],
+            quarantined
+                .iter()
+                .map(|r| r.key.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(1), cas.purge_quarantine());
+        assert_eq!(Ok(vec![]), cas.quarantined());
+
+        // checksums survive a rewrite
+        cas.rewrite_all(
+            SerializationFormat::Cbor,
+            10,
+            RewriteProgress::default(),
+            |_| (),
+        )
+        .unwrap();
+        tamper(
+            &cas.lmdb,
+            cas.lmdb.store,
+            intact.address().to_string().as_bytes(),
+        );
+        assert!(cas.fetch(&intact.address()).is_err());
+    }
+
+    #[test]
+    fn lmdb_trash_and_restore() {
+        let (cas, _dir) = test_lmdb_cas();
+        let cas = cas.with_checksums();
+        let (kept, cleaned) = (
+            Content::from_json("\"kept\""),
+            Content::from_json("\"cleaned\""),
+        );
+        cas.add(&kept).unwrap();
+        cas.add(&cleaned).unwrap();
+        let events = Arc::new(RwLock::new(Vec::new()));
+        let log = events.clone();
+        cas.events()
+            .subscribe(move |event| log.write().unwrap().push(event.clone()))
+            .unwrap();
+
+        assert_eq!(Ok(true), cas.trash(&cleaned.address()));
+        assert_eq!(Ok(false), cas.trash(&cleaned.address()));
+        assert_eq!(Ok(false), cas.contains(&cleaned.address()));
+        assert_eq!(Ok(None), cas.fetch(&cleaned.address()));
+        assert_eq!(
+            vec![cleaned.address()],
+            cas.trashed()
+                .unwrap()
+                .into_iter()
+                .map(|trashed| trashed.address)
+                .collect::<Vec<_>>()
+        );
+
+        // restored content is as it was stored, checksum included
+        assert_eq!(Ok(true), cas.restore(&cleaned.address()));
+        assert_eq!(Ok(false), cas.restore(&kept.address()));
+        assert_eq!(Ok(Some(cleaned.clone())), cas.fetch(&cleaned.address()));
+        tamper(
+            &cas.lmdb,
+            cas.lmdb.store,
+            cleaned.address().to_string().as_bytes(),
+        );
+        assert!(cas.fetch(&cleaned.address()).is_err());
+        assert_eq!(
+            vec![
+                StorageEvent::Removed(cleaned.address()),
+                StorageEvent::Added(cleaned.address()),
+                StorageEvent::Removed(cleaned.address()),
+            ],
+            *events.read().unwrap()
+        );
+
+        // only what has been in the trash long enough is dropped
+        cas.trash(&kept.address()).unwrap();
+        assert_eq!(Ok(0), cas.empty_trash(Duration::from_secs(3600)));
+        assert_eq!(Ok(1), cas.empty_trash(Duration::from_secs(0)));
+        assert_eq!(Ok(vec![]), cas.trashed());
+        assert_eq!(Ok(false), cas.restore(&kept.address()));
+    }
+
+    #[test]
+    fn lmdb_contains_with_bloom_filter() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let existing = Content::from_json("\"existing\"");
+        let cas = LmdbStorage::new(dir.path(), None, None);
+        cas.add(&existing).unwrap();
+
+        // reopening fills the filter from what is already stored
+        let cas = LmdbStorage::new(dir.path(), None, None)
+            .with_bloom_filter(100, 0.01)
+            .unwrap();
+        assert_eq!(Ok(true), cas.contains(&existing.address()));
+
+        let added = Content::from_json("\"added\"");
+        let missing = Content::from_json("\"missing\"");
+        assert_eq!(Ok(false), cas.contains(&added.address()));
+        cas.add(&added).unwrap();
+        assert_eq!(Ok(true), cas.contains(&added.address()));
+        assert_eq!(Ok(false), cas.contains(&missing.address()));
+    }
+
+    #[test]
+    fn lmdb_bloom_filter_remembers_content_added_through_other_stores() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let filtered = LmdbStorage::new(dir.path(), None, None)
+            .with_bloom_filter(100, 0.01)
+            .unwrap();
+        let other = LmdbStorage::new(dir.path(), None, None);
+
+        let added = Content::from_json("\"added elsewhere\"");
+        other.add(&added).unwrap();
+        assert_eq!(Ok(true), filtered.contains(&added.address()));
+        let queued = Content::from_json("\"queued elsewhere\"");
+        other.add_async(&queued).wait().unwrap();
+        assert_eq!(Ok(true), filtered.contains(&queued.address()));
+    }
+
+    #[test]
+    fn lmdb_stream_content_in_chunks() {
+        let content =
+            Content::from_json(&format!("{{\"payload\":\"{}\"}}", "streamed".repeat(1000)));
+        for (format, checksums) in &[
+            (SerializationFormat::Json, false),
+            (SerializationFormat::Json, true),
+            (SerializationFormat::MessagePack, true),
+        ] {
+            let (cas, _dir) = test_lmdb_cas();
+            let mut cas = cas.with_serialization_format(*format);
+            if *checksums {
+                cas = cas.with_checksums();
+            }
+            cas.add(&content).unwrap();
+
+            let chunks: Vec<_> = cas
+                .stream_content(&content.address(), 1000)
+                .collect::<PersistenceResult<_>>()
+                .unwrap();
+            assert_eq!(9, chunks.len());
+            assert!(chunks.iter().all(|chunk| chunk.len() <= 1000));
+            assert_eq!(
+                Ok(content.clone()),
+                reassemble(chunks.into_iter().map(Ok), Some(content.address()))
+            );
+        }
+
+        let (cas, _dir) = test_lmdb_cas();
+        let mut stream = cas.stream_content(&content.address(), 1000);
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn lmdb_content_beyond_the_limit_is_not_added() {
+        let (cas, _dir) = test_lmdb_cas();
+        let cas = cas.with_limits(Limits {
+            max_content_bytes: Some(16),
+            ..Limits::default()
+        });
+        let big = Content::from_json("\"far more than sixteen bytes\"");
+        match cas.add(&big) {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected the content to be too big, got {:?}", other),
+        }
+        assert!(cas.add_async(&big).wait().is_err());
+        assert_eq!(Ok(false), cas.contains(&big.address()));
+        assert_eq!(Ok(()), cas.add(&Content::from_json("\"small\"")));
+    }
+
+    #[test]
+    fn lmdb_cas_from_config() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let config = LmdbConfig {
+            serialization_format: SerializationFormat::MessagePack,
+            checksums: true,
+            bloom_filter: Some(BloomFilterConfig {
+                expected_items: 100,
+                false_positive_rate: 0.01,
+            }),
+            ..LmdbConfig::new(dir.path())
+        };
+        let cas = LmdbStorage::from_config(&config).unwrap();
+        let content = Content::from_json("{\"configured\":true}");
+        cas.add(&content).unwrap();
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        assert!(cas.lmdb.keys.read().unwrap().is_some());
+
+        let key = content.address().to_string();
+        let stored = cas
+            .lmdb
+            .read(|reader| match cas.lmdb.store.get(reader, &key)? {
+                Some(Value::Blob(bytes)) => Ok(SerializationFormat::of(checksum::unseal(bytes)?)),
+                _ => Ok(None),
+            })
+            .unwrap();
+        assert_eq!(Some(SerializationFormat::MessagePack), stored);
+    }
+
+    #[test]
+    fn lmdb_add_fails_cleanly_once_the_map_cannot_grow() {
+        let initial_map_bytes = 1024 * 1024;
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let cas = LmdbStorage::new(dir.path(), Some(initial_map_bytes), None)
+            .with_max_map_bytes(Some(2 * initial_map_bytes));
+        let resized = Arc::new(RwLock::new(Vec::new()));
+        let log = resized.clone();
+        cas.events()
+            .subscribe(move |event| log.write().unwrap().push(event.clone()))
+            .unwrap();
+
+        let too_big = Content::from_json(&format!("\"{}\"", "x".repeat(3 * initial_map_bytes)));
+        match cas.add(&too_big) {
+            Err(PersistenceError::AddressSpaceExhausted(_)) => (),
+            other => panic!("expected the map to be exhausted, got {:?}", other),
+        }
+        assert_eq!(2 * initial_map_bytes, cas.lmdb.info().unwrap().map_size());
+        assert_eq!(
+            vec![StorageEvent::Resized(2 * initial_map_bytes)],
+            *resized.read().unwrap()
+        );
+
+        // what still fits is still written
+        let small = Content::from_json("\"small\"");
+        cas.add(&small).unwrap();
+        assert_eq!(Ok(Some(small.clone())), cas.fetch(&small.address()));
+    }
+
+    #[test]
+    fn lmdb_fetch_from_more_threads_than_reader_slots() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let cas = LmdbStorage::new(dir.path(), None, Some(2));
+        let content = Content::from_json("some bytes");
+        cas.add(&content).expect("could not add to CAS");
+
+        let handles: Vec<_> = (0..32)
+            .map(|_| {
+                let cas = cas.clone();
+                let address = content.address();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        assert!(cas.fetch(&address).expect("fetch failed").is_some());
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("reader thread panicked");
+        }
+
+        assert_eq!(
+            cas.get_storage_report().unwrap().reader_slots,
+            Some(ReaderSlotReport {
+                in_use: 0,
+                max_readers: 2,
+            }),
+        );
     }
 }
@@ -0,0 +1,214 @@
+//! Per entry checksums and the quarantine for entries that fail them.
+//!
+//! A store opened `with_checksums` seals every value it writes: a tag byte, the xxhash64 of the
+//! encoded value and the encoded value itself. Sealed values are verified on every read, whether
+//! or not the reading store writes checksums itself. An entry that doesn't match its checksum
+//! is moved into the `QUARANTINE` store of its environment and the read fails with
+//! `PersistenceError::Corruption`.
+
+use crate::common::{LmdbInstance, QUARANTINE};
+use holochain_persistence_api::error::{PersistenceError, PersistenceResult};
+use rkv::{error::StoreError, SingleStore, Value};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    hash::Hasher,
+    io,
+};
+use twox_hash::XxHash64;
+
+/// outside the range of `SerializationFormat` tags
+const SEALED_TAG: u8 = 0xC5;
+const CHECKSUM_BYTES: usize = 8;
+
+/// An entry moved out of a store because it failed its checksum.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuarantinedRecord {
+    /// the key it was stored under
+    pub key: String,
+    /// the value as it was stored
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ChecksumMismatch {
+    key: Vec<u8>,
+}
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "checksum mismatch")
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(bytes);
+    hasher.finish().to_le_bytes()
+}
+
+/// The encoded value with its checksum in front.
+pub(crate) fn seal(encoded: Vec<u8>) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(encoded.len() + CHECKSUM_BYTES + 1);
+    sealed.push(SEALED_TAG);
+    sealed.extend_from_slice(&checksum(&encoded));
+    sealed.extend(encoded);
+    sealed
+}
+
+/// The encoded value inside a sealed one once it's verified, `bytes` unchanged if they aren't
+/// sealed.
+pub(crate) fn unseal(bytes: &[u8]) -> Result<&[u8], StoreError> {
+    if bytes.first() != Some(&SEALED_TAG) {
+        return Ok(bytes);
+    }
+    match bytes.get(1..=CHECKSUM_BYTES) {
+        Some(sum) if bytes.len() > CHECKSUM_BYTES + 1 => {
+            let encoded = &bytes[CHECKSUM_BYTES + 1..];
+            if sum == checksum(encoded) {
+                return Ok(encoded);
+            }
+        }
+        _ => (),
+    }
+    Err(StoreError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        ChecksumMismatch { key: Vec::new() },
+    )))
+}
+
+fn mismatch(e: &StoreError) -> Option<&ChecksumMismatch> {
+    match e {
+        StoreError::IoError(e) => e.get_ref()?.downcast_ref(),
+        _ => None,
+    }
+}
+
+/// Notes the key a checksum mismatch was found at, other errors are passed through.
+pub(crate) fn at_key(mut e: StoreError, key: &[u8]) -> StoreError {
+    if let StoreError::IoError(io_error) = &mut e {
+        if let Some(mismatch) = io_error
+            .get_mut()
+            .and_then(|inner| inner.downcast_mut::<ChecksumMismatch>())
+        {
+            mismatch.key = key.to_vec();
+        }
+    }
+    e
+}
+
+/// The key of the entry if the error is a checksum mismatch.
+pub(crate) fn mismatched_key(e: &StoreError) -> Option<Vec<u8>> {
+    mismatch(e).map(|mismatch| mismatch.key.clone())
+}
+
+/// Moves the entry at `key` out of `store` into the quarantine.
+pub(crate) fn quarantine(
+    lmdb: &LmdbInstance,
+    store: SingleStore,
+    key: &[u8],
+) -> Result<(), StoreError> {
+    let quarantine = lmdb.open_store(QUARANTINE);
+    let env = lmdb.manager.read().unwrap();
+    let mut writer = env.write()?;
+    let bytes = match store.get(&writer, key)? {
+        Some(Value::Blob(bytes)) => bytes.to_vec(),
+        Some(Value::Json(json)) => json.as_bytes().to_vec(),
+        Some(_) | None => return Ok(()),
+    };
+    quarantine.put(&mut writer, key, &Value::Blob(&bytes))?;
+    store.delete(&mut writer, key)?;
+    writer.commit()
+}
+
+/// Turns a checksum mismatch into `PersistenceError::Corruption`, quarantining the entry, and
+/// any other error into a generic one described by `context`.
+pub(crate) fn persistence_error(
+    lmdb: &LmdbInstance,
+    store: SingleStore,
+    e: StoreError,
+    context: &str,
+) -> PersistenceError {
+    let key = match mismatched_key(&e) {
+        Some(key) => key,
+        None => return PersistenceError::from(format!("{}: {}", context, e)),
+    };
+    let shown = String::from_utf8_lossy(&key);
+    match quarantine(lmdb, store, &key) {
+        Ok(()) => PersistenceError::Corruption(format!(
+            "{}: {} failed its checksum and was quarantined",
+            context, shown
+        )),
+        Err(e) => PersistenceError::Corruption(format!(
+            "{}: {} failed its checksum and could not be quarantined: {}",
+            context, shown, e
+        )),
+    }
+}
+
+/// Everything in the quarantine of this environment.
+pub(crate) fn quarantined(lmdb: &LmdbInstance) -> PersistenceResult<Vec<QuarantinedRecord>> {
+    let quarantine = lmdb.open_store(QUARANTINE);
+    lmdb.read(|reader| {
+        let mut records = Vec::new();
+        for entry in quarantine.iter_start(reader)? {
+            if let (key, Some(Value::Blob(bytes))) = entry? {
+                records.push(QuarantinedRecord {
+                    key: String::from_utf8_lossy(key).to_string(),
+                    bytes: bytes.to_vec(),
+                });
+            }
+        }
+        Ok(records)
+    })
+    .map_err(|e| PersistenceError::from(format!("LMDB quarantine error: {}", e)))
+}
+
+/// Empties the quarantine of this environment, returning how many records it held.
+pub(crate) fn purge_quarantine(lmdb: &LmdbInstance) -> PersistenceResult<usize> {
+    let quarantine = lmdb.open_store(QUARANTINE);
+    let purge = || -> Result<usize, StoreError> {
+        let env = lmdb.manager.read().unwrap();
+        let mut writer = env.write()?;
+        let purged = quarantine.iter_start(&writer)?.count();
+        quarantine.clear(&mut writer)?;
+        writer.commit()?;
+        Ok(purged)
+    };
+    purge().map_err(|e| PersistenceError::from(format!("LMDB quarantine error: {}", e)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// flips a bit of the sealed value stored at `key`
+    pub(crate) fn tamper(lmdb: &LmdbInstance, store: SingleStore, key: &[u8]) {
+        let mut bytes = lmdb
+            .read(|reader| match store.get(reader, key)? {
+                Some(Value::Blob(bytes)) => Ok(bytes.to_vec()),
+                other => panic!("expected a sealed value, got {:?}", other),
+            })
+            .unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        lmdb.put_many(&[(store, key, Value::Blob(&bytes))]).unwrap();
+    }
+
+    #[test]
+    fn sealed_values_unseal_until_tampered_with() {
+        let sealed = seal(b"\x00\"some json\"".to_vec());
+        assert_eq!(
+            Ok(&b"\x00\"some json\""[..]),
+            unseal(&sealed).map_err(|_| ())
+        );
+        assert_eq!(Ok(&b"\x00plain"[..]), unseal(b"\x00plain").map_err(|_| ()));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let e = at_key(unseal(&tampered).unwrap_err(), b"key");
+        assert_eq!(Some(b"key".to_vec()), mismatched_key(&e));
+        assert!(unseal(&sealed[..4]).is_err());
+    }
+}
@@ -1,46 +1,392 @@
+#[cfg(test)]
+use crate::crash::{CrashPoint, CrashSwitch};
+use crate::{
+    checksum,
+    writer::{WriteQueue, WriteReceipt},
+};
 use holochain_logging::prelude::*;
+use holochain_persistence_api::{
+    cas::bloom::BloomFilter,
+    error::{PersistenceError, PersistenceResult},
+    events::{EventBus, StorageEvent},
+    format::SerializationFormat,
+    retry::RetryPolicy,
+};
+use lazy_static::lazy_static;
 use lmdb::Error as LmdbError;
 use rkv::{
-    DatabaseFlags, EnvironmentFlags, Manager, Rkv, SingleStore, StoreError, StoreOptions, Value,
+    DatabaseFlags, EnvironmentFlags, Reader, Rkv, SingleStore, StoreError, StoreOptions, Value,
+    Writer,
 };
 use std::{
-    path::Path,
-    sync::{Arc, RwLock},
+    borrow::Cow,
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, Weak,
+    },
+    time::Duration,
 };
 
 const DEFAULT_INITIAL_MAP_BYTES: usize = 100 * 1024 * 1024;
+/// the smallest map an environment is opened with when there isn't room for the one asked for
+const MIN_MAP_BYTES: usize = 1024 * 1024;
+/// maps only grow up to this size, on 32 bit targets doubling would soon ask for more address
+/// space than there is
+#[cfg(target_pointer_width = "32")]
+const DEFAULT_MAX_MAP_BYTES: usize = 1024 * 1024 * 1024;
+#[cfg(not(target_pointer_width = "32"))]
+const DEFAULT_MAX_MAP_BYTES: usize = usize::MAX;
+/// what mmap fails with when there is no address space left
+const ENOMEM: i32 = 12;
+
+/// attempts at a write that doesn't fit, growing the map in between, before giving up: doubling
+/// from the smallest map reaches the end of a 64 bit address space well before, and a map that
+/// can't grow ends the attempts anyway
+const MAX_WRITE_ATTEMPTS: usize = 64;
+
+/// the file of an environment holding its data, next to the lock file
+const DATA_FILE: &str = "data.mdb";
+
+/// LMDB's own default for the size of the reader lock table
+pub const DEFAULT_MAX_READERS: u32 = 126;
+/// store of the identity of the store an environment holds
+pub(crate) const META: &str = "META";
+/// entries that failed their checksum, see `checksum`
+pub(crate) const QUARANTINE: &str = "QUARANTINE";
+/// content moved out of the CAS, see `trash`
+pub(crate) const TRASH: &str = "TRASH";
+/// term frequencies of the CAS search index, see `search`
+pub(crate) const SEARCH: &str = "SEARCH";
+/// counters handed out by `sequence::LmdbSequences`
+pub(crate) const SEQUENCES: &str = "SEQUENCES";
+/// secondary index keyed by value, stored in the same environment as the EAVs
+pub(crate) const EAV_VALUE_INDEX: &str = "EAV_VALUES";
+/// secondary index keyed by attribute and entity, for `entities_with_attribute`
+pub(crate) const EAV_ATTRIBUTE_INDEX: &str = "EAV_ATTRIBUTES";
+/// statistics of the EAVs, see `eav::stats`
+pub(crate) const EAV_STATS: &str = "EAV_STATS";
+
+/// Every store opened next to the main store of an environment. The CAS environment holds the
+/// meta store, the quarantine, the trash, the search index and the sequences, the EAV
+/// environment the meta store, the quarantine, the two indexes and the statistics. A store
+/// `open_store` is asked for has to be in here so `MAX_DBS` leaves room for it.
+const NAMED_STORES: [&str; 8] = [
+    META,
+    QUARANTINE,
+    TRASH,
+    SEARCH,
+    SEQUENCES,
+    EAV_VALUE_INDEX,
+    EAV_ATTRIBUTE_INDEX,
+    EAV_STATS,
+];
+/// slots left free for stores added later, which environments created now can then still open
+const SPARE_DBS: usize = 4;
+/// room for the main store, every named store and the spare slots
+const MAX_DBS: u32 = (1 + NAMED_STORES.len() + SPARE_DBS) as u32;
+
+lazy_static! {
+    // LMDB allows an environment to be opened only once per process, so instances on the same
+    // path share it. Unlike rkv's `Manager` this doesn't keep it open once they are all dropped.
+    static ref ENVIRONMENTS: Mutex<HashMap<PathBuf, SharedEnvironment>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A bloom filter of the keys in the main store of an environment, None until a store asks for
+/// one. It belongs to the environment so writes through any instance on it are remembered.
+pub(crate) type KeyFilter = Arc<RwLock<Option<BloomFilter>>>;
+
+/// An environment open in this process, the reader slots of the instances on it and its key
+/// filter. Reader slots belong to an environment, not to a store, so every instance on it draws
+/// from the same pool. All are held weakly, they go with the last instance.
+struct SharedEnvironment {
+    env: Weak<RwLock<Rkv>>,
+    max_readers: usize,
+    readers_in_use: Weak<(Mutex<usize>, Condvar)>,
+    keys: Weak<RwLock<Option<BloomFilter>>>,
+}
+
+/// The environment open at `path`, its reader pool and its key filter, opening it with `open`
+/// if there is none.
+fn shared_environment<F>(
+    path: &Path,
+    open: F,
+) -> Result<(Arc<RwLock<Rkv>>, ReaderPool, KeyFilter), StoreError>
+where
+    F: FnOnce(&Path) -> Result<Rkv, StoreError>,
+{
+    let path = path.canonicalize()?;
+    let mut environments = ENVIRONMENTS.lock().unwrap();
+    if let Some(shared) = environments.get_mut(&path) {
+        if let Some(env) = shared.env.upgrade() {
+            let readers = match shared.readers_in_use.upgrade() {
+                Some(in_use) => ReaderPool {
+                    max_readers: shared.max_readers,
+                    in_use,
+                },
+                None => ReaderPool::new(shared.max_readers),
+            };
+            let keys = shared.keys.upgrade().unwrap_or_default();
+            shared.readers_in_use = Arc::downgrade(&readers.in_use);
+            shared.keys = Arc::downgrade(&keys);
+            return Ok((env, readers, keys));
+        }
+    }
+    environments.retain(|_, shared| shared.env.strong_count() > 0);
+    let env = open(&path)?;
+    let max_readers = env
+        .info()
+        .map(|info| info.max_readers())
+        .unwrap_or(DEFAULT_MAX_READERS) as usize;
+    let env = Arc::new(RwLock::new(env));
+    let readers = ReaderPool::new(max_readers);
+    let keys = KeyFilter::default();
+    environments.insert(
+        path,
+        SharedEnvironment {
+            env: Arc::downgrade(&env),
+            max_readers,
+            readers_in_use: Arc::downgrade(&readers.in_use),
+            keys: Arc::downgrade(&keys),
+        },
+    );
+    Ok((env, readers, keys))
+}
+
+/// true if the environment at `path` is open in this process
+#[cfg(test)]
+pub(crate) fn environment_is_open(path: &Path) -> bool {
+    path.canonicalize()
+        .ok()
+        .and_then(|path| {
+            ENVIRONMENTS
+                .lock()
+                .unwrap()
+                .get(&path)
+                .map(|shared| shared.env.strong_count() > 0)
+        })
+        .unwrap_or(false)
+}
+
+/// Bounds the number of read transactions open at once on an environment.
+/// Readers beyond the number of slots LMDB was configured with wait for a slot to be released
+/// rather than failing with `ReadersFull`.
+#[derive(Clone)]
+pub(crate) struct ReaderPool {
+    max_readers: usize,
+    in_use: Arc<(Mutex<usize>, Condvar)>,
+}
+
+// the counter has to live behind a mutex to be waited on with the condvar
+#[allow(clippy::mutex_atomic)]
+impl ReaderPool {
+    fn new(max_readers: usize) -> ReaderPool {
+        ReaderPool {
+            max_readers,
+            in_use: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// blocks until a reader slot is free and holds it until the returned guard is dropped
+    fn acquire(&self) -> ReaderSlot {
+        let (lock, available) = &*self.in_use;
+        let mut in_use = lock.lock().unwrap();
+        while *in_use >= self.max_readers {
+            in_use = available.wait(in_use).unwrap();
+        }
+        *in_use += 1;
+        ReaderSlot {
+            in_use: self.in_use.clone(),
+        }
+    }
+
+    pub fn in_use(&self) -> usize {
+        *self.in_use.0.lock().unwrap()
+    }
+
+    pub fn max_readers(&self) -> usize {
+        self.max_readers
+    }
+}
+
+struct ReaderSlot {
+    in_use: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Drop for ReaderSlot {
+    fn drop(&mut self) {
+        let (lock, available) = &*self.in_use;
+        *lock.lock().unwrap() -= 1;
+        available.notify_one();
+    }
+}
+
+/// JSON the way it is written to a store: as JSON or tagged and encoded in a binary format.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Encoded {
+    Json(String),
+    Blob(Vec<u8>),
+}
+
+impl Encoded {
+    pub fn new(format: SerializationFormat, json: String) -> PersistenceResult<Encoded> {
+        match format {
+            SerializationFormat::Json => Ok(Encoded::Json(json)),
+            _ => Ok(Encoded::Blob(format.encode(&json)?)),
+        }
+    }
+
+    /// The same value with a checksum, see `checksum::seal`.
+    pub fn sealed(self) -> PersistenceResult<Encoded> {
+        let bytes = match self {
+            Encoded::Json(json) => SerializationFormat::Json.encode(&json)?,
+            Encoded::Blob(bytes) => bytes,
+        };
+        Ok(Encoded::Blob(checksum::seal(bytes)))
+    }
+
+    pub fn value(&self) -> Value {
+        match self {
+            Encoded::Json(json) => Value::Json(json),
+            Encoded::Blob(bytes) => Value::Blob(bytes),
+        }
+    }
+}
+
+/// The JSON of a stored value, whichever format it was written in and verified if it has a
+/// checksum.
+pub(crate) fn stored_json(value: Option<Value>) -> Result<Cow<str>, StoreError> {
+    match value {
+        Some(Value::Json(json)) => Ok(Cow::Borrowed(json)),
+        Some(Value::Blob(bytes)) => SerializationFormat::decode(checksum::unseal(bytes)?)
+            .map(Cow::Owned)
+            .map_err(|e| StoreError::IoError(io::Error::new(io::ErrorKind::InvalidData, e))),
+        Some(_) => Err(StoreError::DataError(rkv::DataError::UnexpectedType {
+            actual: rkv::value::Type::Json,
+            expected: rkv::value::Type::Json,
+        })),
+        None => Err(StoreError::DataError(rkv::DataError::Empty)),
+    }
+}
 
 #[derive(Clone)]
 pub(crate) struct LmdbInstance {
     pub store: SingleStore,
     pub manager: Arc<RwLock<Rkv>>,
+    /// directory of the environment
+    path: PathBuf,
+    readers: ReaderPool,
+    /// shared with every instance on the environment
+    pub keys: KeyFilter,
+    writer: Option<WriteQueue>,
+    // shared with the clone the write queue writes through
+    max_map_bytes: Arc<AtomicUsize>,
+    retry: Arc<RwLock<RetryPolicy>>,
+    pub events: EventBus,
+    #[cfg(test)]
+    pub crash: CrashSwitch,
+}
+
+/// true if a write failed for lack of map space
+fn map_full(e: &StoreError) -> bool {
+    match e {
+        StoreError::LmdbError(LmdbError::MapFull) => true,
+        _ => false,
+    }
+}
+
+/// The retries of a write unless set otherwise: a write that doesn't fit is tried again once the
+/// map has grown, any other error is returned straight away.
+fn map_full_retries() -> RetryPolicy {
+    RetryPolicy::new(MAX_WRITE_ATTEMPTS).with_retry_on(|e| match e {
+        PersistenceError::AddressSpaceExhausted(_) => true,
+        _ => false,
+    })
+}
+
+/// What a retry policy is asked about a failed write.
+fn retry_error(e: &StoreError) -> PersistenceError {
+    if let Some(aborted) = aborted(e) {
+        return aborted.clone();
+    }
+    match e {
+        e if out_of_space(e) => PersistenceError::AddressSpaceExhausted(e.to_string()),
+        StoreError::IoError(e) => PersistenceError::IoError(e.to_string()),
+        e => PersistenceError::from(e.to_string()),
+    }
+}
+
+/// true if a write failed for lack of map space or address space
+fn out_of_space(e: &StoreError) -> bool {
+    match e {
+        StoreError::LmdbError(LmdbError::MapFull) => true,
+        StoreError::LmdbError(LmdbError::Other(code)) => *code == ENOMEM,
+        _ => false,
+    }
+}
+
+/// Fails the write transaction `e` was raised in while staging a write, `write_error` hands it
+/// back as it was.
+pub(crate) fn abort_write(e: PersistenceError) -> StoreError {
+    StoreError::IoError(io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// The error a write was failed with by `abort_write`, if it was.
+fn aborted(e: &StoreError) -> Option<&PersistenceError> {
+    match e {
+        StoreError::IoError(io_error) => io_error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<PersistenceError>()),
+        _ => None,
+    }
+}
+
+/// Describes a failed write, as `PersistenceError::AddressSpaceExhausted` if the map is as big
+/// as it can get and as the error it was aborted with if it was staged by `abort_write`.
+pub(crate) fn write_error(e: StoreError, context: &str) -> PersistenceError {
+    if let Some(aborted) = aborted(&e) {
+        aborted.clone()
+    } else if out_of_space(&e) {
+        PersistenceError::AddressSpaceExhausted(format!(
+            "{}: the memory map can't grow any further: {}",
+            context, e
+        ))
+    } else {
+        PersistenceError::from(format!("{}: {}", context, e))
+    }
 }
 
 impl LmdbInstance {
+    /// `max_readers` only takes effect for the first instance opened on an environment,
+    /// the environment is shared by everything opened on the same path afterwards.
     pub fn new<P: AsRef<Path> + Clone>(
         db_name: &str,
         path: P,
         initial_map_bytes: Option<usize>,
+        max_readers: Option<u32>,
     ) -> LmdbInstance {
         let db_path = path.as_ref().join(db_name).with_extension("db");
         std::fs::create_dir_all(db_path.clone()).expect("Could not create file path for store");
 
-        let manager = Manager::singleton()
-            .write()
-            .unwrap()
-            .get_or_create(db_path.as_path(), |path: &Path| {
-                let mut env_builder = Rkv::environment_builder();
-                env_builder
-                    // max size of memory map, can be changed later
-                    .set_map_size(initial_map_bytes.unwrap_or(DEFAULT_INITIAL_MAP_BYTES))
-                    // max number of DBs in this environment
-                    .set_max_dbs(1)
-                    // Thes flags make writes waaaaay faster by async writing to disk rather than blocking
-                    // There is some loss of data integrity guarantees that comes with this
-                    .set_flags(EnvironmentFlags::WRITE_MAP | EnvironmentFlags::MAP_ASYNC);
-                Rkv::from_env(path, env_builder)
-            })
-            .expect("Could not create the environment");
+        let (manager, readers, keys) = shared_environment(db_path.as_path(), |path: &Path| {
+            let mut map_bytes = initial_map_bytes.unwrap_or(DEFAULT_INITIAL_MAP_BYTES);
+            loop {
+                match Self::open_environment(path, map_bytes, max_readers) {
+                    Err(ref e) if out_of_space(e) && map_bytes > MIN_MAP_BYTES => {
+                        warn!(
+                            "Could not map {} bytes, halving and trying again",
+                            map_bytes
+                        );
+                        map_bytes /= 2;
+                    }
+                    r => return r,
+                }
+            }
+        })
+        .expect("Could not create the environment");
 
         let env = manager
             .read()
@@ -56,29 +402,270 @@ impl LmdbInstance {
             .expect("Could not create store");
 
         LmdbInstance {
-            store: store,
+            store,
             manager: manager.clone(),
+            path: db_path,
+            readers,
+            keys,
+            writer: None,
+            max_map_bytes: Arc::new(AtomicUsize::new(DEFAULT_MAX_MAP_BYTES)),
+            retry: Arc::new(RwLock::new(map_full_retries())),
+            events: EventBus::new(),
+            #[cfg(test)]
+            crash: CrashSwitch::default(),
+        }
+    }
+
+    fn open_environment(
+        path: &Path,
+        map_bytes: usize,
+        max_readers: Option<u32>,
+    ) -> Result<Rkv, StoreError> {
+        let mut env_builder = Rkv::environment_builder();
+        env_builder
+            // max size of memory map, can be changed later
+            .set_map_size(map_bytes)
+            // max number of DBs in this environment
+            .set_max_dbs(MAX_DBS)
+            // max number of read transactions open at the same time
+            .set_max_readers(max_readers.unwrap_or(DEFAULT_MAX_READERS))
+            // Thes flags make writes waaaaay faster by async writing to disk rather than blocking
+            // There is some loss of data integrity guarantees that comes with this
+            // NO_TLS ties reader slots to transactions instead of threads so a slot is
+            // released as soon as the read is done, which the reader pool relies on
+            .set_flags(
+                EnvironmentFlags::WRITE_MAP
+                    | EnvironmentFlags::MAP_ASYNC
+                    | EnvironmentFlags::NO_TLS,
+            );
+        Rkv::from_env(path, env_builder)
+    }
+
+    /// Stops the map from growing past `max_map_bytes`, writes that don't fit then fail with
+    /// `MapFull`. `None` keeps the default, 1GB on 32 bit targets and no cap otherwise.
+    pub fn with_max_map_bytes(self, max_map_bytes: Option<usize>) -> LmdbInstance {
+        self.max_map_bytes.store(
+            max_map_bytes.unwrap_or(DEFAULT_MAX_MAP_BYTES),
+            Ordering::Relaxed,
+        );
+        self
+    }
+
+    /// Retries writes as `policy` says. Writes that don't fit grow the map and fail with
+    /// `PersistenceError::AddressSpaceExhausted`, which `policy` has to retry for them to be
+    /// written into the grown map. Once the map can't grow they fail without being retried.
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> LmdbInstance {
+        *self.retry.write().unwrap() = policy;
+        self
+    }
+
+    /// Doubles the map, up to `max_map_bytes`, publishing `StorageEvent::Resized`.
+    fn grow_map(&self, env: &Rkv) -> Result<(), StoreError> {
+        let map_size = env.info()?.map_size();
+        let new_size = map_size
+            .saturating_mul(2)
+            .min(self.max_map_bytes.load(Ordering::Relaxed));
+        if new_size <= map_size {
+            return Err(StoreError::LmdbError(LmdbError::MapFull));
         }
+        trace!(
+            "Insufficient space in MMAP, growing it to {} bytes and trying again",
+            new_size
+        );
+        env.set_map_size(new_size)?;
+        #[cfg(test)]
+        self.crash.reached(CrashPoint::MidGrow);
+        self.events.publish(&StorageEvent::Resized(new_size));
+        Ok(())
     }
 
+    /// Hands writes made through `add_async` to a background writer thread with room for
+    /// `capacity` pending writes. With a `commit_window` the writer merges the writes queued
+    /// within that window into one transaction.
+    pub fn with_write_queue(
+        mut self,
+        capacity: usize,
+        commit_window: Option<Duration>,
+    ) -> LmdbInstance {
+        self.writer = Some(WriteQueue::spawn(self.clone(), capacity, commit_window));
+        self
+    }
+
+    /// Queues a write on the background writer if there is one, otherwise writes straight away.
+    pub fn add_async<K: AsRef<[u8]>>(&self, key: K, value: Encoded) -> WriteReceipt {
+        self.put_many_async(vec![(self.store, key.as_ref().to_vec(), value)])
+    }
+
+    /// Runs `f` inside a read transaction once a reader slot is available.
+    pub fn read<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&Reader) -> Result<T, StoreError>,
+    {
+        let _slot = self.readers.acquire();
+        let env = self.manager.read().unwrap();
+        let reader = env.read()?;
+        f(&reader)
+    }
+
+    /// number of reader slots in use and the total number of reader slots on this environment
+    pub fn reader_slots(&self) -> (usize, usize) {
+        (self.readers.in_use(), self.readers.max_readers())
+    }
+
+    // the crash hook between staging and returning only exists in tests
+    #[allow(clippy::let_and_return)]
     pub fn add<K: AsRef<[u8]> + Clone>(&self, key: K, value: &Value) -> Result<(), StoreError> {
+        self.commit_with_retries(|writer| {
+            let staged = self.store.put(writer, key.clone(), value);
+            #[cfg(test)]
+            self.crash.reached(CrashPoint::AfterStagingWrite);
+            staged
+        })
+    }
+
+    /// Opens (creating it if needed) another named store in the same environment, so that it
+    /// can be written in the same transactions as the main store.
+    pub fn open_store(&self, name: &str) -> SingleStore {
+        debug_assert!(
+            NAMED_STORES.contains(&name),
+            "{} is missing from NAMED_STORES",
+            name
+        );
+        self.manager
+            .read()
+            .expect("Could not get a read lock on the manager")
+            .open_single(
+                name,
+                StoreOptions {
+                    create: true,
+                    flags: DatabaseFlags::empty(),
+                },
+            )
+            .expect("Could not create store")
+    }
+
+    /// Runs `f` in a write transaction and commits it, running it again in a new one if the
+    /// map had to grow.
+    #[allow(clippy::let_and_return)]
+    pub fn write<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: Fn(&mut Writer) -> Result<T, StoreError>,
+    {
+        self.commit_with_retries(|writer| {
+            let staged = f(writer);
+            #[cfg(test)]
+            self.crash.reached(CrashPoint::AfterStagingWrite);
+            staged
+        })
+    }
+
+    /// Stages a write with `stage` and commits it, staging it again in a new transaction as
+    /// long as the retry policy allows. A write that doesn't fit grows the map before it is
+    /// retried, and fails straight away if the map can't grow any further.
+    fn commit_with_retries<T, F>(&self, stage: F) -> Result<T, StoreError>
+    where
+        F: Fn(&mut Writer) -> Result<T, StoreError>,
+    {
         let env = self.manager.read().unwrap();
-        let mut writer = env.write()?;
+        let policy = self.retry.read().unwrap().for_errors(retry_error);
+        // the inner error is final, the policy doesn't get to retry it
+        policy
+            .run(|_| {
+                let mut writer = env.write()?;
+                let committed = stage(&mut writer).and_then(|result| {
+                    self.commit(writer)?;
+                    Ok(result)
+                });
+                match committed {
+                    Ok(result) => Ok(Ok(result)),
+                    Err(e) if map_full(&e) => match self.grow_map(&env) {
+                        Ok(()) => Err(e),
+                        Err(cant_grow) => Ok(Err(cant_grow)),
+                    },
+                    Err(e) if out_of_space(&e) => Ok(Err(e)),
+                    Err(e) => Err(e),
+                }
+            })
+            .and_then(|committed| committed)
+    }
 
-        match self
-            .store
-            .put(&mut writer, key.clone(), value)
-            .and_then(|_| writer.commit())
-        {
-            Err(StoreError::LmdbError(LmdbError::MapFull)) => {
-                trace!("Insufficient space in MMAP, doubling and trying again");
-                let map_size = env.info()?.map_size();
-                env.set_map_size(map_size * 2)?;
-                self.add(key, value)
+    fn commit(&self, writer: Writer) -> Result<(), StoreError> {
+        #[cfg(test)]
+        self.crash.reached(CrashPoint::BeforeCommit);
+        writer.commit()
+    }
+
+    /// Writes all entries, each into its own store, in a single transaction.
+    pub fn put_many<K: AsRef<[u8]>>(
+        &self,
+        entries: &[(SingleStore, K, Value)],
+    ) -> Result<(), StoreError> {
+        self.commit_with_retries(|writer| {
+            for (store, key, value) in entries {
+                let staged = store.put(writer, key, value);
+                #[cfg(test)]
+                self.crash.reached(CrashPoint::AfterStagingWrite);
+                staged?;
             }
-            r => r, // preserve any other errors
-        }?;
+            Ok(())
+        })
+    }
+
+    /// Like `add_async` but for several entries that have to be committed together.
+    pub fn put_many_async(&self, entries: Vec<(SingleStore, Vec<u8>, Encoded)>) -> WriteReceipt {
+        self.write_async(
+            move |writer| {
+                for (store, key, value) in &entries {
+                    store.put(writer, key, &value.value())?;
+                }
+                Ok(())
+            },
+            |()| (),
+        )
+    }
 
+    /// Stages a write with `stage` on the background writer if there is one, otherwise writes
+    /// it straight away. Once it has been committed `committed` is called with what was staged,
+    /// the receipt resolves to what it returns.
+    pub fn write_async<T, U, S, C>(&self, stage: S, committed: C) -> WriteReceipt<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        S: Fn(&mut Writer) -> Result<T, StoreError> + Send + 'static,
+        C: FnOnce(T) -> U + Send + 'static,
+    {
+        match &self.writer {
+            Some(writer) => writer.push(stage, committed),
+            None => WriteReceipt::completed(
+                self.write(stage)
+                    .map(committed)
+                    .map_err(|e| write_error(e, "LMDB write error")),
+            ),
+        }
+    }
+
+    /// Copies the environment into `dest`, the path an instance of the same name is then opened
+    /// on. Reads and writes through this process wait for the copy, writes still queued on the
+    /// write queue aren't in it.
+    pub fn copy_to(&self, dest: &Path) -> PersistenceResult<()> {
+        copy_together(&[(self, dest)])
+    }
+
+    /// Copies the environment while `env`, its write lock, is held.
+    fn copy_locked(&self, env: &Rkv, dest: &Path) -> PersistenceResult<()> {
+        let copy_error = |e: &dyn std::fmt::Display| {
+            PersistenceError::from(format!("LMDB copy error: {}: {}", self.path.display(), e))
+        };
+        // with MAP_ASYNC committed writes may not have reached the file yet
+        env.sync(true).map_err(|e| copy_error(&e))?;
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| copy_error(&"the environment has no name"))?;
+        let dest = dest.join(name);
+        std::fs::create_dir_all(&dest).map_err(|e| copy_error(&e))?;
+        std::fs::copy(self.path.join(DATA_FILE), dest.join(DATA_FILE))
+            .map_err(|e| copy_error(&e))?;
         Ok(())
     }
 
@@ -88,12 +675,66 @@ impl LmdbInstance {
     }
 }
 
+/// Copies each instance into its destination, as `copy_to` does, holding off reads and writes
+/// on all of them until the last one is copied, so the copies are of the same moment.
+pub(crate) fn copy_together(copies: &[(&LmdbInstance, &Path)]) -> PersistenceResult<()> {
+    // instances on the same environment share its lock, it is only taken once
+    let mut locked: Vec<(&Arc<RwLock<Rkv>>, RwLockWriteGuard<Rkv>)> = Vec::new();
+    for (lmdb, _) in copies {
+        if !locked
+            .iter()
+            .any(|(manager, _)| Arc::ptr_eq(manager, &lmdb.manager))
+        {
+            locked.push((&lmdb.manager, lmdb.manager.write()?));
+        }
+    }
+    for (lmdb, dest) in copies {
+        let (_, env) = locked
+            .iter()
+            .find(|(manager, _)| Arc::ptr_eq(manager, &lmdb.manager))
+            .expect("every environment copied is locked");
+        lmdb.copy_locked(env, dest)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use holochain_persistence_api::cas::{content::AddressableContent, storage::CasBencher};
     use tempfile::tempdir;
 
+    #[test]
+    fn can_grow_map_on_batch_write() {
+        let inititial_mmap_size = 1024 * 1024;
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let lmdb = LmdbInstance::new(
+            "can_grow_map_on_batch_write",
+            dir.path(),
+            Some(inititial_mmap_size),
+            None,
+        );
+
+        // a single batch bigger than the whole map
+        let contents: Vec<(String, String)> = (0..20_000)
+            .map(|_| {
+                let content = CasBencher::random_addressable_content();
+                (content.address().to_string(), content.content().to_string())
+            })
+            .collect();
+        let entries: Vec<(SingleStore, &str, Value)> = contents
+            .iter()
+            .map(|(key, json)| (lmdb.store, key.as_str(), Value::Json(json)))
+            .collect();
+        lmdb.put_many(&entries).unwrap();
+
+        assert!(lmdb.info().unwrap().map_size() > inititial_mmap_size);
+        let stored = lmdb
+            .read(|reader| Ok(lmdb.store.iter_start(reader)?.count()))
+            .unwrap();
+        assert_eq!(stored, contents.len());
+    }
+
     #[test]
     fn can_grow_map_on_write() {
         // make a db with a 1MB MMAP. This seems to be the lowest you an go (probably OS dependent)
@@ -103,6 +744,7 @@ pub mod tests {
             "can_grow_map_on_write",
             dir.path(),
             Some(inititial_mmap_size),
+            None,
         );
 
         // put data in there until the mmap size changes
@@ -130,6 +772,64 @@ pub mod tests {
         assert_eq!(lmdb.info().unwrap().map_size(), inititial_mmap_size * 4,);
     }
 
+    #[test]
+    fn writes_that_cannot_fit_are_not_retried() {
+        let inititial_mmap_size = 1024 * 1024;
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let lmdb = LmdbInstance::new(
+            "writes_that_cannot_fit_are_not_retried",
+            dir.path(),
+            Some(inititial_mmap_size),
+            None,
+        )
+        .with_max_map_bytes(Some(2 * inititial_mmap_size));
+
+        let json = "x".repeat(3 * inititial_mmap_size);
+        let attempts = AtomicUsize::new(0);
+        let written = lmdb.write(|writer| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            lmdb.store.put(writer, "a", &Value::Json(&json))
+        });
+        assert!(map_full(&written.unwrap_err()));
+        // once in the initial map and once in the grown one
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn no_cap_keeps_the_default_cap() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let lmdb = LmdbInstance::new("no_cap_keeps_the_default_cap", dir.path(), None, None)
+            .with_max_map_bytes(Some(1))
+            .with_max_map_bytes(None);
+        assert_eq!(
+            DEFAULT_MAX_MAP_BYTES,
+            lmdb.max_map_bytes.load(Ordering::Relaxed)
+        );
+    }
+
+    #[test]
+    fn environments_have_room_for_every_named_store() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let lmdb = LmdbInstance::new("named_stores", dir.path(), None, None);
+        for name in NAMED_STORES.iter() {
+            lmdb.open_store(name);
+        }
+    }
+
+    #[test]
+    fn instances_on_one_environment_share_its_reader_slots() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let lmdb = LmdbInstance::new("readers", dir.path(), None, Some(4));
+        // another spelling of the same path
+        let other = LmdbInstance::new("readers", dir.path().join("."), None, None);
+        assert!(Arc::ptr_eq(&lmdb.readers.in_use, &other.readers.in_use));
+        assert_eq!(4, other.readers.max_readers());
+
+        let in_use = Arc::downgrade(&lmdb.readers.in_use);
+        drop((lmdb, other));
+        assert!(in_use.upgrade().is_none());
+    }
+
     #[test]
     fn can_write_entry_larger_than_map() {
         // can write a single entry that is much larger than the current mmap
@@ -139,6 +839,7 @@ pub mod tests {
             "can_grow_map_on_write",
             dir.path(),
             Some(inititial_mmap_size),
+            None,
         );
 
         let data: Vec<u8> = std::iter::repeat(0)
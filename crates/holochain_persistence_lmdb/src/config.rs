@@ -0,0 +1,185 @@
+//! Settings for opening the LMDB stores, so hosts can keep them in a config file.
+//!
+//! Every field but `path` can be left out and falls back to the same default as the
+//! corresponding constructor argument or builder method:
+//!
+//! ```json
+//! {
+//!     "path": "/var/lib/holochain/cas",
+//!     "initial_map_bytes": 1073741824,
+//!     "serialization_format": "MessagePack",
+//!     "checksums": true,
+//!     "write_queue": { "capacity": 256, "commit_window_ms": 20 }
+//! }
+//! ```
+
+#[cfg(feature = "search")]
+use crate::search::SearchConfig;
+use holochain_persistence_api::{
+    error::{PersistenceError, PersistenceResult},
+    format::SerializationFormat,
+    limits::Limits,
+    registry::StorageUri,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{path::PathBuf, time::Duration};
+
+/// See `LmdbStorage::with_write_queue`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WriteQueueConfig {
+    pub capacity: usize,
+    #[serde(default)]
+    pub commit_window_ms: Option<u64>,
+}
+
+impl WriteQueueConfig {
+    pub fn commit_window(&self) -> Option<Duration> {
+        self.commit_window_ms.map(Duration::from_millis)
+    }
+}
+
+/// See `LmdbStorage::with_bloom_filter`, only used by the CAS.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BloomFilterConfig {
+    pub expected_items: usize,
+    pub false_positive_rate: f64,
+}
+
+/// How to open an `LmdbStorage` or an `EavLmdbStorage`, see `from_config` on either.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LmdbConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub initial_map_bytes: Option<usize>,
+    /// leave out for the platform default, see `LmdbStorage::with_max_map_bytes`
+    #[serde(default)]
+    pub max_map_bytes: Option<usize>,
+    #[serde(default)]
+    pub max_readers: Option<u32>,
+    #[serde(default)]
+    pub serialization_format: SerializationFormat,
+    #[serde(default)]
+    pub checksums: bool,
+    #[serde(default)]
+    pub write_queue: Option<WriteQueueConfig>,
+    #[serde(default)]
+    pub bloom_filter: Option<BloomFilterConfig>,
+    /// see `with_limits` on either store
+    #[serde(default)]
+    pub limits: Limits,
+    /// see `LmdbStorage::with_search_index`, only used by the CAS
+    #[cfg(feature = "search")]
+    #[serde(default)]
+    pub search: Option<SearchConfig>,
+}
+
+impl LmdbConfig {
+    /// The defaults for a store at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> LmdbConfig {
+        LmdbConfig {
+            path: path.into(),
+            initial_map_bytes: None,
+            max_map_bytes: None,
+            max_readers: None,
+            serialization_format: SerializationFormat::default(),
+            checksums: false,
+            write_queue: None,
+            bloom_filter: None,
+            limits: Limits::default(),
+            #[cfg(feature = "search")]
+            search: None,
+        }
+    }
+
+    /// The config for a storage URI like `lmdb:///var/lib/holochain?map_size=1073741824`.
+    /// Understands `map_size`, `max_map_size`, `max_readers`, `format`, `checksums`,
+    /// `max_content_bytes` and `max_eavis_per_attribute`.
+    pub fn from_uri(uri: &StorageUri) -> PersistenceResult<LmdbConfig> {
+        uri.check_params(&[
+            "map_size",
+            "max_map_size",
+            "max_readers",
+            "format",
+            "checksums",
+            "max_content_bytes",
+            "max_eavis_per_attribute",
+        ])?;
+        if uri.path.as_os_str().is_empty() {
+            return Err(PersistenceError::from(format!(
+                "{} storage URI has no path",
+                uri.scheme
+            )));
+        }
+        let mut config = LmdbConfig::new(&uri.path);
+        config.initial_map_bytes = uri.param("map_size")?;
+        config.max_map_bytes = uri.param("max_map_size")?;
+        config.max_readers = uri.param("max_readers")?;
+        config.serialization_format = uri.param("format")?.unwrap_or_default();
+        config.limits = Limits {
+            max_content_bytes: uri.param("max_content_bytes")?,
+            max_eavis_per_attribute: uri.param("max_eavis_per_attribute")?,
+        };
+        // a bare `checksums` turns them on too
+        config.checksums = match uri.params.get("checksums") {
+            Some(value) if value.is_empty() => true,
+            Some(_) => uri.param("checksums")?.unwrap_or_default(),
+            None => false,
+        };
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_take_the_defaults() {
+        let config: LmdbConfig = serde_json::from_str(r#"{"path":"/tmp/store"}"#).unwrap();
+        assert_eq!(LmdbConfig::new("/tmp/store"), config);
+
+        let config: LmdbConfig = serde_json::from_str(
+            r#"{
+                "path": "/tmp/store",
+                "max_readers": 512,
+                "serialization_format": "Cbor",
+                "write_queue": { "capacity": 8 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(Some(512), config.max_readers);
+        assert_eq!(SerializationFormat::Cbor, config.serialization_format);
+        assert_eq!(
+            None,
+            config.write_queue.as_ref().and_then(|q| q.commit_window())
+        );
+    }
+
+    #[test]
+    fn uris_set_the_fields_they_name() {
+        let uri: StorageUri = "lmdb:///tmp/store?map_size=4096&format=cbor&checksums"
+            .parse()
+            .unwrap();
+        let config = LmdbConfig::from_uri(&uri).unwrap();
+        assert_eq!(Some(4096), config.initial_map_bytes);
+        assert_eq!(SerializationFormat::Cbor, config.serialization_format);
+        assert!(config.checksums);
+        assert_eq!(None, config.max_readers);
+        assert_eq!(Limits::default(), config.limits);
+
+        let uri: StorageUri = "lmdb:///tmp/store?max_content_bytes=1048576"
+            .parse()
+            .unwrap();
+        let config = LmdbConfig::from_uri(&uri).unwrap();
+        assert_eq!(Some(1_048_576), config.limits.max_content_bytes);
+        assert_eq!(None, config.limits.max_eavis_per_attribute);
+
+        for uri in &[
+            "lmdb://?map_size=1",
+            "lmdb:///tmp/store?map_size=big",
+            "lmdb:///tmp/store?size=1",
+        ] {
+            assert!(LmdbConfig::from_uri(&uri.parse().unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Simulated power loss, for testing that the stores recover from a crash at any point of a write.
+//!
+//! Every `LmdbInstance` in a test build has a `CrashSwitch`. Armed at a `CrashPoint`, it unwinds
+//! out of the write that reaches that point without committing it, as if the process had died
+//! there. `CrashableLmdbManager` runs writes against a CAS and an EAV store until the switch
+//! trips, then closes and reopens them and checks the invariants a crash must not break.
+
+use crate::{
+    cas::lmdb::LmdbStorage,
+    common::{environment_is_open, stored_json, LmdbInstance},
+    eav::lmdb::{attribute_key, value_key, EavLmdbStorage},
+};
+use holochain_persistence_api::{
+    cas::{
+        content::{Address, AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    },
+    eav::{EntityAttributeValueIndex, ExampleAttribute},
+};
+use rkv::{SingleStore, StoreError};
+use std::{
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, PoisonError},
+};
+use tempfile::{tempdir, TempDir};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CrashPoint {
+    /// after an entry was put into the write transaction, before the rest of the transaction
+    AfterStagingWrite,
+    /// with the whole transaction staged, right before it is committed
+    BeforeCommit,
+    /// after the map grew for a write that didn't fit, before the write is tried again
+    MidGrow,
+}
+
+/// The panic payload of a simulated crash.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PowerLoss(pub CrashPoint);
+
+/// Clones trip together.
+#[derive(Clone, Default)]
+pub(crate) struct CrashSwitch {
+    armed: Arc<Mutex<Option<(CrashPoint, usize)>>>,
+}
+
+impl CrashSwitch {
+    /// Crashes the write that reaches `point` after it has been reached `skip` times.
+    pub fn arm(&self, point: CrashPoint, skip: usize) {
+        *self.armed.lock().unwrap_or_else(PoisonError::into_inner) = Some((point, skip));
+    }
+
+    pub fn disarm(&self) {
+        *self.armed.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    pub fn reached(&self, point: CrashPoint) {
+        let mut armed = self.armed.lock().unwrap_or_else(PoisonError::into_inner);
+        match armed.as_mut() {
+            Some((at, skip)) if *at == point && *skip > 0 => *skip -= 1,
+            Some((at, _)) if *at == point => {
+                *armed = None;
+                drop(armed);
+                // unlike panic! this doesn't run the panic hook, the crash is expected
+                panic::resume_unwind(Box::new(PowerLoss(point)));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A CAS and an EAV store whose writes can be crashed.
+pub(crate) struct CrashableLmdbManager {
+    dir: TempDir,
+    initial_map_bytes: Option<usize>,
+    switch: CrashSwitch,
+    pub cas: LmdbStorage,
+    pub eav: EavLmdbStorage<ExampleAttribute>,
+}
+
+impl CrashableLmdbManager {
+    pub fn new(initial_map_bytes: Option<usize>) -> CrashableLmdbManager {
+        let dir = tempdir().expect("Could not create a tempdir for crash testing");
+        let switch = CrashSwitch::default();
+        let (cas, eav) = Self::open(&dir, initial_map_bytes, &switch);
+        CrashableLmdbManager {
+            dir,
+            initial_map_bytes,
+            switch,
+            cas,
+            eav,
+        }
+    }
+
+    fn open(
+        dir: &TempDir,
+        initial_map_bytes: Option<usize>,
+        switch: &CrashSwitch,
+    ) -> (LmdbStorage, EavLmdbStorage<ExampleAttribute>) {
+        let mut cas = LmdbStorage::new(dir.path().join("cas"), initial_map_bytes, None);
+        let mut eav = EavLmdbStorage::new(dir.path().join("eav"), initial_map_bytes, None);
+        cas.lmdb.crash = switch.clone();
+        eav.lmdb.crash = switch.clone();
+        (cas, eav)
+    }
+
+    pub fn crash_at(&self, point: CrashPoint, skip: usize) {
+        self.switch.arm(point, skip);
+    }
+
+    /// Runs `writes` until they finish or crash. The switch is disarmed either way.
+    pub fn run<T, F>(&mut self, writes: F) -> Result<T, PowerLoss>
+    where
+        F: FnOnce(&mut LmdbStorage, &mut EavLmdbStorage<ExampleAttribute>) -> T,
+    {
+        let cas = &mut self.cas;
+        let eav = &mut self.eav;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| writes(cas, eav)));
+        self.switch.disarm();
+        result.map_err(|payload| match payload.downcast::<PowerLoss>() {
+            Ok(crash) => *crash,
+            Err(payload) => panic::resume_unwind(payload),
+        })
+    }
+
+    /// Closes both environments, reopens them from disk and checks their invariants.
+    pub fn recover(self) -> Result<CrashableLmdbManager, String> {
+        let CrashableLmdbManager {
+            dir,
+            initial_map_bytes,
+            switch,
+            cas,
+            eav,
+        } = self;
+        drop(cas);
+        drop(eav);
+        for env in &["cas/cas.db", "eav/EAV.db"] {
+            if environment_is_open(&dir.path().join(env)) {
+                return Err(format!("{} is still open", env));
+            }
+        }
+        let (cas, eav) = Self::open(&dir, initial_map_bytes, &switch);
+        let manager = CrashableLmdbManager {
+            dir,
+            initial_map_bytes,
+            switch,
+            cas,
+            eav,
+        };
+        manager.check_invariants()?;
+        Ok(manager)
+    }
+
+    fn check_invariants(&self) -> Result<(), String> {
+        let error = |e: StoreError| e.to_string();
+        // every CAS entry decodes to content stored at its own address
+        for (key, json) in entries(&self.cas.lmdb, self.cas.lmdb.store).map_err(error)? {
+            let address = Address::from(key.clone());
+            let content = Content::from_json(&json);
+            if content.address() != address {
+                return Err(format!(
+                    "CAS content at {} has address {}",
+                    key,
+                    content.address()
+                ));
+            }
+            match self.cas.fetch(&address) {
+                Ok(Some(_)) => (),
+                other => {
+                    return Err(format!(
+                        "CAS content at {} can't be fetched: {:?}",
+                        key, other
+                    ))
+                }
+            }
+        }
+        // the EAVs and their indexes hold the same EAVIs
+        let eavis = entries(&self.eav.lmdb, self.eav.lmdb.store).map_err(error)?;
+        let values = entries(&self.eav.lmdb, self.eav.values).map_err(error)?;
+        let attributes = entries(&self.eav.lmdb, self.eav.attributes).map_err(error)?;
+        if eavis.len() != values.len() || eavis.len() != attributes.len() {
+            return Err(format!(
+                "{} EAVIs but {} value and {} attribute index entries",
+                eavis.len(),
+                values.len(),
+                attributes.len()
+            ));
+        }
+        for (key, json) in eavis {
+            let eavi: EntityAttributeValueIndex<ExampleAttribute> = serde_json::from_str(&json)
+                .map_err(|e| format!("EAVI at {} doesn't decode: {}", key, e))?;
+            if values.get(&value_key(&eavi)) != Some(&json)
+                || attributes.get(&attribute_key(&eavi)) != Some(&json)
+            {
+                return Err(format!("EAVI at {} is missing from an index", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every entry of `store` as JSON, by key.
+fn entries(
+    lmdb: &LmdbInstance,
+    store: SingleStore,
+) -> Result<BTreeMap<String, String>, StoreError> {
+    lmdb.read(|reader| {
+        let mut entries = BTreeMap::new();
+        for entry in store.iter_start(reader)? {
+            let (key, value) = entry?;
+            let key = String::from_utf8_lossy(key).to_string();
+            entries.insert(key, stored_json(value)?.to_string());
+        }
+        Ok(entries)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holochain_persistence_api::eav::EntityAttributeValueStorage;
+
+    const MAP_BYTES: usize = 1024 * 1024;
+
+    fn content(i: usize) -> Content {
+        // a few of these fill the map, so the writes have to grow it
+        Content::from_json(&format!("\"{}{}\"", i, "x".repeat(MAP_BYTES / 8)))
+    }
+
+    fn eavi(i: usize) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new(
+            &content(i).address(),
+            &ExampleAttribute::WithoutPayload,
+            &Address::from(format!("value {}", i)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn stores_recover_from_a_crash_at_any_point_of_a_write() {
+        for point in &[
+            CrashPoint::AfterStagingWrite,
+            CrashPoint::BeforeCommit,
+            CrashPoint::MidGrow,
+        ] {
+            for skip in 0..3 {
+                let mut manager = CrashableLmdbManager::new(Some(MAP_BYTES));
+                manager.crash_at(*point, skip);
+                let mut committed = Vec::new();
+                let crashed = manager.run(|cas, eav| {
+                    for i in 0..48 {
+                        cas.add(&content(i)).unwrap();
+                        eav.add_eavi(&eavi(i)).unwrap();
+                        committed.push(i);
+                    }
+                });
+                assert_eq!(Err(PowerLoss(*point)), crashed, "{:?} {}", point, skip);
+
+                let mut manager = manager
+                    .recover()
+                    .unwrap_or_else(|e| panic!("{:?} {}: {}", point, skip, e));
+                // what was committed before the crash survives it
+                for i in &committed {
+                    assert_eq!(Ok(true), manager.cas.contains(&content(*i).address()));
+                }
+                // and the stores can be written again
+                let next = committed.len() + 1;
+                manager.cas.add(&content(next)).unwrap();
+                manager.eav.add_eavi(&eavi(next)).unwrap();
+                manager.recover().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Dropping messages that have been seen before, across restarts.
+//!
+//! The DHT and gossip hand the ID of every bundle they receive to `DedupCache::first_sighting`
+//! and drop the bundle if it isn't the first. IDs are recorded in a `dedup` table of recently
+//! seen IDs, so a node reconnecting after a restart doesn't process and gossip again what it
+//! already had. A rolling bloom filter in front of the table answers for most IDs never seen
+//! without reading it. The filter starts a new generation once the current one is a retention
+//! window old and keeps the one before, so every ID seen within the window is in one of them.
+
+use crate::common::{write_error, LmdbInstance};
+use holochain_persistence_api::{
+    cas::bloom::BloomFilter,
+    error::{PersistenceError, PersistenceResult},
+};
+use rkv::{StoreError, Value};
+use std::{
+    mem,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DEDUP: &str = "dedup";
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+const ID_PREFIX: &str = "i:";
+const TIME_PREFIX: &str = "t:";
+
+/// when an ID was seen, by ID
+fn id_key(id: &str) -> String {
+    format!("{}{}", ID_PREFIX, id)
+}
+
+/// the IDs by when they were seen, oldest first, for pruning
+fn time_key(seen_at: u64, id: &str) -> String {
+    format!("{}{:020}:{}", TIME_PREFIX, seen_at, id)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+struct RollingBloom {
+    current: BloomFilter,
+    previous: BloomFilter,
+    capacity: usize,
+    /// when the current generation started, in milliseconds since the epoch
+    started: u64,
+    /// how long a generation lasts, in milliseconds
+    span: u64,
+}
+
+impl RollingBloom {
+    fn new(capacity: usize, span: u64) -> RollingBloom {
+        RollingBloom {
+            current: BloomFilter::new(capacity, FALSE_POSITIVE_RATE),
+            previous: BloomFilter::new(capacity, FALSE_POSITIVE_RATE),
+            capacity,
+            started: 0,
+            span,
+        }
+    }
+
+    fn insert(&mut self, id: &str, seen_at: u64) {
+        if seen_at >= self.started.saturating_add(self.span) {
+            let next = BloomFilter::new(self.capacity, FALSE_POSITIVE_RATE);
+            self.previous = mem::replace(&mut self.current, next);
+            self.started = seen_at;
+        }
+        self.current.insert(id);
+    }
+
+    fn may_contain(&self, id: &str) -> bool {
+        self.current.may_contain(id) || self.previous.may_contain(id)
+    }
+}
+
+/// Message IDs seen within the retention window.
+#[derive(Clone)]
+pub struct DedupCache {
+    lmdb: LmdbInstance,
+    bloom: Arc<RwLock<RollingBloom>>,
+    retention: Duration,
+}
+
+impl DedupCache {
+    /// Opens the table under `path`, pruning what fell out of the `retention` window while it
+    /// was closed. The bloom filters are sized for `capacity` IDs seen within the window.
+    pub fn new<P: AsRef<Path> + Clone>(
+        path: P,
+        capacity: usize,
+        retention: Duration,
+    ) -> PersistenceResult<DedupCache> {
+        let cache = DedupCache {
+            lmdb: LmdbInstance::new(DEDUP, path, None, None),
+            bloom: Arc::new(RwLock::new(RollingBloom::new(
+                capacity,
+                retention.as_millis() as u64,
+            ))),
+            retention,
+        };
+        cache.prune()?;
+        // oldest first, so the generations roll over as they did
+        let ids = cache
+            .lmdb
+            .read(|reader| {
+                let mut ids = Vec::new();
+                for entry in cache.lmdb.store.iter_from(reader, TIME_PREFIX)? {
+                    let (key, value) = entry?;
+                    if !key.starts_with(TIME_PREFIX.as_bytes()) {
+                        break;
+                    }
+                    if let Some(Value::U64(seen_at)) = value {
+                        let key = String::from_utf8_lossy(key);
+                        ids.push((key[TIME_PREFIX.len() + 21..].to_string(), seen_at));
+                    }
+                }
+                Ok(ids)
+            })
+            .map_err(|e| PersistenceError::from(format!("dedup read error: {}", e)))?;
+        {
+            let mut bloom = cache.bloom.write()?;
+            for (id, seen_at) in ids {
+                bloom.insert(&id, seen_at);
+            }
+        }
+        Ok(cache)
+    }
+
+    /// true if `id` has been seen and not pruned since.
+    pub fn seen(&self, id: &str) -> PersistenceResult<bool> {
+        if !self.bloom.read()?.may_contain(id) {
+            return Ok(false);
+        }
+        self.lmdb
+            .read(|reader| Ok(self.lmdb.store.get(reader, id_key(id))?.is_some()))
+            .map_err(|e| PersistenceError::from(format!("dedup read error: {}", e)))
+    }
+
+    /// Records `id` as seen now, true if it hadn't been seen before. The bundle is a duplicate
+    /// to drop if not.
+    pub fn first_sighting(&self, id: &str) -> PersistenceResult<bool> {
+        self.first_sighting_at(id, now())
+    }
+
+    /// Like `first_sighting` at `seen_at` milliseconds since the epoch.
+    pub fn first_sighting_at(&self, id: &str, seen_at: u64) -> PersistenceResult<bool> {
+        if self.seen(id)? {
+            return Ok(false);
+        }
+        // checked again in the write transaction, in case it was recorded in the meantime
+        let first = self
+            .lmdb
+            .write(|writer| {
+                if self.lmdb.store.get(writer, id_key(id))?.is_some() {
+                    return Ok(false);
+                }
+                let seen = Value::U64(seen_at);
+                self.lmdb.store.put(writer, id_key(id), &seen)?;
+                self.lmdb.store.put(writer, time_key(seen_at, id), &seen)?;
+                Ok(true)
+            })
+            .map_err(|e| write_error(e, "dedup write error"))?;
+        if first {
+            self.bloom.write()?.insert(id, seen_at);
+        }
+        Ok(first)
+    }
+
+    /// Forgets the IDs seen longer than the retention window ago, returning how many.
+    pub fn prune(&self) -> PersistenceResult<usize> {
+        self.prune_at(now())
+    }
+
+    /// Like `prune` at `now` milliseconds since the epoch.
+    pub fn prune_at(&self, now: u64) -> PersistenceResult<usize> {
+        let cutoff = now.saturating_sub(self.retention.as_millis() as u64);
+        let cutoff_key = time_key(cutoff, "");
+        self.lmdb
+            .write(|writer| {
+                let mut expired = Vec::new();
+                for entry in self.lmdb.store.iter_from(writer, TIME_PREFIX)? {
+                    let (key, _) = entry?;
+                    if !key.starts_with(TIME_PREFIX.as_bytes()) || key >= cutoff_key.as_bytes() {
+                        break;
+                    }
+                    expired.push(String::from_utf8_lossy(key).to_string());
+                }
+                for key in &expired {
+                    // the ID follows the 20 digit time and a colon
+                    let id = &key[TIME_PREFIX.len() + 21..];
+                    self.lmdb.store.delete(writer, id_key(id))?;
+                    self.lmdb.store.delete(writer, key)?;
+                }
+                Ok(expired.len())
+            })
+            .map_err(|e: StoreError| write_error(e, "dedup prune error"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn duplicates_are_dropped_across_restarts() {
+        let dir = tempdir().expect("Could not create a tempdir for dedup testing");
+        let hour = Duration::from_secs(3600);
+        let start = now();
+        let ids: Vec<String> = (0..20).map(|i| format!("bundle-{}", i)).collect();
+
+        // seen over two windows, so the filter rolls over
+        let cache = DedupCache::new(dir.path(), 8, hour).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            let seen_at = start + i as u64 * hour.as_millis() as u64 / 10;
+            assert_eq!(Ok(true), cache.first_sighting_at(id, seen_at));
+        }
+        for id in &ids {
+            assert_eq!(Ok(false), cache.first_sighting_at(id, start + 1));
+        }
+        assert_eq!(Ok(false), cache.seen("never"));
+        drop(cache);
+
+        let cache = DedupCache::new(dir.path(), 4, hour).unwrap();
+        assert_eq!(Ok(true), cache.seen(&ids[0]));
+        assert_eq!(Ok(false), cache.first_sighting(&ids[19]));
+        assert_eq!(Ok(true), cache.first_sighting_at("late", start + 1000));
+
+        // once out of the window an ID is new again
+        let later = start + 3 * hour.as_millis() as u64;
+        assert_eq!(Ok(21), cache.prune_at(later));
+        assert_eq!(Ok(false), cache.seen(&ids[0]));
+        assert_eq!(Ok(true), cache.first_sighting_at(&ids[0], later));
+        assert_eq!(Ok(false), cache.first_sighting_at(&ids[0], later));
+    }
+}
@@ -0,0 +1,120 @@
+//! Arrow export of the EAV store, for pulling EAVIs into dataframe tools.
+//!
+//! EAVIs are stored as JSON in LMDB so there is no layout Arrow could point into directly;
+//! each column is built in one pass over the query results instead.
+
+use crate::eav::lmdb::EavLmdbStorage;
+use arrow::{
+    array::{ArrayRef, Int64Builder, StringBuilder},
+    datatypes::{DataType, Field, Schema},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use holochain_persistence_api::{
+    eav::{Attribute, EaviQuery, EntityAttributeValueStorage},
+    error::{PersistenceError, PersistenceResult},
+};
+use std::sync::Arc;
+
+/// Schema of the batches returned by `to_arrow`.
+/// Attributes are serialized to JSON since their type is up to the caller.
+pub fn eavi_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("entity", DataType::Utf8, false),
+        Field::new("attribute", DataType::Utf8, false),
+        Field::new("value", DataType::Utf8, false),
+        Field::new("index", DataType::Int64, false),
+    ])
+}
+
+impl<A: Attribute> EavLmdbStorage<A>
+where
+    A: Sync + Send + serde::de::DeserializeOwned,
+{
+    /// Runs the query and returns the matching EAVIs as a single record batch, in index order.
+    pub fn to_arrow(&self, query: &EaviQuery<A>) -> PersistenceResult<RecordBatch> {
+        let eavis = self.fetch_eavi(query)?;
+        let mut entities = StringBuilder::new(eavis.len());
+        let mut attributes = StringBuilder::new(eavis.len());
+        let mut values = StringBuilder::new(eavis.len());
+        let mut indexes = Int64Builder::new(eavis.len());
+        for eavi in &eavis {
+            let attribute = serde_json::to_string(&eavi.attribute())
+                .map_err(|e| PersistenceError::from(format!("Arrow export error: {}", e)))?;
+            entities
+                .append_value(&eavi.entity().to_string())
+                .and_then(|_| attributes.append_value(&attribute))
+                .and_then(|_| values.append_value(&eavi.value().to_string()))
+                .and_then(|_| indexes.append_value(eavi.index()))
+                .map_err(arrow_error)?;
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(entities.finish()),
+            Arc::new(attributes.finish()),
+            Arc::new(values.finish()),
+            Arc::new(indexes.finish()),
+        ];
+        RecordBatch::try_new(Arc::new(eavi_schema()), columns).map_err(arrow_error)
+    }
+}
+
+fn arrow_error(e: ArrowError) -> PersistenceError {
+    PersistenceError::from(format!("Arrow export error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eav::lmdb::EavLmdbStorage;
+    use arrow::array::{Int64Array, StringArray};
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, ExampleAddressableContent},
+        eav::{
+            EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute,
+        },
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn exports_query_results_as_record_batch() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: &'static str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let (entity, value) = (address("e"), address("v"));
+        let added = eav_storage
+            .add_eavi(
+                &EntityAttributeValueIndex::new(&entity, &ExampleAttribute::default(), &value)
+                    .unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+
+        let batch = eav_storage.to_arrow(&EaviQuery::default()).unwrap();
+        assert_eq!(4, batch.num_columns());
+        assert_eq!(1, batch.num_rows());
+
+        let column = |i: usize| {
+            batch
+                .column(i)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .value(0)
+                .to_string()
+        };
+        assert_eq!(entity.to_string(), column(0));
+        assert_eq!("\"WithoutPayload\"", column(1));
+        assert_eq!(value.to_string(), column(2));
+        let indexes = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(added.index(), indexes.value(0));
+    }
+}
@@ -1,138 +1,884 @@
 use holochain_persistence_api::{
-    cas::content::AddressableContent,
+    cas::content::{Address, AddressableContent},
     eav::{
-        Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
+        Attribute, AttributeHistogram, EavFilter, EaviQuery, Entity, EntityAttributeValueIndex,
+        EntityAttributeValueStorage, EntityCursor, IndexFilter, OrderBy, Value as EavValue,
     },
     error::{PersistenceError, PersistenceResult},
+    events::EventBus,
+    format::SerializationFormat,
+    identity::StoreIdentity,
+    limits::Limits,
     reporting::{ReportStorage, StorageReport},
+    retry::RetryPolicy,
 };
 // use kv::{Config, Manager, Store, Error as KvError};
-use crate::common::LmdbInstance;
+use crate::{
+    checksum::{self, QuarantinedRecord},
+    common::{
+        abort_write, stored_json, write_error, Encoded, LmdbInstance, EAV_ATTRIBUTE_INDEX,
+        EAV_STATS, EAV_VALUE_INDEX,
+    },
+    config::LmdbConfig,
+    eav::{
+        plan::{EavStats, PlanCache, QueryExplanation, QueryPlan, QueryProfile, QueryStage},
+        stats,
+    },
+    identity,
+    rewrite::{self, RewriteProgress},
+    writer::WriteReceipt,
+};
+use lmdb::Error as LmdbError;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use rkv::{
     error::{DataError, StoreError},
-    Value,
+    store::single::Iter,
+    Readable, Reader, SingleStore, Writer,
 };
 use std::{
-    collections::BTreeSet,
+    borrow::Cow,
+    collections::{BTreeSet, VecDeque},
     fmt::{Debug, Error, Formatter},
+    io,
     marker::{PhantomData, Send, Sync},
     path::Path,
+    str,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
 };
-use uuid::Uuid;
 
 const EAV_BUCKET: &str = "EAV";
+/// entities read from the attribute index per read transaction
+const ENTITY_BATCH: usize = 256;
+/// below this many EAVIs a full scan isn't worth handing to the thread pool
+#[cfg(feature = "parallel")]
+const PARALLEL_SCAN_THRESHOLD: usize = 1024;
 
 #[derive(Clone)]
 pub struct EavLmdbStorage<A: Attribute> {
-    id: Uuid,
-    lmdb: LmdbInstance,
+    identity: StoreIdentity,
+    pub(crate) lmdb: LmdbInstance,
+    pub(crate) values: SingleStore,
+    pub(crate) attributes: SingleStore,
+    /// where the statistics are persisted, `stats` caches them
+    stats_store: SingleStore,
+    stats: Arc<RwLock<EavStats<A>>>,
+    plans: Arc<Mutex<PlanCache>>,
+    format: SerializationFormat,
+    checksums: bool,
+    limits: Limits,
     attribute: PhantomData<A>,
 }
 
+pub(crate) fn value_key<A: Attribute>(eav: &EntityAttributeValueIndex<A>) -> String {
+    format!("{}::{}::{}", eav.value(), eav.entity(), eav.index())
+}
+
+/// Attributes aren't `Display`, so their keys start with their JSON.
+fn attribute_prefix<A: Attribute>(attribute: &A) -> String {
+    format!("{}::", serde_json::to_string(attribute).unwrap_or_default())
+}
+
+pub(crate) fn attribute_key<A: Attribute>(eav: &EntityAttributeValueIndex<A>) -> String {
+    format!(
+        "{}{}::{}",
+        attribute_prefix(&eav.attribute()),
+        eav.entity(),
+        eav.index()
+    )
+}
+
+/// true if any key in the store starts with `prefix`
+pub(crate) fn has_prefix<T: Readable>(
+    store: SingleStore,
+    reader: &T,
+    prefix: &str,
+) -> Result<bool, StoreError> {
+    match store.iter_from(reader, prefix)?.next() {
+        Some(Ok((key, _))) => Ok(key.starts_with(prefix.as_bytes())),
+        Some(Err(e)) => Err(e),
+        None => Ok(false),
+    }
+}
+
 impl<A: Attribute> EavLmdbStorage<A> {
+    /// Opens the store at `db_path`, panicking if it can't be, see `open`.
     pub fn new<P: AsRef<Path> + Clone>(
         db_path: P,
         initial_map_bytes: Option<usize>,
-    ) -> EavLmdbStorage<A> {
-        EavLmdbStorage {
-            id: Uuid::new_v4(),
-            lmdb: LmdbInstance::new(EAV_BUCKET, db_path, initial_map_bytes),
+        max_readers: Option<u32>,
+    ) -> EavLmdbStorage<A>
+    where
+        A: Sync + Send + serde::de::DeserializeOwned,
+    {
+        Self::open(db_path, initial_map_bytes, max_readers).expect("Could not open the EAV store")
+    }
+
+    /// Opens the store at `db_path`. A store written before its statistics were persisted is
+    /// counted first, see `stats`.
+    pub fn open<P: AsRef<Path> + Clone>(
+        db_path: P,
+        initial_map_bytes: Option<usize>,
+        max_readers: Option<u32>,
+    ) -> PersistenceResult<EavLmdbStorage<A>>
+    where
+        A: Sync + Send + serde::de::DeserializeOwned,
+    {
+        let lmdb = LmdbInstance::new(EAV_BUCKET, db_path, initial_map_bytes, max_readers);
+        let values = lmdb.open_store(EAV_VALUE_INDEX);
+        let attributes = lmdb.open_store(EAV_ATTRIBUTE_INDEX);
+        let stats_store = lmdb.open_store(EAV_STATS);
+        let stats = stats::load_or_count(&lmdb, stats_store, values, attributes)?;
+        let identity = identity::load_or_create(&lmdb, &StoreIdentity::for_attribute::<A>())?;
+        Ok(EavLmdbStorage {
+            identity,
+            lmdb,
+            values,
+            attributes,
+            stats_store,
+            stats: Arc::new(RwLock::new(stats)),
+            plans: Arc::new(Mutex::new(PlanCache::default())),
+            format: SerializationFormat::default(),
+            checksums: false,
+            limits: Limits::default(),
             attribute: PhantomData,
+        })
+    }
+
+    /// Opens the store described by `config`. The bloom filter settings only apply to the CAS.
+    pub fn from_config(config: &LmdbConfig) -> PersistenceResult<EavLmdbStorage<A>>
+    where
+        A: Sync + Send + serde::de::DeserializeOwned,
+    {
+        let mut eav =
+            EavLmdbStorage::open(&config.path, config.initial_map_bytes, config.max_readers)?
+                .with_serialization_format(config.serialization_format)
+                .with_limits(config.limits);
+        if config.checksums {
+            eav = eav.with_checksums();
+        }
+        if config.max_map_bytes.is_some() {
+            eav = eav.with_max_map_bytes(config.max_map_bytes);
+        }
+        if let Some(queue) = &config.write_queue {
+            eav = eav.with_write_queue(queue.capacity, queue.commit_window());
         }
+        Ok(eav)
+    }
+
+    /// What this store is, written when it was created.
+    pub fn identity(&self) -> &StoreIdentity {
+        &self.identity
+    }
+
+    /// Gives this store an identity of its own, e.g. after it was copied from another.
+    pub(crate) fn renew_identity(&mut self) -> PersistenceResult<()> {
+        let fresh = StoreIdentity::for_attribute::<A>();
+        identity::replace(&self.lmdb, &fresh)?;
+        self.identity = fresh;
+        Ok(())
+    }
+
+    /// Row counts used to plan queries.
+    pub fn stats(&self) -> PersistenceResult<EavStats<A>> {
+        Ok(self.stats.read()?.clone())
+    }
+
+    /// The access path `fetch_eavi` will use for this query.
+    pub fn query_plan(&self, query: &EaviQuery<A>) -> PersistenceResult<QueryPlan> {
+        let stats = self.stats.read()?;
+        Ok(self.plans.lock()?.plan(query, &stats))
+    }
+
+    /// The access path `fetch_eavi` will use for this query and how many EAVIs it is expected
+    /// to read and return.
+    pub fn explain(&self, query: &EaviQuery<A>) -> PersistenceResult<QueryExplanation> {
+        let stats = self.stats.read()?;
+        let plan = self.plans.lock()?.plan(query, &stats);
+        Ok(stats.explain(plan, query))
+    }
+
+    /// Writes new EAVIs in `format`. EAVIs already stored are read whatever format they were
+    /// written in.
+    pub fn with_serialization_format(mut self, format: SerializationFormat) -> EavLmdbStorage<A> {
+        self.format = format;
+        self
+    }
+
+    /// Caps how far the memory map may grow, see `LmdbStorage::with_max_map_bytes`.
+    pub fn with_max_map_bytes(mut self, max_map_bytes: Option<usize>) -> EavLmdbStorage<A> {
+        self.lmdb = self.lmdb.with_max_map_bytes(max_map_bytes);
+        self
+    }
+
+    /// Retries writes as `policy` says, see `LmdbStorage::with_retry_policy`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> EavLmdbStorage<A> {
+        self.lmdb = self.lmdb.with_retry_policy(policy);
+        self
+    }
+
+    /// Writes new EAVIs with a checksum. EAVIs that fail their checksum when read are moved into
+    /// the quarantine and the read returns `PersistenceError::Corruption`.
+    pub fn with_checksums(mut self) -> EavLmdbStorage<A> {
+        self.checksums = true;
+        self
+    }
+
+    /// Fails adding an EAVI to an entity that has `limits.max_eavis_per_attribute` EAVIs with
+    /// its attribute already with `PersistenceError::LimitExceeded`. Upserts replace what the
+    /// entity has, so they are let through. Only `max_eavis_per_attribute` applies to the EAV
+    /// store.
+    pub fn with_limits(mut self, limits: Limits) -> EavLmdbStorage<A> {
+        self.limits = limits;
+        self
+    }
+
+    /// EAVIs that failed their checksum, see `with_checksums`.
+    pub fn quarantined(&self) -> PersistenceResult<Vec<QuarantinedRecord>> {
+        checksum::quarantined(&self.lmdb)
+    }
+
+    /// Where this store publishes growing its map.
+    pub fn events(&self) -> &EventBus {
+        &self.lmdb.events
+    }
+
+    /// Drops everything in the quarantine, returning how many records were dropped.
+    pub fn purge_quarantine(&self) -> PersistenceResult<usize> {
+        checksum::purge_quarantine(&self.lmdb)
+    }
+
+    fn encode(&self, json: String) -> PersistenceResult<Encoded> {
+        let encoded = Encoded::new(self.format, json)?;
+        if self.checksums {
+            encoded.sealed()
+        } else {
+            Ok(encoded)
+        }
+    }
+
+    /// Commits writes made with `add_eavi_async` on a background thread, queueing up to `capacity`
+    /// of them before `add_eavi_async` blocks. With a `commit_window` the writes queued within that
+    /// window are committed together in one transaction.
+    pub fn with_write_queue(
+        mut self,
+        capacity: usize,
+        commit_window: Option<Duration>,
+    ) -> EavLmdbStorage<A> {
+        self.lmdb = self.lmdb.with_write_queue(capacity, commit_window);
+        self
+    }
+
+    /// Re-encodes all stored EAVIs, and their index entries, in `format`. See
+    /// `LmdbStorage::rewrite_all`.
+    pub fn rewrite_all<F: FnMut(&RewriteProgress)>(
+        &self,
+        format: SerializationFormat,
+        batch_size: usize,
+        resume: RewriteProgress,
+        mut progress: F,
+    ) -> PersistenceResult<RewriteProgress>
+    where
+        A: serde::de::DeserializeOwned,
+    {
+        let (values, attributes) = (self.values, self.attributes);
+        rewrite::rewrite_all(
+            &self.lmdb,
+            format,
+            self.checksums,
+            batch_size,
+            resume,
+            &|json| {
+                let eav: EntityAttributeValueIndex<A> = serde_json::from_str(json)?;
+                Ok(vec![
+                    (values, value_key(&eav).into_bytes()),
+                    (attributes, attribute_key(&eav).into_bytes()),
+                ])
+            },
+            &mut progress,
+        )
+    }
+
+    /// Drops the value and attribute indexes and rebuilds them from the stored EAVIs, e.g.
+    /// after a change of their key layout or corruption. Batches, progress and resuming work as
+    /// in `rewrite_all`; a resumed rebuild keeps what was rebuilt before. The statistics are
+    /// counted anew once the rebuild is done.
+    pub fn reindex<F: FnMut(&RewriteProgress)>(
+        &self,
+        batch_size: usize,
+        resume: RewriteProgress,
+        mut progress: F,
+    ) -> PersistenceResult<RewriteProgress>
+    where
+        A: Sync + Send + serde::de::DeserializeOwned,
+    {
+        let (values, attributes) = (self.values, self.attributes);
+        let done = rewrite::reindex(
+            &self.lmdb,
+            &[values, attributes],
+            batch_size,
+            resume,
+            &|json| {
+                let eav: EntityAttributeValueIndex<A> = serde_json::from_str(json)?;
+                Ok(vec![
+                    (values, value_key(&eav).into_bytes()),
+                    (attributes, attribute_key(&eav).into_bytes()),
+                ])
+            },
+            &mut progress,
+        )?;
+        let stats = stats::count(&self.lmdb, self.stats_store, values, attributes, false)?;
+        *self.stats.write()? = stats;
+        *self.plans.lock()? = PlanCache::default();
+        Ok(done)
     }
 }
 
 impl<A: Attribute> Debug for EavLmdbStorage<A> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         f.debug_struct("EavLmdbStorage")
-            .field("id", &self.id)
+            .field("id", &self.identity.id)
             .finish()
     }
 }
 
+/// An EAVI written in a transaction not committed yet, with what its statistics need.
+struct StagedEavi<A: Attribute> {
+    eavi: EntityAttributeValueIndex<A>,
+    bytes: usize,
+    new_entity: bool,
+    new_value: bool,
+}
+
+impl<A: Attribute> StagedEavi<A> {
+    /// Counts the EAVI once its transaction has been committed.
+    fn record(self, stats: &RwLock<EavStats<A>>) -> EntityAttributeValueIndex<A> {
+        // counters can't be left half updated, so a poisoned lock is still usable
+        stats
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record(
+                self.eavi.attribute(),
+                self.bytes as u64,
+                self.new_entity,
+                self.new_value,
+            );
+        self.eavi
+    }
+}
+
+/// An EAVI that couldn't be encoded, as the error the write transaction it was staged in fails
+/// with.
+fn encode_error(e: PersistenceError) -> StoreError {
+    StoreError::IoError(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+pub(crate) fn raw_json<'r>(
+    result: Result<(&'r [u8], Option<rkv::Value<'r>>), StoreError>,
+) -> Result<Cow<'r, str>, StoreError> {
+    result.and_then(|(key, value)| stored_json(value).map_err(|e| checksum::at_key(e, key)))
+}
+
+/// Keys in both stores end in the index of their EAVI.
+fn key_index(key: &[u8]) -> Option<i64> {
+    str::from_utf8(key).ok()?.rsplit("::").next()?.parse().ok()
+}
+
+/// Keys in both stores start with the address they are sorted by, the entity in the EAV store
+/// and the value in the value index.
+fn key_address(key: &[u8]) -> Option<&str> {
+    str::from_utf8(key).ok()?.split("::").next()
+}
+
+/// Whether the index a key ends in is in the range of the query, keys without one are let
+/// through to be checked once decoded.
+fn key_in_range<A: Attribute>(key: &[u8], query: &EaviQuery<A>) -> bool {
+    match (key_index(key), query.index()) {
+        (Some(index), IndexFilter::Range(start, end)) => {
+            start.map_or(true, |lo| lo <= index) && end.map_or(true, |hi| index <= hi)
+        }
+        _ => true,
+    }
+}
+
+/// Iterates the keys of `store` from `prefix` on, all of them if it is empty.
+fn iter_prefix<'r>(
+    store: SingleStore,
+    reader: &'r Reader,
+    prefix: &str,
+) -> Result<Iter<'r>, StoreError> {
+    if prefix.is_empty() {
+        store.iter_start(reader)
+    } else {
+        store.iter_from(reader, prefix)
+    }
+}
+
 fn handle_cursor_result<A: Attribute>(
     result: Result<(&[u8], Option<rkv::Value>), StoreError>,
 ) -> Result<EntityAttributeValueIndex<A>, StoreError>
 where
     A: Sync + Send + serde::de::DeserializeOwned,
 {
-    match result {
-        Ok((_k, Some(Value::Json(s)))) => Ok(serde_json::from_str(&s).unwrap()),
-        Ok((_k, None)) => Err(StoreError::DataError(rkv::DataError::Empty)),
-        Ok((_k, Some(_v))) => Err(StoreError::DataError(rkv::DataError::UnexpectedType {
-            actual: rkv::value::Type::Json,
-            expected: rkv::value::Type::Json,
-        })),
-        Err(e) => Err(e),
-    }
+    raw_json(result).map(|s| serde_json::from_str(&s).unwrap())
 }
 
 impl<A: Attribute> EavLmdbStorage<A>
 where
     A: Sync + Send + serde::de::DeserializeOwned,
 {
-    fn add_lmdb_eavi(
-        &mut self,
+    /// Finds a free key for the EAVI and whether its entity and value are new to the store.
+    /// Run it in the write transaction the EAVI is written in, so no other write can take the
+    /// key in between.
+    fn next_key<T: Readable>(
+        &self,
+        reader: &T,
         eav: &EntityAttributeValueIndex<A>,
-    ) -> Result<Option<EntityAttributeValueIndex<A>>, StoreError> {
-        let env = self.lmdb.manager.read().unwrap();
-        let reader = env.read()?;
-
+    ) -> Result<(String, EntityAttributeValueIndex<A>, bool, bool), StoreError> {
+        let new_entity = !has_prefix(self.lmdb.store, reader, &format!("{}::", eav.entity()))?;
+        let new_value = !has_prefix(self.values, reader, &format!("{}::", eav.value()))?;
         // use a clever key naming scheme to speed up exact match queries on the entity
         let mut new_eav = eav.clone();
         let mut key = format!("{}::{}", new_eav.entity(), new_eav.index());
         // need to check there isn't a duplicate key though and if there is create a new EAVI which
         // will have a more recent timestamp
-        while let Ok(Some(_)) = self.lmdb.store.get(&reader, key.clone()) {
+        while let Ok(Some(_)) = self.lmdb.store.get(reader, key.clone()) {
             new_eav = EntityAttributeValueIndex::new(&eav.entity(), &eav.attribute(), &eav.value())
                 .map_err(|_| StoreError::DataError(DataError::Empty))?;
             key = format!("{}::{}", new_eav.entity(), new_eav.index());
         }
+        Ok((key, new_eav, new_entity, new_value))
+    }
+
+    /// Writes the EAVI and its index entries under a free key, failing with
+    /// `PersistenceError::LimitExceeded` if the entity has as many EAVIs with its attribute as
+    /// the limits allow. They are counted in `writer`, so no write can add to them in between.
+    fn stage_eavi(
+        &self,
+        writer: &mut Writer,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<StagedEavi<A>, StoreError> {
+        self.check_limits(writer, eav)?;
+        self.put_eavi(writer, eav)
+    }
+
+    /// Writes the EAVI and its index entries under a free key, whatever the limits.
+    fn put_eavi(
+        &self,
+        writer: &mut Writer,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<StagedEavi<A>, StoreError> {
+        let (key, eavi, new_entity, new_value) = self.next_key(writer, eav)?;
+        let json = eavi.content().to_string();
+        let bytes = json.len();
+        let encoded = self.encode(json).map_err(encode_error)?;
+        self.lmdb.store.put(writer, &key, &encoded.value())?;
+        self.values
+            .put(writer, value_key(&eavi), &encoded.value())?;
+        self.attributes
+            .put(writer, attribute_key(&eavi), &encoded.value())?;
+        stats::record(
+            self.stats_store,
+            writer,
+            &eavi.attribute(),
+            bytes as u64,
+            new_entity,
+            new_value,
+        )?;
+        Ok(StagedEavi {
+            eavi,
+            bytes,
+            new_entity,
+            new_value,
+        })
+    }
+
+    /// Counts the EAVIs the entity has with the attribute in the attribute index, if there is
+    /// a limit on them.
+    fn check_limits<T: Readable>(
+        &self,
+        reader: &T,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<(), StoreError> {
+        if self.limits.max_eavis_per_attribute.is_none() {
+            return Ok(());
+        }
+        let prefix = format!("{}{}::", attribute_prefix(&eav.attribute()), eav.entity());
+        let mut stored = 0;
+        for entry in self.attributes.iter_from(reader, &prefix)? {
+            if !entry?.0.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            stored += 1;
+        }
+        self.limits
+            .check_eavi_count(eav, stored)
+            .map_err(abort_write)
+    }
 
+    fn add_lmdb_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let staged = self
+            .lmdb
+            .write(|writer| self.stage_eavi(writer, eav))
+            .map_err(|e| write_error(e, "EAV add error"))?;
+        Ok(Some(staged.record(&self.stats)))
+    }
+
+    /// Deletes the EAVIs of the entity with the attribute and adds the new one in the same write
+    /// transaction.
+    fn upsert_lmdb_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let prefix = format!("{}::", eav.entity());
+        let (replaced, staged) = self
+            .lmdb
+            .write(|writer| {
+                let mut replaced = Vec::new();
+                for entry in self.lmdb.store.iter_from(writer, &prefix)? {
+                    let (old_key, value) = entry?;
+                    if !old_key.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+                    let old: EntityAttributeValueIndex<A> =
+                        handle_cursor_result(Ok((old_key, value)))?;
+                    if old.attribute() == eav.attribute() {
+                        replaced.push((old_key.to_vec(), old));
+                    }
+                }
+                // staged while the EAVIs it replaces are still there, so their entity isn't
+                // counted as new, nor are they counted against the limits
+                let staged = self.put_eavi(writer, eav)?;
+                for (old_key, old) in &replaced {
+                    self.lmdb.store.delete(writer, old_key)?;
+                    stats::forget(
+                        self.stats_store,
+                        writer,
+                        &old.attribute(),
+                        old.content().to_string().len() as u64,
+                    )?;
+                    for (index, index_key) in &[
+                        (self.values, value_key(old)),
+                        (self.attributes, attribute_key(old)),
+                    ] {
+                        match index.delete(writer, index_key) {
+                            Err(StoreError::LmdbError(LmdbError::NotFound)) | Ok(()) => (),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok((replaced, staged))
+            })
+            .map_err(|e| write_error(e, "EAV upsert error"))?;
+
+        {
+            let mut stats = self.stats.write().unwrap_or_else(PoisonError::into_inner);
+            for (_, old) in replaced {
+                stats.forget(&old.attribute(), old.content().to_string().len() as u64);
+            }
+        }
+        Ok(Some(staged.record(&self.stats)))
+    }
+
+    /// Adds an EAVI without waiting for the write to be committed. Its key is picked in the
+    /// transaction it is written in, so the receipt hands out the EAVI as stored: with a later
+    /// index if an EAVI of the entity, committed or queued before it, already had its index.
+    /// The limits are checked in that transaction as well, so an EAVI beyond them fails its
+    /// receipt.
+    pub fn add_eavi_async(
+        &self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<WriteReceipt<EntityAttributeValueIndex<A>>>
+    where
+        A: 'static,
+    {
+        let storage = self.clone();
+        let stats = self.stats.clone();
+        let eav = eav.clone();
+        Ok(self.lmdb.write_async(
+            move |writer| storage.stage_eavi(writer, &eav),
+            move |staged| staged.record(&stats),
+        ))
+    }
+
+    /// In this case all we can do is iterate the entire database
+    #[cfg(not(feature = "parallel"))]
+    fn full_scan(
+        &self,
+        reader: &Reader,
+    ) -> Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError> {
         self.lmdb
-            .add(key, &Value::Json(&new_eav.content().to_string()))?;
-        Ok(Some(new_eav))
+            .store
+            .iter_start(reader)?
+            .map(handle_cursor_result)
+            .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()
     }
 
-    fn fetch_lmdb_eavi(
+    /// In this case all we can do is iterate the entire database.
+    /// The cursor walk only collects JSON borrowed from the map, deserializing it is what takes
+    /// the time and that happens on the rayon pool. Collecting into the set restores index order.
+    #[cfg(feature = "parallel")]
+    fn full_scan(
+        &self,
+        reader: &Reader,
+    ) -> Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError> {
+        let raw = self
+            .lmdb
+            .store
+            .iter_start(reader)?
+            .map(raw_json)
+            .collect::<Result<Vec<Cow<str>>, StoreError>>()?;
+        if raw.len() < PARALLEL_SCAN_THRESHOLD {
+            return Ok(raw
+                .into_iter()
+                .map(|s| serde_json::from_str(&s).unwrap())
+                .collect());
+        }
+        Ok(raw
+            .into_par_iter()
+            .map(|s| serde_json::from_str(&s).unwrap())
+            .collect::<Vec<EntityAttributeValueIndex<A>>>()
+            .into_iter()
+            .collect())
+    }
+
+    /// The EAVIs the plan goes through, before they are filtered by the query.
+    fn read_lmdb_eavi(
         &self,
         query: &EaviQuery<A>,
+        plan: QueryPlan,
     ) -> Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError> {
-        let env = self.lmdb.manager.read().unwrap();
-        let reader = env.read()?;
+        self.lmdb
+            .read(|reader| match (plan, &query.entity, &query.value) {
+                (QueryPlan::EntityPrefix, EavFilter::Exact(entity), _) => {
+                    // Can optimize here thanks to the sorted keys and only iterate matching entities
+                    self.lmdb
+                        .store
+                        .iter_from(reader, format!("{}::{}", entity, 0))? // start at the first key containing the entity address
+                        .take_while(|r| {
+                            // stop at the first key that doesn't match (but keep taking errors)
+                            match r {
+                                Ok((k, _)) => String::from_utf8(k.to_vec())
+                                    .unwrap()
+                                    .contains(&entity.to_string()),
+                                _ => true,
+                            }
+                        })
+                        .map(handle_cursor_result)
+                        .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()
+                }
 
-        let entries = match &query.entity {
-            EavFilter::Exact(entity) => {
-                // Can optimize here thanks to the sorted keys and only iterate matching entities
-                self.lmdb
-                    .store
-                    .iter_from(&reader, format!("{}::{}", entity, 0))? // start at the first key containing the entity address
-                    .take_while(|r| {
-                        // stop at the first key that doesn't match (but keep taking errors)
-                        match r {
-                            Ok((k, _)) => String::from_utf8(k.to_vec())
-                                .unwrap()
-                                .contains(&entity.to_string()),
+                (QueryPlan::ValuePrefix, _, EavFilter::Exact(value)) => {
+                    // the value index is sorted by value first, so the same trick works there
+                    let prefix = format!("{}::", value);
+                    self.values
+                        .iter_from(reader, prefix.clone())?
+                        .take_while(|r| match r {
+                            Ok((k, _)) => k.starts_with(prefix.as_bytes()),
                             _ => true,
-                        }
-                    })
-                    .map(handle_cursor_result)
-                    .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()?
-            }
+                        })
+                        .map(handle_cursor_result)
+                        .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()
+                }
 
-            _ => {
-                // In this case all we can do is iterate the entire database
-                self.lmdb
-                    .store
-                    .iter_start(&reader)?
-                    .map(handle_cursor_result)
-                    .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()?
-            }
-        };
+                _ => self.full_scan(reader),
+            })
+    }
 
+    fn fetch_lmdb_eavi(
+        &self,
+        query: &EaviQuery<A>,
+        plan: QueryPlan,
+    ) -> Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError> {
+        let entries = self.read_lmdb_eavi(query, plan)?;
         let entries_iter = entries.iter().cloned();
         Ok(query.run(entries_iter))
     }
+
+    /// A failed read of the store the plan goes through, quarantining what failed its checksum.
+    fn fetch_error(
+        &self,
+        plan: QueryPlan,
+        query: &EaviQuery<A>,
+        e: StoreError,
+    ) -> PersistenceError {
+        let store = match (plan, &query.value) {
+            (QueryPlan::ValuePrefix, EavFilter::Exact(_)) => self.values,
+            _ => self.lmdb.store,
+        };
+        checksum::persistence_error(&self.lmdb, store, e, "EAV fetch error")
+    }
+
+    /// Fetches like `fetch_eavi`, also returning the plan it used, the time taken by each stage
+    /// and how many EAVIs were read to find the ones returned.
+    pub fn fetch_eavi_profiled(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<(BTreeSet<EntityAttributeValueIndex<A>>, QueryProfile)> {
+        let started = Instant::now();
+        let explanation = self.explain(query)?;
+        let planned = Instant::now();
+        let entries = self
+            .read_lmdb_eavi(query, explanation.plan)
+            .map_err(|e| self.fetch_error(explanation.plan, query, e))?;
+        let read = Instant::now();
+        let found = query.run(entries.iter().cloned());
+        let profile = QueryProfile {
+            explanation,
+            stages: vec![
+                (QueryStage::Plan, planned - started),
+                (QueryStage::Read, read - planned),
+                (QueryStage::Filter, read.elapsed()),
+            ],
+            scanned: entries.len() as u64,
+            returned: found.len() as u64,
+        };
+        Ok((found, profile))
+    }
+
+    /// The latest `limit` EAVIs matching a query on an index range. The keys in range are sorted
+    /// by the index they end in and only decoded newest first until the limit is filled, so
+    /// asking for the latest few doesn't decode everything.
+    fn fetch_latest(
+        &self,
+        query: &EaviQuery<A>,
+        plan: QueryPlan,
+        limit: usize,
+    ) -> Result<Vec<EntityAttributeValueIndex<A>>, StoreError> {
+        let (store, prefix) = match (plan, &query.entity, &query.value) {
+            (QueryPlan::EntityPrefix, EavFilter::Exact(entity), _) => {
+                (self.lmdb.store, format!("{}::", entity))
+            }
+            (QueryPlan::ValuePrefix, _, EavFilter::Exact(value)) => {
+                (self.values, format!("{}::", value))
+            }
+            _ => (self.lmdb.store, String::new()),
+        };
+        self.lmdb.read(|reader| {
+            let mut keys = Vec::new();
+            for entry in iter_prefix(store, reader, &prefix)? {
+                let (key, value) = entry?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                if key_in_range(key, query) {
+                    keys.push((key_index(key), key, value));
+                }
+            }
+            // keys that don't end in an index are decoded last, there shouldn't be any
+            keys.sort_by(|a, b| b.0.cmp(&a.0));
+
+            let mut latest: Vec<EntityAttributeValueIndex<A>> = Vec::new();
+            for (index, key, value) in keys {
+                // carry on through EAVIs sharing the last index so ties are broken as in a set
+                if latest.len() >= limit && latest.last().map(|last| last.index()) != index {
+                    break;
+                }
+                let eavi = handle_cursor_result(Ok((key, value)))?;
+                if query.matches(&eavi) {
+                    latest.push(eavi);
+                }
+            }
+            latest.sort_by(|a, b| b.cmp(a));
+            latest.truncate(limit);
+            Ok(latest)
+        })
+    }
+
+    /// The distinct addresses the keys of `store` from `prefix` on start with, for those that
+    /// have an EAVI matching a query on an index range. The keys of an address are next to each
+    /// other, so its EAVIs are only decoded until one of them matches and the rest are skipped
+    /// by key, as are keys outside the index range.
+    fn distinct_addresses(
+        &self,
+        store: SingleStore,
+        prefix: &str,
+        query: &EaviQuery<A>,
+    ) -> Result<BTreeSet<Address>, StoreError> {
+        self.lmdb.read(|reader| {
+            let mut found = BTreeSet::new();
+            let mut last_found = None;
+            for entry in iter_prefix(store, reader, prefix)? {
+                let (key, value) = entry?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                let address = key_address(key);
+                if address.is_some() && address == last_found || !key_in_range(key, query) {
+                    continue;
+                }
+                let eavi = handle_cursor_result(Ok((key, value)))?;
+                if query.matches(&eavi) {
+                    found.insert(Address::from(address.unwrap_or_default()));
+                    last_found = address;
+                }
+            }
+            Ok(found)
+        })
+    }
+}
+
+/// The entities of the keys in the attribute index starting with `prefix`, read a batch per
+/// read transaction. Each entity is read once, the keys of its other EAVIs are skipped by seeking
+/// past them.
+struct AttributeEntities<'a, A: Attribute> {
+    eav: &'a EavLmdbStorage<A>,
+    prefix: String,
+    /// where the next batch starts, None once the index has been read to the end of the prefix
+    from: Option<String>,
+    batch: VecDeque<Entity>,
+}
+
+impl<'a, A: Attribute> AttributeEntities<'a, A> {
+    fn read_batch(&self, from: &str) -> Result<(VecDeque<Entity>, Option<String>), StoreError> {
+        let prefix = &self.prefix;
+        self.eav.lmdb.read(|reader| {
+            let mut entities = VecDeque::new();
+            let mut from = from.to_string();
+            while entities.len() < ENTITY_BATCH {
+                let key = match self.eav.attributes.iter_from(reader, &from)?.next() {
+                    Some(entry) => entry?.0,
+                    None => return Ok((entities, None)),
+                };
+                if !key.starts_with(prefix.as_bytes()) {
+                    return Ok((entities, None));
+                }
+                let rest = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+                let entity = rest.rsplitn(2, "::").nth(1).unwrap_or(&rest).to_string();
+                // ';' sorts right after ':', so this is past every key of the entity
+                from = format!("{}{}:;", prefix, entity);
+                entities.push_back(Address::from(entity));
+            }
+            Ok((entities, Some(from)))
+        })
+    }
+}
+
+impl<'a, A: Attribute> Iterator for AttributeEntities<'a, A> {
+    type Item = PersistenceResult<Entity>;
+
+    fn next(&mut self) -> Option<PersistenceResult<Entity>> {
+        if self.batch.is_empty() {
+            let from = self.from.take()?;
+            match self.read_batch(&from) {
+                Ok((batch, next)) => {
+                    self.batch = batch;
+                    self.from = next;
+                }
+                Err(e) => {
+                    return Some(Err(PersistenceError::from(format!(
+                        "EAV fetch error: {}",
+                        e
+                    ))))
+                }
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
 }
 
 impl<A: Attribute> EntityAttributeValueStorage<A> for EavLmdbStorage<A>
@@ -144,15 +890,90 @@ where
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
         self.add_lmdb_eavi(eav)
-            .map_err(|e| PersistenceError::from(format!("EAV add error: {}", e)))
+    }
+
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        self.upsert_lmdb_eavi(eav)
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
-        self.fetch_lmdb_eavi(query)
-            .map_err(|e| PersistenceError::from(format!("EAV fetch error: {}", e)))
+        let plan = self.query_plan(query)?;
+        self.fetch_lmdb_eavi(query, plan)
+            .map_err(|e| self.fetch_error(plan, query, e))
+    }
+
+    /// Pushes "latest N" queries on an index range down to the keys, see `fetch_latest`.
+    fn fetch_eavi_ordered(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        let limit = match (query.order_by(), query.limit(), query.index()) {
+            (OrderBy::IndexDescending, Some(limit), IndexFilter::Range(..)) => limit,
+            _ => return Ok(query.order(self.fetch_eavi(query)?)),
+        };
+        let plan = self.query_plan(query)?;
+        self.fetch_latest(query, plan, limit)
+            .map_err(|e| self.fetch_error(plan, query, e))
+    }
+
+    /// Scans the value index by key unless the query is on a single entity, in which case
+    /// decoding just its EAVIs is cheaper.
+    fn fetch_distinct_values(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<EavValue>> {
+        let prefix = match (&query.entity, &query.value, query.index()) {
+            (EavFilter::Exact(_), _, _) | (_, _, IndexFilter::LatestByAttribute) => {
+                return Ok(self
+                    .fetch_eavi(query)?
+                    .into_iter()
+                    .map(|eavi| eavi.value())
+                    .collect())
+            }
+            (_, EavFilter::Exact(value), _) => format!("{}::", value),
+            _ => String::new(),
+        };
+        self.distinct_addresses(self.values, &prefix, query)
+            .map_err(|e| checksum::persistence_error(&self.lmdb, self.values, e, "EAV fetch error"))
+    }
+
+    /// Scans the EAV store by key unless the query is on a single value, in which case decoding
+    /// just its EAVIs is cheaper.
+    fn fetch_distinct_entities(&self, query: &EaviQuery<A>) -> PersistenceResult<BTreeSet<Entity>> {
+        let prefix = match (&query.entity, &query.value, query.index()) {
+            (_, EavFilter::Exact(_), _) | (_, _, IndexFilter::LatestByAttribute) => {
+                return Ok(self
+                    .fetch_eavi(query)?
+                    .into_iter()
+                    .map(|eavi| eavi.entity())
+                    .collect())
+            }
+            (EavFilter::Exact(entity), _, _) => format!("{}::", entity),
+            _ => String::new(),
+        };
+        self.distinct_addresses(self.lmdb.store, &prefix, query)
+            .map_err(|e| {
+                checksum::persistence_error(&self.lmdb, self.lmdb.store, e, "EAV fetch error")
+            })
+    }
+
+    /// Taken from the statistics kept up to date on every write.
+    fn attribute_histogram(&self) -> PersistenceResult<AttributeHistogram<A>> {
+        Ok(self.stats.read()?.attribute_histogram())
+    }
+
+    /// A prefix scan of the attribute index.
+    fn entities_with_attribute<'a>(&'a self, attribute: &A) -> EntityCursor<'a> {
+        let prefix = attribute_prefix(attribute);
+        Box::new(AttributeEntities {
+            eav: self,
+            from: Some(prefix.clone()),
+            prefix,
+            batch: VecDeque::new(),
+        })
     }
 }
 
@@ -161,21 +982,37 @@ where
     A: Sync + Send + serde::de::DeserializeOwned,
 {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
-        Ok(StorageReport::new(0)) // TODO: implement this
+        let (in_use, max_readers) = self.lmdb.reader_slots();
+        Ok(StorageReport::new(0).with_reader_slots(in_use, max_readers)) // TODO: implement bytes_total
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::eav::lmdb::EavLmdbStorage;
+    use crate::{
+        checksum::tests::tamper,
+        eav::{
+            lmdb::{EavLmdbStorage, ENTITY_BATCH},
+            plan::{QueryPlan, QueryStage},
+        },
+        rewrite::RewriteProgress,
+    };
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
-            content::{AddressableContent, ExampleAddressableContent},
+            content::{Address, AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::{storage::EavBencher, Attribute, ExampleAttribute},
+        eav::{
+            storage::EavBencher, Attribute, EaviQuery, EntityAttributeValueIndex,
+            EntityAttributeValueStorage, ExampleAttribute, IndexFilter,
+        },
+        error::{PersistenceError, PersistenceResult},
+        format::SerializationFormat,
+        limits::Limits,
     };
+    use rkv::{SingleStore, Value};
+    use std::{collections::BTreeSet, time::Duration};
     use tempfile::tempdir;
 
     #[test]
@@ -190,17 +1027,290 @@ pub mod tests {
             ExampleAddressableContent::try_from_content(&RawString::from("blue").into()).unwrap();
 
         EavTestSuite::test_round_trip(
-            EavLmdbStorage::new(temp_path, None),
+            EavLmdbStorage::new(temp_path, None, None),
             entity_content,
             attribute,
             value_content,
         )
     }
 
-    fn new_store<A: Attribute>() -> EavLmdbStorage<A> {
+    fn new_store<A: Attribute + Sync + Send + serde::de::DeserializeOwned>() -> EavLmdbStorage<A> {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        EavLmdbStorage::new(temp_path, None)
+        EavLmdbStorage::new(temp_path, None, None)
+    }
+
+    #[test]
+    fn lmdb_eav_add_async_round_trip() {
+        let eav_storage =
+            new_store::<ExampleAttribute>().with_write_queue(4, Some(Duration::from_millis(10)));
+        let entity =
+            ExampleAddressableContent::try_from_content(&RawString::from("foo").into()).unwrap();
+        let value =
+            ExampleAddressableContent::try_from_content(&RawString::from("bar").into()).unwrap();
+        let eav = EntityAttributeValueIndex::new(
+            &entity.address(),
+            &ExampleAttribute::default(),
+            &value.address(),
+        )
+        .unwrap();
+
+        let added = eav_storage
+            .add_eavi_async(&eav)
+            .unwrap()
+            .wait()
+            .expect("queued write failed");
+
+        let fetched = eav_storage
+            .fetch_eavi(&EaviQuery::new(
+                Some(entity.address()).into(),
+                Some(ExampleAttribute::default()).into(),
+                Some(value.address()).into(),
+                IndexFilter::LatestByAttribute,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(vec![added], fetched.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn lmdb_eav_queued_eavis_failing_a_shared_commit_fail_on_their_own() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None)
+                .with_limits(Limits {
+                    max_eavis_per_attribute: Some(2),
+                    ..Limits::default()
+                })
+                .with_write_queue(4, Some(Duration::from_millis(50)));
+        let address = |s: &str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let link = |value: &str| {
+            EntityAttributeValueIndex::new(
+                &address("entity"),
+                &ExampleAttribute::default(),
+                &address(value),
+            )
+            .unwrap()
+        };
+
+        // queued in the same commit window, the third goes past the limit
+        let receipts = ["a", "b", "c"]
+            .iter()
+            .map(|value| eav_storage.add_eavi_async(&link(value)).unwrap())
+            .collect::<Vec<_>>();
+        let results = receipts
+            .into_iter()
+            .map(|receipt| receipt.wait())
+            .collect::<Vec<_>>();
+        assert!(results[0].is_ok() && results[1].is_ok());
+        match &results[2] {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+        let stored = eav_storage
+            .fetch_eavi(&EaviQuery::new(
+                Some(address("entity")).into(),
+                Default::default(),
+                Default::default(),
+                IndexFilter::Range(None, None),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(2, stored.len());
+    }
+
+    #[test]
+    fn lmdb_eav_queued_eavis_with_the_same_index_get_keys_of_their_own() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None)
+                .with_write_queue(4, Some(Duration::from_millis(50)));
+        let address = |s: &str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let eav = EntityAttributeValueIndex::new(
+            &address("entity"),
+            &ExampleAttribute::default(),
+            &address("value"),
+        )
+        .unwrap();
+
+        // both are queued before either is committed, in the same commit window
+        let first = eav_storage.add_eavi_async(&eav).unwrap();
+        let second = eav_storage.add_eavi_async(&eav).unwrap();
+        let (first, second) = (first.wait().unwrap(), second.wait().unwrap());
+        assert_ne!(first.index(), second.index());
+
+        let expected: BTreeSet<_> = vec![first, second].into_iter().collect();
+        let by_entity = EaviQuery::new(
+            Some(address("entity")).into(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let by_value = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Some(address("value")).into(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(Ok(expected.clone()), eav_storage.fetch_eavi(&by_entity));
+        assert_eq!(Ok(expected), eav_storage.fetch_eavi(&by_value));
+        assert_eq!(2, eav_storage.stats().unwrap().total);
+    }
+
+    #[test]
+    fn lmdb_eav_plans_with_stats_and_value_index() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let entity = ExampleAddressableContent::try_from_content(&RawString::from("e").into())
+            .unwrap()
+            .address();
+        let values: Vec<_> = (0..10)
+            .map(|i| {
+                ExampleAddressableContent::try_from_content(
+                    &RawString::from(format!("v{}", i)).into(),
+                )
+                .unwrap()
+                .address()
+            })
+            .collect();
+        for value in &values {
+            let eav = EntityAttributeValueIndex::new(&entity, &ExampleAttribute::default(), value)
+                .unwrap();
+            eav_storage.add_eavi(&eav).unwrap();
+        }
+
+        let stats = eav_storage.stats().unwrap();
+        assert_eq!((10, 1, 10), (stats.total, stats.entities, stats.values));
+        assert_eq!(
+            Some(&10),
+            stats.per_attribute.get(&ExampleAttribute::default())
+        );
+
+        // one entity with ten values, so the value index is the narrower one
+        let query = EaviQuery::new(
+            Some(entity).into(),
+            Some(ExampleAttribute::default()).into(),
+            Some(values[3].clone()).into(),
+            IndexFilter::LatestByAttribute,
+            None,
+        );
+        assert_eq!(
+            QueryPlan::ValuePrefix,
+            eav_storage.query_plan(&query).unwrap()
+        );
+        let fetched = eav_storage.fetch_eavi(&query).unwrap();
+        assert_eq!(1, fetched.len());
+        assert_eq!(values[3], fetched.iter().next().unwrap().value());
+
+        // stats are rebuilt from what is stored when the store is opened again
+        let reopened: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None);
+        assert_eq!(stats, reopened.stats().unwrap());
+    }
+
+    #[test]
+    fn lmdb_eav_explain_and_profile() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: String| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        // four entities linking to the same five values
+        for e in 0..4 {
+            for v in 0..5 {
+                let eav = EntityAttributeValueIndex::new(
+                    &address(format!("e{}", e)),
+                    &ExampleAttribute::default(),
+                    &address(format!("v{}", v)),
+                )
+                .unwrap();
+                eav_storage.add_eavi(&eav).unwrap();
+            }
+        }
+
+        let query = EaviQuery::new(
+            Some(address("e1".to_string())).into(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let explanation = eav_storage.explain(&query).unwrap();
+        assert_eq!(QueryPlan::EntityPrefix, explanation.plan);
+        assert_eq!(
+            (5, 5),
+            (
+                explanation.estimated_scanned,
+                explanation.estimated_returned
+            )
+        );
+
+        let (fetched, profile) = eav_storage.fetch_eavi_profiled(&query).unwrap();
+        assert_eq!(eav_storage.fetch_eavi(&query).unwrap(), fetched);
+        assert_eq!(explanation, profile.explanation);
+        assert_eq!((5, 5), (profile.scanned, profile.returned));
+        let stages: Vec<_> = profile.stages.iter().map(|(stage, _)| *stage).collect();
+        assert_eq!(
+            vec![QueryStage::Plan, QueryStage::Read, QueryStage::Filter],
+            stages
+        );
+
+        // without an exact entity or value everything is read to find the few that match
+        let query = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(Some(fetched.iter().next().unwrap().index()), None),
+            None,
+        );
+        let (_, profile) = eav_storage.fetch_eavi_profiled(&query).unwrap();
+        assert_eq!(QueryPlan::FullScan, profile.explanation.plan);
+        assert_eq!(20, profile.scanned);
+        assert!(profile.returned < profile.scanned);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn lmdb_eav_parallel_full_scan() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let entity = ExampleAddressableContent::try_from_content(&RawString::from("e").into())
+            .unwrap()
+            .address();
+        let count = super::PARALLEL_SCAN_THRESHOLD + 100;
+        for i in 0..count {
+            let value = ExampleAddressableContent::try_from_content(
+                &RawString::from(format!("v{}", i)).into(),
+            )
+            .unwrap()
+            .address();
+            let eav = EntityAttributeValueIndex::new(&entity, &ExampleAttribute::default(), &value)
+                .unwrap();
+            eav_storage.add_eavi(&eav).unwrap();
+        }
+
+        let query = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(QueryPlan::FullScan, eav_storage.query_plan(&query).unwrap());
+        let fetched = eav_storage.fetch_eavi(&query).unwrap();
+        assert_eq!(count, fetched.len());
     }
 
     #[bench]
@@ -225,7 +1335,7 @@ pub mod tests {
     fn lmdb_eav_one_to_many() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavLmdbStorage::new(temp_path, None);
+        let eav_storage = EavLmdbStorage::new(temp_path, None, None);
         EavTestSuite::test_one_to_many::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -237,7 +1347,7 @@ pub mod tests {
     fn lmdb_eav_many_to_one() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavLmdbStorage::new(temp_path, None);
+        let eav_storage = EavLmdbStorage::new(temp_path, None, None);
         EavTestSuite::test_many_to_one::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -249,7 +1359,7 @@ pub mod tests {
     fn lmdb_eav_range() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavLmdbStorage::new(temp_path, None);
+        let eav_storage = EavLmdbStorage::new(temp_path, None, None);
         EavTestSuite::test_range::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -257,11 +1367,111 @@ pub mod tests {
         >(eav_storage, &ExampleAttribute::default());
     }
 
+    #[test]
+    fn lmdb_eav_upsert() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_upsert::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavLmdbStorage<ExampleAttribute>,
+        >(
+            eav_storage,
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_iter() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_iter::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavLmdbStorage<ExampleAttribute>,
+        >(eav_storage, ExampleAttribute::WithoutPayload);
+    }
+
+    #[test]
+    fn lmdb_eav_entities_with_attribute() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_entities_with_attribute::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavLmdbStorage<ExampleAttribute>,
+        >(
+            eav_storage,
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+                ExampleAttribute::WithoutPayload,
+            ],
+        );
+
+        // more entities than are read per transaction, each with several EAVIs
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let (published, other) = (
+            ExampleAttribute::WithPayload("published".to_string()),
+            ExampleAttribute::WithPayload("other".to_string()),
+        );
+        let entities: BTreeSet<Address> = (0..ENTITY_BATCH * 2 + 1)
+            .map(|i| Address::from(format!("agent{}", i)))
+            .collect();
+        for entity in &entities {
+            for attribute in &[&published, &published, &other] {
+                let eavi = EntityAttributeValueIndex::new(entity, *attribute, entity).unwrap();
+                eav_storage.add_eavi(&eavi).unwrap();
+            }
+        }
+        let found: PersistenceResult<BTreeSet<Address>> =
+            eav_storage.entities_with_attribute(&published).collect();
+        assert_eq!(Ok(entities), found);
+    }
+
+    #[test]
+    fn lmdb_eav_distinct() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_distinct::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavLmdbStorage<ExampleAttribute>,
+        >(
+            eav_storage,
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_ordering() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_ordering::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavLmdbStorage<ExampleAttribute>,
+        >(
+            eav_storage,
+            vec![
+                ExampleAttribute::WithPayload("b_".to_string()),
+                ExampleAttribute::WithPayload("a_".to_string()),
+            ],
+        );
+    }
+
     #[test]
     fn lmdb_eav_prefixes() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavLmdbStorage::new(temp_path, None);
+        let eav_storage = EavLmdbStorage::new(temp_path, None, None);
         EavTestSuite::test_multiple_attributes::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -275,11 +1485,360 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn lmdb_eav_reads_eavis_written_in_other_formats() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut json_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: &'static str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let eavi = |entity: &'static str| {
+            EntityAttributeValueIndex::new(
+                &address(entity),
+                &ExampleAttribute::default(),
+                &address("value"),
+            )
+            .unwrap()
+        };
+        let old = json_storage.add_eavi(&eavi("old")).unwrap().unwrap();
+
+        let mut cbor_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None)
+                .with_serialization_format(SerializationFormat::Cbor);
+        let new = cbor_storage.add_eavi(&eavi("new")).unwrap().unwrap();
+        let queued = cbor_storage
+            .add_eavi_async(&eavi("queued"))
+            .unwrap()
+            .wait()
+            .unwrap();
+
+        // through the value index and through a full scan
+        let by_value = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Some(address("value")).into(),
+            IndexFilter::LatestByAttribute,
+            None,
+        );
+        let expected: BTreeSet<_> = vec![old, new, queued].into_iter().collect();
+        for storage in &[&json_storage, &cbor_storage] {
+            assert_eq!(expected, storage.fetch_eavi(&by_value).unwrap());
+            assert_eq!(expected, storage.fetch_eavi(&EaviQuery::default()).unwrap());
+        }
+    }
+
+    #[test]
+    fn lmdb_eav_rewrite_all_with_value_index() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: String| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let mut expected = BTreeSet::new();
+        for i in 0..12 {
+            let eavi = EntityAttributeValueIndex::new(
+                &address(format!("e{}", i)),
+                &ExampleAttribute::default(),
+                &address(format!("v{}", i % 3)),
+            )
+            .unwrap();
+            expected.insert(eav_storage.add_eavi(&eavi).unwrap().unwrap());
+        }
+
+        let mut batches = 0;
+        let done = eav_storage
+            .rewrite_all(
+                SerializationFormat::Cbor,
+                5,
+                RewriteProgress::default(),
+                |_| batches += 1,
+            )
+            .unwrap();
+        assert_eq!((3, 12, 12), (batches, done.visited, done.rewritten));
+
+        // both the main store and the value index were rewritten
+        let stored_formats = |store: SingleStore| {
+            eav_storage
+                .lmdb
+                .read(|reader| {
+                    let mut formats = Vec::new();
+                    for entry in store.iter_start(reader)? {
+                        formats.push(match entry?.1 {
+                            Some(Value::Blob(bytes)) => SerializationFormat::of(bytes),
+                            _ => Some(SerializationFormat::Json),
+                        });
+                    }
+                    Ok(formats)
+                })
+                .unwrap()
+        };
+        for store in &[eav_storage.lmdb.store, eav_storage.values] {
+            let formats = stored_formats(*store);
+            assert_eq!(12, formats.len());
+            assert!(formats
+                .iter()
+                .all(|f| *f == Some(SerializationFormat::Cbor)));
+        }
+
+        let by_value = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Some(address("v1".to_string())).into(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(4, eav_storage.fetch_eavi(&by_value).unwrap().len());
+        assert_eq!(
+            expected,
+            eav_storage
+                .fetch_eavi(&EaviQuery::new(
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    IndexFilter::Range(None, None),
+                    None,
+                ))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_reindex_rebuilds_dropped_indexes() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: String| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        for i in 0..12 {
+            let eavi = EntityAttributeValueIndex::new(
+                &address(format!("e{}", i)),
+                &ExampleAttribute::default(),
+                &address(format!("v{}", i % 3)),
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap();
+        }
+        let by_value = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Some(address("v1".to_string())).into(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let index_entries = |store: SingleStore| {
+            eav_storage
+                .lmdb
+                .read(|reader| Ok(store.iter_start(reader)?.count()))
+                .unwrap()
+        };
+
+        // a value index entry that points nowhere, as an old key layout would leave behind
+        eav_storage
+            .lmdb
+            .put_many(&[(eav_storage.values, "stale", Value::Json("{}"))])
+            .unwrap();
+        assert_eq!(13, index_entries(eav_storage.values));
+
+        let mut batches = Vec::new();
+        let done = eav_storage
+            .reindex(5, RewriteProgress::default(), |done| {
+                batches.push(done.clone())
+            })
+            .unwrap();
+        assert_eq!((3, 12, 12), (batches.len(), done.visited, done.rewritten));
+
+        // resuming after the first batch leaves the entries of that batch as they are
+        let resumed = eav_storage.reindex(5, batches[0].clone(), |_| ()).unwrap();
+        assert_eq!(done, resumed);
+
+        assert_eq!(12, index_entries(eav_storage.values));
+        assert_eq!(12, index_entries(eav_storage.attributes));
+        assert_eq!(4, eav_storage.fetch_eavi(&by_value).unwrap().len());
+        assert_eq!(12, eav_storage.stats().unwrap().total);
+    }
+
+    #[test]
+    fn lmdb_eav_persists_its_stats() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None);
+        let address = |s: String| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        for i in 0..10 {
+            let eavi = EntityAttributeValueIndex::new(
+                &address(format!("e{}", i % 4)),
+                &ExampleAttribute::WithPayload(format!("a{}", i % 2)),
+                &address(format!("v{}", i % 3)),
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap();
+        }
+        let stats = eav_storage.stats().unwrap();
+        assert_eq!((10, 4, 3), (stats.total, stats.entities, stats.values));
+
+        // reopening reads the stored counters
+        let reopened: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::open(temp.path(), None, None).unwrap();
+        assert_eq!(stats, reopened.stats().unwrap());
+
+        // a store without stored counters, as older stores are, is counted when it is opened
+        eav_storage
+            .lmdb
+            .write(|writer| eav_storage.stats_store.clear(writer))
+            .unwrap();
+        let counted: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::open(temp.path(), None, None).unwrap();
+        assert_eq!(stats, counted.stats().unwrap());
+
+        // an entry that isn't an EAVI fails the count instead of panicking
+        eav_storage
+            .lmdb
+            .write(|writer| {
+                eav_storage.stats_store.clear(writer)?;
+                eav_storage
+                    .lmdb
+                    .store
+                    .put(writer, "bad", &Value::Json("{}"))
+            })
+            .unwrap();
+        assert!(EavLmdbStorage::<ExampleAttribute>::open(temp.path(), None, None).is_err());
+    }
+
+    #[test]
+    fn lmdb_eav_limits_eavis_per_attribute() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None).with_limits(Limits {
+                max_eavis_per_attribute: Some(2),
+                ..Limits::default()
+            });
+        let address = |s: &str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let link = |entity: &str, attribute: &ExampleAttribute, value: &str| {
+            EntityAttributeValueIndex::new(&address(entity), attribute, &address(value)).unwrap()
+        };
+        let linked = ExampleAttribute::WithPayload("linked".to_string());
+        eav_storage.add_eavi(&link("a", &linked, "b")).unwrap();
+        eav_storage.add_eavi(&link("a", &linked, "c")).unwrap();
+        match eav_storage.add_eavi(&link("a", &linked, "d")) {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+        match eav_storage
+            .add_eavi_async(&link("a", &linked, "d"))
+            .and_then(|receipt| receipt.wait())
+        {
+            Err(PersistenceError::LimitExceeded(_)) => (),
+            other => panic!("expected too many EAVIs, got {:?}", other),
+        }
+
+        // other entities and attributes have counts of their own, upserts replace
+        eav_storage.add_eavi(&link("b", &linked, "d")).unwrap();
+        eav_storage
+            .add_eavi(&link("a", &ExampleAttribute::WithoutPayload, "d"))
+            .unwrap();
+        eav_storage.upsert_eavi(&link("a", &linked, "d")).unwrap();
+    }
+
+    #[test]
+    fn lmdb_eav_quarantines_eavis_failing_their_checksum() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None).with_checksums();
+        let address = |s: &'static str| {
+            ExampleAddressableContent::try_from_content(&RawString::from(s).into())
+                .unwrap()
+                .address()
+        };
+        let eavi = |entity: &'static str| {
+            EntityAttributeValueIndex::new(
+                &address(entity),
+                &ExampleAttribute::default(),
+                &address("value"),
+            )
+            .unwrap()
+        };
+        let intact = eav_storage.add_eavi(&eavi("intact")).unwrap().unwrap();
+        let corrupt = eav_storage.add_eavi(&eavi("corrupt")).unwrap().unwrap();
+        let reopened_corrupt = eav_storage.add_eavi(&eavi("reopened")).unwrap().unwrap();
+        let key = |eavi: &EntityAttributeValueIndex<ExampleAttribute>| {
+            format!("{}::{}", eavi.entity(), eavi.index())
+        };
+        tamper(
+            &eav_storage.lmdb,
+            eav_storage.lmdb.store,
+            key(&corrupt).as_bytes(),
+        );
+
+        match eav_storage.fetch_eavi(&EaviQuery::default()) {
+            Err(PersistenceError::Corruption(_)) => (),
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        let expected: BTreeSet<_> = vec![intact, reopened_corrupt.clone()].into_iter().collect();
+        assert_eq!(
+            expected,
+            eav_storage.fetch_eavi(&EaviQuery::default()).unwrap()
+        );
+
+        // found while counting what is stored when the store is opened again
+        tamper(
+            &eav_storage.lmdb,
+            eav_storage.lmdb.store,
+            key(&reopened_corrupt).as_bytes(),
+        );
+        let reopened: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None);
+        assert_eq!(1, reopened.stats().unwrap().total);
+        let quarantined: Vec<_> = reopened
+            .quarantined()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.key)
+            .collect();
+        assert_eq!(2, quarantined.len());
+        assert!(quarantined.contains(&key(&corrupt)));
+        assert!(quarantined.contains(&key(&reopened_corrupt)));
+        assert_eq!(Ok(2), reopened.purge_quarantine());
+    }
+
+    #[test]
+    fn lmdb_eav_attribute_histogram() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage = EavLmdbStorage::new(temp.path(), None, None);
+        EavTestSuite::test_attribute_histogram::<ExampleAddressableContent, _, _>(
+            eav_storage.clone(),
+            vec![
+                ExampleAttribute::default(),
+                ExampleAttribute::WithoutPayload,
+            ],
+        );
+
+        // rebuilt from what is stored when the store is opened again
+        let reopened: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(temp.path(), None, None);
+        assert_eq!(
+            eav_storage.attribute_histogram().unwrap(),
+            reopened.attribute_histogram().unwrap()
+        );
+    }
+
     #[test]
     fn lmdb_tombstone() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavLmdbStorage::new(temp_path, None);
+        let eav_storage = EavLmdbStorage::new(temp_path, None, None);
         EavTestSuite::test_tombstone::<ExampleAddressableContent, EavLmdbStorage<_>>(eav_storage)
     }
 }
@@ -1 +1,5 @@
+#[cfg(feature = "analytics")]
+pub mod analytics;
 pub mod lmdb;
+pub mod plan;
+mod stats;
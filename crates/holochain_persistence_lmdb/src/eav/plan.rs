@@ -0,0 +1,312 @@
+//! Statistics and query planning for the LMDB EAV store.
+//!
+//! EAVIs can be found through the entity prefixed main store, through the value index or by
+//! scanning everything. The store keeps row counts up to date on every write and uses them to
+//! pick the access path that reads the fewest EAVIs for a given query. Plans are cached per
+//! query shape and only recomputed once the store has grown or shrunk enough to matter.
+//!
+//! `EavLmdbStorage::explain` tells which plan a query gets and what it is expected to read,
+//! `EavLmdbStorage::fetch_eavi_profiled` runs it and reports what it actually took.
+
+use holochain_persistence_api::eav::{
+    Attribute, AttributeHistogram, AttributeUsage, EavFilter, EaviQuery,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+/// How a query reads EAVIs out of the store before filtering them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueryPlan {
+    /// iterate only the keys of the queried entity
+    EntityPrefix,
+    /// iterate only the value index entries of the queried value
+    ValuePrefix,
+    /// iterate the whole store
+    FullScan,
+}
+
+/// The plan of a query and what running it is expected to cost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryExplanation {
+    pub plan: QueryPlan,
+    /// expected number of EAVIs read from the store
+    pub estimated_scanned: u64,
+    /// expected number of EAVIs returned
+    pub estimated_returned: u64,
+}
+
+/// The stages a query runs through, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueryStage {
+    /// picking the plan
+    Plan,
+    /// reading and decoding the EAVIs the plan goes through
+    Read,
+    /// filtering the EAVIs read down to those the query asks for
+    Filter,
+}
+
+/// What running a query took, next to what was expected of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryProfile {
+    pub explanation: QueryExplanation,
+    /// time spent in each stage
+    pub stages: Vec<(QueryStage, Duration)>,
+    /// number of EAVIs read from the store
+    pub scanned: u64,
+    /// number of EAVIs returned
+    pub returned: u64,
+}
+
+impl QueryProfile {
+    /// time spent in all stages
+    pub fn total(&self) -> Duration {
+        self.stages.iter().map(|(_, took)| *took).sum()
+    }
+}
+
+/// Row counts the EAV store maintains on every write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EavStats<A: Attribute> {
+    /// number of EAVIs stored
+    pub total: u64,
+    /// number of distinct entities
+    pub entities: u64,
+    /// number of distinct values
+    pub values: u64,
+    /// number of EAVIs stored for each attribute
+    pub per_attribute: BTreeMap<A, u64>,
+    /// size of the EAVIs stored for each attribute, as JSON
+    pub attribute_bytes: BTreeMap<A, u64>,
+}
+
+impl<A: Attribute> Default for EavStats<A> {
+    fn default() -> EavStats<A> {
+        EavStats {
+            total: 0,
+            entities: 0,
+            values: 0,
+            per_attribute: BTreeMap::new(),
+            attribute_bytes: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: Attribute> EavStats<A> {
+    pub(crate) fn record(&mut self, attribute: A, bytes: u64, new_entity: bool, new_value: bool) {
+        self.total += 1;
+        self.entities += new_entity as u64;
+        self.values += new_value as u64;
+        *self.attribute_bytes.entry(attribute.clone()).or_insert(0) += bytes;
+        *self.per_attribute.entry(attribute).or_insert(0) += 1;
+    }
+
+    /// Takes a deleted EAVI out of the counts. The distinct entity and value counts are only
+    /// used for estimates and are left as they are.
+    pub(crate) fn forget(&mut self, attribute: &A, bytes: u64) {
+        self.total = self.total.saturating_sub(1);
+        if let Some(count) = self.per_attribute.get_mut(attribute) {
+            *count = count.saturating_sub(1);
+        }
+        if let Some(total) = self.attribute_bytes.get_mut(attribute) {
+            *total = total.saturating_sub(bytes);
+        }
+    }
+
+    pub fn attribute_histogram(&self) -> AttributeHistogram<A> {
+        self.per_attribute
+            .iter()
+            .map(|(attribute, count)| {
+                let bytes = self.attribute_bytes.get(attribute).cloned().unwrap_or(0);
+                let usage = AttributeUsage {
+                    count: *count,
+                    bytes,
+                };
+                (attribute.clone(), usage)
+            })
+            .collect()
+    }
+
+    /// expected number of EAVIs a plan has to read
+    pub fn scanned(&self, plan: QueryPlan) -> u64 {
+        let per = |distinct: u64| (self.total + distinct.max(1) - 1) / distinct.max(1);
+        match plan {
+            QueryPlan::EntityPrefix => per(self.entities),
+            QueryPlan::ValuePrefix => per(self.values),
+            QueryPlan::FullScan => self.total,
+        }
+    }
+
+    /// expected number of EAVIs a query returns when run with the given plan,
+    /// narrowed down by the attribute cardinality if the query asks for an exact attribute
+    pub fn estimate(&self, plan: QueryPlan, query: &EaviQuery<A>) -> u64 {
+        let scanned = self.scanned(plan);
+        match &query.attribute {
+            EavFilter::Exact(attribute) if self.total > 0 => {
+                let matching = self.per_attribute.get(attribute).cloned().unwrap_or(0);
+                (scanned * matching + self.total - 1) / self.total
+            }
+            _ => scanned,
+        }
+    }
+
+    pub fn explain(&self, plan: QueryPlan, query: &EaviQuery<A>) -> QueryExplanation {
+        QueryExplanation {
+            plan,
+            estimated_scanned: self.scanned(plan),
+            estimated_returned: self.estimate(plan, query),
+        }
+    }
+
+    /// the cheapest plan for a query of the given shape
+    fn best_plan(&self, shape: QueryShape) -> QueryPlan {
+        match (shape.exact_entity, shape.exact_value) {
+            (true, true) => {
+                if self.scanned(QueryPlan::ValuePrefix) < self.scanned(QueryPlan::EntityPrefix) {
+                    QueryPlan::ValuePrefix
+                } else {
+                    QueryPlan::EntityPrefix
+                }
+            }
+            (true, false) => QueryPlan::EntityPrefix,
+            (false, true) => QueryPlan::ValuePrefix,
+            (false, false) => QueryPlan::FullScan,
+        }
+    }
+}
+
+/// The parts of a query that decide which plans can be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct QueryShape {
+    exact_entity: bool,
+    exact_value: bool,
+}
+
+impl QueryShape {
+    fn of<A: Attribute>(query: &EaviQuery<A>) -> QueryShape {
+        QueryShape {
+            exact_entity: match query.entity {
+                EavFilter::Exact(_) => true,
+                _ => false,
+            },
+            exact_value: match query.value {
+                EavFilter::Exact(_) => true,
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PlanCache {
+    /// plan and the number of EAVIs stored when it was made
+    plans: HashMap<QueryShape, (QueryPlan, u64)>,
+}
+
+impl PlanCache {
+    pub fn plan<A: Attribute>(&mut self, query: &EaviQuery<A>, stats: &EavStats<A>) -> QueryPlan {
+        let shape = QueryShape::of(query);
+        match self.plans.get(&shape) {
+            // a plan stays good while the store is within a factor of two of its size back then
+            Some((plan, planned_at))
+                if stats.total / 2 <= *planned_at && *planned_at / 2 <= stats.total =>
+            {
+                *plan
+            }
+            _ => {
+                let plan = stats.best_plan(shape);
+                self.plans.insert(shape, (plan, stats.total));
+                plan
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holochain_persistence_api::eav::{ExampleAttribute, IndexFilter};
+
+    fn stats(total: u64, entities: u64, values: u64) -> EavStats<ExampleAttribute> {
+        EavStats {
+            total,
+            entities,
+            values,
+            per_attribute: BTreeMap::new(),
+            attribute_bytes: BTreeMap::new(),
+        }
+    }
+
+    fn query<'a>(entity: bool, value: bool) -> EaviQuery<'a, ExampleAttribute> {
+        let exact = |exact: bool| {
+            if exact {
+                EavFilter::Exact("Qm".into())
+            } else {
+                EavFilter::default()
+            }
+        };
+        EaviQuery::new(
+            exact(entity),
+            EavFilter::default(),
+            exact(value),
+            IndexFilter::LatestByAttribute,
+            None,
+        )
+    }
+
+    #[test]
+    fn picks_the_more_selective_index() {
+        let mut cache = PlanCache::default();
+        // few entities with many EAVIs each, values are nearly unique
+        let stats = stats(1000, 2, 900);
+        assert_eq!(
+            QueryPlan::ValuePrefix,
+            cache.plan(&query(true, true), &stats)
+        );
+        assert_eq!(
+            QueryPlan::EntityPrefix,
+            cache.plan(&query(true, false), &stats)
+        );
+        assert_eq!(
+            QueryPlan::ValuePrefix,
+            cache.plan(&query(false, true), &stats)
+        );
+        assert_eq!(
+            QueryPlan::FullScan,
+            cache.plan(&query(false, false), &stats)
+        );
+        assert_eq!(4, cache.plans.len());
+    }
+
+    #[test]
+    fn replans_once_the_store_has_changed_enough() {
+        let mut cache = PlanCache::default();
+        assert_eq!(
+            QueryPlan::ValuePrefix,
+            cache.plan(&query(true, true), &stats(100, 10, 100))
+        );
+        // still within a factor of two, the cached plan is kept
+        assert_eq!(
+            QueryPlan::ValuePrefix,
+            cache.plan(&query(true, true), &stats(150, 150, 10))
+        );
+        assert_eq!(
+            QueryPlan::EntityPrefix,
+            cache.plan(&query(true, true), &stats(1000, 1000, 10))
+        );
+    }
+
+    #[test]
+    fn estimate_uses_attribute_cardinality() {
+        let mut stats = stats(100, 10, 50);
+        stats
+            .per_attribute
+            .insert(ExampleAttribute::WithoutPayload, 20);
+        let mut query = query(true, false);
+        assert_eq!(10, stats.estimate(QueryPlan::EntityPrefix, &query));
+        query.attribute = EavFilter::Exact(ExampleAttribute::WithoutPayload);
+        assert_eq!(2, stats.estimate(QueryPlan::EntityPrefix, &query));
+    }
+}
@@ -0,0 +1,290 @@
+//! The statistics of the LMDB EAV store, kept in a store of their own.
+//!
+//! `EavStats` are stored as counters in the `EAV_STATS` store of the environment and updated in
+//! the transaction of every write, so opening a store reads a counter per attribute instead of
+//! decoding every EAVI. A store written before there were persisted statistics is counted once
+//! when it is opened, its value and attribute indexes rebuilt along the way, a batch per
+//! transaction. Until the count is done the store isn't marked as counted, so a count that
+//! didn't finish starts over on the next open.
+//!
+//! EAVIs quarantined after failing their checksum can't be decoded to tell what they counted
+//! for, so they stay in the counts. The statistics are only used to plan queries, `reindex`
+//! counts them anew.
+
+use crate::{
+    checksum,
+    common::{write_error, Encoded, LmdbInstance},
+    eav::{
+        lmdb::{attribute_key, has_prefix, raw_json, value_key},
+        plan::EavStats,
+    },
+};
+use holochain_persistence_api::{
+    eav::{Attribute, EntityAttributeValueIndex},
+    error::{PersistenceError, PersistenceResult},
+};
+use rkv::{Readable, SingleStore, StoreError, Value, Writer};
+use serde::de::DeserializeOwned;
+
+/// EAVIs counted per transaction when a store is counted
+const COUNT_BATCH: usize = 1024;
+
+const TOTAL: &str = "total";
+const ENTITIES: &str = "entities";
+const VALUES: &str = "values";
+/// set once every EAVI stored has been counted
+const COUNTED: &str = "counted";
+/// followed by the JSON of an attribute
+const COUNT_PREFIX: &str = "count::";
+const BYTES_PREFIX: &str = "bytes::";
+
+fn stats_error<E: std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::from(format!("EAV statistics error: {}", e))
+}
+
+fn attribute_json<A: Attribute>(attribute: &A) -> String {
+    serde_json::to_string(attribute).unwrap_or_default()
+}
+
+fn counter<T: Readable>(stats: SingleStore, reader: &T, key: &str) -> Result<u64, StoreError> {
+    match stats.get(reader, key)? {
+        Some(Value::U64(count)) => Ok(count),
+        _ => Ok(0),
+    }
+}
+
+/// Adds `delta` to the counter at `key`, not going below zero.
+fn add(stats: SingleStore, writer: &mut Writer, key: &str, delta: i64) -> Result<(), StoreError> {
+    let count = counter(stats, writer, key)?;
+    let count = if delta < 0 {
+        count.saturating_sub(-delta as u64)
+    } else {
+        count.saturating_add(delta as u64)
+    };
+    stats.put(writer, key, &Value::U64(count))
+}
+
+/// Counts an EAVI written in the same transaction.
+pub(crate) fn record<A: Attribute>(
+    stats: SingleStore,
+    writer: &mut Writer,
+    attribute: &A,
+    bytes: u64,
+    new_entity: bool,
+    new_value: bool,
+) -> Result<(), StoreError> {
+    let attribute = attribute_json(attribute);
+    add(stats, writer, TOTAL, 1)?;
+    add(stats, writer, ENTITIES, new_entity as i64)?;
+    add(stats, writer, VALUES, new_value as i64)?;
+    add(stats, writer, &format!("{}{}", COUNT_PREFIX, attribute), 1)?;
+    add(
+        stats,
+        writer,
+        &format!("{}{}", BYTES_PREFIX, attribute),
+        bytes as i64,
+    )
+}
+
+/// Takes an EAVI deleted in the same transaction out of the counts, see `EavStats::forget`.
+pub(crate) fn forget<A: Attribute>(
+    stats: SingleStore,
+    writer: &mut Writer,
+    attribute: &A,
+    bytes: u64,
+) -> Result<(), StoreError> {
+    let attribute = attribute_json(attribute);
+    add(stats, writer, TOTAL, -1)?;
+    add(stats, writer, &format!("{}{}", COUNT_PREFIX, attribute), -1)?;
+    add(
+        stats,
+        writer,
+        &format!("{}{}", BYTES_PREFIX, attribute),
+        -(bytes as i64),
+    )
+}
+
+/// The stored statistics, None if the store hasn't been counted yet.
+fn read<A>(lmdb: &LmdbInstance, stats: SingleStore) -> PersistenceResult<Option<EavStats<A>>>
+where
+    A: Attribute + DeserializeOwned,
+{
+    let counters = lmdb
+        .read(|reader| {
+            if stats.get(reader, COUNTED)?.is_none() {
+                return Ok(None);
+            }
+            let mut counters = Vec::new();
+            for entry in stats.iter_start(reader)? {
+                if let (key, Some(Value::U64(count))) = entry? {
+                    counters.push((String::from_utf8_lossy(key).to_string(), count));
+                }
+            }
+            Ok(Some(counters))
+        })
+        .map_err(stats_error)?;
+    let counters = match counters {
+        Some(counters) => counters,
+        None => return Ok(None),
+    };
+    let mut read = EavStats::default();
+    for (key, count) in counters {
+        if key == TOTAL {
+            read.total = count;
+        } else if key == ENTITIES {
+            read.entities = count;
+        } else if key == VALUES {
+            read.values = count;
+        } else if key.starts_with(COUNT_PREFIX) {
+            let attribute =
+                serde_json::from_str(&key[COUNT_PREFIX.len()..]).map_err(stats_error)?;
+            read.per_attribute.insert(attribute, count);
+        } else if key.starts_with(BYTES_PREFIX) {
+            let attribute =
+                serde_json::from_str(&key[BYTES_PREFIX.len()..]).map_err(stats_error)?;
+            read.attribute_bytes.insert(attribute, count);
+        }
+    }
+    Ok(Some(read))
+}
+
+/// The statistics of the store, counting it first if it wasn't counted before.
+pub(crate) fn load_or_count<A>(
+    lmdb: &LmdbInstance,
+    stats: SingleStore,
+    values: SingleStore,
+    attributes: SingleStore,
+) -> PersistenceResult<EavStats<A>>
+where
+    A: Attribute + DeserializeOwned,
+{
+    match read(lmdb, stats)? {
+        Some(read) => Ok(read),
+        None => count(lmdb, stats, values, attributes, true),
+    }
+}
+
+/// Counts every EAVI stored, a batch per transaction, replacing the stored statistics. With
+/// `rebuild_indexes` the value and attribute indexes are dropped and rebuilt in the same
+/// transactions, otherwise they have to be complete already.
+pub(crate) fn count<A>(
+    lmdb: &LmdbInstance,
+    stats: SingleStore,
+    values: SingleStore,
+    attributes: SingleStore,
+    rebuild_indexes: bool,
+) -> PersistenceResult<EavStats<A>>
+where
+    A: Attribute + DeserializeOwned,
+{
+    let count_error = |e| write_error(e, "EAV statistics error");
+    lmdb.write(|writer| {
+        stats.clear(writer)?;
+        if rebuild_indexes {
+            values.clear(writer)?;
+            attributes.clear(writer)?;
+        }
+        Ok(())
+    })
+    .map_err(count_error)?;
+
+    // the main store is sorted by entity, so an entity is new whenever it differs from the one
+    // before
+    let mut last_entity = None;
+    let mut from: Option<Vec<u8>> = None;
+    loop {
+        let (batch, corrupt, next) = read_batch(lmdb, from.as_deref())?;
+        for key in corrupt {
+            checksum::quarantine(lmdb, lmdb.store, &key).map_err(count_error)?;
+        }
+        let mut counted = Vec::with_capacity(batch.len());
+        for (json, stored) in batch {
+            let eavi: EntityAttributeValueIndex<A> =
+                serde_json::from_str(&json).map_err(stats_error)?;
+            let new_entity = last_entity.as_ref() != Some(&eavi.entity());
+            last_entity = Some(eavi.entity());
+            counted.push((eavi, json.len() as u64, stored, new_entity));
+        }
+        lmdb.write(|writer| {
+            for (eavi, bytes, stored, new_entity) in &counted {
+                let new_value = if rebuild_indexes {
+                    // the value index is rebuilt in the order EAVIs are counted
+                    let new_value = !has_prefix(values, writer, &format!("{}::", eavi.value()))?;
+                    values.put(writer, value_key(eavi), &stored.value())?;
+                    attributes.put(writer, attribute_key(eavi), &stored.value())?;
+                    new_value
+                } else {
+                    first_of_value(values, writer, eavi)?
+                };
+                record(
+                    stats,
+                    writer,
+                    &eavi.attribute(),
+                    *bytes,
+                    *new_entity,
+                    new_value,
+                )?;
+            }
+            Ok(())
+        })
+        .map_err(count_error)?;
+        from = match next {
+            Some(next) => Some(next),
+            None => break,
+        };
+    }
+    lmdb.write(|writer| stats.put(writer, COUNTED, &Value::Bool(true)))
+        .map_err(count_error)?;
+    read(lmdb, stats)?.ok_or_else(|| stats_error("the count was not stored"))
+}
+
+/// Whether the entry of the EAVI is the first of its value in the complete value index.
+fn first_of_value<A: Attribute, T: Readable>(
+    values: SingleStore,
+    reader: &T,
+    eavi: &EntityAttributeValueIndex<A>,
+) -> Result<bool, StoreError> {
+    let prefix = format!("{}::", eavi.value());
+    match values.iter_from(reader, &prefix)?.next() {
+        Some(Ok((key, _))) => Ok(key == value_key(eavi).as_bytes()),
+        Some(Err(e)) => Err(e),
+        None => Ok(false),
+    }
+}
+
+/// The JSON and stored value of up to `COUNT_BATCH` EAVIs from `from` on, the keys of those
+/// failing their checksum and the key the next batch starts at, None at the end of the store.
+#[allow(clippy::type_complexity)]
+fn read_batch(
+    lmdb: &LmdbInstance,
+    from: Option<&[u8]>,
+) -> PersistenceResult<(Vec<(String, Encoded)>, Vec<Vec<u8>>, Option<Vec<u8>>)> {
+    lmdb.read(|reader| {
+        let entries = match from {
+            Some(from) => lmdb.store.iter_from(reader, from)?,
+            None => lmdb.store.iter_start(reader)?,
+        };
+        let mut batch = Vec::new();
+        let mut corrupt = Vec::new();
+        for entry in entries {
+            let (key, value) = entry?;
+            if batch.len() + corrupt.len() == COUNT_BATCH {
+                return Ok((batch, corrupt, Some(key.to_vec())));
+            }
+            let stored = match value {
+                Some(Value::Json(json)) => Encoded::Json(json.to_string()),
+                Some(Value::Blob(bytes)) => Encoded::Blob(bytes.to_vec()),
+                _ => continue,
+            };
+            match raw_json(Ok((key, value))) {
+                Ok(json) => batch.push((json.into_owned(), stored)),
+                Err(e) => match checksum::mismatched_key(&e) {
+                    Some(key) => corrupt.push(key),
+                    None => return Err(e),
+                },
+            }
+        }
+        Ok((batch, corrupt, None))
+    })
+    .map_err(stats_error)
+}
@@ -0,0 +1,78 @@
+//! The `StoreIdentity` of a store, kept in a `META` store of its environment.
+
+use crate::common::{write_error, LmdbInstance, META};
+use holochain_persistence_api::{error::PersistenceResult, identity::StoreIdentity};
+use rkv::{value::Type, DataError, StoreError, Value};
+
+const IDENTITY: &str = "identity";
+
+/// The identity stored in the environment, storing `fresh` if there is none yet.
+pub(crate) fn load_or_create(
+    lmdb: &LmdbInstance,
+    fresh: &StoreIdentity,
+) -> PersistenceResult<StoreIdentity> {
+    let meta = lmdb.open_store(META);
+    let fresh_json = serde_json::to_string(fresh)?;
+    let stored = lmdb
+        .write(|writer| match meta.get(writer, IDENTITY)? {
+            Some(Value::Json(json)) => Ok(Some(json.to_string())),
+            Some(other) => Err(StoreError::DataError(DataError::UnexpectedType {
+                expected: Type::Json,
+                actual: Type::from_tag(other.to_bytes()?[0])?,
+            })),
+            None => {
+                meta.put(writer, IDENTITY, &Value::Json(&fresh_json))?;
+                Ok(None)
+            }
+        })
+        .map_err(|e| write_error(e, "LMDB identity error"))?;
+    match stored {
+        Some(json) => Ok(serde_json::from_str(&json)?),
+        None => Ok(fresh.clone()),
+    }
+}
+
+/// Replaces the identity stored in the environment with `fresh`, e.g. for a copy of a store.
+pub(crate) fn replace(lmdb: &LmdbInstance, fresh: &StoreIdentity) -> PersistenceResult<()> {
+    let meta = lmdb.open_store(META);
+    let json = serde_json::to_string(fresh)?;
+    lmdb.write(|writer| meta.put(writer, IDENTITY, &Value::Json(&json)))
+        .map_err(|e| write_error(e, "LMDB identity error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cas::lmdb::LmdbStorage, eav::lmdb::EavLmdbStorage};
+    use holochain_persistence_api::{
+        cas::storage::ContentAddressableStorage, eav::ExampleAttribute, identity::FORMAT_VERSION,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn identity_is_kept_across_opens() {
+        let dir = tempdir().expect("Could not create a tempdir for identity testing");
+        let (cas_path, eav_path) = (dir.path().join("cas"), dir.path().join("eav"));
+        let cas = LmdbStorage::new(&cas_path, None, None);
+        let eav: EavLmdbStorage<ExampleAttribute> = EavLmdbStorage::new(&eav_path, None, None);
+        assert_eq!(cas.identity().id, cas.get_id());
+        assert_eq!(None, cas.identity().attribute_type);
+        assert_eq!(FORMAT_VERSION, cas.identity().format_version);
+        assert!(eav
+            .identity()
+            .attribute_type
+            .as_ref()
+            .unwrap()
+            .ends_with("ExampleAttribute"));
+        assert_ne!(cas.identity().id, eav.identity().id);
+
+        let (cas_identity, eav_identity) = (cas.identity().clone(), eav.identity().clone());
+        drop((cas, eav));
+        let reopened_eav: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(&eav_path, None, None);
+        assert_eq!(
+            cas_identity,
+            *LmdbStorage::new(&cas_path, None, None).identity()
+        );
+        assert_eq!(eav_identity, *reopened_eav.identity());
+    }
+}
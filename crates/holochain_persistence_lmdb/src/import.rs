@@ -0,0 +1,221 @@
+//! Moving stores kept in the file layout of holochain-rust conductors into LMDB.
+//!
+//! Those conductors wrote their CAS to a `cas` directory, one `{address}.txt` file of JSON per
+//! address, and their EAV store to an `eav` directory, where every EAVI is a JSON file under
+//! `e/{entity}/{index}`, and again under `a/` and `v/` for the attribute and the value.
+//! `import_legacy` walks those files in path order and adds them to a manager, content before
+//! EAVIs, calling the progress callback after every batch. Handing the last `ImportProgress`
+//! back in resumes after the last file it saw; importing a file again is harmless too.
+
+use glob::glob;
+use holochain_json_api::{error::JsonError, json::JsonString};
+use holochain_persistence_api::{
+    cas::content::{Address, AddressableContent, Content},
+    eav::{Attribute, EntityAttributeValueIndex},
+    error::{PersistenceError, PersistenceResult},
+    registry::DynManager,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+/// How far an import has got.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// CAS entries imported
+    pub content: u64,
+    /// EAVIs imported
+    pub eavis: u64,
+    /// path of the last file imported, relative to the legacy directory
+    pub last_file: Option<PathBuf>,
+}
+
+/// Content stored at the address it had in the legacy CAS, which isn't always the hash of the
+/// content (agent IDs are stored at their key).
+struct LegacyContent {
+    address: Address,
+    content: Content,
+}
+
+impl AddressableContent for LegacyContent {
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(_content: &Content) -> Result<LegacyContent, JsonError> {
+        Err(JsonError::ErrorGeneric(
+            "legacy content has no address of its own".to_string(),
+        ))
+    }
+}
+
+fn import_error<E: std::fmt::Display>(path: &Path, e: E) -> PersistenceError {
+    PersistenceError::from(format!("legacy import error: {}: {}", path.display(), e))
+}
+
+/// The files to import, relative to `legacy_dir`, in the order they are imported.
+fn legacy_files(legacy_dir: &Path) -> PersistenceResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for pattern in &["cas/*.txt", "eav/e/*/*/*.txt"] {
+        let pattern = legacy_dir.join(pattern);
+        let paths = glob(&pattern.to_string_lossy()).map_err(|e| import_error(&pattern, e))?;
+        for path in paths {
+            let path = path.map_err(|e| import_error(e.path(), e.error()))?;
+            if let Ok(relative) = path.strip_prefix(legacy_dir) {
+                files.push(relative.to_path_buf());
+            }
+        }
+    }
+    // "cas" sorts before "eav"
+    files.sort();
+    Ok(files)
+}
+
+/// Imports the legacy stores in `legacy_dir` into `manager`, `batch_size` files between calls
+/// to `progress`. Pass the last progress back in as `resume` to carry on where an import
+/// stopped, or `ImportProgress::default()` to start from the beginning.
+pub fn import_legacy<A, F>(
+    legacy_dir: &Path,
+    manager: &mut dyn DynManager<A>,
+    batch_size: usize,
+    resume: ImportProgress,
+    mut progress: F,
+) -> PersistenceResult<ImportProgress>
+where
+    A: Attribute + DeserializeOwned,
+    F: FnMut(&ImportProgress),
+{
+    let batch_size = batch_size.max(1);
+    let mut done = resume;
+    let files: Vec<_> = legacy_files(legacy_dir)?
+        .into_iter()
+        .filter(|file| done.last_file.as_ref().map_or(true, |last| file > last))
+        .collect();
+    for batch in files.chunks(batch_size) {
+        for file in batch {
+            let path = legacy_dir.join(file);
+            let json = read_to_string(&path).map_err(|e| import_error(&path, e))?;
+            if file.starts_with("cas") {
+                let address = file
+                    .file_stem()
+                    .map(|stem| Address::from(stem.to_string_lossy().to_string()))
+                    .ok_or_else(|| import_error(&path, "no address"))?;
+                manager.cas().add(&LegacyContent {
+                    address,
+                    content: JsonString::from_json(&json),
+                })?;
+                done.content += 1;
+            } else {
+                let eavi = EntityAttributeValueIndex::<A>::try_from_content(
+                    &JsonString::from_json(json.trim_end()),
+                )
+                .map_err(|e| import_error(&path, e))?;
+                manager.eav_mut().add_eavi(&eavi)?;
+                done.eavis += 1;
+            }
+            done.last_file = Some(file.clone());
+        }
+        progress(&done);
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_legacy, ImportProgress};
+    use crate::{cas::lmdb::LmdbStorage, eav::lmdb::EavLmdbStorage};
+    use holochain_persistence_api::{
+        cas::{
+            content::{Address, AddressableContent, Content},
+            storage::ContentAddressableStorage,
+        },
+        eav::{
+            EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute,
+        },
+    };
+    use holochain_persistence_file::{cas::file::FilesystemStorage, eav::file::EavFileStorage};
+    use std::fs::{read_to_string, rename};
+    use tempfile::tempdir;
+
+    #[test]
+    fn legacy_stores_are_imported_and_resumed() {
+        let legacy = tempdir().expect("Could not create a tempdir for legacy stores");
+        let cas = FilesystemStorage::new(legacy.path().join("cas")).unwrap();
+        let mut eav = EavFileStorage::new(legacy.path().join("eav")).unwrap();
+        let content: Vec<_> = (0..5)
+            .map(|n| Content::from_json(&format!("{{\"n\":{}}}", n)))
+            .collect();
+        for pair in content.windows(2) {
+            cas.add(&pair[0]).unwrap();
+            eav.add_eavi(
+                &EntityAttributeValueIndex::new(
+                    &pair[0].address(),
+                    &ExampleAttribute::WithoutPayload,
+                    &pair[1].address(),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        }
+        // content stored at an address that isn't its hash
+        let agent = Address::from("HcAgentKey");
+        rename(
+            legacy
+                .path()
+                .join("cas")
+                .join(format!("{}.txt", content[0].address())),
+            legacy.path().join("cas").join("HcAgentKey.txt"),
+        )
+        .unwrap();
+
+        let dir = tempdir().expect("Could not create a tempdir for LMDB testing");
+        let mut manager = (
+            LmdbStorage::new(dir.path().join("cas"), None, None),
+            EavLmdbStorage::<ExampleAttribute>::new(dir.path().join("eav"), None, None),
+        );
+        let mut batches = Vec::new();
+        let stopped = import_legacy(
+            legacy.path(),
+            &mut manager,
+            3,
+            ImportProgress::default(),
+            |progress| batches.push(progress.clone()),
+        )
+        .unwrap();
+        assert_eq!(3, batches.len());
+        assert_eq!((4, 4), (stopped.content, stopped.eavis));
+        assert_eq!(
+            Ok(Some(content[1].clone())),
+            manager.0.fetch(&content[1].address())
+        );
+        assert_eq!(
+            Some(read_to_string(legacy.path().join("cas").join("HcAgentKey.txt")).unwrap()),
+            manager.0.fetch(&agent).unwrap().map(String::from)
+        );
+        assert_eq!(
+            4,
+            manager.1.fetch_eavi(&EaviQuery::default()).unwrap().len()
+        );
+
+        // resuming from the first batch imports the rest
+        let dir = tempdir().expect("Could not create a tempdir for LMDB testing");
+        let mut manager = (
+            LmdbStorage::new(dir.path().join("cas"), None, None),
+            EavLmdbStorage::<ExampleAttribute>::new(dir.path().join("eav"), None, None),
+        );
+        let resumed =
+            import_legacy(legacy.path(), &mut manager, 3, batches[0].clone(), |_| ()).unwrap();
+        assert_eq!(stopped, resumed);
+        assert_eq!(Ok(false), manager.0.contains(&agent));
+        assert_eq!(
+            4,
+            manager.1.fetch_eavi(&EaviQuery::default()).unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{
+    common::{write_error, LmdbInstance},
+    identity,
+};
+use holochain_persistence_api::{
+    error::{PersistenceError, PersistenceResult},
+    identity::StoreIdentity,
+    kv::{KvCursor, KvStorage},
+};
+use lmdb::Error as LmdbError;
+use rkv::{value::Type, DataError, StoreError, Value};
+use std::{
+    fmt::{Debug, Error, Formatter},
+    iter,
+    path::Path,
+};
+
+const KV_BUCKET: &str = "kv";
+
+#[derive(Clone)]
+pub struct KvLmdbStorage {
+    identity: StoreIdentity,
+    pub(crate) lmdb: LmdbInstance,
+}
+
+impl Debug for KvLmdbStorage {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_struct("KvLmdbStorage")
+            .field("id", &self.identity.id)
+            .finish()
+    }
+}
+
+fn read_error(e: StoreError) -> PersistenceError {
+    PersistenceError::from(format!("LMDB kv read error: {}", e))
+}
+
+fn stored_bytes(value: Option<Value>) -> Result<Option<Vec<u8>>, StoreError> {
+    match value {
+        None => Ok(None),
+        Some(Value::Blob(bytes)) => Ok(Some(bytes.to_vec())),
+        Some(other) => Err(StoreError::DataError(DataError::UnexpectedType {
+            expected: Type::Blob,
+            actual: Type::from_tag(other.to_bytes()?[0])?,
+        })),
+    }
+}
+
+impl KvLmdbStorage {
+    pub fn new<P: AsRef<Path> + Clone>(
+        db_path: P,
+        initial_map_bytes: Option<usize>,
+        max_readers: Option<u32>,
+    ) -> KvLmdbStorage {
+        let lmdb = LmdbInstance::new(KV_BUCKET, db_path, initial_map_bytes, max_readers);
+        let identity = identity::load_or_create(&lmdb, &StoreIdentity::new(None))
+            .expect("Could not load the store identity");
+        KvLmdbStorage { identity, lmdb }
+    }
+
+    /// What this store is, written when it was created.
+    pub fn identity(&self) -> &StoreIdentity {
+        &self.identity
+    }
+
+    /// Gives this store an identity of its own, e.g. after it was copied from another.
+    pub(crate) fn renew_identity(&mut self) -> PersistenceResult<()> {
+        let fresh = StoreIdentity::new(None);
+        identity::replace(&self.lmdb, &fresh)?;
+        self.identity = fresh;
+        Ok(())
+    }
+}
+
+impl KvStorage for KvLmdbStorage {
+    fn get(&self, key: &str) -> PersistenceResult<Option<Vec<u8>>> {
+        self.lmdb
+            .read(|reader| stored_bytes(self.lmdb.store.get(reader, key)?))
+            .map_err(read_error)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> PersistenceResult<()> {
+        self.lmdb
+            .add(key, &Value::Blob(value))
+            .map_err(|e| write_error(e, "LMDB kv write error"))
+    }
+
+    fn delete(&self, key: &str) -> PersistenceResult<bool> {
+        self.lmdb
+            .write(|writer| match self.lmdb.store.delete(writer, key) {
+                Ok(()) => Ok(true),
+                Err(StoreError::LmdbError(LmdbError::NotFound)) => Ok(false),
+                Err(e) => Err(e),
+            })
+            .map_err(|e| write_error(e, "LMDB kv write error"))
+    }
+
+    fn scan<'a>(&'a self, prefix: &str) -> KvCursor<'a> {
+        let entries = self.lmdb.read(|reader| {
+            let mut entries = Vec::new();
+            // LMDB doesn't take empty keys, not even to seek to
+            let cursor = match prefix {
+                "" => self.lmdb.store.iter_start(reader)?,
+                prefix => self.lmdb.store.iter_from(reader, prefix)?,
+            };
+            for entry in cursor {
+                let (key, value) = entry?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                if let Some(value) = stored_bytes(value)? {
+                    entries.push((String::from_utf8_lossy(key).to_string(), value));
+                }
+            }
+            Ok(entries)
+        });
+        match entries {
+            Ok(entries) => Box::new(entries.into_iter().map(Ok)),
+            Err(e) => Box::new(iter::once(Err(read_error(e)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::lmdb::KvLmdbStorage;
+    use holochain_persistence_api::kv::{test_kv_storage, KvStorage};
+    use tempfile::tempdir;
+
+    #[test]
+    fn lmdb_kv_round_trip() {
+        let dir = tempdir().expect("Could not create a tempdir for KV testing");
+        test_kv_storage(KvLmdbStorage::new(dir.path(), None, None));
+
+        // the entries outlive the store
+        let kv = KvLmdbStorage::new(dir.path(), None, None);
+        assert_eq!(Ok(Some(b"{\"replaced\":true}".to_vec())), kv.get("config"));
+        assert_eq!(2, kv.scan("checkpoint/").count());
+    }
+}
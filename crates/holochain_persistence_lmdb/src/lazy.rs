@@ -0,0 +1,134 @@
+//! Opening stores on demand.
+//!
+//! A host running many instances doesn't need all of their environments open at once.
+//! `StoreCache` opens a store the first time it is asked for and hands out clones of it after
+//! that. Stores that haven't been asked for within the idle timeout, and the least recently used
+//! ones beyond the capacity, are let go of. An environment is closed once the last handle on it
+//! is dropped and opened again the next time its store is asked for.
+
+use holochain_persistence_api::error::PersistenceResult;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+type Opener<S> = Box<dyn Fn(&str) -> PersistenceResult<S> + Send + Sync>;
+
+/// Stores opened by name on first use, at most `capacity` of them at a time.
+pub struct StoreCache<S> {
+    open: Opener<S>,
+    capacity: usize,
+    idle_timeout: Option<Duration>,
+    stores: Mutex<HashMap<String, (S, Instant)>>,
+}
+
+impl<S: Clone> StoreCache<S> {
+    /// `open` opens the store with the given name, e.g. with `LmdbStorage::from_config`.
+    pub fn new<F>(capacity: usize, idle_timeout: Option<Duration>, open: F) -> StoreCache<S>
+    where
+        F: Fn(&str) -> PersistenceResult<S> + Send + Sync + 'static,
+    {
+        StoreCache {
+            open: Box::new(open),
+            capacity: capacity.max(1),
+            idle_timeout,
+            stores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The store with this name, opening it if it isn't open.
+    pub fn get(&self, name: &str) -> PersistenceResult<S> {
+        let mut stores = self.stores.lock()?;
+        let now = Instant::now();
+        self.drop_idle(&mut stores, now);
+        if let Some((store, last_used)) = stores.get_mut(name) {
+            *last_used = now;
+            return Ok(store.clone());
+        }
+
+        let store = (self.open)(name)?;
+        stores.insert(name.to_string(), (store.clone(), now));
+        while stores.len() > self.capacity {
+            let least_recent = stores
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(name, _)| name.clone());
+            if let Some(name) = least_recent {
+                stores.remove(&name);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Lets go of the stores idle for longer than the idle timeout, returning how many there were.
+    /// `get` does this too, so this is only needed to close environments when nothing is asked for.
+    pub fn close_idle(&self) -> PersistenceResult<usize> {
+        let mut stores = self.stores.lock()?;
+        Ok(self.drop_idle(&mut stores, Instant::now()))
+    }
+
+    /// Lets go of the store with this name, returning whether it was open.
+    pub fn close(&self, name: &str) -> PersistenceResult<bool> {
+        Ok(self.stores.lock()?.remove(name).is_some())
+    }
+
+    /// Names of the stores currently held open.
+    pub fn open_stores(&self) -> PersistenceResult<Vec<String>> {
+        Ok(self.stores.lock()?.keys().cloned().collect())
+    }
+
+    fn drop_idle(&self, stores: &mut HashMap<String, (S, Instant)>, now: Instant) -> usize {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return 0,
+        };
+        let before = stores.len();
+        stores.retain(|_, (_, last_used)| now.duration_since(*last_used) < idle_timeout);
+        before - stores.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cas::lmdb::LmdbStorage, common::environment_is_open, config::LmdbConfig};
+    use holochain_persistence_api::cas::{
+        content::{AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    };
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn stores_open_on_demand_and_close_when_evicted_or_idle() {
+        let dir = tempdir().expect("Could not create a tempdir for CAS testing");
+        let root = dir.path().to_path_buf();
+        let cache = StoreCache::new(2, Some(Duration::from_millis(100)), move |name| {
+            LmdbStorage::from_config(&LmdbConfig::new(root.join(name)))
+        });
+        let is_open = |name: &str| environment_is_open(&dir.path().join(name).join("cas.db"));
+
+        let content = Content::from_json("\"kept\"");
+        cache.get("a").unwrap().add(&content).unwrap();
+        assert!(is_open("a"));
+
+        // "a" is the least recently used once "c" is opened
+        cache.get("b").unwrap();
+        cache.get("c").unwrap();
+        assert!(!is_open("a"));
+        assert!(is_open("b") && is_open("c"));
+
+        // and opened again, with its content, when asked for
+        assert_eq!(
+            Ok(Some(content.clone())),
+            cache.get("a").unwrap().fetch(&content.address())
+        );
+        assert!(!is_open("b"));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(Ok(2), cache.close_idle());
+        assert!(!is_open("a") && !is_open("c"));
+        assert_eq!(Ok(vec![]), cache.open_stores());
+    }
+}
@@ -10,5 +10,47 @@
 extern crate test;
 
 pub mod cas;
+pub mod checksum;
 mod common;
+pub mod config;
+#[cfg(test)]
+mod crash;
+pub mod dedup;
 pub mod eav;
+mod identity;
+pub mod import;
+pub mod kv;
+pub mod lazy;
+pub mod manager;
+pub mod rewrite;
+pub mod scrub;
+#[cfg(feature = "search")]
+pub mod search;
+pub mod sequence;
+pub mod trash;
+pub mod writer;
+
+use holochain_persistence_api::{
+    eav::Attribute,
+    error::PersistenceResult,
+    registry::{self, StorageManager, StorageUri},
+};
+use serde::de::DeserializeOwned;
+
+/// The stores of an `LmdbManager` under the path of the URI, configured by
+/// `LmdbConfig::from_uri`.
+fn open<A>(uri: &StorageUri) -> PersistenceResult<StorageManager<A>>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    let config = config::LmdbConfig::from_uri(uri)?;
+    manager::LmdbManager::open(&config)?.into_storage_manager()
+}
+
+/// Registers the LMDB stores for `lmdb:///path` URIs, see `registry::create_manager`.
+pub fn register_storage<A>() -> PersistenceResult<()>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    registry::register("lmdb", open::<A>)
+}
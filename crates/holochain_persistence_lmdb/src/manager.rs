@@ -0,0 +1,282 @@
+//! The LMDB stores opened together under one directory, the way `lmdb://` URIs open them.
+//!
+//! `LmdbManager::open` opens the CAS in the `cas`, the EAV store in the `eav` and the key value
+//! store in the `kv` directory under the configured path. `fork` copies all three into another
+//! directory and opens the copy, which is independent of the original from then on, so test
+//! suites and trial migrations can start from a production store without changing it. For now
+//! a fork is a full copy of the stores, with an identity of its own. `warm` and `warm_query`
+//! read content into memory ahead of the first fetches after opening.
+
+use crate::{
+    cas::lmdb::LmdbStorage, common::copy_together, config::LmdbConfig, eav::lmdb::EavLmdbStorage,
+    kv::lmdb::KvLmdbStorage,
+};
+use holochain_persistence_api::{
+    cas::content::Address,
+    eav::{Attribute, EaviQuery},
+    error::{PersistenceError, PersistenceResult},
+    events::EventBus,
+    registry::StorageManager,
+    warm::{self, Warming},
+};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+const CAS_DIR: &str = "cas";
+const EAV_DIR: &str = "eav";
+const KV_DIR: &str = "kv";
+
+/// The CAS, EAV and key value stores under one directory.
+#[derive(Clone, Debug)]
+pub struct LmdbManager<A: Attribute> {
+    config: LmdbConfig,
+    pub cas: LmdbStorage,
+    pub eav: EavLmdbStorage<A>,
+    pub kv: KvLmdbStorage,
+}
+
+impl<A> LmdbManager<A>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    /// Opens the stores under `config.path`, each with the settings of `config`.
+    pub fn open(config: &LmdbConfig) -> PersistenceResult<LmdbManager<A>> {
+        let kv = KvLmdbStorage::new(
+            config.path.join(KV_DIR),
+            config.initial_map_bytes,
+            config.max_readers,
+        );
+        let cas_config = LmdbConfig {
+            path: config.path.join(CAS_DIR),
+            ..config.clone()
+        };
+        let eav_config = LmdbConfig {
+            path: config.path.join(EAV_DIR),
+            ..config.clone()
+        };
+        Ok(LmdbManager {
+            config: config.clone(),
+            cas: LmdbStorage::from_config(&cas_config)?,
+            eav: EavLmdbStorage::from_config(&eav_config)?,
+            kv,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Copies the stores into `dest_path` and opens the copy with the same settings.
+    /// `dest_path` has to be empty or not exist yet. Writes to all three stores wait until the
+    /// last one is copied, so the copies are of the same moment and EAVIs written after their
+    /// content don't end up in the fork without it.
+    pub fn fork<P: Into<PathBuf>>(&self, dest_path: P) -> PersistenceResult<LmdbManager<A>> {
+        let dest_path = dest_path.into();
+        let occupied = std::fs::read_dir(&dest_path)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        if occupied {
+            return Err(PersistenceError::from(format!(
+                "can't fork {} into {}, it isn't empty",
+                self.path().display(),
+                dest_path.display()
+            )));
+        }
+        copy_together(&[
+            (&self.cas.lmdb, &dest_path.join(CAS_DIR)),
+            (&self.eav.lmdb, &dest_path.join(EAV_DIR)),
+            (&self.kv.lmdb, &dest_path.join(KV_DIR)),
+        ])?;
+        let mut fork = LmdbManager::open(&LmdbConfig {
+            path: dest_path,
+            ..self.config.clone()
+        })?;
+        // the copy is another store from now on
+        fork.cas.renew_identity()?;
+        fork.eav.renew_identity()?;
+        fork.kv.renew_identity()?;
+        Ok(fork)
+    }
+
+    /// Reads the content at `addresses` into memory in the background, see
+    /// `LmdbStorage::warm`.
+    pub fn warm(&self, addresses: &[Address]) -> Warming {
+        self.cas.warm(addresses)
+    }
+
+    /// Runs `query`, reading the EAV pages it needs, and then warms the entities and values of
+    /// the EAVIs it matches in the background.
+    pub fn warm_query(&self, query: &EaviQuery<A>) -> PersistenceResult<Warming> {
+        Ok(self.warm(&warm::query_addresses(&self.eav, query)?))
+    }
+
+    /// The stores behind a `StorageManager`, publishing to its event bus what they publish
+    /// about themselves along with their writes.
+    pub fn into_storage_manager(self) -> PersistenceResult<StorageManager<A>> {
+        let events = EventBus::new();
+        for store_events in &[self.cas.events(), self.eav.events()] {
+            let events = events.clone();
+            store_events.subscribe(move |event| events.publish(event))?;
+        }
+        let sequences = self.cas.sequences();
+        Ok(StorageManager::new(self.cas, self.eav, events)
+            .with_kv(self.kv)
+            .with_sequences(sequences))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holochain_persistence_api::{
+        cas::{
+            content::{AddressableContent, Content},
+            storage::ContentAddressableStorage,
+        },
+        eav::{
+            EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute, IndexFilter,
+        },
+        kv::KvStorage,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn forks_are_independent_copies() {
+        let dir = tempdir().expect("Could not create a tempdir for fork testing");
+        let mut original: LmdbManager<ExampleAttribute> =
+            LmdbManager::open(&LmdbConfig::new(dir.path().join("original"))).unwrap();
+        let content = Content::from_json("\"production\"");
+        original.cas.add(&content).unwrap();
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        original.eav.add_eavi(&eavi).unwrap();
+        original.kv.put("checkpoint", b"1").unwrap();
+
+        let mut fork = original.fork(dir.path().join("fork")).unwrap();
+        assert_eq!(
+            Ok(Some(content.clone())),
+            fork.cas.fetch(&content.address())
+        );
+        assert_eq!(1, fork.eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+        assert_eq!(Ok(Some(b"1".to_vec())), fork.kv.get("checkpoint"));
+        assert_ne!(original.cas.get_id(), fork.cas.get_id());
+        assert_ne!(original.eav.identity().id, fork.eav.identity().id);
+        assert_ne!(original.kv.identity().id, fork.kv.identity().id);
+
+        // writes to either don't show in the other
+        let migrated = Content::from_json("\"migrated\"");
+        fork.cas.add(&migrated).unwrap();
+        fork.kv.put("checkpoint", b"2").unwrap();
+        let other = EntityAttributeValueIndex::new(
+            &migrated.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        fork.eav.add_eavi(&other).unwrap();
+        assert_eq!(Ok(false), original.cas.contains(&migrated.address()));
+        assert_eq!(Ok(Some(b"1".to_vec())), original.kv.get("checkpoint"));
+        assert_eq!(
+            1,
+            original
+                .eav
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .len()
+        );
+        original.kv.put("later", b"3").unwrap();
+        assert_eq!(Ok(None), fork.kv.get("later"));
+
+        // nor can a fork overwrite stores
+        assert!(original.fork(dir.path().join("fork")).is_err());
+    }
+
+    #[test]
+    fn forks_taken_during_writes_have_the_content_of_every_eavi() {
+        let dir = tempdir().expect("Could not create a tempdir for fork testing");
+        let original: LmdbManager<ExampleAttribute> =
+            LmdbManager::open(&LmdbConfig::new(dir.path().join("original"))).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writing = {
+            let (mut original, stop) = (original.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut written = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let content = Content::from_json(&format!("\"{}\"", written));
+                    original.cas.add(&content).unwrap();
+                    let eavi = EntityAttributeValueIndex::new(
+                        &content.address(),
+                        &ExampleAttribute::default(),
+                        &content.address(),
+                    )
+                    .unwrap();
+                    original.eav.add_eavi(&eavi).unwrap();
+                    written += 1;
+                }
+                written
+            })
+        };
+
+        let forks: Vec<LmdbManager<ExampleAttribute>> = (0..5)
+            .map(|i| {
+                original
+                    .fork(dir.path().join(format!("fork{}", i)))
+                    .unwrap()
+            })
+            .collect();
+        stop.store(true, Ordering::SeqCst);
+        assert!(writing.join().unwrap() > 0);
+        for fork in forks {
+            for eavi in fork.eav.fetch_eavi(&EaviQuery::default()).unwrap() {
+                assert_eq!(Ok(true), fork.cas.contains(&eavi.value()));
+            }
+        }
+    }
+
+    #[test]
+    fn warming_counts_what_is_stored() {
+        let dir = tempdir().expect("Could not create a tempdir for warming testing");
+        let mut manager: LmdbManager<ExampleAttribute> =
+            LmdbManager::open(&LmdbConfig::new(dir.path())).unwrap();
+        let (post, comment, missing) = (
+            Content::from_json("\"post\""),
+            Content::from_json("\"comment\""),
+            Content::from_json("\"missing\""),
+        );
+        manager.cas.add(&post).unwrap();
+        manager.cas.add(&comment).unwrap();
+        for value in &[&comment, &missing] {
+            let eavi = EntityAttributeValueIndex::new(
+                &post.address(),
+                &ExampleAttribute::default(),
+                &value.address(),
+            )
+            .unwrap();
+            manager.eav.add_eavi(&eavi).unwrap();
+        }
+
+        assert_eq!(
+            Ok(1),
+            manager.warm(&[post.address(), missing.address()]).wait()
+        );
+        let query = EaviQuery::new(
+            Some(post.address()).into(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(Ok(2), manager.warm_query(&query).unwrap().wait());
+    }
+}
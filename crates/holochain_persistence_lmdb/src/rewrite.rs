@@ -0,0 +1,166 @@
+//! Re-encoding a store in place.
+//!
+//! `rewrite_all` on the LMDB stores walks every entry in key order and rewrites the ones not
+//! yet stored in the target `SerializationFormat`, a batch per transaction. After every batch
+//! the progress callback gets a `RewriteProgress`; handing the last one back in resumes after
+//! the last key it saw. Starting over is also safe, entries already in the target format are
+//! skipped. Stores that write checksums keep them on the rewritten entries.
+//!
+//! `reindex` rebuilds the secondary indexes of a store from its main store in the same batches,
+//! resumable the same way, after dropping them when it doesn't resume.
+
+use crate::common::{stored_json, write_error, Encoded, LmdbInstance};
+use holochain_persistence_api::{
+    error::{PersistenceError, PersistenceResult},
+    format::SerializationFormat,
+};
+use rkv::{SingleStore, Value};
+
+/// How far a rewrite has got.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RewriteProgress {
+    /// entries looked at
+    pub visited: u64,
+    /// entries that had to be re-encoded
+    pub rewritten: u64,
+    /// key of the last entry looked at
+    pub last_key: Option<Vec<u8>>,
+}
+
+fn rewrite_error<E: std::fmt::Display>(e: E) -> PersistenceError {
+    PersistenceError::from(format!("LMDB rewrite error: {}", e))
+}
+
+fn as_stored(value: &Option<Value>) -> Option<Encoded> {
+    match value {
+        Some(Value::Json(json)) => Some(Encoded::Json((*json).to_string())),
+        Some(Value::Blob(bytes)) => Some(Encoded::Blob(bytes.to_vec())),
+        _ => None,
+    }
+}
+
+/// Key, JSON and stored value of up to `batch_size` entries of the main store of `lmdb` after
+/// the last key of `done`.
+fn read_batch(
+    lmdb: &LmdbInstance,
+    done: &RewriteProgress,
+    batch_size: usize,
+) -> PersistenceResult<Vec<(Vec<u8>, String, Option<Encoded>)>> {
+    lmdb.read(|reader| {
+        let entries = match &done.last_key {
+            Some(key) => lmdb.store.iter_from(reader, key)?,
+            None => lmdb.store.iter_start(reader)?,
+        };
+        let mut batch = Vec::with_capacity(batch_size);
+        for entry in entries {
+            let (key, value) = entry?;
+            // iter_from starts at the last key itself
+            if Some(key) == done.last_key.as_deref() {
+                continue;
+            }
+            let stored = as_stored(&value);
+            let json = stored_json(value)?.into_owned();
+            batch.push((key.to_vec(), json, stored));
+            if batch.len() == batch_size {
+                break;
+            }
+        }
+        Ok(batch)
+    })
+    .map_err(rewrite_error)
+}
+
+/// Rewrites the main store of `lmdb`, sealing the entries with a checksum if `checksums` is set.
+/// `companions` names the entries in other stores that hold
+/// the same value as an entry of the main store (like an index) and are rewritten with it.
+pub(crate) fn rewrite_all(
+    lmdb: &LmdbInstance,
+    format: SerializationFormat,
+    checksums: bool,
+    batch_size: usize,
+    resume: RewriteProgress,
+    companions: &dyn Fn(&str) -> PersistenceResult<Vec<(SingleStore, Vec<u8>)>>,
+    progress: &mut dyn FnMut(&RewriteProgress),
+) -> PersistenceResult<RewriteProgress> {
+    let batch_size = batch_size.max(1);
+    let mut done = resume;
+    loop {
+        let batch = read_batch(lmdb, &done, batch_size)?;
+        if batch.is_empty() {
+            return Ok(done);
+        }
+
+        let mut writes = Vec::new();
+        for (key, json, stored) in batch {
+            let mut encoded = Encoded::new(format, json.clone())?;
+            if checksums {
+                encoded = encoded.sealed()?;
+            }
+            if stored.as_ref() != Some(&encoded) {
+                for (store, companion_key) in companions(&json)? {
+                    writes.push((store, companion_key, encoded.clone()));
+                }
+                writes.push((lmdb.store, key.clone(), encoded));
+                done.rewritten += 1;
+            }
+            done.visited += 1;
+            done.last_key = Some(key);
+        }
+        let entries: Vec<_> = writes
+            .iter()
+            .map(|(store, key, encoded)| (*store, key.as_slice(), encoded.value()))
+            .collect();
+        lmdb.put_many(&entries)
+            .map_err(|e| write_error(e, "LMDB rewrite error"))?;
+        progress(&done);
+    }
+}
+
+/// Writes the entries `companions` names in other stores of `lmdb` for every entry of its main
+/// store, with the value stored there. Unless resuming, everything in `indexes` is dropped
+/// first. `rewritten` counts the entries whose index entries were written.
+pub(crate) fn reindex(
+    lmdb: &LmdbInstance,
+    indexes: &[SingleStore],
+    batch_size: usize,
+    resume: RewriteProgress,
+    companions: &dyn Fn(&str) -> PersistenceResult<Vec<(SingleStore, Vec<u8>)>>,
+    progress: &mut dyn FnMut(&RewriteProgress),
+) -> PersistenceResult<RewriteProgress> {
+    if resume.last_key.is_none() {
+        lmdb.write(|writer| {
+            for index in indexes {
+                index.clear(writer)?;
+            }
+            Ok(())
+        })
+        .map_err(|e| write_error(e, "LMDB reindex error"))?;
+    }
+    let batch_size = batch_size.max(1);
+    let mut done = resume;
+    loop {
+        let batch = read_batch(lmdb, &done, batch_size)?;
+        if batch.is_empty() {
+            return Ok(done);
+        }
+
+        let mut writes = Vec::new();
+        for (key, json, stored) in batch {
+            if let Some(stored) = stored {
+                for (store, companion_key) in companions(&json)? {
+                    writes.push((store, companion_key, stored.clone()));
+                }
+                done.rewritten += 1;
+            }
+            done.visited += 1;
+            done.last_key = Some(key);
+        }
+        let entries: Vec<_> = writes
+            .iter()
+            .map(|(store, key, encoded)| (*store, key.as_slice(), encoded.value()))
+            .collect();
+        lmdb.put_many(&entries)
+            .map_err(|e| write_error(e, "LMDB reindex error"))?;
+        progress(&done);
+    }
+}
@@ -0,0 +1,489 @@
+//! A background check of the LMDB stores, so corruption is found before a read fails on it.
+//!
+//! Every interval a `Scrubber` reads the next share of the CAS and of the EAV store, carrying on
+//! where it stopped the time before and starting over once it has been through a store. It
+//! checks that every record passes its checksum, that content is stored at the address it
+//! hashes to and that the entity and value of every EAVI are in the CAS. Reads are throttled to
+//! a budget of bytes per second so the check doesn't compete with writers for IO. Findings are
+//! published as `StorageEvent::IntegrityIssue` on the events of the store they were found in,
+//! nothing is quarantined or changed.
+
+use crate::{
+    cas::lmdb::LmdbStorage,
+    common::{stored_json, LmdbInstance},
+    eav::lmdb::EavLmdbStorage,
+};
+use holochain_logging::prelude::*;
+use holochain_persistence_api::{
+    cas::{
+        content::{Address, AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    },
+    eav::{Attribute, EntityAttributeValueIndex},
+    error::{PersistenceError, PersistenceResult},
+    events::{EventBus, StorageEvent},
+};
+use rkv::{SingleStore, StoreError};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// records read per read transaction, the throttle is applied between them
+const CHUNK: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScrubConfig {
+    /// share of the records of each store checked per interval, between 0 and 1
+    pub fraction: f64,
+    pub interval_ms: u64,
+    /// bytes read per second at most, None to read as fast as the disk allows
+    pub max_bytes_per_second: Option<u64>,
+    /// content has to be stored at the address it hashes to. Turn this off for a CAS that
+    /// stores content under addresses of its own choosing
+    pub verify_addresses: bool,
+    /// the entity and value of EAVIs have to be in the CAS
+    pub check_references: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> ScrubConfig {
+        ScrubConfig {
+            fraction: 0.01,
+            interval_ms: 60_000,
+            max_bytes_per_second: Some(1024 * 1024),
+            verify_addresses: true,
+            check_references: true,
+        }
+    }
+}
+
+impl ScrubConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+/// Something a scrub found wrong.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Finding {
+    /// the record stored under `key` can't be read, e.g. it failed its checksum
+    Unreadable { key: String, error: String },
+    /// the content stored at `address` hashes to `actual`
+    AddressMismatch { address: Address, actual: Address },
+    /// the EAVI stored under `key` refers to `address`, which isn't in the CAS
+    DanglingReference { key: String, address: Address },
+}
+
+impl Finding {
+    /// The address the finding is published under.
+    pub fn address(&self) -> Address {
+        match self {
+            Finding::Unreadable { key, .. } | Finding::DanglingReference { key, .. } => {
+                Address::from(key.as_str())
+            }
+            Finding::AddressMismatch { address, .. } => address.clone(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Finding::Unreadable { key, error } => write!(f, "{} can't be read: {}", key, error),
+            Finding::AddressMismatch { address, actual } => {
+                write!(f, "content at {} hashes to {}", address, actual)
+            }
+            Finding::DanglingReference { key, address } => write!(
+                f,
+                "EAVI {} refers to {}, which isn't in the CAS",
+                key, address
+            ),
+        }
+    }
+}
+
+/// A stored record, the JSON of its value or why it can't be read.
+type Record = (String, Result<String, String>);
+
+/// How far a store has been scrubbed.
+#[derive(Clone, Debug, Default)]
+struct Pass {
+    /// the key the next step starts at, None to start from the first one
+    from: Option<Vec<u8>>,
+    /// records in the store when the pass started
+    total: usize,
+}
+
+/// Sleeps whenever more bytes were read than the budget allows for the time since it started.
+struct Throttle {
+    max_bytes_per_second: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(max_bytes_per_second: Option<u64>) -> Throttle {
+        Throttle {
+            max_bytes_per_second,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn spend(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        if let Some(max) = self.max_bytes_per_second {
+            let due = Duration::from_secs_f64(self.bytes as f64 / max.max(1) as f64);
+            let elapsed = self.started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+fn read_error(e: StoreError) -> PersistenceError {
+    PersistenceError::from(format!("scrub read error: {}", e))
+}
+
+fn count(lmdb: &LmdbInstance) -> Result<usize, StoreError> {
+    lmdb.read(|reader| {
+        let mut count = 0;
+        for entry in lmdb.store.iter_start(reader)? {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    })
+}
+
+/// Up to `limit` records from `from` on, with the key the next read starts at, None once the
+/// end of the store was reached.
+fn read_chunk(
+    lmdb: &LmdbInstance,
+    store: SingleStore,
+    from: Option<&[u8]>,
+    limit: usize,
+) -> Result<(Vec<Record>, Option<Vec<u8>>), StoreError> {
+    lmdb.read(|reader| {
+        let cursor = match from {
+            Some(from) => store.iter_from(reader, from)?,
+            None => store.iter_start(reader)?,
+        };
+        let mut records = Vec::new();
+        for entry in cursor {
+            let (key, value) = entry?;
+            if records.len() == limit {
+                return Ok((records, Some(key.to_vec())));
+            }
+            let json = stored_json(value)
+                .map(|json| json.into_owned())
+                .map_err(|e| e.to_string());
+            records.push((String::from_utf8_lossy(key).to_string(), json));
+        }
+        Ok((records, None))
+    })
+}
+
+fn record_bytes(records: &[Record]) -> usize {
+    records
+        .iter()
+        .map(|(key, json)| key.len() + json.as_ref().map_or(0, String::len))
+        .sum()
+}
+
+/// The next share of the records of `lmdb`, read a chunk at a time within the budget of
+/// `throttle`.
+fn step_records(
+    lmdb: &LmdbInstance,
+    pass: &mut Pass,
+    fraction: f64,
+    throttle: &mut Throttle,
+) -> PersistenceResult<Vec<Record>> {
+    if pass.from.is_none() {
+        pass.total = count(lmdb).map_err(read_error)?;
+    }
+    let share = ((pass.total as f64 * fraction).ceil() as usize).max(1);
+    let mut records = Vec::new();
+    while records.len() < share {
+        let limit = (share - records.len()).min(CHUNK);
+        let (chunk, next) =
+            read_chunk(lmdb, lmdb.store, pass.from.as_deref(), limit).map_err(read_error)?;
+        throttle.spend(record_bytes(&chunk));
+        records.extend(chunk);
+        pass.from = next;
+        if pass.from.is_none() {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+fn publish(events: &EventBus, findings: &[Finding]) {
+    for finding in findings {
+        events.publish(&StorageEvent::IntegrityIssue(
+            finding.address(),
+            finding.to_string(),
+        ));
+    }
+}
+
+/// Checks a CAS and the EAV store indexing it a share at a time.
+#[derive(Debug)]
+pub struct Scrubber<A: Attribute> {
+    cas: LmdbStorage,
+    eav: EavLmdbStorage<A>,
+    config: ScrubConfig,
+    cas_pass: Pass,
+    eav_pass: Pass,
+}
+
+impl<A> Scrubber<A>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    pub fn new(cas: LmdbStorage, eav: EavLmdbStorage<A>, config: ScrubConfig) -> Scrubber<A> {
+        Scrubber {
+            cas,
+            eav,
+            config,
+            cas_pass: Pass::default(),
+            eav_pass: Pass::default(),
+        }
+    }
+
+    pub fn config(&self) -> &ScrubConfig {
+        &self.config
+    }
+
+    fn check_content(&self, records: Vec<Record>) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for (key, json) in records {
+            match json {
+                Err(error) => findings.push(Finding::Unreadable { key, error }),
+                Ok(json) if self.config.verify_addresses => {
+                    let address = Address::from(key.as_str());
+                    let actual = Content::from_json(&json).address();
+                    if actual != address {
+                        findings.push(Finding::AddressMismatch { address, actual });
+                    }
+                }
+                Ok(_) => (),
+            }
+        }
+        findings
+    }
+
+    fn check_eavis(&self, records: Vec<Record>) -> PersistenceResult<Vec<Finding>> {
+        let mut findings = Vec::new();
+        for (key, json) in records {
+            let json = match json {
+                Ok(json) => json,
+                Err(error) => {
+                    findings.push(Finding::Unreadable { key, error });
+                    continue;
+                }
+            };
+            let eavi: EntityAttributeValueIndex<A> = match serde_json::from_str(&json) {
+                Ok(eavi) => eavi,
+                Err(e) => {
+                    findings.push(Finding::Unreadable {
+                        key,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            if !self.config.check_references {
+                continue;
+            }
+            let mut referenced = vec![eavi.entity()];
+            if eavi.value() != eavi.entity() {
+                referenced.push(eavi.value());
+            }
+            for address in referenced {
+                if !self.cas.contains(&address)? {
+                    findings.push(Finding::DanglingReference {
+                        key: key.clone(),
+                        address,
+                    });
+                }
+            }
+        }
+        Ok(findings)
+    }
+
+    /// Checks the next share of both stores and publishes what it finds.
+    pub fn step(&mut self) -> PersistenceResult<Vec<Finding>> {
+        let mut throttle = Throttle::new(self.config.max_bytes_per_second);
+        let content = step_records(
+            &self.cas.lmdb,
+            &mut self.cas_pass,
+            self.config.fraction,
+            &mut throttle,
+        )?;
+        let mut findings = self.check_content(content);
+        publish(self.cas.events(), &findings);
+
+        let eavis = step_records(
+            &self.eav.lmdb,
+            &mut self.eav_pass,
+            self.config.fraction,
+            &mut throttle,
+        )?;
+        let eav_findings = self.check_eavis(eavis)?;
+        publish(self.eav.events(), &eav_findings);
+        findings.extend(eav_findings);
+        Ok(findings)
+    }
+
+    /// Runs a step every interval on a thread of its own until the handle is stopped or
+    /// dropped.
+    pub fn spawn(mut self) -> PersistenceResult<ScrubberHandle> {
+        let (stop, stopped) = mpsc::channel();
+        let interval = self.config.interval();
+        let thread = thread::Builder::new()
+            .name("lmdb-scrubber".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = self.step() {
+                        warn!("Scrub step failed: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| PersistenceError::from(format!("could not start scrubber: {}", e)))?;
+        Ok(ScrubberHandle {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Keeps a spawned scrubber running.
+#[derive(Debug)]
+pub struct ScrubberHandle {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ScrubberHandle {
+    /// Stops the scrubber once its current step is done.
+    pub fn stop(self) {}
+}
+
+impl Drop for ScrubberHandle {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::tests::tamper;
+    use holochain_persistence_api::eav::{EntityAttributeValueStorage, ExampleAttribute};
+    use rkv::Value;
+    use std::{
+        collections::BTreeSet,
+        sync::{Arc, Mutex},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn scrubs_report_what_is_wrong_a_share_at_a_time() {
+        let dir = tempdir().expect("Could not create a tempdir for scrub testing");
+        let cas = LmdbStorage::new(dir.path().join("cas"), None, None).with_checksums();
+        let mut eav: EavLmdbStorage<ExampleAttribute> =
+            EavLmdbStorage::new(dir.path().join("eav"), None, None);
+        let (post, comment, tampered) = (
+            Content::from_json("\"post\""),
+            Content::from_json("\"comment\""),
+            Content::from_json("\"tampered\""),
+        );
+        for content in &[&post, &comment, &tampered] {
+            cas.add(*content).unwrap();
+        }
+        tamper(
+            &cas.lmdb,
+            cas.lmdb.store,
+            tampered.address().to_string().as_bytes(),
+        );
+        cas.lmdb.add("QmMoved", &Value::Json("\"post\"")).unwrap();
+        let missing = Content::from_json("\"missing\"");
+        let eavi = EntityAttributeValueIndex::new(
+            &post.address(),
+            &ExampleAttribute::default(),
+            &missing.address(),
+        )
+        .unwrap();
+        eav.add_eavi(&eavi).unwrap();
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        for events in &[cas.events(), eav.events()] {
+            let published = published.clone();
+            events
+                .subscribe(move |event| {
+                    if let StorageEvent::IntegrityIssue(address, _) = event {
+                        published.lock().unwrap().push(address.clone());
+                    }
+                })
+                .unwrap();
+        }
+
+        let mut scrubber = Scrubber::new(
+            cas.clone(),
+            eav.clone(),
+            ScrubConfig {
+                fraction: 0.5,
+                max_bytes_per_second: None,
+                ..ScrubConfig::default()
+            },
+        );
+        // half of the four records of the CAS per step, the single EAVI every step
+        let first = scrubber.step().unwrap();
+        let second = scrubber.step().unwrap();
+        let findings: Vec<_> = first.into_iter().chain(second).collect();
+        let found: BTreeSet<_> = findings.iter().map(Finding::address).collect();
+        let expected: BTreeSet<_> = vec![
+            Address::from("QmMoved"),
+            tampered.address(),
+            Address::from(format!("{}::{}", post.address(), eavi.index())),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, found);
+        assert!(findings.contains(&Finding::AddressMismatch {
+            address: Address::from("QmMoved"),
+            actual: post.address(),
+        }));
+        assert_eq!(findings.len(), published.lock().unwrap().len());
+
+        // the next step starts over, now in the background
+        published.lock().unwrap().clear();
+        let handle = Scrubber::new(
+            cas,
+            eav,
+            ScrubConfig {
+                interval_ms: 10,
+                max_bytes_per_second: None,
+                ..ScrubConfig::default()
+            },
+        )
+        .spawn()
+        .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while published.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        assert!(!published.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+//! A full-text index over the content of the LMDB CAS, behind the `search` feature.
+//!
+//! A CAS opened `with_search_index` splits the content it adds, or the fields of it picked out
+//! by JSON pointers, into lower cased alphanumeric terms. Their postings are kept in a `SEARCH`
+//! store of the same environment and written in the same transaction as the content.
+//! `LmdbStorage::search` ranks the content matching any term of a query by BM25.
+
+use crate::common::{stored_json, LmdbInstance, SEARCH};
+use holochain_persistence_api::cas::content::Address;
+use rkv::{Readable, SingleStore, StoreError, Value, Writer};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// number of documents indexed
+const DOCS: &str = "n:docs";
+/// number of terms in all documents indexed, for the average document length
+const TERMS: &str = "n:terms";
+/// content indexed per write transaction when indexing what is already stored
+const BATCH_SIZE: usize = 1000;
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+/// What to index, see `LmdbStorage::with_search_index`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SearchConfig {
+    /// JSON pointers to the fields to index, e.g. `/title`, the whole content if empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+/// The terms of some text.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn collect_text(value: &JsonValue, text: &mut Vec<String>) {
+    match value {
+        JsonValue::String(s) => text.push(s.clone()),
+        JsonValue::Number(n) => text.push(n.to_string()),
+        JsonValue::Array(values) => values.iter().for_each(|v| collect_text(v, text)),
+        JsonValue::Object(fields) => fields.values().for_each(|v| collect_text(v, text)),
+        JsonValue::Bool(_) | JsonValue::Null => (),
+    }
+}
+
+fn doc_key(address: &str) -> String {
+    format!("d:{}", address)
+}
+
+fn term_prefix(term: &str) -> String {
+    format!("t:{}:", term)
+}
+
+fn count<T: Readable>(store: SingleStore, reader: &T, key: &str) -> Result<u64, StoreError> {
+    match store.get(reader, key)? {
+        Some(Value::U64(n)) => Ok(n),
+        _ => Ok(0),
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SearchIndex {
+    store: SingleStore,
+    fields: Vec<String>,
+}
+
+impl SearchIndex {
+    pub(crate) fn new(lmdb: &LmdbInstance, config: &SearchConfig) -> SearchIndex {
+        SearchIndex {
+            store: lmdb.open_store(SEARCH),
+            fields: config.fields.clone(),
+        }
+    }
+
+    fn terms(&self, json: &str) -> Vec<String> {
+        let value: JsonValue = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(_) => return tokenize(json),
+        };
+        let mut text = Vec::new();
+        if self.fields.is_empty() {
+            collect_text(&value, &mut text);
+        }
+        for field in &self.fields {
+            if let Some(value) = value.pointer(field) {
+                collect_text(value, &mut text);
+            }
+        }
+        text.iter().flat_map(|text| tokenize(text)).collect()
+    }
+
+    /// Writes the postings of the content at `address`, unless it is indexed already.
+    pub(crate) fn index(
+        &self,
+        writer: &mut Writer,
+        address: &str,
+        json: &str,
+    ) -> Result<(), StoreError> {
+        if self.store.get(writer, doc_key(address))?.is_some() {
+            return Ok(());
+        }
+        let terms = self.terms(json);
+        let mut frequencies = BTreeMap::new();
+        for term in &terms {
+            *frequencies.entry(term.as_str()).or_insert(0u64) += 1;
+        }
+        for (term, frequency) in frequencies {
+            let key = format!("{}{}", term_prefix(term), address);
+            self.store.put(writer, key, &Value::U64(frequency))?;
+        }
+        let length = terms.len() as u64;
+        self.store
+            .put(writer, doc_key(address), &Value::U64(length))?;
+        let docs = count(self.store, writer, DOCS)?;
+        let total = count(self.store, writer, TERMS)?;
+        self.store.put(writer, DOCS, &Value::U64(docs + 1))?;
+        self.store.put(writer, TERMS, &Value::U64(total + length))
+    }
+
+    /// Indexes the content in the main store that isn't indexed yet.
+    pub(crate) fn index_stored(&self, lmdb: &LmdbInstance) -> Result<(), StoreError> {
+        let missing = lmdb.read(|reader| {
+            let mut missing = Vec::new();
+            for entry in lmdb.store.iter_start(reader)? {
+                let (key, _) = entry?;
+                let address = String::from_utf8_lossy(key).to_string();
+                if self.store.get(reader, doc_key(&address))?.is_none() {
+                    missing.push(address);
+                }
+            }
+            Ok(missing)
+        })?;
+        for batch in missing.chunks(BATCH_SIZE) {
+            lmdb.write(|writer| {
+                for address in batch {
+                    let json = stored_json(lmdb.store.get(writer, address)?)?.to_string();
+                    self.index(writer, address, &json)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The content matching any term of `query`, best match first.
+    pub(crate) fn search(
+        &self,
+        lmdb: &LmdbInstance,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<(Address, f32)>, StoreError> {
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        lmdb.read(|reader| {
+            let docs = count(self.store, reader, DOCS)? as f32;
+            if docs == 0.0 {
+                return Ok(Vec::new());
+            }
+            let average_length = (count(self.store, reader, TERMS)? as f32 / docs).max(1.0);
+            let mut scores: BTreeMap<String, f32> = BTreeMap::new();
+            for term in terms {
+                let prefix = term_prefix(&term);
+                let mut postings = Vec::new();
+                for entry in self.store.iter_from(reader, &prefix)? {
+                    let (key, value) = entry?;
+                    if !key.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+                    if let Some(Value::U64(frequency)) = value {
+                        let address = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+                        postings.push((address, frequency as f32));
+                    }
+                }
+                let matching = postings.len() as f32;
+                let idf = (1.0 + (docs - matching + 0.5) / (matching + 0.5)).ln();
+                for (address, frequency) in postings {
+                    let length = count(self.store, reader, &doc_key(&address))? as f32;
+                    let norm = K1 * (1.0 - B + B * length / average_length);
+                    *scores.entry(address).or_insert(0.0) +=
+                        idf * frequency * (K1 + 1.0) / (frequency + norm);
+                }
+            }
+            let mut ranked: Vec<_> = scores
+                .into_iter()
+                .map(|(address, score)| (Address::from(address), score))
+                .collect();
+            // a stable sort keeps equal scores in address order
+            ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+            ranked.truncate(limit);
+            Ok(ranked)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::lmdb::LmdbStorage;
+    use holochain_persistence_api::cas::{
+        content::{AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn content_is_found_by_its_fields() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let post = |title: &str, body: &str| {
+            Content::from_json(&format!(
+                r#"{{"title":"{}","body":"{}","tags":["gossip"]}}"#,
+                title, body
+            ))
+        };
+        let posts = vec![
+            post("Holochain persistence", "LMDB stores and their indexes"),
+            post("Gossip", "how peers hear about new entries in the DHT"),
+            post(
+                "Persistence of gossip",
+                "the outbox keeps gossip for offline peers",
+            ),
+        ];
+
+        // content added before the index is turned on is indexed then
+        let plain = LmdbStorage::new(temp.path(), None, None);
+        plain.add(&posts[0]).unwrap();
+        drop(plain);
+        let config = SearchConfig {
+            fields: vec!["/title".to_string(), "/body".to_string()],
+        };
+        let cas = LmdbStorage::new(temp.path(), None, None)
+            .with_search_index(&config)
+            .unwrap();
+        cas.add(&posts[1]).unwrap();
+        cas.add(&posts[2]).unwrap();
+        cas.add(&posts[2]).unwrap();
+
+        let found = |query: &str| -> Vec<Address> {
+            cas.search(query, 10)
+                .unwrap()
+                .into_iter()
+                .map(|(address, _)| address)
+                .collect()
+        };
+        assert_eq!(
+            vec![posts[2].address(), posts[1].address()],
+            found("GOSSIP")
+        );
+        assert_eq!(
+            vec![posts[0].address(), posts[2].address()],
+            found("persistence")
+        );
+        // only the fields asked for are indexed
+        assert_eq!(vec![posts[2].address()], found("outbox tags"));
+        assert_eq!(Vec::<Address>::new(), found("nothing"));
+        assert_eq!(1, cas.search("persistence gossip", 1).unwrap().len());
+    }
+}
@@ -0,0 +1,80 @@
+//! Sequences kept in a `SEQUENCES` store next to the content of a CAS, see
+//! `LmdbStorage::sequences`.
+
+use crate::common::{write_error, LmdbInstance, SEQUENCES};
+use holochain_persistence_api::{
+    error::{PersistenceError, PersistenceResult},
+    sequence::SequenceStorage,
+};
+use rkv::{value::Type, DataError, Readable, SingleStore, StoreError, Value};
+use std::fmt::{self, Debug, Formatter};
+
+/// Clones share the sequences.
+#[derive(Clone)]
+pub struct LmdbSequences {
+    lmdb: LmdbInstance,
+    sequences: SingleStore,
+}
+
+impl Debug for LmdbSequences {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("LmdbSequences").finish()
+    }
+}
+
+impl LmdbSequences {
+    pub(crate) fn new(lmdb: &LmdbInstance) -> LmdbSequences {
+        LmdbSequences {
+            lmdb: lmdb.clone(),
+            sequences: lmdb.open_store(SEQUENCES),
+        }
+    }
+
+    fn current<T: Readable>(&self, txn: &T, name: &str) -> Result<Option<u64>, StoreError> {
+        match self.sequences.get(txn, name)? {
+            None => Ok(None),
+            Some(Value::U64(current)) => Ok(Some(current)),
+            Some(other) => Err(StoreError::DataError(DataError::UnexpectedType {
+                expected: Type::U64,
+                actual: Type::from_tag(other.to_bytes()?[0])?,
+            })),
+        }
+    }
+}
+
+impl SequenceStorage for LmdbSequences {
+    fn next_sequence(&self, name: &str) -> PersistenceResult<u64> {
+        // there is one writer per environment, so nothing can come between the read and the put
+        self.lmdb
+            .write(|writer| {
+                let next = self.current(writer, name)?.unwrap_or(0) + 1;
+                self.sequences.put(writer, name, &Value::U64(next))?;
+                Ok(next)
+            })
+            .map_err(|e| write_error(e, "LMDB sequence error"))
+    }
+
+    fn current_sequence(&self, name: &str) -> PersistenceResult<Option<u64>> {
+        self.lmdb
+            .read(|reader| self.current(reader, name))
+            .map_err(|e| PersistenceError::from(format!("LMDB sequence error: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cas::lmdb::LmdbStorage;
+    use holochain_persistence_api::sequence::{test_sequences, SequenceStorage};
+    use tempfile::tempdir;
+
+    #[test]
+    fn lmdb_sequences_count_up_across_restarts() {
+        let dir = tempdir().expect("Could not create a tempdir for sequence testing");
+        test_sequences(LmdbStorage::new(dir.path(), None, None).sequences());
+
+        let sequences = LmdbStorage::new(dir.path(), None, None).sequences();
+        assert_eq!(Ok(Some(101)), sequences.current_sequence("headers"));
+        assert_eq!(Ok(102), sequences.next_sequence("headers"));
+        assert_eq!(Ok(Some(1)), sequences.current_sequence("writes"));
+    }
+}
@@ -0,0 +1,142 @@
+//! Content taken out of a CAS without losing it yet.
+//!
+//! `LmdbStorage::trash` moves content out of the CAS into the `TRASH` store of its environment
+//! in one transaction, so fetches, `contains` and searches no longer see it. `restore` moves it
+//! back unchanged, checksum and format included, and `empty_trash` drops what has been in the
+//! trash for longer than a given time. Until then a cleanup that went too far can be undone.
+
+use crate::common::{write_error, LmdbInstance, TRASH};
+use holochain_persistence_api::{
+    cas::content::Address,
+    error::{PersistenceError, PersistenceResult},
+};
+use rkv::{StoreError, Value};
+use std::{
+    convert::TryInto,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// trashed values start with the time they were trashed, then whether they were stored as JSON
+const HEADER_BYTES: usize = 9;
+const JSON_TAG: u8 = 0;
+const BLOB_TAG: u8 = 1;
+
+/// Content in the trash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrashedContent {
+    pub address: Address,
+    /// milliseconds since the epoch
+    pub trashed_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn trash_error(e: StoreError) -> PersistenceError {
+    write_error(e, "LMDB trash error")
+}
+
+fn trashed_at(bytes: &[u8]) -> u64 {
+    bytes
+        .get(..8)
+        .and_then(|header| header.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or_default()
+}
+
+/// Moves the content at `address` into the trash, false if there is none.
+pub(crate) fn trash(lmdb: &LmdbInstance, address: &Address) -> PersistenceResult<bool> {
+    let trash = lmdb.open_store(TRASH);
+    let key = address.to_string();
+    lmdb.write(|writer| {
+        let mut trashed = now().to_be_bytes().to_vec();
+        match lmdb.store.get(writer, &key)? {
+            Some(Value::Json(json)) => {
+                trashed.push(JSON_TAG);
+                trashed.extend_from_slice(json.as_bytes());
+            }
+            Some(Value::Blob(bytes)) => {
+                trashed.push(BLOB_TAG);
+                trashed.extend_from_slice(bytes);
+            }
+            Some(_) | None => return Ok(false),
+        }
+        trash.put(writer, &key, &Value::Blob(&trashed))?;
+        lmdb.store.delete(writer, &key)?;
+        Ok(true)
+    })
+    .map_err(trash_error)
+}
+
+/// Moves the content at `address` out of the trash back into the CAS, false if it isn't in
+/// the trash.
+pub(crate) fn restore(lmdb: &LmdbInstance, address: &Address) -> PersistenceResult<bool> {
+    let trash = lmdb.open_store(TRASH);
+    let key = address.to_string();
+    lmdb.write(|writer| {
+        let trashed = match trash.get(writer, &key)? {
+            Some(Value::Blob(bytes)) if bytes.len() >= HEADER_BYTES => bytes.to_vec(),
+            _ => return Ok(false),
+        };
+        let stored = &trashed[HEADER_BYTES..];
+        match trashed[HEADER_BYTES - 1] {
+            JSON_TAG => {
+                lmdb.store
+                    .put(writer, &key, &Value::Json(&String::from_utf8_lossy(stored)))?
+            }
+            _ => lmdb.store.put(writer, &key, &Value::Blob(stored))?,
+        }
+        trash.delete(writer, &key)?;
+        Ok(true)
+    })
+    .map_err(trash_error)
+}
+
+/// Whether the content at `address` is in the trash.
+pub(crate) fn contains(lmdb: &LmdbInstance, address: &Address) -> Result<bool, StoreError> {
+    let trash = lmdb.open_store(TRASH);
+    lmdb.read(|reader| Ok(trash.get(reader, address.to_string())?.is_some()))
+}
+
+/// Everything in the trash, in address order.
+pub(crate) fn trashed(lmdb: &LmdbInstance) -> PersistenceResult<Vec<TrashedContent>> {
+    let trash = lmdb.open_store(TRASH);
+    lmdb.read(|reader| {
+        let mut trashed = Vec::new();
+        for entry in trash.iter_start(reader)? {
+            if let (key, Some(Value::Blob(bytes))) = entry? {
+                trashed.push(TrashedContent {
+                    address: Address::from(String::from_utf8_lossy(key).to_string()),
+                    trashed_at: trashed_at(bytes),
+                });
+            }
+        }
+        Ok(trashed)
+    })
+    .map_err(trash_error)
+}
+
+/// Drops what was trashed more than `older_than` ago, returning how much was dropped.
+pub(crate) fn empty_trash(lmdb: &LmdbInstance, older_than: Duration) -> PersistenceResult<usize> {
+    let trash = lmdb.open_store(TRASH);
+    let cutoff = now().saturating_sub(older_than.as_millis() as u64);
+    lmdb.write(|writer| {
+        let mut expired = Vec::new();
+        for entry in trash.iter_start(writer)? {
+            if let (key, Some(Value::Blob(bytes))) = entry? {
+                if trashed_at(bytes) <= cutoff {
+                    expired.push(key.to_vec());
+                }
+            }
+        }
+        for key in &expired {
+            trash.delete(writer, key)?;
+        }
+        Ok(expired.len())
+    })
+    .map_err(trash_error)
+}
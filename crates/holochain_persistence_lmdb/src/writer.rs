@@ -0,0 +1,194 @@
+//! A single background writer per store.
+//!
+//! Writes pushed onto the queue are committed in order by one thread that owns the write side
+//! of the environment, so callers don't contend on the environment write lock and only block
+//! when the queue is full. Every queued write hands back a `WriteReceipt` that resolves once the
+//! write has been committed.
+//!
+//! With a commit window the writer keeps collecting queued writes for that long after the first
+//! one arrives and commits them together in a single transaction, so bursts of writes share one
+//! commit (and one map resize if the map fills up). If the shared commit fails, the writes are
+//! committed again one by one so each receipt resolves to the outcome of its own write.
+//!
+//! Queued writes are staged in the writer's transaction rather than handed over as finished
+//! entries, so a write can pick its keys against everything committed or staged before it,
+//! including the writes queued ahead of it in the same batch.
+
+use crate::common::{write_error, LmdbInstance};
+use holochain_persistence_api::error::{PersistenceError, PersistenceResult};
+use rkv::{StoreError, Writer};
+use std::{
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Upper bound on the number of writes committed in a single transaction.
+const MAX_BATCH: usize = 1024;
+
+struct QueuedWrite {
+    /// stages the write, again if the transaction has to be retried after the map grew
+    stage: Box<dyn Fn(&mut Writer) -> Result<(), StoreError> + Send>,
+    /// called with the outcome of the transaction once it is over
+    done: Box<dyn FnOnce(PersistenceResult<()>) + Send>,
+}
+
+#[derive(Clone)]
+pub(crate) struct WriteQueue {
+    sender: SyncSender<QueuedWrite>,
+}
+
+impl WriteQueue {
+    /// Spawns the writer thread. It runs until every handle to the queue has been dropped.
+    /// Without a `commit_window` every write gets its own transaction.
+    pub fn spawn(
+        lmdb: LmdbInstance,
+        capacity: usize,
+        commit_window: Option<Duration>,
+    ) -> WriteQueue {
+        let (sender, receiver) = sync_channel::<QueuedWrite>(capacity);
+        thread::Builder::new()
+            .name("lmdb-writer".to_string())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let batch = match commit_window {
+                        Some(window) => collect_batch(&receiver, first, window),
+                        None => vec![first],
+                    };
+                    commit_batch(&lmdb, batch);
+                }
+            })
+            .expect("Could not spawn the LMDB writer thread");
+        WriteQueue { sender }
+    }
+
+    /// Queues a write, blocking only while the queue is full. `stage` writes it in the writer's
+    /// transaction. Once that has been committed `committed` is called with what was staged and
+    /// the receipt resolves to what it returns.
+    pub fn push<T, U, S, C>(&self, stage: S, committed: C) -> WriteReceipt<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        S: Fn(&mut Writer) -> Result<T, StoreError> + Send + 'static,
+        C: FnOnce(T) -> U + Send + 'static,
+    {
+        let (done, receiver) = channel();
+        // what the last attempt at staging the write staged
+        let staged = Arc::new(Mutex::new(None));
+        let stage = {
+            let staged = staged.clone();
+            move |writer: &mut Writer| {
+                let result = stage(writer)?;
+                *staged.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+                Ok(())
+            }
+        };
+        let done = move |outcome: PersistenceResult<()>| {
+            let outcome = outcome
+                .and_then(|()| {
+                    staged
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take()
+                        .ok_or_else(|| PersistenceError::from("LMDB writer lost a staged write"))
+                })
+                .map(committed);
+            // the caller may have dropped its receipt, that's fine
+            let _ = done.send(outcome);
+        };
+        let write = QueuedWrite {
+            stage: Box::new(stage),
+            done: Box::new(done),
+        };
+        match self.sender.send(write) {
+            Ok(()) => WriteReceipt { receiver },
+            Err(_) => WriteReceipt::completed(Err(PersistenceError::from(
+                "LMDB writer thread is no longer running",
+            ))),
+        }
+    }
+}
+
+/// Collects writes arriving within `window` of the first one, up to `MAX_BATCH` of them.
+fn collect_batch(
+    receiver: &Receiver<QueuedWrite>,
+    first: QueuedWrite,
+    window: Duration,
+) -> Vec<QueuedWrite> {
+    let deadline = Instant::now() + window;
+    let mut batch = vec![first];
+    while batch.len() < MAX_BATCH {
+        let now = Instant::now();
+        let next = if now >= deadline {
+            // the window is over but anything already queued can still go in
+            receiver.try_recv().ok()
+        } else {
+            receiver.recv_timeout(deadline - now).ok()
+        };
+        match next {
+            Some(write) => batch.push(write),
+            None => break,
+        }
+    }
+    batch
+}
+
+/// Commits the writes in one transaction. If that fails they are committed again one at a time,
+/// so a write that can't be committed fails only its own receipt.
+fn commit_batch(lmdb: &LmdbInstance, batch: Vec<QueuedWrite>) {
+    let result = lmdb.write(|writer| {
+        for write in &batch {
+            (write.stage)(writer)?;
+        }
+        Ok(())
+    });
+    if result.is_ok() || batch.len() == 1 {
+        let result = result.map_err(|e| write_error(e, "LMDB write error"));
+        for write in batch {
+            (write.done)(result.clone());
+        }
+        return;
+    }
+    for write in batch {
+        let result = lmdb
+            .write(|writer| (write.stage)(writer))
+            .map_err(|e| write_error(e, "LMDB write error"));
+        (write.done)(result);
+    }
+}
+
+/// Completion notification for a queued write, handing out what the write staged.
+pub struct WriteReceipt<T = ()> {
+    receiver: Receiver<PersistenceResult<T>>,
+}
+
+impl<T> WriteReceipt<T> {
+    pub(crate) fn completed(result: PersistenceResult<T>) -> WriteReceipt<T> {
+        let (done, receiver) = channel();
+        // can't fail, the receiver is still alive
+        let _ = done.send(result);
+        WriteReceipt { receiver }
+    }
+
+    /// Blocks until the write has been committed.
+    pub fn wait(self) -> PersistenceResult<T> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(PersistenceError::from("LMDB writer dropped a queued write")))
+    }
+
+    /// Returns the outcome of the write once it has been committed and `None` while it is still
+    /// queued. The outcome is only handed out once.
+    pub fn try_wait(&self) -> Option<PersistenceResult<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(PersistenceError::from(
+                "LMDB writer dropped a queued write",
+            ))),
+        }
+    }
+}
@@ -54,6 +54,24 @@ where
         Ok(Some(new_eav))
     }
 
+    fn upsert_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let mut map = self.storage.write()?;
+        let replaced: Vec<_> = map
+            .iter()
+            .filter(|old| old.entity() == eav.entity() && old.attribute() == eav.attribute())
+            .cloned()
+            .collect();
+        for old in replaced {
+            map.remove(&old);
+        }
+        let new_eav = increment_key_till_no_collision(eav.clone(), map.clone())?;
+        map.insert(new_eav.clone());
+        Ok(Some(new_eav))
+    }
+
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
@@ -103,6 +121,21 @@ pub mod tests {
         >(eav_storage, &ExampleAttribute::default())
     }
 
+    #[test]
+    fn memory_eav_upsert() {
+        EavTestSuite::test_upsert::<
+            ExampleAddressableContent,
+            ExampleAttribute,
+            EavMemoryStorage<ExampleAttribute>,
+        >(
+            EavMemoryStorage::new(),
+            vec![
+                ExampleAttribute::WithPayload("a_".to_string()),
+                ExampleAttribute::WithPayload("b_".to_string()),
+            ],
+        );
+    }
+
     #[test]
     fn memory_eav_many_to_one() {
         let eav_storage = EavMemoryStorage::new();
@@ -139,6 +172,17 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn memory_eav_attribute_histogram() {
+        EavTestSuite::test_attribute_histogram::<ExampleAddressableContent, _, _>(
+            EavMemoryStorage::new(),
+            vec![
+                ExampleAttribute::default(),
+                ExampleAttribute::WithoutPayload,
+            ],
+        );
+    }
+
     #[test]
     fn memory_tombstone() {
         let eav_storage = EavMemoryStorage::new();
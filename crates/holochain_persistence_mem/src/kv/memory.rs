@@ -0,0 +1,62 @@
+use holochain_persistence_api::{
+    error::PersistenceResult,
+    kv::{KvCursor, KvStorage},
+};
+use std::{
+    collections::BTreeMap,
+    iter,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct KvMemoryStorage {
+    storage: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl KvMemoryStorage {
+    pub fn new() -> KvMemoryStorage {
+        Default::default()
+    }
+}
+
+impl KvStorage for KvMemoryStorage {
+    fn get(&self, key: &str) -> PersistenceResult<Option<Vec<u8>>> {
+        let map = self.storage.read()?;
+        Ok(map.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> PersistenceResult<()> {
+        let mut map = self.storage.write()?;
+        map.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> PersistenceResult<bool> {
+        let mut map = self.storage.write()?;
+        Ok(map.remove(key).is_some())
+    }
+
+    fn scan<'a>(&'a self, prefix: &str) -> KvCursor<'a> {
+        let map = match self.storage.read() {
+            Ok(map) => map,
+            Err(e) => return Box::new(iter::once(Err(e.into()))),
+        };
+        let entries: Vec<_> = map
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::kv::memory::KvMemoryStorage;
+    use holochain_persistence_api::kv::test_kv_storage;
+
+    #[test]
+    fn memory_kv_round_trip() {
+        test_kv_storage(KvMemoryStorage::new());
+    }
+}
@@ -8,3 +8,27 @@
 
 pub mod cas;
 pub mod eav;
+pub mod kv;
+
+use holochain_persistence_api::{
+    eav::Attribute,
+    error::PersistenceResult,
+    registry::{self, StorageManager, StorageUri},
+};
+
+fn open<A: Attribute + Send + Sync + 'static>(
+    uri: &StorageUri,
+) -> PersistenceResult<StorageManager<A>> {
+    uri.check_params(&[])?;
+    Ok(StorageManager::new(
+        cas::memory::MemoryStorage::new(),
+        eav::memory::EavMemoryStorage::new(),
+        Default::default(),
+    )
+    .with_kv(kv::memory::KvMemoryStorage::new()))
+}
+
+/// Registers the memory stores for `memory://` URIs, see `registry::create_manager`.
+pub fn register_storage<A: Attribute + Send + Sync + 'static>() -> PersistenceResult<()> {
+    registry::register("memory", open::<A>)
+}
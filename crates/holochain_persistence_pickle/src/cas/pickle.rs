@@ -5,6 +5,7 @@ use holochain_persistence_api::{
         storage::ContentAddressableStorage,
     },
     error::PersistenceResult,
+    format::SerializationFormat,
     reporting::{ReportStorage, StorageReport},
 };
 
@@ -23,6 +24,7 @@ const PERSISTENCE_INTERVAL: Duration = Duration::from_millis(5000);
 pub struct PickleStorage {
     id: Uuid,
     db: Arc<RwLock<PickleDb>>,
+    format: SerializationFormat,
 }
 
 impl Debug for PickleStorage {
@@ -52,17 +54,33 @@ impl PickleStorage {
                     )
                 }),
             )),
+            format: SerializationFormat::default(),
         }
     }
+
+    /// Writes new content in `format`. Content already stored is read whatever format it was
+    /// written in.
+    pub fn with_serialization_format(mut self, format: SerializationFormat) -> PickleStorage {
+        self.format = format;
+        self
+    }
+}
+
+/// Content written in a binary format is stored as the bytes `SerializationFormat` encoded.
+fn decode_content(bytes: Vec<u8>) -> PersistenceResult<Content> {
+    Ok(Content::from_json(&SerializationFormat::decode(&bytes)?))
 }
 
 impl ContentAddressableStorage for PickleStorage {
-    fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+    fn add(&self, content: &dyn AddressableContent) -> PersistenceResult<()> {
         let mut inner = self.db.write().unwrap();
+        let key = content.address().to_string();
 
-        inner
-            .set(&content.address().to_string(), &content.content())
-            .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
+        match self.format {
+            SerializationFormat::Json => inner.set(&key, &content.content()),
+            format => inner.set(&key, &format.encode(&content.content().to_string())?),
+        }
+        .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
 
         Ok(())
     }
@@ -75,8 +93,13 @@ impl ContentAddressableStorage for PickleStorage {
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
         let inner = self.db.read().unwrap();
+        let key = address.to_string();
 
-        Ok(inner.get(&address.to_string()))
+        inner
+            .get::<Content>(&key)
+            .map(Ok)
+            .or_else(|| inner.get::<Vec<u8>>(&key).map(decode_content))
+            .transpose()
     }
 
     fn get_id(&self) -> Uuid {
@@ -87,10 +110,14 @@ impl ContentAddressableStorage for PickleStorage {
 impl ReportStorage for PickleStorage {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
         let db = self.db.read()?;
-        let bytes_total = db.iter().fold(0, |total_bytes, kv| {
-            let value = kv.get_value::<Content>().unwrap();
-            total_bytes + value.to_string().bytes().len()
-        });
+        let mut bytes_total = 0;
+        for kv in db.iter() {
+            let value = match kv.get_value::<Content>() {
+                Some(value) => value,
+                None => decode_content(kv.get_value().unwrap_or_default())?,
+            };
+            bytes_total += value.to_string().bytes().len();
+        }
         Ok(StorageReport::new(bytes_total))
     }
 }
@@ -101,9 +128,13 @@ mod tests {
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
-            content::{Content, ExampleAddressableContent, OtherExampleAddressableContent},
+            content::{
+                AddressableContent, Content, ExampleAddressableContent,
+                OtherExampleAddressableContent,
+            },
             storage::{CasBencher, ContentAddressableStorage, StorageTestSuite},
         },
+        format::SerializationFormat,
         reporting::{ReportStorage, StorageReport},
     };
     use tempfile::{tempdir, TempDir};
@@ -137,9 +168,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn pickle_content_round_trip_in_binary_formats() {
+        for format in &[SerializationFormat::MessagePack, SerializationFormat::Cbor] {
+            let (cas, _dir) = test_pickle_cas();
+            let test_suite = StorageTestSuite::new(cas.with_serialization_format(*format));
+            test_suite
+                .round_trip_test::<ExampleAddressableContent, OtherExampleAddressableContent>(
+                    RawString::from("foo").into(),
+                    RawString::from("bar").into(),
+                );
+        }
+    }
+
+    #[test]
+    fn pickle_reads_content_written_in_other_formats() {
+        let (json_cas, _dir) = test_pickle_cas();
+        // clones share the database
+        let msgpack_cas = json_cas
+            .clone()
+            .with_serialization_format(SerializationFormat::MessagePack);
+        let old = Content::from_json(r#"{"written":"as json"}"#);
+        let new = Content::from_json(r#"{"written":"as msgpack"}"#);
+        json_cas.add(&old).unwrap();
+        msgpack_cas.add(&new).unwrap();
+
+        for content in &[old, new] {
+            assert_eq!(
+                Ok(Some(content.clone())),
+                json_cas.fetch(&content.address())
+            );
+        }
+        assert_eq!(
+            json_cas.get_storage_report(),
+            msgpack_cas.get_storage_report()
+        );
+    }
+
     #[test]
     fn pickle_report_storage_test() {
-        let (mut cas, _) = test_pickle_cas();
+        let (cas, _) = test_pickle_cas();
         // add some content
         cas.add(&Content::from_json("some bytes"))
             .expect("could not add to CAS");
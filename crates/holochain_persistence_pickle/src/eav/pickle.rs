@@ -1,8 +1,9 @@
-use holochain_json_api::error::JsonError;
+use holochain_json_api::{error::JsonError, json::JsonString};
 use holochain_persistence_api::{
     cas::content::AddressableContent,
     eav::{Attribute, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage},
     error::PersistenceResult,
+    format::SerializationFormat,
     reporting::{ReportStorage, StorageReport},
 };
 
@@ -22,6 +23,7 @@ const PERSISTENCE_PERIODICITY_MS: Duration = Duration::from_millis(5000);
 pub struct EavPickleStorage<A: Attribute> {
     db: Arc<RwLock<PickleDb>>,
     id: Uuid,
+    format: SerializationFormat,
     attribute: PhantomData<A>,
 }
 
@@ -44,9 +46,28 @@ impl<A: Attribute> EavPickleStorage<A> {
                     )
                 }),
             )),
+            format: SerializationFormat::default(),
             attribute: PhantomData,
         }
     }
+
+    /// Writes new EAVIs in `format`. EAVIs already stored are read whatever format they were
+    /// written in.
+    pub fn with_serialization_format(mut self, format: SerializationFormat) -> EavPickleStorage<A> {
+        self.format = format;
+        self
+    }
+}
+
+/// EAVIs written in a binary format are stored as the bytes `SerializationFormat` encoded.
+fn decode_eavi<A: Attribute>(bytes: Vec<u8>) -> PersistenceResult<EntityAttributeValueIndex<A>>
+where
+    A: serde::de::DeserializeOwned,
+{
+    let json = SerializationFormat::decode(&bytes)?;
+    Ok(EntityAttributeValueIndex::try_from_content(
+        &JsonString::from_json(&json),
+    )?)
 }
 
 impl<A: Attribute> Debug for EavPickleStorage<A> {
@@ -69,17 +90,17 @@ where
 
         //hate to introduce mutability but it is saved by the immutable clones at the end
         let mut index_str = eav.index().to_string();
-        let mut value = inner.get::<EntityAttributeValueIndex<A>>(&index_str);
         let mut new_eav = eav.clone();
-        while value.is_some() {
+        while inner.exists(&index_str) {
             new_eav =
                 EntityAttributeValueIndex::new(&eav.entity(), &eav.attribute(), &eav.value())?;
             index_str = new_eav.index().to_string();
-            value = inner.get::<EntityAttributeValueIndex<A>>(&index_str);
         }
-        inner
-            .set(&*index_str, &new_eav)
-            .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
+        match self.format {
+            SerializationFormat::Json => inner.set(&*index_str, &new_eav),
+            format => inner.set(&*index_str, &format.encode(&new_eav.content().to_string())?),
+        }
+        .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
         Ok(Some(new_eav))
     }
 
@@ -89,13 +110,14 @@ where
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
         let inner = self.db.read()?;
 
-        //this not too bad because it is lazy evaluated
-        let entries = inner
-            .iter()
-            .map(|item| item.get_value())
-            .filter(|filter| filter.is_some())
-            .map(|y| y.unwrap())
-            .collect::<BTreeSet<EntityAttributeValueIndex<A>>>();
+        let mut entries: BTreeSet<EntityAttributeValueIndex<A>> = BTreeSet::new();
+        for item in inner.iter() {
+            if let Some(eavi) = item.get_value() {
+                entries.insert(eavi);
+            } else if let Some(bytes) = item.get_value() {
+                entries.insert(decode_eavi(bytes)?);
+            }
+        }
         let entries_iter = entries.iter().cloned();
         Ok(query.run(entries_iter))
     }
@@ -107,10 +129,14 @@ where
 {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
         let db = self.db.read()?;
-        let total_bytes = db.iter().fold(0, |total_bytes, kv| {
-            let value = kv.get_value::<EntityAttributeValueIndex<A>>().unwrap();
-            total_bytes + value.content().to_string().bytes().len()
-        });
+        let mut total_bytes = 0;
+        for kv in db.iter() {
+            let value: EntityAttributeValueIndex<A> = match kv.get_value() {
+                Some(value) => value,
+                None => decode_eavi(kv.get_value().unwrap_or_default())?,
+            };
+            total_bytes += value.content().to_string().bytes().len();
+        }
         Ok(StorageReport::new(total_bytes))
     }
 }
@@ -124,8 +150,14 @@ pub mod tests {
             content::{AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::{Attribute, EavBencher, ExampleAttribute},
+        eav::{
+            Attribute, EavBencher, EaviQuery, EntityAttributeValueIndex,
+            EntityAttributeValueStorage, ExampleAttribute, IndexFilter,
+        },
+        format::SerializationFormat,
+        reporting::ReportStorage,
     };
+    use std::collections::BTreeSet;
     use tempfile::tempdir;
 
     fn new_store<A: Attribute>() -> EavPickleStorage<A> {
@@ -225,6 +257,41 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn pickle_eav_reads_eavis_written_in_other_formats() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let mut json_storage = EavPickleStorage::new(temp.path());
+        // clones share the database
+        let mut cbor_storage = json_storage
+            .clone()
+            .with_serialization_format(SerializationFormat::Cbor);
+        let address = ExampleAddressableContent::try_from_content(&RawString::from("foo").into())
+            .unwrap()
+            .address();
+        let eavi = EntityAttributeValueIndex::new(&address, &ExampleAttribute::default(), &address)
+            .unwrap();
+
+        let expected: BTreeSet<_> = vec![
+            json_storage.add_eavi(&eavi).unwrap().unwrap(),
+            cbor_storage.add_eavi(&eavi).unwrap().unwrap(),
+        ]
+        .into_iter()
+        .collect();
+        let query = EaviQuery::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        assert_eq!(2, expected.len());
+        assert_eq!(expected, json_storage.fetch_eavi(&query).unwrap());
+        assert_eq!(
+            json_storage.get_storage_report(),
+            cbor_storage.get_storage_report()
+        );
+    }
+
     #[test]
     fn pickle_tombstone() {
         let temp = tempdir().expect("test was supposed to create temp dir");
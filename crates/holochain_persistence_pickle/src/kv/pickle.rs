@@ -0,0 +1,96 @@
+use holochain_persistence_api::{
+    error::{PersistenceError, PersistenceResult},
+    kv::{KvCursor, KvStorage},
+};
+
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use std::{
+    fmt::{Debug, Error, Formatter},
+    iter,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+const PERSISTENCE_INTERVAL: Duration = Duration::from_millis(5000);
+
+#[derive(Clone)]
+pub struct KvPickleStorage {
+    db: Arc<RwLock<PickleDb>>,
+}
+
+impl Debug for KvPickleStorage {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_struct("KvPickleStorage").finish()
+    }
+}
+
+impl KvPickleStorage {
+    pub fn new<P: AsRef<Path> + Clone>(db_path: P) -> KvPickleStorage {
+        let kv_db = db_path.as_ref().join("kv").with_extension("db");
+        KvPickleStorage {
+            db: Arc::new(RwLock::new(
+                PickleDb::load(
+                    kv_db.clone(),
+                    PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
+                    SerializationMethod::Cbor,
+                )
+                .unwrap_or_else(|_| {
+                    PickleDb::new(
+                        kv_db,
+                        PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
+                        SerializationMethod::Cbor,
+                    )
+                }),
+            )),
+        }
+    }
+}
+
+impl KvStorage for KvPickleStorage {
+    fn get(&self, key: &str) -> PersistenceResult<Option<Vec<u8>>> {
+        Ok(self.db.read()?.get::<Vec<u8>>(key))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> PersistenceResult<()> {
+        self.db
+            .write()?
+            .set(key, &value.to_vec())
+            .map_err(|e| PersistenceError::from(format!("pickle kv error: {}", e)))
+    }
+
+    fn delete(&self, key: &str) -> PersistenceResult<bool> {
+        self.db
+            .write()?
+            .rem(key)
+            .map_err(|e| PersistenceError::from(format!("pickle kv error: {}", e)))
+    }
+
+    fn scan<'a>(&'a self, prefix: &str) -> KvCursor<'a> {
+        let db = match self.db.read() {
+            Ok(db) => db,
+            Err(e) => return Box::new(iter::once(Err(e.into()))),
+        };
+        // pickle keeps its entries in a hash map, so they are sorted here
+        let mut entries: Vec<_> = db
+            .iter()
+            .filter(|kv| kv.get_key().starts_with(prefix))
+            .map(|kv| (kv.get_key().to_string(), kv.get_value().unwrap_or_default()))
+            .collect();
+        entries.sort();
+        Box::new(entries.into_iter().map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::pickle::KvPickleStorage;
+    use holochain_persistence_api::kv::test_kv_storage;
+    use tempfile::tempdir;
+
+    #[test]
+    fn pickle_kv_round_trip() {
+        let dir = tempdir().expect("Could not create a tempdir for KV testing");
+        test_kv_storage(KvPickleStorage::new(dir.path()));
+    }
+}
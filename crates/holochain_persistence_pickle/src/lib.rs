@@ -11,3 +11,34 @@ extern crate test;
 
 pub mod cas;
 pub mod eav;
+pub mod kv;
+
+use holochain_persistence_api::{
+    eav::Attribute,
+    error::PersistenceResult,
+    registry::{self, StorageManager, StorageUri},
+};
+use serde::de::DeserializeOwned;
+
+/// The CAS, EAV and key value stores in the directory of the URI, optionally with a `format` to write in.
+fn open<A>(uri: &StorageUri) -> PersistenceResult<StorageManager<A>>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    uri.check_params(&["format"])?;
+    let format = uri.param("format")?.unwrap_or_default();
+    Ok(StorageManager::new(
+        cas::pickle::PickleStorage::new(&uri.path).with_serialization_format(format),
+        eav::pickle::EavPickleStorage::new(&uri.path).with_serialization_format(format),
+        Default::default(),
+    )
+    .with_kv(kv::pickle::KvPickleStorage::new(&uri.path)))
+}
+
+/// Registers the pickle stores for `pickle:///path` URIs, see `registry::create_manager`.
+pub fn register_storage<A>() -> PersistenceResult<()>
+where
+    A: Attribute + Send + Sync + DeserializeOwned + 'static,
+{
+    registry::register("pickle", open::<A>)
+}
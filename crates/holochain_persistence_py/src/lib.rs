@@ -0,0 +1,199 @@
+//! Python bindings for the LMDB stores
+//!
+//! Lets data migration and analysis scripts read and write a store directly instead of going
+//! through the CLI dump format:
+//!
+//! ```python
+//! from holochain_persistence import LmdbStore
+//!
+//! store = LmdbStore("/path/to/db")
+//! address = store.add('"some content"')
+//! store.add_eavi(address, "name", address)
+//! store.fetch(address)         # '"some content"'
+//! store.query(attribute="name") # [{"entity": ..., "attribute": "name", ...}]
+//! ```
+//!
+//! Content goes in and comes out as JSON strings, EAVIs come out as dicts. Storage failures
+//! are raised as `RuntimeError`, content that isn't JSON as `ValueError`.
+#![warn(unused_extern_crates)]
+
+use holochain_json_api::json::JsonString;
+use holochain_persistence_api::{
+    cas::{
+        content::{Address, AddressableContent},
+        storage::ContentAddressableStorage,
+    },
+    eav::{
+        EntityAttributeValueIndex, EntityAttributeValueStorage, IndexFilter, OwnedEaviQuery,
+        StringAttribute,
+    },
+    error::PersistenceError,
+};
+use holochain_persistence_lmdb::{cas::lmdb::LmdbStorage, eav::lmdb::EavLmdbStorage};
+use pyo3::{
+    exceptions::{RuntimeError, ValueError},
+    prelude::*,
+    types::PyDict,
+};
+use std::{fs, path::Path};
+
+fn runtime_error<E: ToString>(e: E) -> PyErr {
+    RuntimeError::py_err(e.to_string())
+}
+
+fn persistence_error(e: PersistenceError) -> PyErr {
+    runtime_error(e)
+}
+
+fn eavi_dict(py: Python, eavi: &EntityAttributeValueIndex<StringAttribute>) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("entity", eavi.entity().to_string())?;
+    dict.set_item("attribute", eavi.attribute().0)?;
+    dict.set_item("value", eavi.value().to_string())?;
+    dict.set_item("index", eavi.index())?;
+    Ok(dict.to_object(py))
+}
+
+/// A CAS and an EAV store kept in the `cas` and `eav` subdirectories of one directory.
+#[pyclass]
+pub struct LmdbStore {
+    cas: LmdbStorage,
+    eav: EavLmdbStorage<StringAttribute>,
+}
+
+#[pymethods]
+impl LmdbStore {
+    /// Opens (or creates) the stores in the directory at `path`.
+    #[new]
+    #[args(initial_map_bytes = "None")]
+    fn new(path: &str, initial_map_bytes: Option<usize>) -> PyResult<Self> {
+        let path = Path::new(path);
+        let (cas_path, eav_path) = (path.join("cas"), path.join("eav"));
+        fs::create_dir_all(&cas_path).map_err(runtime_error)?;
+        fs::create_dir_all(&eav_path).map_err(runtime_error)?;
+        Ok(LmdbStore {
+            cas: LmdbStorage::new(cas_path, initial_map_bytes, None),
+            eav: EavLmdbStorage::new(eav_path, initial_map_bytes, None),
+        })
+    }
+
+    /// Adds JSON content and returns its address.
+    fn add(&mut self, content_json: &str) -> PyResult<String> {
+        serde_json::from_str::<serde_json::Value>(content_json)
+            .map_err(|e| ValueError::py_err(e.to_string()))?;
+        let content = JsonString::from_json(content_json);
+        self.cas.add(&content).map_err(persistence_error)?;
+        Ok(content.address().to_string())
+    }
+
+    /// Returns the JSON content stored at the address, or `None`.
+    fn fetch(&self, address: &str) -> PyResult<Option<String>> {
+        Ok(self
+            .cas
+            .fetch(&Address::from(address))
+            .map_err(persistence_error)?
+            .map(String::from))
+    }
+
+    fn contains(&self, address: &str) -> PyResult<bool> {
+        self.cas
+            .contains(&Address::from(address))
+            .map_err(persistence_error)
+    }
+
+    /// Adds an EAVI and returns the EAVI stored, as a dict.
+    fn add_eavi(
+        &mut self,
+        py: Python,
+        entity: &str,
+        attribute: &str,
+        value: &str,
+    ) -> PyResult<PyObject> {
+        let eavi = EntityAttributeValueIndex::new(
+            &Address::from(entity),
+            &StringAttribute(attribute.to_string()),
+            &Address::from(value),
+        )
+        .map_err(persistence_error)?;
+        let added = self
+            .eav
+            .add_eavi(&eavi)
+            .map_err(persistence_error)?
+            .unwrap_or(eavi);
+        eavi_dict(py, &added)
+    }
+
+    /// Returns the matching EAVIs as a list of dicts. `None` matches anything; with `latest`
+    /// only the newest EAVI per attribute is returned, otherwise every index.
+    #[args(entity = "None", attribute = "None", value = "None", latest = "true")]
+    fn query(
+        &self,
+        py: Python,
+        entity: Option<&str>,
+        attribute: Option<&str>,
+        value: Option<&str>,
+        latest: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        let index = if latest {
+            IndexFilter::LatestByAttribute
+        } else {
+            IndexFilter::Range(None, None)
+        };
+        let query = OwnedEaviQuery::new(
+            entity.map(Address::from),
+            attribute.map(|attribute| StringAttribute(attribute.to_string())),
+            value.map(Address::from),
+            index,
+            None,
+        );
+        let eavis = self
+            .eav
+            .fetch_eavi(&query.as_query())
+            .map_err(persistence_error)?;
+        eavis.iter().map(|eavi| eavi_dict(py, eavi)).collect()
+    }
+}
+
+#[pymodule]
+fn holochain_persistence(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<LmdbStore>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::IntoPyDict;
+    use tempfile::tempdir;
+
+    fn run(py: Python, store: &PyObject, code: &str) {
+        let locals = [("store", store)].into_py_dict(py);
+        py.run(code, None, Some(locals))
+            .map_err(|e| e.print(py))
+            .expect(code);
+    }
+
+    #[test]
+    fn content_and_eavis_from_python() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let locals = [("LmdbStore", py.get_type::<LmdbStore>())].into_py_dict(py);
+        let store: PyObject = py
+            .eval(
+                &format!("LmdbStore({:?})", temp.path().to_str().unwrap()),
+                None,
+                Some(locals),
+            )
+            .unwrap()
+            .to_object(py);
+
+        run(py, &store, "address = store.add('\"foo\"')\nassert store.fetch(address) == '\"foo\"'\nassert store.contains(address)\nassert store.fetch('QmMissing') is None");
+        run(py, &store, "address = store.add('\"foo\"')\neavi = store.add_eavi(address, 'name', address)\nassert eavi['attribute'] == 'name'\nassert store.query(attribute='name') == [eavi]\nassert store.query(entity='QmMissing') == []");
+        run(
+            py,
+            &store,
+            "try:\n    store.add('not json')\n    assert False\nexcept ValueError:\n    pass",
+        );
+    }
+}
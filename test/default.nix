@@ -9,7 +9,14 @@ let
   && hn-rust-clippy \
   && cargo test
   '';
+
+  # the python bindings are left out of the workspace, they need an interpreter to build
+  py-script = pkgs.writeShellScriptBin "${name}-py"
+  ''
+  RUST_BACKTRACE=1 \
+  cargo test --manifest-path crates/holochain_persistence_py/Cargo.toml
+  '';
 in
 {
- buildInputs = [ script ];
+ buildInputs = [ script py-script pkgs.python3 ];
 }